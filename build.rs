@@ -0,0 +1,41 @@
+use std::{
+    env,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Embed build information, see `src/build_info.rs`.
+fn main() {
+    // builds without a git checkout (e.g. in a container) can pass the SHA explicitly
+    let git_sha = env::var("SERO_GIT_SHA").ok().or_else(|| {
+        let output = Command::new("git")
+            .args(["rev-parse", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())?;
+        Some(String::from_utf8(output.stdout).ok()?.trim().to_owned())
+    });
+    println!(
+        "cargo:rustc-env=SERO_GIT_SHA={}",
+        git_sha.as_deref().unwrap_or("unknown")
+    );
+
+    let build_timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=SERO_BUILD_TIMESTAMP={build_timestamp}");
+
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| {
+            let feature = key.strip_prefix("CARGO_FEATURE_")?;
+            Some(feature.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=SERO_FEATURES={}", features.join(","));
+
+    println!("cargo:rerun-if-env-changed=SERO_GIT_SHA");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
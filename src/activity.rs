@@ -0,0 +1,336 @@
+use crate::endpoint_watcher::EndpointWatcherHandle;
+use crate::metrics;
+use crate::status::PlannedAction;
+use crate::tasks;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use std::{
+    collections::VecDeque,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::watch;
+use tracing::*;
+
+/// Bounds of the adaptive idle timeout. Longer gaps between connections are treated as
+/// separate sessions, which waiting would not have bridged.
+const AUTO_MIN: Duration = Duration::from_secs(30);
+const AUTO_MAX: Duration = Duration::from_secs(60 * 60);
+/// Effective idle timeout until enough gaps have been observed
+const AUTO_INITIAL: Duration = Duration::from_secs(5 * 60);
+const AUTO_MIN_SAMPLES: usize = 10;
+const MAX_GAPS: usize = 1024;
+
+/// How long the backend may be idle before it is scaled down.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IdleTimeout {
+    Fixed(Duration),
+    /// Adapt to the gaps between connections: the 99th percentile gap within sessions plus 25%.
+    Auto,
+}
+
+impl FromStr for IdleTimeout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "auto" {
+            Ok(IdleTimeout::Auto)
+        } else {
+            Ok(IdleTimeout::Fixed(humantime::parse_duration(s)?))
+        }
+    }
+}
+
+/// Client activity of a service, which decides when its backend may go to sleep.
+#[derive(Clone, Copy, Debug)]
+pub struct Activity {
+    pub active_connections: usize,
+    /// Connections reported by sidecars in backend pods, including ones not proxied by sero
+    pub remote_connections: usize,
+    /// Most simultaneous connections during the current awake period
+    pub peak_connections: usize,
+    pub last_activity: DateTime<Utc>,
+    /// Currently effective idle timeout, if any
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Activity {
+    /// What happens next if nothing changes.
+    pub fn planned_action(&self) -> PlannedAction {
+        let active_connections = self.active_connections + self.remote_connections;
+        if active_connections > 0 {
+            return PlannedAction::BlockedByConnections { active_connections };
+        }
+        match self
+            .idle_timeout
+            .and_then(|timeout| chrono::Duration::from_std(timeout).ok())
+        {
+            Some(timeout) => PlannedAction::ScaleDown {
+                at: self.last_activity + timeout,
+            },
+            None => PlannedAction::None,
+        }
+    }
+}
+
+/// Recent gaps between new connections, used by `IdleTimeout::Auto`.
+#[derive(Default)]
+struct Gaps {
+    last_opened: Option<Instant>,
+    gaps: VecDeque<Duration>,
+}
+
+impl Gaps {
+    /// Record a new connection and return the updated idle timeout.
+    fn record(&mut self) -> Duration {
+        let now = Instant::now();
+        if let Some(gap) = self.last_opened.map(|last| now - last) {
+            if gap <= AUTO_MAX {
+                if self.gaps.len() == MAX_GAPS {
+                    self.gaps.pop_front();
+                }
+                self.gaps.push_back(gap);
+            }
+        }
+        self.last_opened = Some(now);
+        self.timeout()
+    }
+
+    fn timeout(&self) -> Duration {
+        if self.gaps.len() < AUTO_MIN_SAMPLES {
+            return AUTO_INITIAL;
+        }
+        let mut gaps: Vec<Duration> = self.gaps.iter().copied().collect();
+        gaps.sort_unstable();
+        let p99 = gaps[(gaps.len() - 1) * 99 / 100];
+        (p99 + p99 / 4).clamp(AUTO_MIN, AUTO_MAX)
+    }
+}
+
+/// Decrements the number of active connections when dropped.
+pub struct ConnectionGuard {
+    sender: Arc<watch::Sender<Activity>>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.sender.send_modify(|activity| {
+            activity.active_connections -= 1;
+            activity.last_activity = Utc::now();
+        });
+    }
+}
+
+#[derive(Clone)]
+pub struct ActivityHandle {
+    sender: Arc<watch::Sender<Activity>>,
+    gaps: Option<Arc<Mutex<Gaps>>>,
+}
+
+impl ActivityHandle {
+    pub fn new(
+        deploy_name: &str,
+        idle_timeout: Option<IdleTimeout>,
+        endpoints: EndpointWatcherHandle,
+    ) -> Self {
+        let (sender, _) = watch::channel(Activity {
+            active_connections: 0,
+            remote_connections: 0,
+            peak_connections: 0,
+            last_activity: Utc::now(),
+            idle_timeout: match idle_timeout {
+                Some(IdleTimeout::Fixed(timeout)) => Some(timeout),
+                Some(IdleTimeout::Auto) => Some(AUTO_INITIAL),
+                None => None,
+            },
+        });
+        let sender = Arc::new(sender);
+        let gaps = (idle_timeout == Some(IdleTimeout::Auto)).then(Default::default);
+        tasks::spawn(
+            "activity",
+            track(deploy_name.to_owned(), sender.clone(), endpoints),
+        );
+        ActivityHandle { sender, gaps }
+    }
+
+    /// Count a connection as active until the returned guard is dropped.
+    pub fn connection_opened(&self) -> ConnectionGuard {
+        let idle_timeout = self
+            .gaps
+            .as_ref()
+            .map(|gaps| gaps.lock().unwrap_or_else(|e| e.into_inner()).record());
+        self.sender.send_modify(|activity| {
+            activity.active_connections += 1;
+            activity.peak_connections = activity.peak_connections.max(activity.active_connections);
+            activity.last_activity = Utc::now();
+            if idle_timeout.is_some() {
+                activity.idle_timeout = idle_timeout;
+            }
+        });
+        ConnectionGuard {
+            sender: self.sender.clone(),
+        }
+    }
+
+    /// Take connections and the latest activity reported by sidecars into account.
+    pub fn set_remote(&self, connections: usize, last_activity: Option<DateTime<Utc>>) {
+        self.sender.send_if_modified(|activity| {
+            let before = (activity.remote_connections, activity.last_activity);
+            activity.remote_connections = connections;
+            if let Some(last_activity) = last_activity {
+                activity.last_activity = activity.last_activity.max(last_activity);
+            }
+            before != (activity.remote_connections, activity.last_activity)
+        });
+    }
+
+    pub fn activity(&self) -> Activity {
+        *self.sender.borrow()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Activity> {
+        self.sender.subscribe()
+    }
+
+    /// Wait until all proxied connections are closed, or until the timeout expires.
+    pub async fn drain(&self, timeout: Duration) {
+        let mut receiver = self.subscribe();
+        let active_connections = receiver.borrow_and_update().active_connections;
+        if active_connections == 0 {
+            return;
+        }
+        info!("Draining {active_connections} active connections for up to {timeout:?}.");
+        let drained = async {
+            while receiver.borrow_and_update().active_connections > 0 {
+                if receiver.changed().await.is_err() {
+                    return;
+                }
+            }
+        };
+        if tokio::time::timeout(timeout, drained).await.is_err() {
+            warn!(
+                "Dropping {} connections which did not finish within {timeout:?}.",
+                self.activity().active_connections
+            );
+        }
+    }
+}
+
+/// Reset the peak at the start of each awake period and keep the metrics up to date.
+async fn track(
+    deploy_name: String,
+    sender: Arc<watch::Sender<Activity>>,
+    mut endpoints: EndpointWatcherHandle,
+) {
+    let active_connections = metrics::ACTIVE_CONNECTIONS.with_label_values(&[&deploy_name]);
+    let peak_connections = metrics::PEAK_CONNECTIONS.with_label_values(&[&deploy_name]);
+    let idle_timeout = metrics::IDLE_TIMEOUT.with_label_values(&[&deploy_name]);
+    let scale_down_at = metrics::SCALE_DOWN_SCHEDULED.with_label_values(&[&deploy_name]);
+    let mut receiver = sender.subscribe();
+    let mut was_serving = endpoints.backend_is_serving();
+    loop {
+        let is_serving = endpoints.backend_is_serving();
+        if is_serving != was_serving {
+            if was_serving {
+                let peak = receiver.borrow().peak_connections;
+                info!("Awake period of deployment/{deploy_name} ended with a peak of {peak} concurrent connections.");
+                metrics::WAKE_PEAK_CONNECTIONS
+                    .with_label_values(&[&deploy_name])
+                    .observe(peak as f64);
+            }
+            sender.send_modify(|activity| {
+                activity.peak_connections = activity.active_connections;
+                // give a backend woken without a connection a full idle period
+                if is_serving {
+                    activity.last_activity = activity.last_activity.max(Utc::now());
+                }
+            });
+            was_serving = is_serving;
+        }
+
+        let activity = *receiver.borrow_and_update();
+        active_connections.set(activity.active_connections as i64);
+        peak_connections.set(activity.peak_connections as i64);
+        idle_timeout.set(activity.idle_timeout.map_or(0, |t| t.as_secs() as i64));
+        match activity.planned_action() {
+            PlannedAction::ScaleDown { at } => scale_down_at.set(at.timestamp()),
+            _ => scale_down_at.set(0),
+        }
+
+        tokio::select! {
+            _ = receiver.changed() => {},
+            _ = endpoints.changed() => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_idle_timeouts() {
+        assert_eq!("auto".parse::<IdleTimeout>().unwrap(), IdleTimeout::Auto);
+        assert_eq!(
+            "5m".parse::<IdleTimeout>().unwrap(),
+            IdleTimeout::Fixed(Duration::from_secs(300))
+        );
+        assert!("soon".parse::<IdleTimeout>().is_err());
+    }
+
+    fn gaps(secs: impl IntoIterator<Item = u64>) -> Gaps {
+        Gaps {
+            last_opened: None,
+            gaps: secs.into_iter().map(Duration::from_secs).collect(),
+        }
+    }
+
+    #[test]
+    fn auto_timeout_waits_for_enough_gaps() {
+        assert_eq!(gaps([60; AUTO_MIN_SAMPLES - 1]).timeout(), AUTO_INITIAL);
+    }
+
+    #[test]
+    fn auto_timeout_bridges_gaps_within_sessions() {
+        assert_eq!(
+            gaps([60; AUTO_MIN_SAMPLES]).timeout(),
+            Duration::from_secs(75)
+        );
+        assert_eq!(gaps([1; AUTO_MIN_SAMPLES]).timeout(), AUTO_MIN);
+        assert_eq!(gaps([3000; AUTO_MIN_SAMPLES]).timeout(), AUTO_MAX);
+    }
+
+    #[test]
+    fn plans_scale_down_once_idle() {
+        let activity = Activity {
+            active_connections: 0,
+            remote_connections: 0,
+            peak_connections: 0,
+            last_activity: Utc::now(),
+            idle_timeout: Some(Duration::from_secs(60)),
+        };
+        assert_eq!(
+            activity.planned_action(),
+            PlannedAction::ScaleDown {
+                at: activity.last_activity + chrono::Duration::seconds(60)
+            }
+        );
+        let busy = Activity {
+            remote_connections: 2,
+            ..activity
+        };
+        assert_eq!(
+            busy.planned_action(),
+            PlannedAction::BlockedByConnections {
+                active_connections: 2
+            }
+        );
+        let unbounded = Activity {
+            idle_timeout: None,
+            ..activity
+        };
+        assert_eq!(unbounded.planned_action(), PlannedAction::None);
+    }
+}
@@ -0,0 +1,346 @@
+use crate::activity::ActivityHandle;
+use crate::build_info::BuildInfo;
+use crate::endpoint_watcher::EndpointWatcherHandle;
+use crate::injector::InjectorHandle;
+use crate::metrics;
+use crate::next_wake_debug::NextWakeDebugHandle;
+use crate::proxy::ConnectionRegistry;
+use crate::scaler::{
+    ScaleDownDeferred, ScalerHandle, SleepCause, Target, WakeCause, WakeSuppressed,
+};
+use crate::self_test::SelfTestHandle;
+use crate::status::{ServiceStatus, WakeRecord};
+use crate::tasks;
+use crate::usage::UsageHandle;
+use crate::wake_history::WakeHistoryHandle;
+
+use anyhow::Result;
+use hyper::{
+    header::{HeaderValue, CONTENT_TYPE},
+    server::{conn::AddrIncoming, Builder},
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use kube::Client;
+use serde::Serialize;
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+use tokio::sync::watch;
+use tracing::*;
+
+/// Handles to everything the admin API reports on.
+#[derive(Clone)]
+pub struct AdminState {
+    pub service: String,
+    pub deployment: String,
+    pub build_info: Arc<BuildInfo>,
+    pub listen_addrs: watch::Receiver<Vec<SocketAddr>>,
+    pub endpoints: EndpointWatcherHandle,
+    pub activity: ActivityHandle,
+    pub usage: UsageHandle,
+    pub self_test: Option<SelfTestHandle>,
+    pub wakes: WakeHistoryHandle,
+    pub next_wake_debug: NextWakeDebugHandle,
+    pub target: Target,
+    pub client: Arc<Client>,
+    pub scaler: ScalerHandle,
+    pub injector: Option<InjectorHandle>,
+    pub connections: ConnectionRegistry,
+}
+
+/// Connections captured after the next wake unless `POST /debug/next-wake` asks otherwise.
+const DEFAULT_CAPTURED_CONNECTIONS: usize = 10;
+
+pub struct AdminServer {
+    addr: SocketAddr,
+    builder: Builder<AddrIncoming>,
+    state: AdminState,
+    /// Only serve GET requests of routes safe to expose to the cluster, e.g. to Prometheus or
+    /// application developers
+    read_only: bool,
+}
+
+impl AdminServer {
+    pub fn try_new(addr: SocketAddr, state: AdminState) -> Result<Self> {
+        let builder = Server::try_bind(&addr)?;
+        Ok(AdminServer {
+            addr,
+            builder,
+            state,
+            read_only: false,
+        })
+    }
+
+    /// Serve status, metrics, usage and wake history only, e.g. for application developers.
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    pub async fn run(self) {
+        let (state, read_only) = (self.state, self.read_only);
+        match read_only {
+            true => info!("Serving read-only admin API on {}.", self.addr),
+            false => info!("Serving admin API on {}.", self.addr),
+        }
+        let make_svc = make_service_fn(move |_conn| {
+            let state = state.clone();
+            let svc = service_fn(move |req| handle_request(req, state.clone(), read_only));
+            async move { Ok::<_, Infallible>(svc) }
+        });
+        if let Err(e) = self.builder.serve(make_svc).await {
+            error!("Error while serving admin API: {e}");
+        }
+    }
+}
+
+async fn handle_request(
+    req: Request<Body>,
+    state: AdminState,
+    read_only: bool,
+) -> Result<Response<Body>, Infallible> {
+    if read_only && (req.method() != Method::GET || req.uri().path().starts_with("/debug/")) {
+        return Ok(status_response(StatusCode::NOT_FOUND));
+    }
+    let res = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/healthz") => Response::new(Body::from("ok")),
+        (&Method::GET, "/readyz") => readiness(&state),
+        (&Method::GET, "/health") => health(&state),
+        (&Method::GET, "/metrics") => Response::new(Body::from(metrics::gather())),
+        (&Method::GET, "/version") => json_response(&*state.build_info),
+        (&Method::GET, "/status") => json_response(&service_status(&state).await),
+        (&Method::GET, "/wakes") => json_response(&state.wakes.cycles()),
+        (&Method::GET, "/debug/tasks") => json_response(&tasks::running()),
+        (&Method::POST, "/debug/next-wake") => capture_next_wake(&req, &state),
+        (&Method::POST, "/wake") => wake(&state).await,
+        (&Method::POST, "/sleep") => sleep(&state).await,
+        (&Method::POST, "/drain") => drain(&state).await,
+        (&Method::POST, "/inject") => inject(&state, true).await,
+        (&Method::POST, "/eject") => inject(&state, false).await,
+        (&Method::GET, "/usage") => match state.usage.report().await {
+            Ok(report) => json_response(&report),
+            Err(e) => {
+                error!("Error while collecting usage report: {e}");
+                status_response(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        },
+        _ => status_response(StatusCode::NOT_FOUND),
+    };
+    Ok(res)
+}
+
+/// Ready once the listeners are bound and the EndpointSlices were listed. The kube client
+/// is connected before the admin server starts.
+fn readiness(state: &AdminState) -> Response<Body> {
+    let failed = readiness_failures(state);
+    if failed.is_empty() {
+        return Response::new(Body::from("ok"));
+    }
+    let mut res = Response::new(Body::from(failed.join("\n")));
+    *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    res
+}
+
+fn readiness_failures(state: &AdminState) -> Vec<String> {
+    let mut failed = Vec::new();
+    if state.listen_addrs.borrow().is_empty() {
+        failed.push("listeners not bound".to_owned());
+    }
+    if !state.endpoints.is_synced() {
+        failed.push("endpointslices not synced".to_owned());
+    }
+    if let Some(blocker) = state
+        .self_test
+        .as_ref()
+        .and_then(SelfTestHandle::readiness_blocker)
+    {
+        failed.push(blocker);
+    }
+    failed
+}
+
+/// Health of the service for load balancers and ingress controllers in front of sero.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+enum HealthState {
+    Serving,
+    /// The backend sleeps and is woken up by the next request, answered with 200 as well
+    Asleep,
+    /// sero cannot serve, or the backend keeps failing, answered with 503
+    Broken,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Health {
+    state: HealthState,
+    problems: Vec<String>,
+}
+
+fn health(state: &AdminState) -> Response<Body> {
+    let mut problems = readiness_failures(state);
+    if state.endpoints.is_flapping() {
+        problems.push("backend endpoints are flapping".to_owned());
+    }
+    let health = Health {
+        state: if !problems.is_empty() {
+            HealthState::Broken
+        } else if state.endpoints.backend_is_serving() {
+            HealthState::Serving
+        } else {
+            HealthState::Asleep
+        },
+        problems,
+    };
+    let mut res = json_response(&health);
+    if health.state == HealthState::Broken {
+        *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    }
+    res
+}
+
+/// Arm verbose logging for the next wake and the first `?connections=N` after it.
+fn capture_next_wake(req: &Request<Body>, state: &AdminState) -> Response<Body> {
+    let connections = req
+        .uri()
+        .query()
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .find_map(|pair| pair.strip_prefix("connections="))
+        .map(str::parse::<usize>)
+        .unwrap_or(Ok(DEFAULT_CAPTURED_CONNECTIONS));
+    match connections {
+        Ok(connections) if connections > 0 => {
+            if state.next_wake_debug.arm(connections) {
+                status_response(StatusCode::ACCEPTED)
+            } else {
+                status_response(StatusCode::CONFLICT)
+            }
+        }
+        _ => status_response(StatusCode::BAD_REQUEST),
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WakeResponse {
+    /// Whether the backend had to be woken up, as opposed to already serving
+    woke: bool,
+}
+
+/// Wake the backend and answer once it is serving. It goes back to sleep once idle.
+async fn wake(state: &AdminState) -> Response<Body> {
+    match state.scaler.ensure_up(WakeCause::AdminApi).await {
+        Ok(woke) => json_response(&WakeResponse { woke }),
+        Err(e) if e.is::<WakeSuppressed>() => text_response(StatusCode::CONFLICT, e),
+        Err(e) => {
+            error!("Error while waking the backend on request: {e}");
+            text_response(StatusCode::INTERNAL_SERVER_ERROR, e)
+        }
+    }
+}
+
+/// Put the backend to sleep and answer once it no longer serves, even with active connections.
+async fn sleep(state: &AdminState) -> Response<Body> {
+    match state.scaler.ensure_down(SleepCause::AdminApi).await {
+        Ok(()) => status_response(StatusCode::OK),
+        Err(e) if e.is::<ScaleDownDeferred>() => text_response(StatusCode::CONFLICT, e),
+        Err(e) => {
+            error!("Error while putting the backend to sleep on request: {e}");
+            text_response(StatusCode::INTERNAL_SERVER_ERROR, e)
+        }
+    }
+}
+
+/// Stop waking and sleeping the backend, e.g. before sero is replaced. Cannot be undone.
+async fn drain(state: &AdminState) -> Response<Body> {
+    match state.scaler.drain().await {
+        Ok(()) => status_response(StatusCode::ACCEPTED),
+        Err(e) => text_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+/// Add sero's addresses to the EndpointSlices of the service, or remove them.
+async fn inject(state: &AdminState, inject: bool) -> Response<Body> {
+    let Some(injector) = &state.injector else {
+        return text_response(
+            StatusCode::CONFLICT,
+            "sero does not manage EndpointSlices in this topology",
+        );
+    };
+    let result = match inject {
+        true => injector.inject().await,
+        false => injector.eject().await,
+    };
+    match result {
+        Ok(()) => status_response(StatusCode::ACCEPTED),
+        Err(e) => text_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+/// Read from the target rather than asking the scaler, which is busy while it wakes the backend.
+async fn replicas(state: &AdminState) -> Option<i32> {
+    match state
+        .target
+        .api(&state.client)
+        .get(&state.target.name)
+        .await
+    {
+        Ok(target) => target.data["spec"]["replicas"]
+            .as_i64()
+            .map(|replicas| replicas as i32),
+        Err(e) => {
+            warn!("Could not read the replicas of {}: {e}", state.target);
+            None
+        }
+    }
+}
+
+async fn service_status(state: &AdminState) -> ServiceStatus {
+    let activity = state.activity.activity();
+    ServiceStatus {
+        listen_addrs: state.listen_addrs.borrow().clone(),
+        endpoints: state.endpoints.status(),
+        replicas: replicas(state).await,
+        connections: state.connections.list(),
+        active_connections: activity.active_connections,
+        peak_connections: activity.peak_connections,
+        idle_timeout_ms: activity.idle_timeout.map(|t| t.as_millis() as u64),
+        next_action: activity.planned_action(),
+        last_wake: state.wakes.cycles().last().map(|cycle| WakeRecord {
+            started_at: cycle.woke_at
+                - chrono::Duration::milliseconds(cycle.wake_duration_ms as i64),
+            completed_at: Some(cycle.woke_at),
+            duration_ms: Some(cycle.wake_duration_ms),
+        }),
+        ..ServiceStatus::new(&state.service, &state.deployment)
+    }
+}
+
+fn json_response<T: Serialize>(value: &T) -> Response<Body> {
+    match serde_json::to_string(value) {
+        Ok(json) => {
+            let mut res = Response::new(Body::from(json));
+            res.headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            res
+        }
+        Err(e) => {
+            error!("Error while serializing response: {e}");
+            status_response(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+fn text_response(status: StatusCode, text: impl ToString) -> Response<Body> {
+    let mut res = Response::new(Body::from(text.to_string()));
+    *res.status_mut() = status;
+    res
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    let mut res = Response::new(Body::from(
+        status.canonical_reason().unwrap_or_default().to_owned(),
+    ));
+    *res.status_mut() = status;
+    res
+}
@@ -0,0 +1,202 @@
+use crate::metrics;
+
+use crate::tasks;
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use kube::{client::ClientBuilder, Client, Config};
+use std::{
+    path::Path,
+    sync::Once,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tower::{Layer, Service};
+use tracing::*;
+
+/// Projected service account token, which the kubelet rotates and kube reloads from disk.
+const TOKEN_FILE: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
+/// How often the token file is checked for rotations.
+const TOKEN_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+static WATCH_TOKEN: Once = Once::new();
+
+/// Create a kube client which records the latency of every API call.
+pub async fn try_client() -> Result<Client> {
+    try_client_in(None).await
+}
+
+/// Like `try_client`, but defaulting to `namespace` instead of the inferred namespace.
+pub async fn try_client_in(namespace: Option<&str>) -> Result<Client> {
+    let mut config = Config::infer().await?;
+    if let Some(namespace) = namespace {
+        config.default_namespace = namespace.to_owned();
+    }
+    let client = ClientBuilder::try_from(config)?
+        .with_layer(&ApiMetricsLayer)
+        .build();
+    if Path::new(TOKEN_FILE).exists() {
+        WATCH_TOKEN.call_once(|| tasks::spawn("token-refreshes", count_token_refreshes()));
+    }
+    Ok(client)
+}
+
+/// Count rotations of the service account token, so that expired tokens can be told apart
+/// from tokens which were never refreshed.
+async fn count_token_refreshes() {
+    let mut interval = tokio::time::interval(TOKEN_CHECK_INTERVAL);
+    let mut last_modified = None;
+    loop {
+        interval.tick().await;
+        let modified = tokio::fs::metadata(TOKEN_FILE)
+            .await
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        if let (Some(last), Some(current)) = (last_modified, modified) {
+            if current != last {
+                debug!("Service account token was rotated.");
+                metrics::SERVICE_ACCOUNT_TOKEN_REFRESHES.inc();
+            }
+        }
+        last_modified = modified.or(last_modified);
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct ApiMetricsLayer;
+
+impl<S> Layer<S> for ApiMetricsLayer {
+    type Service = ApiMetrics<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ApiMetrics { inner }
+    }
+}
+
+/// Records `sero_kube_api_request_duration_seconds` by verb, resource and status code.
+#[derive(Clone)]
+pub struct ApiMetrics<S> {
+    inner: S,
+}
+
+impl<S, B> Service<Request<Body>> for ApiMetrics<S>
+where
+    S: Service<Request<Body>, Response = Response<B>>,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+    B: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let (verb, resource) = classify(&req);
+        let start = Instant::now();
+        let res = self.inner.call(req);
+        Box::pin(async move {
+            let res = res.await;
+            let code = match &res {
+                Ok(res) => res.status().as_str().to_owned(),
+                Err(_) => "error".to_owned(),
+            };
+            if matches!(&res, Ok(res) if res.status() == StatusCode::UNAUTHORIZED) {
+                warn!("Kube API rejected sero's credentials for {verb} {resource}. The service account token may have expired or been revoked, see sero_service_account_token_refreshes_total.");
+            }
+            metrics::KUBE_API_DURATION
+                .with_label_values(&[verb, &resource, &code])
+                .observe(start.elapsed().as_secs_f64());
+            res
+        })
+    }
+}
+
+/// Derive the kube API verb and resource (including subresource) of a request, e.g.
+/// `PATCH /apis/apps/v1/namespaces/default/deployments/app/scale` is `patch deployments/scale`.
+fn classify(req: &Request<Body>) -> (&'static str, String) {
+    let segments: Vec<&str> = req.uri().path().split('/').skip(1).collect();
+    let mut rest = match segments.first() {
+        Some(&"api") => segments.get(2..).unwrap_or_default(),
+        Some(&"apis") => segments.get(3..).unwrap_or_default(),
+        _ => &[],
+    };
+    if rest.first() == Some(&"namespaces") && rest.len() >= 3 {
+        rest = &rest[2..];
+    }
+    let resource = match rest {
+        [] => "unknown".to_owned(),
+        [resource] | [resource, _] => (*resource).to_owned(),
+        [resource, _, subresource, ..] => format!("{resource}/{subresource}"),
+    };
+    let named = rest.len() >= 2;
+    let is_watch = req
+        .uri()
+        .query()
+        .map(|query| query.split('&').any(|param| param == "watch=true"))
+        == Some(true);
+    let verb = match *req.method() {
+        Method::GET if is_watch => "watch",
+        Method::GET if named => "get",
+        Method::GET => "list",
+        Method::POST => "create",
+        Method::PUT => "update",
+        Method::PATCH => "patch",
+        Method::DELETE if named => "delete",
+        Method::DELETE => "deletecollection",
+        _ => "other",
+    };
+    (verb, resource)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classified(method: Method, uri: &str) -> (&'static str, String) {
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap();
+        classify(&req)
+    }
+
+    #[test]
+    fn classifies_requests_by_verb_and_resource() {
+        let cases = [
+            (Method::GET, "/api/v1/namespaces/default/pods", "list", "pods"),
+            (Method::GET, "/api/v1/namespaces/default/pods/web-0", "get", "pods"),
+            (
+                Method::GET,
+                "/apis/discovery.k8s.io/v1/namespaces/default/endpointslices?labelSelector=a&watch=true",
+                "watch",
+                "endpointslices",
+            ),
+            (
+                Method::PATCH,
+                "/apis/apps/v1/namespaces/default/deployments/app/scale",
+                "patch",
+                "deployments/scale",
+            ),
+            (Method::POST, "/apis/coordination.k8s.io/v1/namespaces/default/leases", "create", "leases"),
+            (Method::PUT, "/api/v1/namespaces/default/configmaps/usage", "update", "configmaps"),
+            (Method::DELETE, "/api/v1/namespaces/default/pods/web-0", "delete", "pods"),
+            (Method::DELETE, "/api/v1/namespaces/default/pods", "deletecollection", "pods"),
+            (Method::GET, "/api/v1/namespaces", "list", "namespaces"),
+            (Method::GET, "/api/v1/namespaces/default", "get", "namespaces"),
+            (Method::GET, "/version", "list", "unknown"),
+        ];
+        for (method, uri, verb, resource) in cases {
+            assert_eq!(
+                classified(method, uri),
+                (verb, resource.to_owned()),
+                "{uri}"
+            );
+        }
+    }
+}
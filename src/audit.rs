@@ -0,0 +1,341 @@
+use crate::metrics;
+use crate::scaler::WakeCause;
+use crate::tasks;
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use hyper::{Body, Client, Request};
+use serde::Serialize;
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+    net::UdpSocket,
+    sync::mpsc,
+};
+use tracing::*;
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+const BATCH_SIZE: usize = 256;
+
+/// A single audit record, emitted once per accepted connection.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditRecord {
+    pub timestamp: DateTime<Utc>,
+    pub service: String,
+    pub client: SocketAddr,
+    pub backend: String,
+    pub outcome: Outcome,
+    pub wake_cause: Option<WakeCause>,
+    pub bytes_from_client: u64,
+    pub bytes_from_backend: u64,
+    pub duration_ms: u64,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Outcome {
+    Proxied,
+    Denied,
+    HandshakeFailed,
+    BackendUnavailable,
+    ConnectFailed,
+    ProxyFailed,
+}
+
+/// Where audit records are persisted to.
+#[derive(Clone, Debug)]
+pub enum AuditSinkConfig {
+    /// Append JSON lines to a file, rotating it once it grows too large
+    File(PathBuf),
+    /// Send RFC 5424 messages to a syslog daemon via UDP
+    Syslog(SocketAddr),
+    /// POST batches of JSON lines to an HTTP endpoint
+    Http(String),
+}
+
+impl FromStr for AuditSinkConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(path) = s.strip_prefix("file:") {
+            Ok(AuditSinkConfig::File(PathBuf::from(path)))
+        } else if let Some(addr) = s.strip_prefix("syslog:") {
+            Ok(AuditSinkConfig::Syslog(
+                addr.parse().context("Invalid syslog address.")?,
+            ))
+        } else if s.starts_with("http://") {
+            Ok(AuditSinkConfig::Http(s.to_owned()))
+        } else {
+            bail!("Unknown audit sink '{s}', expected one of file:<PATH>, syslog:<ADDR> or http://<URL>.")
+        }
+    }
+}
+
+enum Sink {
+    File {
+        path: PathBuf,
+        file: File,
+        size: u64,
+        max_bytes: u64,
+        max_files: usize,
+    },
+    Syslog {
+        socket: UdpSocket,
+        addr: SocketAddr,
+        hostname: String,
+    },
+    Http {
+        url: String,
+        client: Client<hyper::client::HttpConnector>,
+    },
+}
+
+impl Sink {
+    async fn try_new(config: AuditSinkConfig, max_bytes: u64, max_files: usize) -> Result<Self> {
+        let sink = match config {
+            AuditSinkConfig::File(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .await?;
+                let size = file.metadata().await?.len();
+                Sink::File {
+                    path,
+                    file,
+                    size,
+                    max_bytes,
+                    max_files,
+                }
+            }
+            AuditSinkConfig::Syslog(addr) => {
+                let bind_addr = if addr.is_ipv4() {
+                    "0.0.0.0:0"
+                } else {
+                    "[::]:0"
+                };
+                let hostname = hostname::get()?
+                    .to_str()
+                    .context("Hostname is not valid UTF8")?
+                    .to_owned();
+                Sink::Syslog {
+                    socket: UdpSocket::bind(bind_addr).await?,
+                    addr,
+                    hostname,
+                }
+            }
+            AuditSinkConfig::Http(url) => Sink::Http {
+                url,
+                client: Client::new(),
+            },
+        };
+        Ok(sink)
+    }
+
+    async fn write_batch(&mut self, records: &[AuditRecord]) -> Result<()> {
+        match self {
+            Sink::File {
+                path,
+                file,
+                size,
+                max_bytes,
+                max_files,
+            } => {
+                for record in records {
+                    let mut line = serde_json::to_vec(record)?;
+                    line.push(b'\n');
+                    if *size > 0 && *size + line.len() as u64 > *max_bytes {
+                        *file = rotate(path, *max_files).await?;
+                        *size = 0;
+                    }
+                    file.write_all(&line).await?;
+                    *size += line.len() as u64;
+                }
+                file.flush().await?;
+            }
+            Sink::Syslog {
+                socket,
+                addr,
+                hostname,
+            } => {
+                for record in records {
+                    // facility local0, severity informational
+                    let msg = format!(
+                        "<134>1 {} {hostname} sero - audit - {}",
+                        record.timestamp.to_rfc3339(),
+                        serde_json::to_string(record)?
+                    );
+                    socket.send_to(msg.as_bytes(), *addr).await?;
+                }
+            }
+            Sink::Http { url, client } => {
+                let mut body = Vec::new();
+                for record in records {
+                    serde_json::to_writer(&mut body, record)?;
+                    body.push(b'\n');
+                }
+                let req = Request::post(url.as_str())
+                    .header("content-type", "application/x-ndjson")
+                    .body(Body::from(body))?;
+                let res = client.request(req).await?;
+                if !res.status().is_success() {
+                    bail!("Audit endpoint responded with {}", res.status());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Shift `path.1` .. `path.{max_files - 1}` up by one, move `path` to `path.1` and reopen `path`.
+async fn rotate(path: &Path, max_files: usize) -> Result<File> {
+    let rotated = |i: usize| PathBuf::from(format!("{}.{i}", path.display()));
+    for i in (1..max_files).rev() {
+        if fs::metadata(rotated(i)).await.is_ok() {
+            fs::rename(rotated(i), rotated(i + 1)).await?;
+        }
+    }
+    if max_files > 0 {
+        fs::rename(path, rotated(1)).await?;
+    } else {
+        fs::remove_file(path).await?;
+    }
+    debug!("Rotated audit log {}.", path.display());
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    Ok(file)
+}
+
+struct AuditLog {
+    receiver: mpsc::Receiver<AuditRecord>,
+    sink: Sink,
+    dropped: Arc<AtomicU64>,
+}
+
+impl AuditLog {
+    async fn flush(&mut self, batch: &mut Vec<AuditRecord>) {
+        if batch.is_empty() {
+            return;
+        }
+        if let Err(e) = self.sink.write_batch(batch).await {
+            error!(
+                "Error while writing {} audit records, dropping them: {e}",
+                batch.len()
+            );
+            self.dropped
+                .fetch_add(batch.len() as u64, Ordering::Relaxed);
+            metrics::AUDIT_RECORDS_DROPPED.inc_by(batch.len() as u64);
+        }
+        batch.clear();
+    }
+
+    async fn run(mut self) {
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        let mut reported_dropped = 0;
+        loop {
+            tokio::select! {
+                record = self.receiver.recv() => match record {
+                    Some(record) => {
+                        batch.push(record);
+                        if batch.len() >= BATCH_SIZE {
+                            self.flush(&mut batch).await;
+                        }
+                    }
+                    None => {
+                        self.flush(&mut batch).await;
+                        return;
+                    }
+                },
+                _ = interval.tick() => {
+                    self.flush(&mut batch).await;
+                    let dropped = self.dropped.load(Ordering::Relaxed);
+                    if dropped > reported_dropped {
+                        warn!("Dropped {} audit records so far.", dropped);
+                        reported_dropped = dropped;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AuditLogHandle {
+    sender: mpsc::Sender<AuditRecord>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl AuditLogHandle {
+    pub async fn try_new(
+        buffer: usize,
+        config: AuditSinkConfig,
+        max_bytes: u64,
+        max_files: usize,
+    ) -> Result<Self> {
+        info!("Persisting audit records to {config:?}.");
+        let (sender, receiver) = mpsc::channel(buffer);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let audit_log = AuditLog {
+            receiver,
+            sink: Sink::try_new(config, max_bytes, max_files).await?,
+            dropped: dropped.clone(),
+        };
+        tasks::spawn("audit", audit_log.run());
+        Ok(AuditLogHandle { sender, dropped })
+    }
+
+    /// Queue a record for persistence. Never blocks; drops the record if the buffer is full.
+    pub fn record(&self, record: AuditRecord) {
+        if self.sender.try_send(record).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            metrics::AUDIT_RECORDS_DROPPED.inc();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sinks() {
+        assert!(matches!(
+            "file:/var/log/sero/audit.jsonl".parse(),
+            Ok(AuditSinkConfig::File(path)) if path == Path::new("/var/log/sero/audit.jsonl")
+        ));
+        assert!(matches!(
+            "syslog:127.0.0.1:514".parse(),
+            Ok(AuditSinkConfig::Syslog(addr)) if addr == SocketAddr::from(([127, 0, 0, 1], 514))
+        ));
+        assert!(matches!(
+            "http://collector:8080/audit".parse(),
+            Ok(AuditSinkConfig::Http(url)) if url == "http://collector:8080/audit"
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_sinks() {
+        assert!("syslog:localhost".parse::<AuditSinkConfig>().is_err());
+        assert!("https://collector/audit"
+            .parse::<AuditSinkConfig>()
+            .is_err());
+        assert!("/var/log/sero/audit.jsonl"
+            .parse::<AuditSinkConfig>()
+            .is_err());
+    }
+}
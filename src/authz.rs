@@ -0,0 +1,74 @@
+use anyhow::{bail, Result};
+use hyper::{client::HttpConnector, Body, Client, Request, StatusCode};
+use serde::Serialize;
+use std::{net::SocketAddr, time::Duration};
+use tracing::*;
+
+/// Metadata about a new connection, sent to the authorization endpoint.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthzRequest<'a> {
+    pub service: &'a str,
+    pub client: SocketAddr,
+    pub local: Option<SocketAddr>,
+}
+
+/// Asks an external HTTP endpoint whether a connection may wake the backend.
+///
+/// The endpoint receives an `AuthzRequest` as JSON via POST and allows the
+/// connection by answering with any 2xx status code.
+#[derive(Clone)]
+pub struct Authorizer {
+    url: String,
+    timeout: Duration,
+    fail_open: bool,
+    client: Client<HttpConnector>,
+}
+
+impl Authorizer {
+    pub fn new(url: &str, timeout: Duration, fail_open: bool) -> Self {
+        info!("Authorizing new connections via {url}.");
+        Authorizer {
+            url: url.to_owned(),
+            timeout,
+            fail_open,
+            client: Client::new(),
+        }
+    }
+
+    pub async fn is_allowed(&self, req: &AuthzRequest<'_>) -> bool {
+        match tokio::time::timeout(self.timeout, self.query(req)).await {
+            Ok(Ok(status)) if status.is_success() => true,
+            Ok(Ok(status)) => {
+                debug!("Connection from {} denied with {status}.", req.client);
+                false
+            }
+            Ok(Err(e)) => {
+                warn!(
+                    "Error while authorizing connection from {}: {e}",
+                    req.client
+                );
+                self.fail_open
+            }
+            Err(_) => {
+                warn!(
+                    "Timed out after {:?} while authorizing connection from {}.",
+                    self.timeout, req.client
+                );
+                self.fail_open
+            }
+        }
+    }
+
+    async fn query(&self, req: &AuthzRequest<'_>) -> Result<StatusCode> {
+        let body = serde_json::to_vec(req)?;
+        let req = Request::post(self.url.as_str())
+            .header("content-type", "application/json")
+            .body(Body::from(body))?;
+        let res = self.client.request(req).await?;
+        if res.status().is_server_error() {
+            bail!("Authorization endpoint responded with {}", res.status());
+        }
+        Ok(res.status())
+    }
+}
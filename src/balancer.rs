@@ -0,0 +1,98 @@
+use clap::ValueEnum;
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hasher},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// How connections are spread across the addresses of the backend.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum LbStrategy {
+    /// Dial the addresses in turn
+    #[default]
+    RoundRobin,
+    /// Dial the address with the fewest open connections
+    LeastConnections,
+    /// Dial a random address
+    Random,
+}
+
+/// Orders the addresses to dial by a load-balancing strategy, counting the open connections
+/// to each of them.
+#[derive(Clone, Default)]
+pub struct Balancer {
+    strategy: LbStrategy,
+    next: Arc<AtomicU64>,
+    connections: Arc<Mutex<HashMap<SocketAddr, usize>>>,
+}
+
+/// Counts a connection to an address until dropped.
+pub struct BalancedConnection {
+    connections: Arc<Mutex<HashMap<SocketAddr, usize>>>,
+    addr: SocketAddr,
+}
+
+impl Drop for BalancedConnection {
+    fn drop(&mut self) {
+        let mut connections = self.connections.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = connections.get_mut(&self.addr) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.addr);
+            }
+        }
+    }
+}
+
+impl Balancer {
+    pub fn new(strategy: LbStrategy) -> Self {
+        Balancer {
+            strategy,
+            ..Default::default()
+        }
+    }
+
+    /// Sort `addrs` so that the address to dial comes first, followed by the fallbacks.
+    pub fn order(&self, addrs: &mut [SocketAddr]) {
+        if addrs.len() < 2 {
+            return;
+        }
+        let len = addrs.len() as u64;
+        match self.strategy {
+            LbStrategy::RoundRobin => {
+                let next = self.next.fetch_add(1, Ordering::Relaxed);
+                addrs.rotate_left((next % len) as usize);
+            }
+            LbStrategy::LeastConnections => {
+                // rotate first, so that ties are broken in turn by the stable sort
+                let next = self.next.fetch_add(1, Ordering::Relaxed);
+                addrs.rotate_left((next % len) as usize);
+                let connections = self.connections.lock().unwrap_or_else(|e| e.into_inner());
+                addrs.sort_by_key(|addr| connections.get(addr).copied().unwrap_or_default());
+            }
+            LbStrategy::Random => {
+                // randomly keyed hashers are good enough to pick an address
+                let random = RandomState::new().build_hasher().finish();
+                addrs.rotate_left((random % len) as usize);
+            }
+        }
+    }
+
+    /// Count a connection to `addr` for least-connections balancing until it is dropped.
+    pub fn connect(&self, addr: SocketAddr) -> BalancedConnection {
+        *self
+            .connections
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(addr)
+            .or_default() += 1;
+        BalancedConnection {
+            connections: self.connections.clone(),
+            addr,
+        }
+    }
+}
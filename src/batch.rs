@@ -0,0 +1,149 @@
+use crate::api_metrics;
+use crate::cli::TransitionArgs;
+use crate::endpoint_watcher::EndpointWatcherHandle;
+use crate::events::SeroEvents;
+use crate::hooks::JobHooks;
+use crate::scaler::{
+    ScaleDownDeferred, ScaleStrategy, ScalerHandle, SleepCause, Target, WakeCause,
+};
+use crate::svc_info::ServicePortInfo;
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::*;
+
+/// A single transition of the backend requested from the command line.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Transition {
+    Wake,
+    Sleep,
+}
+
+/// How a transition ended, each with its own exit code.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+enum TransitionOutcome {
+    Succeeded,
+    Failed,
+    TimedOut,
+    /// The scale-down was deferred by a rollout or a PodDisruptionBudget
+    Deferred,
+}
+
+impl TransitionOutcome {
+    fn exit_code(&self) -> i32 {
+        match self {
+            TransitionOutcome::Succeeded => 0,
+            TransitionOutcome::Failed => 1,
+            TransitionOutcome::TimedOut => 2,
+            TransitionOutcome::Deferred => 3,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct TransitionSummary {
+    transition: Transition,
+    service: String,
+    deployment: String,
+    outcome: TransitionOutcome,
+    /// Whether the backend had to change state, as opposed to already being there
+    changed: bool,
+    duration_ms: u64,
+    error: Option<String>,
+}
+
+/// Wake or sleep the backend once, print a JSON summary and exit with a code for the outcome.
+pub async fn run(transition: Transition, args: TransitionArgs) -> Result<()> {
+    let TransitionArgs {
+        deployment: deploy_name,
+        service: svc_name,
+        service_port: svc_port,
+        once,
+        timeout,
+    } = args;
+    if !once {
+        bail!("Only one-shot transitions are supported, pass --once.");
+    }
+
+    let client = Arc::new(api_metrics::try_client().await?);
+    let ServicePortInfo {
+        name: svc_port_name,
+        ..
+    } = ServicePortInfo::try_new(&svc_name, svc_port.as_deref(), client.clone()).await?;
+    let mut endpoints =
+        EndpointWatcherHandle::new(&svc_name, &svc_port_name, &[], None, None, client.clone());
+    let scaler = ScalerHandle::new(
+        1,
+        Target::deployment(&deploy_name),
+        ScaleStrategy::PatchScale,
+        JobHooks::none(),
+        None,
+        None,
+        Duration::from_secs(60),
+        None,
+        client,
+        endpoints.clone(),
+        SeroEvents::new(),
+        None,
+        None,
+    );
+    // decide on the current state only once the endpoints are known
+    endpoints.wait_synced().await?;
+
+    let start = Instant::now();
+    let was_serving = endpoints.backend_is_serving();
+    let result = match transition {
+        Transition::Wake => {
+            tokio::time::timeout(timeout, scaler.ensure_up(WakeCause::AdminApi)).await
+        }
+        Transition::Sleep => {
+            let down = async {
+                scaler
+                    .ensure_down(SleepCause::AdminApi)
+                    .await
+                    .map(|()| was_serving)
+            };
+            tokio::time::timeout(timeout, down).await
+        }
+    };
+    let (outcome, changed, error) = match result {
+        Ok(Ok(changed)) => (TransitionOutcome::Succeeded, changed, None),
+        Ok(Err(e)) if e.is::<ScaleDownDeferred>() => {
+            (TransitionOutcome::Deferred, false, Some(e.to_string()))
+        }
+        Ok(Err(e)) => (TransitionOutcome::Failed, false, Some(e.to_string())),
+        Err(_) => (
+            TransitionOutcome::TimedOut,
+            false,
+            Some(format!("Did not complete within {timeout:?}.")),
+        ),
+    };
+    let summary = TransitionSummary {
+        transition,
+        service: svc_name,
+        deployment: deploy_name,
+        outcome,
+        changed,
+        duration_ms: start.elapsed().as_millis() as u64,
+        error,
+    };
+    match &summary.error {
+        None => info!(
+            "{:?} of deployment/{} succeeded.",
+            transition, summary.deployment
+        ),
+        Some(e) => error!(
+            "{:?} of deployment/{} failed: {e}",
+            transition, summary.deployment
+        ),
+    }
+    println!("{}", serde_json::to_string_pretty(&summary)?);
+    std::process::exit(outcome.exit_code());
+}
@@ -0,0 +1,63 @@
+use crate::metrics;
+
+use chrono::{TimeZone, Utc};
+use kube::Client;
+use serde::Serialize;
+use tracing::*;
+
+/// What was deployed, to correlate behavior changes with versions in mixed fleets.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub git_sha: &'static str,
+    pub build_date: String,
+    pub features: Vec<&'static str>,
+    /// Version of the kube API server, detected at startup
+    pub kube_api_version: Option<String>,
+}
+
+impl BuildInfo {
+    /// Collect the build information and publish it as `sero_build_info`.
+    pub async fn detect(client: &Client) -> Self {
+        let kube_api_version = match client.apiserver_version().await {
+            Ok(info) => Some(info.git_version),
+            Err(e) => {
+                warn!("Could not detect kube API server version: {e}");
+                None
+            }
+        };
+        let build_date = env!("SERO_BUILD_TIMESTAMP")
+            .parse()
+            .ok()
+            .and_then(|timestamp| Utc.timestamp_opt(timestamp, 0).single())
+            .map(|date| date.to_rfc3339())
+            .unwrap_or_default();
+        let info = BuildInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            git_sha: env!("SERO_GIT_SHA"),
+            build_date,
+            features: env!("SERO_FEATURES")
+                .split(',')
+                .filter(|feature| !feature.is_empty())
+                .collect(),
+            kube_api_version,
+        };
+        info!(
+            "Running sero {} ({}), kube API server {}.",
+            info.version,
+            info.git_sha,
+            info.kube_api_version.as_deref().unwrap_or("unknown")
+        );
+        metrics::BUILD_INFO
+            .with_label_values(&[
+                info.version,
+                info.git_sha,
+                &info.build_date,
+                &info.features.join(","),
+                info.kube_api_version.as_deref().unwrap_or_default(),
+            ])
+            .set(1);
+        info
+    }
+}
@@ -1,16 +1,522 @@
-use clap::Parser;
+use crate::activity::IdleTimeout;
+use crate::audit::AuditSinkConfig;
+use crate::balancer::LbStrategy;
+use crate::hooks::HookFailurePolicy;
+use crate::injector::{IpFamily, Topology};
+use crate::log_format::LogFormat;
+use crate::proxy::{ConnectionLimitPolicy, PortRange, Protocol, WakeTimeoutPolicy};
+use crate::resolver::ResolverKind;
+use crate::scaler::{ScaleMode, TargetKind, TargetRef};
+use crate::warmup::WarmupMode;
+
+use clap::{Args, Parser, Subcommand};
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
 
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub run: RunArgs,
+}
+
+/// Options of the proxy and scaler, also accepted without the `run` subcommand.
+#[derive(Args)]
+pub struct RunArgs {
+    /// Load options and additional services from this YAML or TOML file; CLI and env take precedence
+    #[arg(env = "SERO_CONFIG", long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
     /// Listen on this address
     #[arg(env, long, default_value = "0.0.0.0", value_name = "HOST")]
     pub listen_host: String,
 
+    /// Listen on both 0.0.0.0 and [::] instead of the listen host, for dual-stack clusters
+    #[arg(env, long, conflicts_with = "listen_host")]
+    pub dual_stack: bool,
+
     /// Listen on this port
     #[arg(env, long, default_value_t = 3000, value_name = "PORT")]
     pub listen_port: u16,
 
+    /// Also listen on these ports, e.g. `7000-7010`, forwarding each to the same port of the service
+    #[arg(env, long, value_name = "FIRST-LAST")]
+    pub listen_port_range: Option<PortRange>,
+
+    /// With services from `--config`, also accept TLS connections here and route them by SNI to the services listing it in `sni-hosts`
+    #[arg(env, long, value_name = "ADDR")]
+    pub sni_listen: Option<SocketAddr>,
+
+    /// Listen on these addresses (e.g. `0.0.0.0:3000` and `[::]:3000`) instead of host and port
+    #[arg(env, long, value_delimiter = ',', value_name = "ADDR")]
+    pub listen: Vec<SocketAddr>,
+
+    /// Also listen on these addresses, but only proxy while the backend is up without waking it or keeping it awake
+    #[arg(env, long, value_delimiter = ',', value_name = "ADDR")]
+    pub passive_listen: Vec<SocketAddr>,
+
+    /// Accept connections on these inherited listening file descriptors, in addition to systemd's
+    #[arg(env, long, value_delimiter = ',', value_name = "FD")]
+    pub listen_fd: Vec<i32>,
+
+    /// Retry connects to the backend this many times, as its pods may take a moment to listen after a wake
+    #[arg(env, long, default_value_t = 0, value_name = "COUNT")]
+    pub connect_retries: u32,
+
+    /// Backoff before the first connect retry, doubled and jittered for each further one
+    #[arg(env, long, default_value = "100ms", value_parser = humantime::parse_duration, value_name = "DURATION")]
+    pub connect_backoff: Duration,
+
+    /// Proxy raw TCP, or HTTP requests which are held while the backend wakes up
+    #[arg(env, long, value_enum, default_value_t = Protocol::Tcp)]
+    pub protocol: Protocol,
+
+    /// In HTTP mode, replay idempotent requests which failed because the backend went away, for this long
+    #[arg(env, long, value_parser = humantime::parse_duration, value_name = "DURATION")]
+    pub replay_timeout: Option<Duration>,
+
+    /// Largest request body buffered for replays
+    #[arg(env, long, default_value_t = 64 * 1024, value_name = "BYTES")]
+    pub replay_max_bytes: u64,
+
+    /// In HTTP mode, spill requests waiting for a wake to this directory and send them after sero restarted
+    #[arg(env, long, value_name = "DIR")]
+    pub spool_dir: Option<PathBuf>,
+
+    /// Most requests spilled to --spool-dir at once
+    #[arg(env, long, default_value_t = 100, value_name = "COUNT")]
+    pub spool_max_requests: usize,
+
+    /// Largest request body spilled to --spool-dir
+    #[arg(env, long, default_value_t = 64 * 1024, value_name = "BYTES")]
+    pub spool_max_bytes: u64,
+
+    /// In HTTP mode, always answer requests for these paths with 200, so that health checks of ingress controllers neither wake the backend nor fail while it sleeps
+    #[arg(env, long, value_delimiter = ',', value_name = "PATH")]
+    pub health_check_path: Vec<String>,
+
+    /// Dial the serving pods of the service directly, as listed in its EndpointSlices, instead of the service's name
+    #[arg(env, long)]
+    pub dial_pods: bool,
+
+    /// How connections are spread across the addresses of the backend
+    #[arg(env, long, value_enum, default_value_t = LbStrategy::RoundRobin, value_name = "STRATEGY")]
+    pub lb_strategy: LbStrategy,
+
+    /// Probe client and backend connections idle for this long with TCP keepalives, which keeps them open in NATs along the way and closes those whose peer stopped answering
+    #[arg(env, long, value_parser = humantime::parse_duration, value_name = "DURATION")]
+    pub tcp_keepalive: Option<Duration>,
+
+    /// Time between unanswered TCP keepalive probes
+    #[arg(env, long, default_value = "15s", value_parser = humantime::parse_duration, requires = "tcp_keepalive", value_name = "DURATION")]
+    pub tcp_keepalive_interval: Duration,
+
+    /// Unanswered TCP keepalive probes after which a connection is closed
+    #[arg(
+        env,
+        long,
+        default_value_t = 4,
+        requires = "tcp_keepalive",
+        value_name = "COUNT"
+    )]
+    pub tcp_keepalive_retries: u32,
+
+    /// Set TCP_NODELAY on client and backend connections, for latency sensitive protocols with small writes
+    #[arg(env, long)]
+    pub tcp_nodelay: bool,
+
+    /// Close proxied connections on which no bytes flowed in either direction for this long
+    #[arg(env, long, value_parser = humantime::parse_duration, value_name = "DURATION")]
+    pub stream_idle_timeout: Option<Duration>,
+
+    /// Give up on a connection or request waiting for the backend to wake up after this long
+    #[arg(env, long, value_parser = humantime::parse_duration, value_name = "DURATION")]
+    pub wake_timeout: Option<Duration>,
+
+    /// Reject further connections and requests right away while this many wait for the backend to wake up, each for at most `--wake-timeout`
+    #[arg(env, long, value_name = "COUNT")]
+    pub max_pending_connections: Option<usize>,
+
+    /// What clients get once `--wake-timeout` passed
+    #[arg(env, long, value_enum, default_value_t = WakeTimeoutPolicy::Respond, requires = "wake_timeout")]
+    pub wake_timeout_policy: WakeTimeoutPolicy,
+
+    /// Keep at most this many client connections and UDP flows open at once, across all listeners
+    #[arg(env, long, value_name = "COUNT")]
+    pub max_connections: Option<usize>,
+
+    /// What happens to connections beyond `--max-connections`
+    #[arg(env, long, value_enum, default_value_t = ConnectionLimitPolicy::Reject, requires = "max_connections")]
+    pub connection_limit_policy: ConnectionLimitPolicy,
+
+    /// Only accept IPv6 connections on IPv6 listeners
+    #[arg(env, long)]
+    pub ipv6_only: bool,
+
+    /// Retry binding a listen address which is in use this many times
+    #[arg(env, long, default_value_t = 5, value_name = "COUNT")]
+    pub bind_retries: u32,
+
+    /// Listen on this port instead if the listen port stays in use
+    #[arg(env, long, value_name = "PORT")]
+    pub fallback_port: Option<u16>,
+
+    /// On shutdown, wait this long for proxied connections to finish; keep below the pod's termination grace period
+    #[arg(env, long, default_value = "25s", value_parser = humantime::parse_duration, value_name = "DURATION")]
+    pub drain_timeout: Duration,
+
+    /// While draining, also ask HTTP clients to wait this long before their next request with a Retry-After header
+    #[arg(env, long, value_parser = humantime::parse_duration, value_name = "DURATION")]
+    pub drain_retry_after: Option<Duration>,
+
+    /// Namespace of the service and deployment, defaults to the namespace sero runs in
+    #[arg(env, short = 'n', long, value_name = "NAMESPACE")]
+    pub namespace: Option<String>,
+
+    /// Namespace of the service, overrides `--namespace`
+    #[arg(env, long, value_name = "NAMESPACE")]
+    pub service_namespace: Option<String>,
+
+    /// Namespace of the deployment, overrides `--namespace`
+    #[arg(env, long, value_name = "NAMESPACE")]
+    pub deployment_namespace: Option<String>,
+
+    /// Deployment (or workload of `--target-kind`) to scale, defaults to the one Deployment whose pods the service selects
+    #[arg(env = "DEPLOYMENT", short = 'd', long, value_name = "NAME")]
+    pub deployment: Option<String>,
+
+    /// Kind of the workload named by `--deployment`, e.g. `statefulset` or `rollout.argoproj.io`
+    #[arg(
+        env,
+        long,
+        default_value = "deployment.apps",
+        value_name = "KIND[.GROUP]"
+    )]
+    pub target_kind: TargetKind,
+
+    /// Workload with a scale subresource to scale instead of `--deployment`
+    #[arg(
+        env,
+        long,
+        conflicts_with_all = ["deployment", "target_kind"],
+        value_name = "KIND[.GROUP]/NAME"
+    )]
+    pub target: Option<TargetRef>,
+
+    /// How to scale the deployment; use `annotation-toggle` if replicas are managed by GitOps
+    #[arg(env, long, value_enum, default_value_t = ScaleMode::PatchScale)]
+    pub scale_mode: ScaleMode,
+
+    /// Executable called as `<hook> <deployment> <replicas>` by `--scale-mode exec-hook`
+    #[arg(env, long, value_name = "PATH")]
+    pub scale_hook: Option<PathBuf>,
+
+    /// Never scale the deployment; only proxy and wait for another autoscaler to wake it
+    #[arg(env, long, conflicts_with_all = ["scale_mode", "scale_hook"])]
+    pub no_scale: bool,
+
+    /// Run this Job (a JSON manifest) before waking the backend and wait for it to complete
+    #[arg(env, long, value_name = "PATH")]
+    pub wake_job: Option<PathBuf>,
+
+    /// Run this Job (a JSON manifest) before scaling the backend down and wait for it to complete
+    #[arg(env, long, value_name = "PATH")]
+    pub sleep_job: Option<PathBuf>,
+
+    /// Give up on a wake or sleep Job after this long
+    #[arg(env, long, default_value = "5m", value_parser = humantime::parse_duration, value_name = "DURATION")]
+    pub hook_timeout: Duration,
+
+    /// Whether to scale anyway when a wake or sleep Job fails or times out
+    #[arg(env, long, value_enum, default_value_t = HookFailurePolicy::Abort)]
+    pub hook_failure_policy: HookFailurePolicy,
+
+    /// Scale the backend down after it was idle this long, or `auto` to adapt it to traffic
+    #[arg(env, long, alias = "scale-down-after", value_name = "DURATION|auto")]
+    pub idle_timeout: Option<IdleTimeout>,
+
+    /// While the backend is awake, scale it to one replica per this many concurrent connections
+    #[arg(env, long, value_name = "COUNT")]
+    pub connections_per_replica: Option<usize>,
+
+    /// Fewest replicas of the awake backend with `--connections-per-replica`
+    #[arg(env, long, default_value_t = 1, value_name = "COUNT")]
+    pub min_replicas: i32,
+
+    /// Most replicas of the backend with `--connections-per-replica`
+    #[arg(env, long, default_value_t = 10, value_name = "COUNT")]
+    pub max_replicas: i32,
+
+    /// Only remove replicas after fewer ones sufficed for this long
+    #[arg(env, long, default_value = "5m", value_parser = humantime::parse_duration, value_name = "DURATION")]
+    pub scale_in_delay: Duration,
+
+    /// After startup, wake and put the sleeping backend back to sleep once (only dry-run the scale of an awake one), reporting unready until that worked
+    #[arg(env, long)]
+    pub self_test_on_start: bool,
+
+    /// Fail the startup self-test if it did not complete within this time
+    #[arg(env, long, default_value = "5m", value_parser = humantime::parse_duration, value_name = "DURATION")]
+    pub self_test_timeout: Duration,
+
+    /// Let activity published by `sero sidecar` in backend pods veto scaling down
+    #[arg(env, long)]
+    pub sidecar_activity: bool,
+
+    /// Report replica changes by other field managers within this period after scaling
+    #[arg(env, long, default_value = "60s", value_parser = humantime::parse_duration, value_name = "DURATION")]
+    pub revert_window: Duration,
+
+    /// Stop scaling the deployment after it was reverted this many times
+    #[arg(env, long, value_name = "COUNT")]
+    pub max_reverts: Option<usize>,
+
+    /// Service to proxy to
+    #[arg(
+        env = "SERVICE",
+        short = 's',
+        long,
+        required_unless_present = "config",
+        value_name = "NAME"
+    )]
+    pub service: Option<String>,
+
+    /// Port name of the service
+    #[arg(env = "PORT", long, value_name = "NAME")]
+    pub service_port: Option<String>,
+
+    /// Also proxy all other ports of the service, each on sero's port of the same number
+    #[arg(env, long)]
+    pub all_ports: bool,
+
+    /// Also proxy these ports of the service, each on sero's port of the same number
+    #[arg(
+        env,
+        long,
+        value_delimiter = ',',
+        conflicts_with = "all_ports",
+        value_name = "NAME"
+    )]
+    pub extra_ports: Vec<String>,
+
+    /// Forward UDP datagrams received on these addresses to a UDP port of the service, waking it on the first datagram of each client
+    #[arg(env, long, value_delimiter = ',', value_name = "ADDR")]
+    pub udp_listen: Vec<SocketAddr>,
+
+    /// UDP port name of the service to forward to, defaults to its first UDP port
+    #[arg(env, long, value_name = "NAME")]
+    pub udp_service_port: Option<String>,
+
+    /// Forget a UDP client, which then no longer keeps the backend awake, after this long without datagrams
+    #[arg(env, long, default_value = "1m", value_parser = humantime::parse_duration, value_name = "DURATION")]
+    pub udp_flow_timeout: Duration,
+
+    /// Also judge readiness by the endpoints of these Services, e.g. an internal one selecting the same pods
+    #[arg(env, long, value_delimiter = ',', value_name = "NAME")]
+    pub also_watch_service: Vec<String>,
+
+    /// Also judge readiness by EndpointSlices matching this label selector
+    #[arg(env, long, value_name = "SELECTOR")]
+    pub endpoint_selector: Option<String>,
+
+    /// Relist EndpointSlices if none changed for this long while connections to the backend fail
+    #[arg(env, long, default_value = "5m", value_parser = humantime::parse_duration, value_name = "DURATION")]
+    pub endpoints_stale_after: Duration,
+
+    /// Restore the scale of the target and sero's endpointslices this often if they drifted from sero's intent, e.g. by manual edits
+    #[arg(env, long, value_parser = humantime::parse_duration, value_name = "DURATION")]
+    pub reconcile_interval: Option<Duration>,
+
+    /// Warn about backend endpoints which change between serving and not serving this many times within `--flap-window`, also with an Event on the Service
+    #[arg(env, long, value_name = "COUNT")]
+    pub flap_threshold: Option<usize>,
+
+    /// Window in which transitions of an endpoint count towards `--flap-threshold`
+    #[arg(env, long, default_value = "5m", value_parser = humantime::parse_duration, value_name = "DURATION")]
+    pub flap_window: Duration,
+
+    /// Hold draining sero's endpoints for readiness gates while backend endpoints flap
+    #[arg(env, long, requires = "flap_threshold")]
+    pub hold_while_flapping: bool,
+
+    /// Whether clients connect to sero (gateway) or to the backend Service (interceptor)
+    #[arg(env, long, value_enum, value_name = "TOPOLOGY")]
+    pub mode: Option<Topology>,
+
+    /// Should sero inject itself into the services Endpoints? Shorthand for `--mode interceptor`
+    #[arg(env, short = 'i', long)]
+    pub inject: bool,
+
+    /// Hint sero's injected endpoint for all zones, for Services using topology aware routing
+    #[arg(env, long)]
+    pub zone_hints: bool,
+
+    /// Patch at most this many EndpointSlices at once when injecting or ejecting sero
+    #[arg(env, long, default_value_t = 8, value_name = "COUNT")]
+    pub injector_concurrency: usize,
+
+    /// Start patches of EndpointSlices at least this far apart, to spare the API server
+    #[arg(env, long, value_parser = humantime::parse_duration, value_name = "DURATION")]
+    pub injector_pacing: Option<Duration>,
+
+    /// Give up on the remaining EndpointSlices of an inject or eject after this long
+    #[arg(env, long, default_value = "60s", value_parser = humantime::parse_duration, value_name = "DURATION")]
+    pub injector_timeout: Duration,
+
+    /// Inject addresses of these families instead of those in the Service's `ipFamilies`
+    #[arg(
+        env,
+        long,
+        value_enum,
+        value_delimiter = ',',
+        ignore_case = true,
+        value_name = "FAMILY"
+    )]
+    pub ip_families: Vec<IpFamily>,
+
+    /// Open the `sero.rs/cutover` readiness gate of backend pods only after sero's endpoints are drained
+    #[arg(env, long)]
+    pub readiness_gate: bool,
+
+    /// Elect a leader among replicas of sero, so that only it scales down and injects or ejects sero
+    #[arg(env, long)]
+    pub leader_elect: bool,
+
+    /// Lease to elect the leader by [default: sero-<SERVICE>]
+    #[arg(env, long, requires = "leader_elect", value_name = "NAME")]
+    pub leader_lease: Option<String>,
+
+    /// Followers take over once the leader did not renew its lease for this long
+    #[arg(env, long, default_value = "15s", value_parser = humantime::parse_duration, value_name = "DURATION")]
+    pub leader_lease_duration: Duration,
+
+    /// Prepare the connection path to the backend as soon as it wakes up
+    #[arg(env, long, value_enum, default_value_t = WarmupMode::Off)]
+    pub warmup: WarmupMode,
+
+    /// Open and briefly hold this many connections to the backend right after each wake
+    #[arg(env, long, default_value_t = 0, value_name = "COUNT")]
+    pub prewarm_connections: usize,
+
+    /// Ask this HTTP endpoint whether a new connection may wake the backend
+    #[arg(env, long, value_name = "URL")]
+    pub authz_url: Option<String>,
+
+    /// Timeout for authorization requests
+    #[arg(env, long, default_value = "1s", value_parser = humantime::parse_duration, value_name = "DURATION")]
+    pub authz_timeout: Duration,
+
+    /// Allow connections if the authorization endpoint fails or times out
+    #[arg(env, long)]
+    pub authz_fail_open: bool,
+
+    /// Terminate TLS of client connections with the certificate of this `kubernetes.io/tls` Secret, reloaded on rotation
+    #[arg(env, long, value_name = "NAME")]
+    pub tls_secret: Option<String>,
+
+    /// How to resolve the backend's hostname
+    #[arg(env, long, value_enum, default_value_t = ResolverKind::Hickory)]
+    pub resolver: ResolverKind,
+
+    /// Try names with at least this many dots as absolute names before the search domains [default: from /etc/resolv.conf]
+    #[arg(env, long, value_name = "COUNT")]
+    pub dns_ndots: Option<usize>,
+
+    /// Search domains to try for the backend's hostname instead of those of /etc/resolv.conf
+    #[arg(env, long, value_delimiter = ',', value_name = "DOMAIN")]
+    pub dns_search: Vec<String>,
+
+    /// Cache failed lookups of the backend's hostname for at most this long
+    #[arg(env, long, value_parser = humantime::parse_duration, value_name = "DURATION")]
+    pub dns_negative_ttl: Option<Duration>,
+
+    /// Expect a PROXY protocol (v1 or v2) header from a load balancer on each connection and use its client address
+    #[arg(env, long)]
+    pub proxy_protocol_in: bool,
+
+    /// Send a PROXY protocol v1 header with the client address to the backend on each TCP connection
+    #[arg(env, long)]
+    pub proxy_protocol_out: bool,
+
+    /// Load client-facing message templates (and localized subdirectories) from this directory
+    #[arg(env, long, value_name = "DIR")]
+    pub templates_dir: Option<PathBuf>,
+
+    /// Serve metrics, probes and the read-only admin API (status, usage and wake history, e.g. for application developers) on this address.
+    /// Breaking: POST routes and `/debug/` moved to `--control-addr`
+    #[arg(env, long, default_value = "0.0.0.0:9090", value_name = "ADDR")]
+    pub admin_addr: SocketAddr,
+
+    /// Serve the whole admin API, which can also wake, sleep, drain, inject, eject and debug, on this address.
+    /// Breaking: it used to be served on `--admin-addr`
+    #[arg(env, long, default_value = "127.0.0.1:9091", value_name = "ADDR")]
+    pub control_addr: SocketAddr,
+
+    /// Persist connection audit records to file:<PATH>, syslog:<ADDR> or http://<URL>
+    #[arg(env, long, value_name = "SINK")]
+    pub audit_sink: Option<AuditSinkConfig>,
+
+    /// Log a structured record of each connection at info level, with target sero::access
+    #[arg(env, long)]
+    pub access_log: bool,
+
+    /// Number of audit records to buffer before dropping new ones
+    #[arg(env, long, default_value_t = 1024, value_name = "RECORDS")]
+    pub audit_buffer: usize,
+
+    /// Rotate the audit file once it exceeds this size
+    #[arg(env, long, default_value_t = 64 * 1024 * 1024, value_name = "BYTES")]
+    pub audit_max_bytes: u64,
+
+    /// Number of rotated audit files to keep
+    #[arg(env, long, default_value_t = 5, value_name = "FILES")]
+    pub audit_max_files: usize,
+
+    /// Persist hourly usage accounting to this ConfigMap
+    #[arg(env, long, value_name = "NAME")]
+    pub usage_configmap: Option<String>,
+
+    /// Write logs as text, or as JSON lines carrying the service and deployment names
+    #[arg(env, long, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Proxy a service and scale its backend, the default without a subcommand
+    Run(Box<RunArgs>),
+    /// Add the service label to sero's EndpointSlices of a service, routing its clients to sero
+    Inject(SliceArgs),
+    /// Remove the service label from sero's EndpointSlices of a service, e.g. to repair them
+    /// after sero crashed while injected
+    Eject(SliceArgs),
+    /// Print the endpoints and replicas of a service and its backend as JSON
+    Status(StatusArgs),
+    /// Run a scripted sleep/wake/sleep scenario against a live cluster
+    E2e(E2eArgs),
+    /// Run inside a backend pod and publish its activity for the gateway
+    Sidecar(SidecarArgs),
+    /// Run a proxy and scaler for each SeroService custom resource
+    Operator(OperatorArgs),
+    /// Run a proxy and scaler for each Service annotated with `sero.rs/enabled: "true"`
+    Discover(DiscoverArgs),
+    /// Scale the backend up and wait until it is serving
+    Wake(TransitionArgs),
+    /// Scale the backend to zero and wait until it is no longer serving
+    Sleep(TransitionArgs),
+}
+
+#[derive(Args)]
+pub struct E2eArgs {
     /// Deployment to scale
     #[arg(env = "DEPLOYMENT", short = 'd', long, value_name = "NAME")]
     pub deployment: String,
@@ -23,7 +529,144 @@ pub struct Cli {
     #[arg(env = "PORT", long, value_name = "NAME")]
     pub service_port: Option<String>,
 
-    /// Should sero inject itself into the services Endpoints?
-    #[arg(env, short = 'i', long)]
-    pub inject: bool,
+    /// Fail if waking the backend takes longer than this
+    #[arg(long, default_value = "2m", value_parser = humantime::parse_duration, value_name = "DURATION")]
+    pub wake_slo: Duration,
+
+    /// Fail if scaling the backend to zero takes longer than this
+    #[arg(long, default_value = "2m", value_parser = humantime::parse_duration, value_name = "DURATION")]
+    pub sleep_timeout: Duration,
+}
+
+#[derive(Args)]
+pub struct TransitionArgs {
+    /// Deployment to scale
+    #[arg(env = "DEPLOYMENT", short = 'd', long, value_name = "NAME")]
+    pub deployment: String,
+
+    /// Service of the backend
+    #[arg(env = "SERVICE", short = 's', long, value_name = "NAME")]
+    pub service: String,
+
+    /// Port name of the service
+    #[arg(env = "PORT", long, value_name = "NAME")]
+    pub service_port: Option<String>,
+
+    /// Perform a single transition, print a JSON summary and exit. Exits with 0 on success, 1
+    /// on failure, 2 on timeout and 3 if a scale-down is deferred by a rollout or PDB
+    #[arg(long)]
+    pub once: bool,
+
+    /// Give up if the transition takes longer than this
+    #[arg(long, default_value = "2m", value_parser = humantime::parse_duration, value_name = "DURATION")]
+    pub timeout: Duration,
+}
+
+#[derive(Args)]
+pub struct SliceArgs {
+    /// Service whose EndpointSlices to label
+    #[arg(env = "SERVICE", short = 's', long, value_name = "NAME")]
+    pub service: String,
+
+    /// Patch this many EndpointSlices concurrently
+    #[arg(long, default_value_t = 8, value_name = "COUNT")]
+    pub concurrency: usize,
+
+    /// Wait at least this long between starting two patches
+    #[arg(long, value_parser = humantime::parse_duration, value_name = "DURATION")]
+    pub pacing: Option<Duration>,
+
+    /// Give up on the remaining EndpointSlices after this long
+    #[arg(long, default_value = "60s", value_parser = humantime::parse_duration, value_name = "DURATION")]
+    pub timeout: Duration,
+}
+
+#[derive(Args)]
+pub struct StatusArgs {
+    /// Service of the backend
+    #[arg(env = "SERVICE", short = 's', long, value_name = "NAME")]
+    pub service: String,
+
+    /// Port name of the service
+    #[arg(env = "PORT", long, value_name = "NAME")]
+    pub service_port: Option<String>,
+
+    /// Deployment of the backend, defaults to the one selected by the service
+    #[arg(env = "DEPLOYMENT", short = 'd', long, value_name = "NAME")]
+    pub deployment: Option<String>,
+}
+
+#[derive(Args)]
+pub struct SidecarArgs {
+    /// Pod to annotate, defaults to the hostname
+    #[arg(env = "POD_NAME", long, value_name = "NAME")]
+    pub pod: Option<String>,
+
+    /// Count established connections to these local ports as activity
+    #[arg(
+        env = "PORTS",
+        long = "port",
+        required = true,
+        value_delimiter = ',',
+        value_name = "PORT"
+    )]
+    pub ports: Vec<u16>,
+
+    /// Publish activity this often
+    #[arg(long, default_value = "10s", value_parser = humantime::parse_duration, value_name = "DURATION")]
+    pub interval: Duration,
+}
+
+#[derive(Args)]
+pub struct OperatorArgs {
+    /// Print the SeroService CustomResourceDefinition and exit
+    #[arg(long)]
+    pub print_crd: bool,
+
+    /// Wake at most this many services at once, sharing slots fairly between tenants
+    #[arg(env, long, value_name = "COUNT")]
+    pub max_concurrent_wakes: Option<usize>,
+
+    /// Also accept TLS connections on this address and route them by SNI to the services listing it in `sniHosts`
+    #[arg(env, long, value_name = "ADDR")]
+    pub sni_listen: Option<SocketAddr>,
+
+    /// Keep at most this many client connections open at once, across all listeners
+    #[arg(env, long, value_name = "COUNT")]
+    pub max_connections: Option<usize>,
+
+    /// What happens to connections beyond `--max-connections`
+    #[arg(env, long, value_enum, default_value_t = ConnectionLimitPolicy::Reject, requires = "max_connections")]
+    pub connection_limit_policy: ConnectionLimitPolicy,
+
+    /// Only manage services whose service and deployment namespaces match this label selector, e.g. `sero.rs/allowed=true`
+    #[arg(env, long, value_name = "SELECTOR")]
+    pub namespace_selector: Option<String>,
+
+    /// Manage at most this many services per deployment namespace
+    #[arg(env, long, value_name = "COUNT")]
+    pub max_services_per_namespace: Option<usize>,
+
+    /// Wake the services of a deployment namespace at most this many times within an hour
+    #[arg(env, long, value_name = "COUNT")]
+    pub max_wakes_per_hour: Option<usize>,
+}
+
+#[derive(Args)]
+pub struct DiscoverArgs {
+    /// Only consider Services matching this label selector
+    #[arg(env, long, value_name = "SELECTOR")]
+    pub selector: Option<String>,
+
+    /// Also accept TLS connections on this address and route them by SNI to the services annotated with `sero.rs/sni-hosts`
+    #[arg(env, long, value_name = "ADDR")]
+    pub sni_listen: Option<SocketAddr>,
+
+    /// Keep at most this many client connections open at once, across all listeners
+    #[arg(env, long, value_name = "COUNT")]
+    pub max_connections: Option<usize>,
+
+    /// What happens to connections beyond `--max-connections`
+    #[arg(env, long, value_enum, default_value_t = ConnectionLimitPolicy::Reject, requires = "max_connections")]
+    pub connection_limit_policy: ConnectionLimitPolicy,
 }
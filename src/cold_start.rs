@@ -0,0 +1,145 @@
+use crate::metrics;
+use crate::readiness_gate::pod_selector;
+use crate::scaler::Target;
+use crate::status::ColdStartBreakdown;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::core::v1::{Event, Pod};
+use kube::{
+    api::{Api, ListParams},
+    Client,
+};
+use std::sync::Arc;
+
+/// Seconds a pod may have been created before a wake started and still count as created by it,
+/// to allow for the second resolution of Kubernetes timestamps.
+const CLOCK_SLACK_SECS: i64 = 2;
+
+/// Attributes the time of wakes to the phases of starting the target's first ready pod, from
+/// the pod's conditions and events.
+#[derive(Clone)]
+pub struct ColdStartTracker {
+    target: Target,
+    client: Arc<Client>,
+}
+
+impl ColdStartTracker {
+    pub fn new(target: Target, client: Arc<Client>) -> Self {
+        ColdStartTracker { target, client }
+    }
+
+    /// Breakdown of the wake from `started` to `completed`, if it started a pod.
+    pub async fn breakdown(
+        &self,
+        started: DateTime<Utc>,
+        completed: DateTime<Utc>,
+    ) -> Result<Option<ColdStartBreakdown>> {
+        let selector = pod_selector(&self.target, &self.client).await?;
+        let pods: Api<Pod> = Api::default_namespaced((*self.client).clone());
+        let earliest = started - chrono::Duration::seconds(CLOCK_SLACK_SECS);
+        // the pod of this wake which became ready first served the waiting clients
+        let Some((pod, ready)) = pods
+            .list(&ListParams::default().labels(&selector))
+            .await?
+            .into_iter()
+            .filter(|pod| {
+                pod.metadata
+                    .creation_timestamp
+                    .as_ref()
+                    .map_or(false, |created| created.0 >= earliest)
+            })
+            .filter_map(|pod| {
+                let ready = condition_time(&pod, "Ready")?;
+                (ready <= completed).then_some((pod, ready))
+            })
+            .min_by_key(|(_, ready)| *ready)
+        else {
+            return Ok(None);
+        };
+        let name = pod.metadata.name.clone().unwrap_or_default();
+        let events: Api<Event> = Api::default_namespaced((*self.client).clone());
+        let events = events
+            .list(&ListParams::default().fields(&format!(
+                "involvedObject.kind=Pod,involvedObject.name={name}"
+            )))
+            .await?
+            .items;
+        let event_times = |reason: &str| -> Vec<DateTime<Utc>> {
+            events
+                .iter()
+                .filter(|event| event.reason.as_deref() == Some(reason))
+                .filter_map(event_time)
+                .collect()
+        };
+
+        let created = pod.metadata.creation_timestamp.as_ref().map(|t| t.0);
+        let scheduled = condition_time(&pod, "PodScheduled");
+        let pulling = event_times("Pulling").into_iter().min();
+        let pulled = event_times("Pulled").into_iter().max();
+        let container_started = event_times("Started").into_iter().max();
+        let image_pull_ms = match (pulling, pulled) {
+            (Some(pulling), Some(pulled)) => millis(pulling, pulled),
+            // cached images are not pulled
+            _ => 0,
+        };
+        let breakdown = ColdStartBreakdown {
+            pod: name,
+            pod_creation_ms: created.map(|created| millis(started, created)),
+            scheduling_ms: created
+                .zip(scheduled)
+                .map(|(created, scheduled)| millis(created, scheduled)),
+            image_pull_ms: Some(image_pull_ms),
+            container_start_ms: scheduled.zip(container_started).map(
+                |(scheduled, container_started)| {
+                    millis(scheduled, container_started).saturating_sub(image_pull_ms)
+                },
+            ),
+            app_boot_ms: container_started
+                .map(|container_started| millis(container_started, ready)),
+            endpoint_propagation_ms: Some(millis(ready, completed)),
+        };
+        let deployment = &self.target.name;
+        for (phase, ms) in breakdown.phases() {
+            if let Some(ms) = ms {
+                metrics::COLD_START_PHASE_DURATION
+                    .with_label_values(&[deployment, phase])
+                    .observe(ms as f64 / 1000.0);
+            }
+        }
+        Ok(Some(breakdown))
+    }
+}
+
+/// When the condition `type_` of `pod` last became true.
+fn condition_time(pod: &Pod, type_: &str) -> Option<DateTime<Utc>> {
+    pod.status
+        .as_ref()?
+        .conditions
+        .as_ref()?
+        .iter()
+        .find(|condition| condition.type_ == type_ && condition.status == "True")?
+        .last_transition_time
+        .as_ref()
+        .map(|time| time.0)
+}
+
+fn event_time(event: &Event) -> Option<DateTime<Utc>> {
+    event
+        .event_time
+        .as_ref()
+        .map(|time| time.0)
+        .or_else(|| event.first_timestamp.as_ref().map(|time| time.0))
+        .or_else(|| {
+            event
+                .metadata
+                .creation_timestamp
+                .as_ref()
+                .map(|time| time.0)
+        })
+}
+
+/// Milliseconds from `from` to `to`, zero if `to` appears to be earlier due to rounding.
+fn millis(from: DateTime<Utc>, to: DateTime<Utc>) -> u64 {
+    (to - from).num_milliseconds().max(0) as u64
+}
@@ -0,0 +1,256 @@
+use crate::api_metrics;
+use crate::cli::Cli;
+use crate::operator::{Pipelines, SeroServiceSpec};
+use crate::proxy::ConnectionLimit;
+use crate::sni::SniRouter;
+
+use anyhow::{bail, Context, Result};
+use clap::{parser::ValueSource, ArgMatches, CommandFactory};
+use serde::{de::Error as _, Deserialize, Deserializer};
+use serde_json::{Map, Value};
+use std::{
+    collections::BTreeMap,
+    env,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tracing::*;
+
+/// Environment variable naming the config file, like `--config`.
+const CONFIG_ENV: &str = "SERO_CONFIG";
+
+/// Ids of the options which also apply to the services of a config file.
+const SERVICES_OPTIONS: &[&str] = &[
+    "config",
+    "sni_listen",
+    "max_connections",
+    "connection_limit_policy",
+    "drain_timeout",
+    "log_format",
+];
+
+/// Options loaded from a YAML or TOML file given by `--config`.
+///
+/// ```yaml
+/// listen-port: 8080
+/// deployment: app
+/// service: app
+/// also-watch-service: [app-internal]
+/// ```
+///
+/// or, for several services:
+///
+/// ```yaml
+/// services:
+///   - service: app
+///     deployment: app
+///     listen-port: 8080
+/// ```
+#[derive(Deserialize, Debug, Default)]
+pub struct ConfigFile {
+    /// Pipelines to run instead of the single service given by the options
+    #[serde(default, deserialize_with = "kebab_case_services")]
+    pub services: Vec<SeroServiceSpec>,
+    /// Options named like their long command line flags
+    #[serde(flatten)]
+    pub options: BTreeMap<String, Value>,
+}
+
+impl ConfigFile {
+    /// Load the file given by `--config` or `SERO_CONFIG`, if any. Its options are exported as
+    /// the environment variables of their flags unless set, so that CLI and env take precedence.
+    /// As setting environment variables is not thread-safe, call this before starting a runtime.
+    pub fn load() -> Result<Option<Self>> {
+        let Some(path) = config_path() else {
+            return Ok(None);
+        };
+        let config = Self::try_from_file(&path)
+            .with_context(|| format!("Could not load config file {}", path.display()))?;
+        config.export_options()?;
+        Ok(Some(config))
+    }
+
+    /// Fail on options given by the file, CLI or env which only apply to a single service if
+    /// the file defines `services`, rather than ignoring them.
+    pub fn check_options(&self, matches: &ArgMatches) -> Result<()> {
+        if self.services.is_empty() {
+            return Ok(());
+        }
+        let matches = matches.subcommand_matches("run").unwrap_or(matches);
+        for arg in Cli::command().get_arguments() {
+            let id = arg.get_id().as_str();
+            let given = matches!(
+                matches.value_source(id),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            );
+            if given && !SERVICES_OPTIONS.contains(&id) {
+                bail!(
+                    "Option --{} is not supported together with services in the config file.",
+                    arg.get_long().unwrap_or(id)
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn try_from_file(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        let config = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&contents)?,
+            Some("yaml" | "yml") => serde_yaml::from_str(&contents)?,
+            _ => bail!("Unknown config file format, expected a .yaml, .yml or .toml file."),
+        };
+        Ok(config)
+    }
+
+    fn export_options(&self) -> Result<()> {
+        let command = Cli::command();
+        for (key, value) in &self.options {
+            let long = key.replace('_', "-");
+            let Some(name) = command
+                .get_arguments()
+                .find(|arg| arg.get_long() == Some(long.as_str()))
+                .and_then(|arg| arg.get_env())
+            else {
+                bail!("Unknown option '{key}'.");
+            };
+            if env::var_os(name).is_some() {
+                debug!("Option '{key}' of the config file is overridden by the environment.");
+                continue;
+            }
+            let value = match value {
+                Value::Null => continue,
+                Value::String(value) => value.clone(),
+                Value::Array(values) => values
+                    .iter()
+                    .map(|value| match value {
+                        Value::String(value) => value.clone(),
+                        value => value.to_string(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(","),
+                Value::Object(_) => bail!("Option '{key}' must not be a map."),
+                value => value.to_string(),
+            };
+            env::set_var(name, value);
+        }
+        Ok(())
+    }
+}
+
+/// Services use kebab-case keys like the options, while the spec of a `SeroService` is
+/// camelCase.
+fn kebab_case_services<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Vec<SeroServiceSpec>, D::Error> {
+    let services: Vec<BTreeMap<String, Value>> = Deserialize::deserialize(deserializer)?;
+    services
+        .into_iter()
+        .map(|service| {
+            let mut spec = Map::new();
+            for (key, value) in service {
+                if key.contains(|c: char| c.is_ascii_uppercase()) {
+                    return Err(D::Error::custom(format!(
+                        "Key '{key}' of a service must be kebab-case, like the options."
+                    )));
+                }
+                spec.insert(camel_case(&key), value);
+            }
+            serde_json::from_value(Value::Object(spec)).map_err(D::Error::custom)
+        })
+        .collect()
+}
+
+fn camel_case(key: &str) -> String {
+    let mut words = key.split('-');
+    let first = words.next().unwrap_or_default().to_owned();
+    words.fold(first, |mut camel, word| {
+        let mut chars = word.chars();
+        if let Some(c) = chars.next() {
+            camel.push(c.to_ascii_uppercase());
+            camel.extend(chars);
+        }
+        camel
+    })
+}
+
+/// Find `--config` before the command line is parsed, as it provides defaults for the rest.
+fn config_path() -> Option<PathBuf> {
+    let mut args = env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+            return Some(PathBuf::from(path));
+        }
+    }
+    env::var_os(CONFIG_ENV).map(PathBuf::from)
+}
+
+/// Start a pipeline for each service defined in the config file, routing TLS connections to
+/// `sni_listen` by the services' `sni-hosts`. All listeners share `connection_limit`.
+pub async fn start_services(
+    services: Vec<SeroServiceSpec>,
+    sni_listen: Option<SocketAddr>,
+    connection_limit: Option<ConnectionLimit>,
+) -> Result<Pipelines> {
+    let client = Arc::new(api_metrics::try_client().await?);
+    let sni_router = match sni_listen {
+        Some(addr) => Some(SniRouter::try_new(addr, connection_limit.clone()).await?),
+        None => None,
+    };
+    let mut pipelines = Pipelines::new("service", client)
+        .with_sni_router(sni_router)
+        .with_connection_limit(connection_limit);
+    info!("Starting {} services from the config file.", services.len());
+    for spec in services {
+        pipelines.reconcile(spec.service.clone(), spec);
+    }
+    Ok(pipelines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn camel_cases_kebab_keys() {
+        assert_eq!(camel_case("service"), "service");
+        assert_eq!(camel_case("listen-port"), "listenPort");
+        assert_eq!(camel_case("service-namespace"), "serviceNamespace");
+    }
+
+    #[test]
+    fn parses_kebab_case_services() {
+        let config: ConfigFile = serde_yaml::from_str(
+            "
+drain-timeout: 10s
+services:
+  - service: app
+    deployment: app
+    listen-port: 8080
+    sni-hosts: [app.example.com]
+",
+        )
+        .unwrap();
+        let spec = &config.services[0];
+        assert_eq!(spec.listen_port, 8080);
+        assert_eq!(spec.sni_hosts, ["app.example.com"]);
+        assert!(config.options.contains_key("drain-timeout"));
+    }
+
+    #[test]
+    fn rejects_camel_case_services() {
+        let config = toml::from_str::<ConfigFile>(
+            "
+[[services]]
+service = 'app'
+deployment = 'app'
+listenPort = 8080
+",
+        );
+        assert!(config.is_err());
+    }
+}
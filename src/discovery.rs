@@ -0,0 +1,125 @@
+use crate::api_metrics;
+use crate::cli::DiscoverArgs;
+use crate::operator::{Pipelines, SeroServiceSpec};
+use crate::proxy::ConnectionLimit;
+use crate::sni::SniRouter;
+
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::Service;
+use kube::{
+    api::Api,
+    core::params::ListParams,
+    runtime::{self, watcher::Event},
+};
+use std::{collections::BTreeMap, sync::Arc};
+use tracing::*;
+
+/// Annotations by which a Service opts into being managed by `sero discover`.
+pub const ENABLED_ANNOTATION: &str = "sero.rs/enabled";
+pub const DEPLOYMENT_ANNOTATION: &str = "sero.rs/deployment";
+pub const DEPLOYMENT_NAMESPACE_ANNOTATION: &str = "sero.rs/deployment-namespace";
+pub const PORT_ANNOTATION: &str = "sero.rs/port";
+pub const LISTEN_PORT_ANNOTATION: &str = "sero.rs/listen-port";
+pub const IDLE_TIMEOUT_ANNOTATION: &str = "sero.rs/idle-timeout";
+pub const SNI_HOSTS_ANNOTATION: &str = "sero.rs/sni-hosts";
+
+/// Watch Services and run a pipeline for each one annotated with `sero.rs/enabled: "true"`.
+pub async fn run(args: DiscoverArgs) -> Result<()> {
+    let client = Arc::new(api_metrics::try_client().await?);
+    let api: Api<Service> = Api::default_namespaced((*client).clone());
+    let params = match &args.selector {
+        Some(selector) => ListParams::default().labels(selector),
+        None => ListParams::default(),
+    };
+    let mut events = runtime::watcher(api, params).boxed();
+    info!("Watching Services annotated with {ENABLED_ANNOTATION}.");
+
+    let connection_limit =
+        ConnectionLimit::from_options(args.max_connections, args.connection_limit_policy)?;
+    let sni_router = match args.sni_listen {
+        Some(addr) => Some(SniRouter::try_new(addr, connection_limit.clone()).await?),
+        None => None,
+    };
+    let mut pipelines = Pipelines::new("service", client)
+        .with_sni_router(sni_router)
+        .with_connection_limit(connection_limit);
+    while let Some(event) = events.next().await {
+        match event {
+            Err(e) => error!("Error getting next event for Services: {e}"),
+            Ok(Event::Applied(svc)) => {
+                let name = svc.metadata.name.clone().unwrap_or_default();
+                match spec_of(&svc) {
+                    Ok(Some(spec)) => pipelines.reconcile(name, spec),
+                    Ok(None) => pipelines.remove(&name),
+                    Err(e) => {
+                        error!("Not managing service/{name}: {e}");
+                        pipelines.remove(&name);
+                    }
+                }
+            }
+            Ok(Event::Deleted(svc)) => pipelines.remove(&svc.metadata.name.unwrap_or_default()),
+            Ok(Event::Restarted(svcs)) => {
+                let specs: Vec<(String, SeroServiceSpec)> = svcs
+                    .iter()
+                    .filter_map(|svc| {
+                        let name = svc.metadata.name.clone()?;
+                        match spec_of(svc) {
+                            Ok(spec) => Some((name, spec?)),
+                            Err(e) => {
+                                error!("Not managing service/{name}: {e}");
+                                None
+                            }
+                        }
+                    })
+                    .collect();
+                let names: Vec<String> = specs.iter().map(|(name, _)| name.clone()).collect();
+                pipelines.retain(&names);
+                for (name, spec) in specs {
+                    pipelines.reconcile(name, spec);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The pipeline spec described by the annotations of a Service, if it is enabled.
+fn spec_of(svc: &Service) -> Result<Option<SeroServiceSpec>> {
+    let empty = BTreeMap::new();
+    let annotations = svc.metadata.annotations.as_ref().unwrap_or(&empty);
+    if annotations.get(ENABLED_ANNOTATION).map(String::as_str) != Some("true") {
+        return Ok(None);
+    }
+    let name = svc.metadata.name.clone().unwrap_or_default();
+    let listen_port = annotations
+        .get(LISTEN_PORT_ANNOTATION)
+        .with_context(|| format!("{LISTEN_PORT_ANNOTATION} is missing."))?
+        .parse()
+        .with_context(|| format!("{LISTEN_PORT_ANNOTATION} is not a port."))?;
+    Ok(Some(SeroServiceSpec {
+        deployment: annotations
+            .get(DEPLOYMENT_ANNOTATION)
+            .cloned()
+            .unwrap_or_else(|| name.clone()),
+        service: name,
+        service_namespace: None,
+        deployment_namespace: annotations.get(DEPLOYMENT_NAMESPACE_ANNOTATION).cloned(),
+        port: annotations.get(PORT_ANNOTATION).cloned(),
+        listen_port,
+        idle_timeout: annotations.get(IDLE_TIMEOUT_ANNOTATION).cloned(),
+        tenant: None,
+        wake_weight: None,
+        sni_hosts: annotations
+            .get(SNI_HOSTS_ANNOTATION)
+            .map(|hosts| {
+                hosts
+                    .split(',')
+                    .map(|host| host.trim().to_owned())
+                    .collect()
+            })
+            .unwrap_or_default(),
+        wake_job: None,
+        sleep_job: None,
+    }))
+}
@@ -0,0 +1,73 @@
+use crate::scaler::Target;
+
+use anyhow::Result;
+use k8s_openapi::{
+    api::policy::v1::PodDisruptionBudget, apimachinery::pkg::apis::meta::v1::LabelSelector,
+};
+use kube::{api::Api, core::params::ListParams, Client};
+use std::collections::BTreeMap;
+
+/// Why scaling `target` to zero right now would interfere with it, if it would.
+///
+/// A scale-down is deferred while the target is mid-rollout, or while a PodDisruptionBudget
+/// selecting its pods does not allow all of them to be disrupted.
+pub async fn scale_down_blocker(target: &Target, client: &Client) -> Result<Option<String>> {
+    let object = target.api(client).get(&target.name).await?;
+    let status = &object.data["status"];
+    let replicas = status["replicas"].as_i64().unwrap_or_default();
+    let updated = status["updatedReplicas"].as_i64().unwrap_or(replicas);
+    if updated < replicas {
+        return Ok(Some(format!(
+            "it is rolling out ({updated} of {replicas} replicas updated)"
+        )));
+    }
+
+    let labels: BTreeMap<String, String> =
+        serde_json::from_value(object.data["spec"]["template"]["metadata"]["labels"].clone())
+            .unwrap_or_default();
+    let pdbs: Api<PodDisruptionBudget> = Api::default_namespaced(client.clone());
+    for pdb in pdbs.list(&ListParams::default()).await? {
+        let selects_target = pdb
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.selector.as_ref())
+            .map_or(false, |selector| selects(selector, &labels));
+        let Some(status) = pdb.status.as_ref() else {
+            continue;
+        };
+        if selects_target && status.disruptions_allowed < status.current_healthy {
+            return Ok(Some(format!(
+                "poddisruptionbudget/{} allows disrupting only {} of {} healthy pods",
+                pdb.metadata.name.as_deref().unwrap_or_default(),
+                status.disruptions_allowed,
+                status.current_healthy
+            )));
+        }
+    }
+    Ok(None)
+}
+
+/// Whether `selector` matches pods labeled with `labels`.
+fn selects(selector: &LabelSelector, labels: &BTreeMap<String, String>) -> bool {
+    let labels_match = selector
+        .match_labels
+        .iter()
+        .flatten()
+        .all(|(key, value)| labels.get(key) == Some(value));
+    let expressions_match = selector
+        .match_expressions
+        .iter()
+        .flatten()
+        .all(|requirement| {
+            let value = labels.get(&requirement.key);
+            let values = requirement.values.as_deref().unwrap_or_default();
+            match requirement.operator.as_str() {
+                "In" => value.map_or(false, |value| values.contains(value)),
+                "NotIn" => value.map_or(true, |value| !values.contains(value)),
+                "Exists" => value.is_some(),
+                "DoesNotExist" => value.is_none(),
+                _ => false,
+            }
+        });
+    labels_match && expressions_match
+}
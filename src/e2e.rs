@@ -0,0 +1,198 @@
+use crate::activity::ActivityHandle;
+use crate::api_metrics;
+use crate::balancer::Balancer;
+use crate::cli::E2eArgs;
+use crate::endpoint_watcher::EndpointWatcherHandle;
+use crate::events::SeroEvents;
+use crate::hooks::JobHooks;
+use crate::messages::Messages;
+use crate::proxy::{self, BindOptions, ConnectionContext, Protocol, Proxy};
+use crate::resolver::BackendResolver;
+use crate::scaler::{ScaleStrategy, ScalerHandle, SleepCause, Target};
+use crate::svc_info::ServicePortInfo;
+use crate::tasks;
+use crate::usage::UsageHandle;
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::net::TcpStream;
+use tracing::*;
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct StepReport {
+    name: &'static str,
+    passed: bool,
+    duration_ms: u64,
+    error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct E2eReport {
+    service: String,
+    deployment: String,
+    passed: bool,
+    steps: Vec<StepReport>,
+}
+
+/// Run a scripted sleep/wake/sleep scenario against a live cluster and print a JSON report.
+pub async fn run(args: E2eArgs) -> Result<()> {
+    let E2eArgs {
+        deployment: deploy_name,
+        service: svc_name,
+        service_port: svc_port,
+        wake_slo,
+        sleep_timeout,
+    } = args;
+    let max_concurrency = 16;
+
+    let client = Arc::new(api_metrics::try_client().await?);
+    let ServicePortInfo {
+        name: svc_port_name,
+        number: svc_port_number,
+        ..
+    } = ServicePortInfo::try_new(&svc_name, svc_port.as_deref(), client.clone()).await?;
+    let mut endpoints =
+        EndpointWatcherHandle::new(&svc_name, &svc_port_name, &[], None, None, client.clone());
+    let scaler = ScalerHandle::new(
+        max_concurrency,
+        Target::deployment(&deploy_name),
+        ScaleStrategy::PatchScale,
+        JobHooks::none(),
+        None,
+        None,
+        Duration::from_secs(60),
+        None,
+        client.clone(),
+        endpoints.clone(),
+        SeroEvents::new(),
+        None,
+        None,
+    );
+    let usage = UsageHandle::new(
+        max_concurrency,
+        &svc_name,
+        None,
+        client.clone(),
+        endpoints.clone(),
+    );
+    let ctx = ConnectionContext {
+        backend_host: svc_name.clone(),
+        backend_port: svc_port_number,
+        resolver: BackendResolver::system(),
+        http_client: proxy::http_client(BackendResolver::system()),
+        pod_port: None,
+        balancer: Balancer::default(),
+        protocol: Protocol::Tcp,
+        replay: None,
+        spool: None,
+        health_check_paths: Default::default(),
+        wake_deadline: None,
+        connect_retries: None,
+        pending: None,
+        keepalive: None,
+        nodelay: false,
+        stream_idle_timeout: None,
+        scaler: scaler.clone(),
+        endpoints: endpoints.clone(),
+        audit: None,
+        access_log: false,
+        activity: ActivityHandle::new(&deploy_name, None, endpoints.clone()),
+        usage,
+        warmup: None,
+        messages: Arc::new(Messages::default()),
+        authorizer: None,
+        tls: None,
+        proxy_protocol_in: false,
+        proxy_protocol_out: false,
+        events: SeroEvents::new(),
+        next_wake_debug: None,
+        drain_notice: Default::default(),
+        connections: Default::default(),
+    };
+    let proxy = Proxy::try_new(
+        &[([127, 0, 0, 1], 0).into()],
+        &[],
+        BindOptions::default(),
+        ctx,
+    )
+    .await?;
+    let proxy_addr = proxy.local_addr()?;
+    tasks::spawn("proxy", proxy.run());
+
+    let mut report = E2eReport {
+        service: svc_name,
+        deployment: deploy_name,
+        passed: true,
+        steps: Vec::new(),
+    };
+
+    // 1. put the backend to sleep
+    report
+        .step(
+            "scale to zero",
+            sleep_timeout,
+            scaler.ensure_down(SleepCause::SelfTest),
+        )
+        .await;
+
+    // 2. wake it by opening a connection through sero
+    let wake = async {
+        let _conn = TcpStream::connect(proxy_addr).await?;
+        endpoints.wait_for(|status| status.backend > 0).await?;
+        Ok::<_, anyhow::Error>(())
+    };
+    report.step("wake via connection", wake_slo, wake).await;
+
+    // 3. put it to sleep again
+    report
+        .step(
+            "scale down",
+            sleep_timeout,
+            scaler.ensure_down(SleepCause::SelfTest),
+        )
+        .await;
+
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    if !report.passed {
+        bail!("End-to-end test failed.");
+    }
+    Ok(())
+}
+
+impl E2eReport {
+    /// Run a single step with a deadline and record its result. Skips steps after a failure.
+    async fn step<F>(&mut self, name: &'static str, timeout: Duration, step: F)
+    where
+        F: Future<Output = Result<()>>,
+    {
+        if !self.passed {
+            return;
+        }
+        info!("Running end-to-end step '{name}'.");
+        let start = Instant::now();
+        let error = match tokio::time::timeout(timeout, step).await {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(e.to_string()),
+            Err(_) => Some(format!("Did not complete within {timeout:?}.")),
+        };
+        let duration_ms = start.elapsed().as_millis() as u64;
+        match &error {
+            None => info!("Step '{name}' passed after {duration_ms}ms."),
+            Some(e) => error!("Step '{name}' failed after {duration_ms}ms: {e}"),
+        }
+        self.passed = error.is_none();
+        self.steps.push(StepReport {
+            name,
+            passed: error.is_none(),
+            duration_ms,
+            error,
+        });
+    }
+}
@@ -1,73 +1,286 @@
-use anyhow::Result;
+use crate::metrics;
+use crate::status::EndpointStatus;
+use crate::tasks;
+
+use anyhow::{Context, Result};
 use futures::{Stream, StreamExt};
-use k8s_openapi::api::discovery::v1::EndpointSlice;
+use k8s_openapi::api::{
+    core::v1::Service,
+    discovery::v1::{Endpoint, EndpointSlice},
+};
 use kube::{
     api::Api,
     core::params::ListParams,
-    runtime::{self, reflector::Store, WatchStreamExt},
-    Client,
+    runtime::{
+        self,
+        events::{self as k8s_events, EventType, Recorder, Reporter},
+        reflector::Store,
+        watcher::Event,
+    },
+    Client, Resource,
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
-use std::{pin::Pin, sync::Arc};
 use tokio::sync::watch;
 use tracing::*;
 
+/// How often the watch is checked for staleness.
+const STALENESS_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
 #[derive(PartialEq, Default, Debug)]
 struct EndpointCount {
     sero: usize,
     backend: usize,
+    /// Whether all reflectors have listed their EndpointSlices at least once
+    synced: bool,
+    /// Backend endpoints exceeding the `FlapLimits`
+    flapping: usize,
+    /// Serving backend endpoints of the Service's own EndpointSlices
+    backends: Vec<BackendEndpoint>,
 }
 
-impl From<(usize, usize)> for EndpointCount {
-    fn from((sero, backend): (usize, usize)) -> Self {
-        EndpointCount { sero, backend }
+impl EndpointCount {
+    fn status(&self) -> EndpointStatus {
+        EndpointStatus {
+            sero: self.sero,
+            backend: self.backend,
+            flapping: self.flapping,
+        }
+    }
+}
+
+/// Address of a serving backend endpoint and its ports by name.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
+struct BackendEndpoint {
+    ip: IpAddr,
+    ports: BTreeMap<String, u16>,
+}
+
+/// An endpoint flaps once it changed between serving and not serving this many times within
+/// the window.
+#[derive(Clone, Copy, Debug)]
+pub struct FlapLimits {
+    pub transitions: usize,
+    pub window: Duration,
+}
+
+/// Serving state of a single backend endpoint over time.
+struct EndpointHistory {
+    serving: bool,
+    /// Transitions within the flap window
+    transitions: VecDeque<Instant>,
+    flapping: bool,
+}
+
+/// Serving state of the backend endpoints of a Service over time, by address.
+struct EndpointHistories {
+    service: String,
+    by_address: HashMap<String, EndpointHistory>,
+    limits: Option<FlapLimits>,
+}
+
+impl EndpointHistories {
+    fn new(service: &str, limits: Option<FlapLimits>) -> Self {
+        EndpointHistories {
+            service: service.to_owned(),
+            by_address: HashMap::new(),
+            limits,
+        }
+    }
+
+    /// Record which backend endpoints started or stopped serving at `now`, and count those
+    /// which flap beyond the `FlapLimits`. Also returns the endpoints which just began to flap.
+    fn record_transitions(
+        &mut self,
+        serving: &HashSet<String>,
+        known: &HashSet<String>,
+        now: Instant,
+    ) -> (usize, Vec<String>) {
+        for address in known {
+            self.by_address
+                .entry(address.clone())
+                .or_insert_with(|| EndpointHistory {
+                    serving: serving.contains(address),
+                    transitions: VecDeque::new(),
+                    flapping: false,
+                });
+        }
+        let (service, limits) = (&self.service, self.limits);
+        let mut flapping = 0;
+        let mut started_flapping = Vec::new();
+        self.by_address.retain(|address, history| {
+            // endpoints which disappeared are no longer serving
+            let is_serving = serving.contains(address);
+            if is_serving != history.serving {
+                history.serving = is_serving;
+                metrics::ENDPOINT_TRANSITIONS
+                    .with_label_values(&[service, address])
+                    .inc();
+                if limits.is_some() {
+                    history.transitions.push_back(now);
+                }
+            }
+            if let Some(limits) = limits {
+                while history
+                    .transitions
+                    .front()
+                    .map_or(false, |at| now.duration_since(*at) > limits.window)
+                {
+                    history.transitions.pop_front();
+                }
+                let is_flapping = history.transitions.len() >= limits.transitions;
+                if is_flapping && !history.flapping {
+                    warn!(
+                        "Endpoint {address} of service/{service} changed between serving and not serving {} times within {:?}.",
+                        history.transitions.len(),
+                        limits.window
+                    );
+                    started_flapping.push(address.clone());
+                } else if !is_flapping && history.flapping {
+                    info!("Endpoint {address} of service/{service} stabilized.");
+                }
+                history.flapping = is_flapping;
+                flapping += is_flapping as usize;
+            }
+            // forget endpoints once they are gone and their transitions expired
+            let keep = known.contains(address) || !history.transitions.is_empty();
+            if !keep {
+                let _ = metrics::ENDPOINT_TRANSITIONS.remove_label_values(&[service, address]);
+            }
+            keep
+        });
+        (flapping, started_flapping)
     }
 }
 
+/// Watch events tagged with the index of the selector they belong to.
+type EventStream = Pin<
+    Box<dyn Stream<Item = (usize, Result<Event<EndpointSlice>, runtime::watcher::Error>)> + Send>,
+>;
+
 struct EndpointWatcher {
     name: String,
     port_name: String,
+    api: Api<EndpointSlice>,
+    /// Label selectors of the watched EndpointSlices, the Service's own first
+    selectors: Vec<String>,
     sender: watch::Sender<EndpointCount>,
     store: Store<EndpointSlice>,
-    events: Pin<Box<dyn Stream<Item = Result<EndpointSlice, runtime::watcher::Error>> + Send>>,
+    /// Stores of EndpointSlices matching the additional selectors
+    extra_stores: Vec<Store<EndpointSlice>>,
+    events: EventStream,
+    synced: Vec<bool>,
+    /// Relist if no event arrived for this long while connections to the backend fail
+    stale_after: Option<Duration>,
+    failures: Arc<AtomicUsize>,
+    history: EndpointHistories,
+    client: Arc<Client>,
 }
 
 impl EndpointWatcher {
     fn new(
         svc_name: &str,
         svc_port_name: &str,
+        extra_selectors: &[String],
+        stale_after: Option<Duration>,
+        flap_limits: Option<FlapLimits>,
         sender: watch::Sender<EndpointCount>,
+        failures: Arc<AtomicUsize>,
         client: Arc<Client>,
     ) -> Self {
         let api: Api<EndpointSlice> = Api::default_namespaced((*client).clone());
-        let selector =
-            ListParams::default().labels(&format!("kubernetes.io/service-name={svc_name}"));
-        let (store, writer) = runtime::reflector::store();
-        let events = runtime::reflector(writer, runtime::watcher(api, selector))
-            .touched_objects()
-            .boxed();
+        let mut selectors = vec![format!("kubernetes.io/service-name={svc_name}")];
+        selectors.extend_from_slice(extra_selectors);
 
         info!(
             "Watching EndpointSlices associated to service/{}.",
             svc_name
         );
+        if !extra_selectors.is_empty() {
+            info!("Also watching EndpointSlices matching {extra_selectors:?}.");
+        }
 
-        EndpointWatcher {
+        let mut watcher = EndpointWatcher {
             name: svc_name.to_owned(),
             port_name: svc_port_name.to_owned(),
+            api,
+            selectors,
             sender,
-            store,
-            events,
+            store: runtime::reflector::store().0,
+            extra_stores: Vec::new(),
+            events: futures::stream::empty().boxed(),
+            synced: Vec::new(),
+            stale_after,
+            failures,
+            history: EndpointHistories::new(svc_name, flap_limits),
+            client,
+        };
+        watcher.watch();
+        watcher
+    }
+
+    /// (Re)start a reflector per selector, which begins with a full list.
+    fn watch(&mut self) {
+        let mut stores = Vec::new();
+        let mut streams = Vec::new();
+        for (index, selector) in self.selectors.iter().enumerate() {
+            let (store, writer) = runtime::reflector::store();
+            let events: EventStream = runtime::reflector(
+                writer,
+                runtime::watcher(self.api.clone(), ListParams::default().labels(selector)),
+            )
+            .map(move |event| (index, event))
+            .boxed();
+            stores.push(store);
+            streams.push(events);
         }
+        self.store = stores.remove(0);
+        self.extra_stores = stores;
+        self.synced = vec![false; streams.len()];
+        self.events = futures::stream::select_all(streams).boxed();
     }
 
     async fn run(mut self) {
-        while let Some(event) = self.events.next().await {
+        let mut last_event = Instant::now();
+        let mut check = tokio::time::interval(STALENESS_CHECK_INTERVAL);
+        loop {
+            let (index, event) = tokio::select! {
+                event = self.events.next() => match event {
+                    Some(event) => event,
+                    None => return,
+                },
+                _ = check.tick() => {
+                    self.check_staleness(last_event);
+                    // let transitions expire from the flap window
+                    if self.history.limits.is_some() {
+                        self.send_state_update();
+                    }
+                    continue;
+                }
+            };
+            last_event = Instant::now();
             match event {
                 Err(e) => error!("Error getting next event for EndpointSlices: {e}"),
-                Ok(ep_slice) => {
+                Ok(event) => {
+                    let ep_slices: Vec<&EndpointSlice> = match &event {
+                        Event::Applied(ep_slice) | Event::Deleted(ep_slice) => vec![ep_slice],
+                        Event::Restarted(ep_slices) => {
+                            // the initial list completed
+                            self.synced[index] = true;
+                            ep_slices.iter().collect()
+                        }
+                    };
                     debug!(
                         "Got a new event for EndpointSlices: {}",
-                        serde_json::to_string(&ep_slice)
+                        serde_json::to_string(&ep_slices)
                             .unwrap_or("Error serialising event.".to_owned())
                     );
                     self.send_state_update();
@@ -76,11 +289,39 @@ impl EndpointWatcher {
         }
     }
 
-    fn send_state_update(&self) {
-        let current_ep = self.serving_endpoints();
+    /// Relist if the watch went quiet while clients fail to reach the backend, which
+    /// indicates that the watch is wedged and the store no longer reflects reality.
+    fn check_staleness(&mut self, last_event: Instant) {
+        let failures = self.failures.swap(0, Ordering::Relaxed);
+        let Some(stale_after) = self.stale_after else {
+            return;
+        };
+        let quiet = last_event.elapsed();
+        if failures == 0 || quiet < stale_after {
+            return;
+        }
+        warn!(
+            "No EndpointSlice events for service/{} in {quiet:?} while {failures} connections to the backend failed, relisting.",
+            self.name
+        );
+        self.watch();
+    }
+
+    fn send_state_update(&mut self) {
+        let (mut current_ep, serving, known) = self.serving_endpoints();
+        let now = Instant::now();
+        let (flapping, started_flapping) = self.history.record_transitions(&serving, &known, now);
+        current_ep.flapping = flapping;
+        for address in started_flapping {
+            let publish = publish_flapping_event(self.name.clone(), address, self.client.clone());
+            tasks::spawn("flapping event", publish);
+        }
         self.sender.send_if_modified(|ep| {
             if *ep != current_ep {
-                info!("Sending state update: {current_ep:?}");
+                info!(
+                    "Sending state update: sero: {}, backend: {}, synced: {}, flapping: {}",
+                    current_ep.sero, current_ep.backend, current_ep.synced, current_ep.flapping
+                );
                 *ep = current_ep;
                 return true;
             }
@@ -88,10 +329,17 @@ impl EndpointWatcher {
         });
     }
 
-    fn serving_endpoints(&self) -> EndpointCount {
-        self.store
+    /// Count serving endpoints by address, so that pods selected by several Services
+    /// are only counted once. Also returns the addresses of serving and of all backend endpoints.
+    fn serving_endpoints(&self) -> (EndpointCount, HashSet<String>, HashSet<String>) {
+        let mut sero = HashSet::new();
+        let mut backend = HashSet::new();
+        let mut known = HashSet::new();
+        let mut backends = Vec::new();
+        let primary: Vec<_> = self
+            .store
             .state()
-            .iter()
+            .into_iter()
             .filter(|ep_slice| {
                 ep_slice.ports.as_ref().map(|ports| {
                     ports
@@ -99,50 +347,183 @@ impl EndpointWatcher {
                         .any(|port| port.name == Some(self.port_name.clone()))
                 }) == Some(true)
             })
-            .fold((0_usize, 0_usize), |(sero, backend), ep_slice| {
-                let serving_ep = ep_slice
+            .collect();
+        // dialable pods, see `EndpointWatcherHandle::backend_addrs`
+        for ep_slice in &primary {
+            if is_sero_slice(ep_slice, &self.name) {
+                continue;
+            }
+            let ports: BTreeMap<String, u16> = ep_slice
+                .ports
+                .iter()
+                .flatten()
+                .filter_map(|port| Some((port.name.clone()?, port.port? as u16)))
+                .collect();
+            backends.extend(
+                ep_slice
                     .endpoints
                     .iter()
-                    .filter(|&ep| {
-                        ep.conditions
-                            .as_ref()
-                            .and_then(|ep_conditions| ep_conditions.serving)
-                            == Some(true)
-                    })
-                    .count();
-                if ep_slice
-                    .metadata
-                    .labels
-                    .as_ref()
-                    .and_then(|labels| labels.get("sero.rs/service-name"))
-                    == Some(&self.name)
-                {
-                    (sero + serving_ep, backend)
-                } else {
-                    (sero, backend + serving_ep)
-                }
-            })
-            .into()
+                    .filter(|ep| ep.conditions.as_ref().and_then(|c| c.serving) == Some(true))
+                    .filter_map(|ep| ep.addresses.first()?.parse().ok())
+                    .map(|ip| BackendEndpoint {
+                        ip,
+                        ports: ports.clone(),
+                    }),
+            );
+        }
+        backends.sort();
+        backends.dedup();
+        let extra = self.extra_stores.iter().flat_map(|store| store.state());
+        for ep_slice in primary.into_iter().chain(extra) {
+            let is_sero = is_sero_slice(&ep_slice, &self.name);
+            let serving = ep_slice
+                .endpoints
+                .iter()
+                .filter(|&ep| is_serving(ep))
+                .filter_map(|ep| ep.addresses.first().cloned());
+            if is_sero {
+                sero.extend(serving);
+            } else {
+                backend.extend(serving);
+                known.extend(
+                    ep_slice
+                        .endpoints
+                        .iter()
+                        .filter_map(|ep| ep.addresses.first().cloned()),
+                );
+            }
+        }
+        let count = EndpointCount {
+            sero: sero.len(),
+            backend: backend.len(),
+            synced: self.synced.iter().all(|&synced| synced),
+            flapping: 0,
+            backends,
+        };
+        (count, backend, known)
+    }
+}
+
+/// Whether `ep` accepts traffic, which endpoints without conditions do not.
+pub fn is_serving(ep: &Endpoint) -> bool {
+    ep.conditions
+        .as_ref()
+        .and_then(|ep_conditions| ep_conditions.serving)
+        == Some(true)
+}
+
+/// Whether `ep_slice` is one sero injected into the service `svc_name`.
+fn is_sero_slice(ep_slice: &EndpointSlice, svc_name: &str) -> bool {
+    ep_slice
+        .metadata
+        .labels
+        .as_ref()
+        .and_then(|labels| labels.get("sero.rs/service-name"))
+        .map(String::as_str)
+        == Some(svc_name)
+}
+
+/// Tell `kubectl describe` that an endpoint of the Service began to flap.
+async fn publish_flapping_event(svc_name: String, address: String, client: Arc<Client>) {
+    let api: Api<Service> = Api::default_namespaced((*client).clone());
+    let svc = match api.get(&svc_name).await {
+        Ok(svc) => svc,
+        Err(e) => {
+            warn!("Could not publish event for service/{svc_name}: {e}");
+            return;
+        }
+    };
+    let reporter = Reporter {
+        controller: "sero".to_owned(),
+        instance: hostname::get()
+            .ok()
+            .and_then(|name| name.into_string().ok()),
+    };
+    let recorder = Recorder::new((*client).clone(), reporter, svc.object_ref(&()));
+    let event = k8s_events::Event {
+        type_: EventType::Warning,
+        reason: "EndpointFlapping".to_owned(),
+        note: Some(format!(
+            "Endpoint {address} keeps changing between serving and not serving."
+        )),
+        action: "Watching".to_owned(),
+        secondary: None,
+    };
+    if let Err(e) = recorder.publish(event).await {
+        warn!("Could not publish event for service/{svc_name}: {e}");
     }
 }
 
 #[derive(Clone)]
 pub struct EndpointWatcherHandle {
     receiver: watch::Receiver<EndpointCount>,
+    failures: Arc<AtomicUsize>,
 }
 
 impl EndpointWatcherHandle {
-    pub fn new(svc_name: &str, svc_port_name: &str, client: Arc<Client>) -> Self {
+    /// Watch the endpoints of `svc_name` and of all EndpointSlices matching `extra_selectors`.
+    pub fn new(
+        svc_name: &str,
+        svc_port_name: &str,
+        extra_selectors: &[String],
+        stale_after: Option<Duration>,
+        flap_limits: Option<FlapLimits>,
+        client: Arc<Client>,
+    ) -> Self {
         let (sender, receiver) = watch::channel(EndpointCount::default());
-        let watcher = EndpointWatcher::new(svc_name, svc_port_name, sender, client);
-        tokio::spawn(watcher.run());
-        EndpointWatcherHandle { receiver }
+        let failures = Arc::new(AtomicUsize::new(0));
+        let watcher = EndpointWatcher::new(
+            svc_name,
+            svc_port_name,
+            extra_selectors,
+            stale_after,
+            flap_limits,
+            sender,
+            failures.clone(),
+            client,
+        );
+        tasks::spawn("endpoint-watcher", watcher.run());
+        EndpointWatcherHandle { receiver, failures }
+    }
+
+    /// Report that a connection to the backend failed, which may indicate a stale watch.
+    pub fn report_unreachable(&self) {
+        self.failures.fetch_add(1, Ordering::Relaxed);
     }
 
     pub fn backend_is_serving(&self) -> bool {
         self.receiver.borrow().backend > 0
     }
 
+    pub fn serving_backends(&self) -> usize {
+        self.receiver.borrow().backend
+    }
+
+    /// Addresses of the serving pods of the Service itself on the target port of `port_name`,
+    /// which never include sero's own endpoints.
+    pub fn backend_addrs(&self, port_name: &str) -> Vec<SocketAddr> {
+        self.receiver
+            .borrow()
+            .backends
+            .iter()
+            .filter_map(|backend| Some(SocketAddr::new(backend.ip, *backend.ports.get(port_name)?)))
+            .collect()
+    }
+
+    pub fn status(&self) -> EndpointStatus {
+        self.receiver.borrow().status()
+    }
+
+    /// Whether any backend endpoint flaps beyond the `FlapLimits`.
+    pub fn is_flapping(&self) -> bool {
+        self.receiver.borrow().flapping > 0
+    }
+
+    /// Whether the initial list of EndpointSlices completed, so that counts are meaningful.
+    pub fn is_synced(&self) -> bool {
+        self.receiver.borrow().synced
+    }
+
     pub fn sero_is_serving(&self) -> bool {
         self.receiver.borrow().sero > 0
     }
@@ -152,4 +533,118 @@ impl EndpointWatcherHandle {
             warn!("Error while waiting for EndpointSlice updates: {e}");
         }
     }
+
+    /// Wait until `predicate` holds for the endpoints. Unlike checking them before awaiting
+    /// `changed`, the current endpoints and every update are passed to `predicate`, so none
+    /// are missed in between. Fails if the watcher stopped, as they would never change again.
+    pub async fn wait_for(
+        &mut self,
+        mut predicate: impl FnMut(&EndpointStatus) -> bool,
+    ) -> Result<()> {
+        self.receiver
+            .wait_for(|count| predicate(&count.status()))
+            .await
+            .context("EndpointSlice watcher stopped")?;
+        Ok(())
+    }
+
+    /// Wait until the initial list of EndpointSlices completed, see `is_synced`.
+    pub async fn wait_synced(&mut self) -> Result<()> {
+        self.receiver
+            .wait_for(|count| count.synced)
+            .await
+            .context("EndpointSlice watcher stopped")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addresses(addresses: &[&str]) -> HashSet<String> {
+        addresses
+            .iter()
+            .map(|address| address.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn counts_endpoints_flapping_within_the_window() {
+        let limits = FlapLimits {
+            transitions: 3,
+            window: Duration::from_secs(60),
+        };
+        let mut histories = EndpointHistories::new("web", Some(limits));
+        let known = addresses(&["10.0.0.1", "10.0.0.2"]);
+        let start = Instant::now();
+        let both = addresses(&["10.0.0.1", "10.0.0.2"]);
+        let second = addresses(&["10.0.0.2"]);
+
+        assert_eq!(
+            histories.record_transitions(&both, &known, start),
+            (0, vec![])
+        );
+        let at = |secs| start + Duration::from_secs(secs);
+        assert_eq!(
+            histories.record_transitions(&second, &known, at(1)),
+            (0, vec![])
+        );
+        assert_eq!(
+            histories.record_transitions(&both, &known, at(2)),
+            (0, vec![])
+        );
+        assert_eq!(
+            histories.record_transitions(&second, &known, at(3)),
+            (1, vec!["10.0.0.1".to_owned()])
+        );
+        // still flapping, but reported only once
+        assert_eq!(
+            histories.record_transitions(&second, &known, at(4)),
+            (1, vec![])
+        );
+        // the transitions expired from the window
+        assert_eq!(
+            histories.record_transitions(&second, &known, at(100)),
+            (0, vec![])
+        );
+    }
+
+    #[test]
+    fn forgets_endpoints_once_gone_and_expired() {
+        let limits = FlapLimits {
+            transitions: 2,
+            window: Duration::from_secs(60),
+        };
+        let mut histories = EndpointHistories::new("web", Some(limits));
+        let start = Instant::now();
+        let known = addresses(&["10.0.0.1"]);
+        histories.record_transitions(&known, &known, start);
+        // disappearing counts as a transition to not serving
+        let none = HashSet::new();
+        histories.record_transitions(&none, &none, start + Duration::from_secs(1));
+        assert_eq!(histories.by_address["10.0.0.1"].transitions.len(), 1);
+        histories.record_transitions(&none, &none, start + Duration::from_secs(100));
+        assert!(histories.by_address.is_empty());
+    }
+
+    #[test]
+    fn tracks_transitions_without_limits() {
+        let mut histories = EndpointHistories::new("web", None);
+        let known = addresses(&["10.0.0.1"]);
+        let start = Instant::now();
+        histories.record_transitions(&known, &known, start);
+        for secs in 1..10 {
+            let serving = match secs % 2 {
+                0 => known.clone(),
+                _ => HashSet::new(),
+            };
+            let at = start + Duration::from_secs(secs);
+            assert_eq!(
+                histories.record_transitions(&serving, &known, at),
+                (0, vec![])
+            );
+        }
+        assert!(histories.by_address["10.0.0.1"].transitions.is_empty());
+    }
 }
@@ -0,0 +1,91 @@
+use crate::audit::Outcome;
+use crate::scaler::WakeCause;
+
+use chrono::{DateTime, Utc};
+use futures::{stream, Stream};
+use serde::Serialize;
+use std::net::SocketAddr;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::*;
+
+/// Events buffered per subscriber before the slowest ones start missing events.
+const CAPACITY: usize = 256;
+
+/// Something that happened to a service, as seen by sero.
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum SeroEvent {
+    #[serde(rename_all = "camelCase")]
+    WakeStarted {
+        deployment: String,
+        cause: WakeCause,
+        timestamp: DateTime<Utc>,
+    },
+    #[serde(rename_all = "camelCase")]
+    WakeCompleted {
+        deployment: String,
+        cause: WakeCause,
+        timestamp: DateTime<Utc>,
+        duration_ms: u64,
+    },
+    #[serde(rename_all = "camelCase")]
+    SleepCompleted {
+        deployment: String,
+        timestamp: DateTime<Utc>,
+    },
+    #[serde(rename_all = "camelCase")]
+    ConnectionAccepted {
+        service: String,
+        client: SocketAddr,
+        timestamp: DateTime<Utc>,
+    },
+    #[serde(rename_all = "camelCase")]
+    ConnectionFailed {
+        service: String,
+        client: SocketAddr,
+        outcome: Outcome,
+        timestamp: DateTime<Utc>,
+    },
+}
+
+/// Broadcasts `SeroEvent`s from where they happen to any number of subscribers, e.g.
+/// notification sinks or code embedding sero.
+#[derive(Clone)]
+pub struct SeroEvents {
+    sender: broadcast::Sender<SeroEvent>,
+}
+
+impl Default for SeroEvents {
+    fn default() -> Self {
+        SeroEvents::new()
+    }
+}
+
+impl SeroEvents {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        SeroEvents { sender }
+    }
+
+    /// Publish an event to all current subscribers. Events without subscribers are dropped.
+    pub fn publish(&self, event: SeroEvent) {
+        trace!("Publishing {event:?}");
+        let _ = self.sender.send(event);
+    }
+
+    /// Stream all events published from now on. A subscriber which falls behind by more
+    /// than the channel capacity skips the oldest events.
+    pub fn subscribe(&self) -> impl Stream<Item = SeroEvent> + Send + 'static {
+        stream::unfold(self.sender.subscribe(), |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => return Some((event, receiver)),
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Event subscriber fell behind, skipped {skipped} events.");
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,179 @@
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
+use k8s_openapi::api::batch::v1::Job;
+use kube::{
+    api::{Api, PostParams},
+    runtime::wait::await_condition,
+    Client,
+};
+use std::{collections::BTreeMap, fmt, fs, path::Path, time::Duration};
+use tracing::*;
+
+/// Label on Jobs created by sero, naming the hook they run for.
+pub const HOOK_LABEL: &str = "sero.rs/hook";
+
+/// Finished hook Jobs are deleted after this long, unless their template says otherwise.
+const HOOK_JOB_TTL: i32 = 60 * 60;
+
+/// When a hook Job runs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HookKind {
+    /// Before the backend is scaled up, e.g. to restore a cache
+    Wake,
+    /// Before the backend is scaled down, e.g. to snapshot its state
+    Sleep,
+}
+
+impl fmt::Display for HookKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HookKind::Wake => f.write_str("wake"),
+            HookKind::Sleep => f.write_str("sleep"),
+        }
+    }
+}
+
+/// What happens when a hook Job fails or times out.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum HookFailurePolicy {
+    /// Do not scale the backend
+    Abort,
+    /// Log the failure and scale the backend anyway
+    Ignore,
+}
+
+/// Job templates run on wake and on sleep, waiting for their completion before scaling.
+#[derive(Clone, Debug)]
+pub struct JobHooks {
+    wake: Option<Job>,
+    sleep: Option<Job>,
+    timeout: Duration,
+    failure_policy: HookFailurePolicy,
+}
+
+impl JobHooks {
+    pub fn new(
+        wake: Option<Job>,
+        sleep: Option<Job>,
+        timeout: Duration,
+        failure_policy: HookFailurePolicy,
+    ) -> Self {
+        JobHooks {
+            wake,
+            sleep,
+            timeout,
+            failure_policy,
+        }
+    }
+
+    /// Hooks with the Job templates read from `wake` and `sleep`.
+    pub fn try_new(
+        wake: Option<&Path>,
+        sleep: Option<&Path>,
+        timeout: Duration,
+        failure_policy: HookFailurePolicy,
+    ) -> Result<Self> {
+        Ok(JobHooks::new(
+            wake.map(load_template).transpose()?,
+            sleep.map(load_template).transpose()?,
+            timeout,
+            failure_policy,
+        ))
+    }
+
+    /// Hooks which never run anything.
+    pub fn none() -> Self {
+        JobHooks {
+            wake: None,
+            sleep: None,
+            timeout: Duration::ZERO,
+            failure_policy: HookFailurePolicy::Ignore,
+        }
+    }
+
+    /// Run the hook Job of `kind` for `deploy_name`, if any, and wait for it to finish.
+    pub async fn run(&self, kind: HookKind, deploy_name: &str, client: &Client) -> Result<()> {
+        let template = match kind {
+            HookKind::Wake => &self.wake,
+            HookKind::Sleep => &self.sleep,
+        };
+        let Some(template) = template else {
+            return Ok(());
+        };
+        let result = tokio::time::timeout(
+            self.timeout,
+            run_job(template.clone(), kind, deploy_name, client),
+        )
+        .await
+        .unwrap_or_else(|_| {
+            Err(anyhow!(
+                "{kind} hook did not finish within {:?}.",
+                self.timeout
+            ))
+        });
+        match (result, self.failure_policy) {
+            (Ok(()), _) => Ok(()),
+            (Err(e), HookFailurePolicy::Abort) => Err(e),
+            (Err(e), HookFailurePolicy::Ignore) => {
+                warn!("Ignoring failed {kind} hook of deployment/{deploy_name}: {e}");
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Read a Job manifest in JSON.
+fn load_template(path: &Path) -> Result<Job> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Could not read Job template {}", path.display()))?;
+    serde_json::from_str(&content)
+        .with_context(|| format!("Could not parse Job template {}", path.display()))
+}
+
+async fn run_job(mut job: Job, kind: HookKind, deploy_name: &str, client: &Client) -> Result<()> {
+    let api: Api<Job> = Api::default_namespaced(client.clone());
+    job.metadata.name = None;
+    job.metadata.generate_name = Some(format!("{deploy_name}-{kind}-"));
+    job.metadata
+        .labels
+        .get_or_insert_with(BTreeMap::new)
+        .insert(HOOK_LABEL.to_owned(), kind.to_string());
+    // do not pile up a finished Job per wake and sleep
+    job.spec
+        .get_or_insert_with(Default::default)
+        .ttl_seconds_after_finished
+        .get_or_insert(HOOK_JOB_TTL);
+    let job = api.create(&PostParams::default(), &job).await?;
+    let name = job.metadata.name.context("Created Job has no name.")?;
+    info!("Running {kind} hook job/{name} for deployment/{deploy_name}.");
+
+    let job = await_condition(api, &name, is_finished)
+        .await?
+        .with_context(|| format!("job/{name} was deleted before it finished."))?;
+    if job_condition(&job, "Complete") {
+        info!("Hook job/{name} completed.");
+        Ok(())
+    } else {
+        bail!("Hook job/{name} failed.")
+    }
+}
+
+fn is_finished(job: Option<&Job>) -> bool {
+    match job {
+        Some(job) => job_condition(job, "Complete") || job_condition(job, "Failed"),
+        // stop waiting, the deletion is reported by the caller
+        None => true,
+    }
+}
+
+fn job_condition(job: &Job, type_: &str) -> bool {
+    job.status
+        .as_ref()
+        .and_then(|status| status.conditions.as_ref())
+        .map(|conditions| {
+            conditions
+                .iter()
+                .any(|condition| condition.type_ == type_ && condition.status == "True")
+        })
+        == Some(true)
+}
@@ -1,10 +1,21 @@
-use anyhow::{Context, Result};
-use json_patch::PatchOperation::{Add, Remove};
-use json_patch::{AddOperation, RemoveOperation};
+use crate::api_metrics;
+use crate::cli::SliceArgs;
+use crate::leader::LeaderElectionHandle;
+use crate::mailbox::Mailbox;
+use crate::tasks;
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use futures::{stream, StreamExt};
 use k8s_openapi::{
     api::{
-        core::v1::{ObjectReference, Pod},
-        discovery::v1::{Endpoint, EndpointPort, EndpointSlice},
+        authorization::v1::{
+            ResourceAttributes, SelfSubjectAccessReview, SelfSubjectAccessReviewSpec,
+        },
+        core::v1::{Node, ObjectReference, Pod},
+        discovery::v1::{
+            Endpoint, EndpointConditions, EndpointHints, EndpointPort, EndpointSlice, ForZone,
+        },
     },
     apimachinery::pkg::apis::meta::v1::OwnerReference,
     Resource,
@@ -14,45 +25,325 @@ use kube::{
     core::ObjectMeta,
     Client,
 };
-use serde_json::Value;
-use std::{collections::BTreeMap, net::IpAddr, str::FromStr, sync::Arc};
-use tokio::sync::mpsc;
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::IpAddr,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::Instant,
+};
 use tracing::*;
 
+/// How clients reach sero while the backend is asleep.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Topology {
+    /// Clients connect to sero directly; no injection is needed
+    Gateway,
+    /// Clients connect to the backend Service; sero injects itself into its EndpointSlices
+    Interceptor,
+}
+
+/// IP family of the addresses sero injects, named like the addressType of EndpointSlices.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum IpFamily {
+    #[value(name = "IPv4")]
+    Ipv4,
+    #[value(name = "IPv6")]
+    Ipv6,
+}
+
+impl IpFamily {
+    pub fn address_type(&self) -> &'static str {
+        match self {
+            IpFamily::Ipv4 => "IPv4",
+            IpFamily::Ipv6 => "IPv6",
+        }
+    }
+
+    pub fn from_address_type(address_type: &str) -> Option<Self> {
+        match address_type {
+            "IPv4" => Some(IpFamily::Ipv4),
+            "IPv6" => Some(IpFamily::Ipv6),
+            _ => None,
+        }
+    }
+
+    fn contains(&self, ip: &IpAddr) -> bool {
+        ip.is_ipv6() == (*self == IpFamily::Ipv6)
+    }
+}
+
+/// Permissions the injector needs, as (group, resource, verb).
+const INJECTOR_PERMISSIONS: [(&str, &str, &str); 4] = [
+    ("", "pods", "get"),
+    ("discovery.k8s.io", "endpointslices", "create"),
+    ("discovery.k8s.io", "endpointslices", "list"),
+    ("discovery.k8s.io", "endpointslices", "patch"),
+];
+
+/// Fail fast if the service account is not allowed to inject sero into EndpointSlices.
+pub async fn check_permissions(client: &Client) -> Result<()> {
+    let api: Api<SelfSubjectAccessReview> = Api::all(client.clone());
+    let mut missing = Vec::new();
+    for (group, resource, verb) in INJECTOR_PERMISSIONS {
+        let review = SelfSubjectAccessReview {
+            spec: SelfSubjectAccessReviewSpec {
+                resource_attributes: Some(ResourceAttributes {
+                    namespace: Some(client.default_namespace().to_owned()),
+                    group: Some(group.to_owned()),
+                    resource: Some(resource.to_owned()),
+                    verb: Some(verb.to_owned()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let allowed = api
+            .create(&PostParams::default(), &review)
+            .await?
+            .status
+            .map(|status| status.allowed)
+            .unwrap_or_default();
+        if !allowed {
+            missing.push(format!("{verb} {resource}"));
+        }
+    }
+    if !missing.is_empty() {
+        bail!(
+            "Interceptor mode requires injecting sero into EndpointSlices, but the service account may not {}. Grant these permissions or run with `--mode gateway` and point clients at sero.",
+            missing.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Label by which a Service selects its EndpointSlices.
+const SERVICE_NAME_LABEL: &str = "kubernetes.io/service-name";
+/// Node label naming the zone a node runs in.
+const ZONE_LABEL: &str = "topology.kubernetes.io/zone";
+
+/// Bounds on patching the EndpointSlices of services with many replicas of sero.
+#[derive(Clone, Copy, Debug)]
+pub struct PatchPacing {
+    /// EndpointSlices patched concurrently
+    pub concurrency: usize,
+    /// Least time between starting two patches
+    pub interval: Option<Duration>,
+    /// Give up on the remaining patches of an inject or eject after this long
+    pub timeout: Duration,
+}
+
+/// Name of the EndpointSlice of a sero pod for a service and address family.
+///
+/// The name is derived from the pod's UID rather than its name, which e.g. StatefulSets reuse,
+/// so that applying it again after a restart or a retry never creates a second slice.
+fn endpointslice_name(svc_name: &str, pod_uid: &str, address_type: &str) -> String {
+    // FNV-1a, which unlike `DefaultHasher` is stable across releases
+    let hash = format!("{svc_name}/{pod_uid}")
+        .bytes()
+        .fold(0xcbf29ce484222325_u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+    match address_type {
+        "IPv6" => format!("{svc_name}-sero-{hash:016x}-v6"),
+        _ => format!("{svc_name}-sero-{hash:016x}"),
+    }
+}
+
+/// sero accepts connections while its EndpointSlices exist, only endpoints with these
+/// conditions receive traffic and count as serving.
+fn serving_conditions() -> EndpointConditions {
+    EndpointConditions {
+        ready: Some(true),
+        serving: Some(true),
+        terminating: Some(false),
+    }
+}
+
+pub fn is_injected(ep_slice: &EndpointSlice) -> bool {
+    ep_slice
+        .metadata
+        .labels
+        .as_ref()
+        .map(|labels| labels.contains_key(SERVICE_NAME_LABEL))
+        == Some(true)
+}
+
+/// Add or remove the `kubernetes.io/service-name` label on all of sero's EndpointSlices
+/// which are not already in the desired state, paced by `PatchPacing`. Fails naming the
+/// slices which could not be patched, or not in time.
+pub async fn set_service_label(
+    client: &Client,
+    svc_name: &str,
+    injected: bool,
+    pacing: PatchPacing,
+) -> Result<()> {
+    let api: Api<EndpointSlice> = Api::default_namespaced(client.clone());
+    let selector = ListParams::default().labels(&format!("sero.rs/service-name={svc_name}"));
+    let ep_slices = api.list(&selector).await?.items;
+    let total = ep_slices.len();
+    let pending: Vec<String> = ep_slices
+        .into_iter()
+        .filter(|ep_slice| is_injected(ep_slice) != injected)
+        .filter_map(|ep_slice| ep_slice.metadata.name)
+        .collect();
+    let modified = pending.len();
+
+    let api = &api;
+    let start = Instant::now();
+    let mut patched = HashSet::new();
+    let mut failed = Vec::new();
+    let patch_all = async {
+        let mut patches = stream::iter(pending.iter().enumerate())
+            .map(|(i, name)| async move {
+                if let Some(interval) = pacing.interval {
+                    tokio::time::sleep_until(start + interval * i as u32).await;
+                }
+                (
+                    name,
+                    apply_label(api, svc_name, name.clone(), injected).await,
+                )
+            })
+            .buffer_unordered(pacing.concurrency);
+        while let Some((name, result)) = patches.next().await {
+            patched.insert(name);
+            if let Err(e) = result {
+                failed.push(format!("{name} ({e})"));
+            }
+        }
+    };
+    let timed_out = tokio::time::timeout(pacing.timeout, patch_all)
+        .await
+        .is_err();
+    if timed_out {
+        let unfinished: Vec<&str> = pending
+            .iter()
+            .filter(|name| !patched.contains(name))
+            .map(String::as_str)
+            .collect();
+        failed.push(format!(
+            "{} not within {:?} ({})",
+            unfinished.len(),
+            pacing.timeout,
+            unfinished.join(", ")
+        ));
+    }
+    if !failed.is_empty() {
+        bail!(
+            "Could not patch all of {modified} endpointslices for service/{svc_name}: {}",
+            failed.join(", ")
+        );
+    }
+    // reconciliations usually find nothing to modify
+    match modified {
+        0 => debug!("Modified none of {total} endpointslices for service/{svc_name}."),
+        _ => info!("Modified {modified} of {total} endpointslices for service/{svc_name}."),
+    }
+    Ok(())
+}
+
+/// Apply the label set of an EndpointSlice as the only owner of `kubernetes.io/service-name`,
+/// so that leaving it out of the applied configuration removes it.
+async fn apply_label(
+    api: &Api<EndpointSlice>,
+    svc_name: &str,
+    name: String,
+    injected: bool,
+) -> Result<()> {
+    let labels = if injected {
+        json!({ SERVICE_NAME_LABEL: svc_name })
+    } else {
+        json!({})
+    };
+    let patch = json!({
+        "apiVersion": "discovery.k8s.io/v1",
+        "kind": "EndpointSlice",
+        "metadata": {
+            "name": name,
+            "labels": labels,
+        },
+    });
+    let params = PatchParams::apply("labels.injector.sero.rs").force();
+    api.patch(&name, &params, &Patch::Apply(&patch)).await?;
+    Ok(())
+}
+
 struct Injector {
     name: String,
-    port: u16,
+    /// Namespace of sero's own pod, which may differ from the service's
+    pod_namespace: String,
     svc_name: String,
-    svc_port_name: String,
+    /// Names of the service's ports and the ports sero listens on for them
+    ports: Vec<(String, u16)>,
+    /// Hint sero's endpoint for all zones, see `zone_hints`
+    zone_hints: bool,
+    /// Families of the addresses to inject, those of the service
+    ip_families: Vec<IpFamily>,
     receiver: mpsc::Receiver<InjectorMessage>,
     client: Arc<Client>,
+    /// Only the leader labels the EndpointSlices of all replicas
+    leader: Option<LeaderElectionHandle>,
+    /// Whether sero was last asked to be injected
+    injected: bool,
+    /// Names of the EndpointSlices of this pod
+    own_slices: Vec<String>,
+    pacing: PatchPacing,
 }
 
 impl Injector {
-    fn try_new(
-        receiver: mpsc::Receiver<InjectorMessage>,
-        svc_name: &str,
-        svc_port_name: &str,
-        port: u16,
-        client: Arc<Client>,
-    ) -> Result<Self> {
-        // read own hostname
-        let hostname = hostname::get()?;
-        let name = hostname.to_str().context("Hostname is not valid UTF8")?;
-        Ok(Injector {
-            name: name.to_owned(),
-            port,
-            svc_name: svc_name.to_owned(),
-            svc_port_name: svc_port_name.to_owned(),
-            receiver,
-            client,
-        })
+    /// Zone of the node sero runs on, and hints routing clients of every zone to sero.
+    ///
+    /// Topology aware routing only routes to endpoints hinted for the client's zone once all
+    /// endpoints of a Service carry hints, so sero's endpoint has to be hinted for all zones
+    /// to not blackhole clients in other zones.
+    async fn topology(&self, node_name: &str) -> Result<(Option<String>, Option<EndpointHints>)> {
+        let nodes: Api<Node> = Api::all((*self.client).clone());
+        let zone_of = |node: &Node| {
+            let labels = node.metadata.labels.as_ref()?;
+            labels.get(ZONE_LABEL).cloned()
+        };
+        let zone = zone_of(&nodes.get(node_name).await?);
+        if !self.zone_hints {
+            return Ok((zone, None));
+        }
+        let mut zones: Vec<String> = nodes
+            .list(&ListParams::default())
+            .await?
+            .iter()
+            .filter_map(zone_of)
+            .collect();
+        zones.sort();
+        zones.dedup();
+        let hints = EndpointHints {
+            for_zones: Some(zones.into_iter().map(|name| ForZone { name }).collect()),
+        };
+        Ok((zone, Some(hints)))
+    }
+
+    async fn init_endpointslice(&mut self) -> Result<()> {
+        self.apply_endpointslices().await?;
+        self.handle_message(InjectorMessage::Inject).await
     }
 
-    async fn init_endpointslice(&self) -> Result<()> {
+    /// Create or update this pod's EndpointSlices and delete stale ones.
+    async fn apply_endpointslices(&mut self) -> Result<()> {
         // query kube api for info about self
-        let pod: Api<Pod> = Api::default_namespaced((*self.client).clone());
+        let pod: Api<Pod> = Api::namespaced((*self.client).clone(), &self.pod_namespace);
         let pod = pod.get(&self.name).await?;
+        let node_name = pod.spec.as_ref().and_then(|spec| spec.node_name.clone());
+        let (zone, hints) = match &node_name {
+            Some(node_name) => self.topology(node_name).await.unwrap_or_else(|e| {
+                warn!("Could not determine the zone of node/{node_name}: {e}");
+                (None, None)
+            }),
+            None => (None, None),
+        };
         // construct managed endpointslice
         let api_version = Pod::API_VERSION.to_owned();
         let kind = Pod::KIND.to_owned();
@@ -70,11 +361,6 @@ impl Injector {
             .filter_map(|pod_ip| pod_ip.ip.as_ref())
             .filter_map(|ip| IpAddr::from_str(ip).ok())
             .collect();
-        let ipv4_addresses: Vec<String> = ip_addresses
-            .iter()
-            .filter(|ip| ip.is_ipv4())
-            .map(|ip| ip.to_string())
-            .collect();
         let owner_ref = OwnerReference {
             api_version: api_version.clone(),
             kind: kind.clone(),
@@ -82,106 +368,188 @@ impl Injector {
             uid: uid.clone(),
             ..Default::default()
         };
+        // owner references cannot cross namespaces, the garbage collector would delete the slice
+        let owner_references =
+            (namespace == self.client.default_namespace()).then(|| vec![owner_ref]);
         let target_ref = ObjectReference {
             api_version: Some(api_version),
             kind: Some(kind),
             name: Some(self.name.clone()),
             namespace: Some(namespace),
-            uid: Some(uid),
+            uid: Some(uid.clone()),
             ..Default::default()
         };
-        let labels: BTreeMap<String, String> = BTreeMap::from([
-            (
-                "kubernetes.io/service-name".to_owned(),
-                self.svc_name.clone(),
-            ),
-            ("sero.rs/service-name".to_owned(), self.svc_name.clone()),
-        ]);
-        // create managed endpointslice (currently only IPv4)
-        let ep_slice_ipv4 = EndpointSlice {
-            address_type: "IPv4".to_owned(),
-            metadata: ObjectMeta {
-                generate_name: Some(format!("{}-sero-", self.svc_name)),
-                owner_references: Some(vec![owner_ref]),
-                labels: Some(labels),
-                ..Default::default()
-            },
-            endpoints: vec![Endpoint {
-                addresses: ipv4_addresses,
-                target_ref: Some(target_ref),
-                ..Default::default()
-            }],
-            ports: Some(vec![EndpointPort {
-                name: Some(self.svc_port_name.clone()),
-                port: Some(self.port as i32),
+        // kubernetes.io/service-name is owned by another field manager, see `apply_label`
+        let labels: BTreeMap<String, String> =
+            BTreeMap::from([("sero.rs/service-name".to_owned(), self.svc_name.clone())]);
+        let ports: Vec<EndpointPort> = self
+            .ports
+            .iter()
+            .map(|(name, port)| EndpointPort {
+                name: Some(name.clone()),
+                port: Some(*port as i32),
                 ..Default::default()
-            }]),
-        };
+            })
+            .collect();
+        // create or update a managed endpointslice per address family of both the pod and service
         let api: Api<EndpointSlice> = Api::default_namespaced((*self.client).clone());
-        let params = PostParams {
-            field_manager: Some("injector.sero.rs".to_owned()),
-            dry_run: false,
-        };
-        api.create(&params, &ep_slice_ipv4).await?;
+        let params = PatchParams::apply("injector.sero.rs").force();
+        let mut own_slices = Vec::new();
+        for family in &self.ip_families {
+            let name = endpointslice_name(&self.svc_name, &uid, family.address_type());
+            let addresses: Vec<String> = ip_addresses
+                .iter()
+                .filter(|ip| family.contains(ip))
+                .map(|ip| ip.to_string())
+                .collect();
+            if addresses.is_empty() {
+                debug!("Pod has no {} address to inject.", family.address_type());
+                continue;
+            }
+            let ep_slice = EndpointSlice {
+                address_type: family.address_type().to_owned(),
+                metadata: ObjectMeta {
+                    name: Some(name.clone()),
+                    owner_references: owner_references.clone(),
+                    labels: Some(labels.clone()),
+                    ..Default::default()
+                },
+                endpoints: vec![Endpoint {
+                    addresses,
+                    target_ref: Some(target_ref.clone()),
+                    node_name: node_name.clone(),
+                    zone: zone.clone(),
+                    hints: hints.clone(),
+                    conditions: Some(serving_conditions()),
+                    ..Default::default()
+                }],
+                ports: Some(ports.clone()),
+            };
+            api.patch(&name, &params, &Patch::Apply(&ep_slice)).await?;
+            own_slices.push(name);
+        }
+        self.own_slices = own_slices;
+        if self.own_slices.is_empty() {
+            bail!(
+                "Pod has no address of the families of service/{} ({:?}).",
+                self.svc_name,
+                self.ip_families
+            );
+        }
+        if let Err(e) = self.remove_stale_endpointslices(&api, &uid).await {
+            warn!(
+                "Could not remove stale endpointslices of service/{}: {e}",
+                self.svc_name
+            );
+        }
         Ok(())
     }
 
-    async fn inject(&self) -> Result<()> {
-        let api: Api<EndpointSlice> = Api::default_namespaced((*self.client).clone());
+    /// Delete sero's EndpointSlices of this service whose pod is gone, which owner references
+    /// do not cover if sero runs in another namespace than the service, and those of this pod
+    /// under a name other than `endpointslice_name` or of a family no longer injected.
+    async fn remove_stale_endpointslices(
+        &self,
+        api: &Api<EndpointSlice>,
+        own_uid: &str,
+    ) -> Result<()> {
         let selector =
             ListParams::default().labels(&format!("sero.rs/service-name={}", self.svc_name));
-        let params = PatchParams::apply("injector.sero.rs").force();
-        let patch = json_patch::Patch(vec![Add(AddOperation {
-            path: "/metadata/labels/kubernetes.io/service-name".to_owned(),
-            value: Value::String(self.svc_name.clone()),
-        })]);
-        let patch = Patch::Json::<()>(patch);
+        for ep_slice in api.list(&selector).await? {
+            let name = ep_slice.metadata.name.clone().unwrap_or_default();
+            if self.own_slices.contains(&name) {
+                continue;
+            }
+            let Some(target) = ep_slice
+                .endpoints
+                .first()
+                .and_then(|endpoint| endpoint.target_ref.as_ref())
+            else {
+                continue;
+            };
+            let (Some(pod_name), Some(namespace)) = (&target.name, &target.namespace) else {
+                continue;
+            };
+            let pods: Api<Pod> = Api::namespaced((*self.client).clone(), namespace);
+            let pod_uid = pods
+                .get_opt(pod_name)
+                .await?
+                .and_then(|pod| pod.metadata.uid);
+            if pod_uid.is_some() && pod_uid == target.uid && pod_uid.as_deref() != Some(own_uid) {
+                continue;
+            }
+            info!("Deleting stale endpointslice/{name} of pod/{pod_name}.");
+            api.delete(&name, &Default::default()).await?;
+        }
+        Ok(())
+    }
 
+    async fn inject(&self) -> Result<()> {
         info!(
-            "Ejecting sero from endpointslices for service/{}.",
+            "Injecting sero into endpointslices for service/{}.",
             self.svc_name
         );
-        let ep_slices = api.list(&selector).await?;
-        for ep_slice in ep_slices {
-            if let Some(name) = ep_slice.metadata.name {
-                api.patch_metadata(&name, &params, &patch).await?;
-            }
-        }
+        set_service_label(&self.client, &self.svc_name, true, self.pacing).await
+    }
 
+    /// Delete this pod's EndpointSlices, so that clients are no longer routed to it.
+    async fn remove(&mut self) -> Result<()> {
+        let api: Api<EndpointSlice> = Api::default_namespaced((*self.client).clone());
+        for name in self.own_slices.drain(..) {
+            info!("Deleting endpointslice/{name}.");
+            api.delete(&name, &Default::default()).await?;
+        }
         Ok(())
     }
 
     async fn eject(&self) -> Result<()> {
-        let api: Api<EndpointSlice> = Api::default_namespaced((*self.client).clone());
-        let selector =
-            ListParams::default().labels(&format!("sero.rs/service-name={}", self.svc_name));
-        let params = PatchParams::apply("injector.sero.rs").force();
-        let patch = json_patch::Patch(vec![Remove(RemoveOperation {
-            path: "/metadata/labels/kubernetes.io/service-name".to_owned(),
-        })]);
-        let patch = Patch::Json::<()>(patch);
-
         info!(
             "Ejecting sero from endpointslices for service/{}.",
             self.svc_name
         );
-        let ep_slices = api.list(&selector).await?;
-        for ep_slice in ep_slices {
-            if let Some(name) = ep_slice.metadata.name {
-                api.patch_metadata(&name, &params, &patch).await?;
-            }
-        }
+        set_service_label(&self.client, &self.svc_name, false, self.pacing).await
+    }
 
-        Ok(())
+    fn is_leader(&self) -> bool {
+        self.leader
+            .as_ref()
+            .map_or(true, LeaderElectionHandle::is_leader)
     }
 
-    async fn handle_message(&self, msg: InjectorMessage) -> Result<()> {
+    async fn handle_message(&mut self, msg: InjectorMessage) -> Result<()> {
         use InjectorMessage::*;
-        match msg {
-            Inject => self.inject().await?,
-            Eject => self.eject().await?,
+        self.injected = match msg {
+            Inject => true,
+            Eject => false,
+            Remove(sender) => {
+                self.remove().await?;
+                return sender
+                    .send(())
+                    .ok()
+                    .context("Could not answer to Remove message because sender end was dropped.");
+            }
+            // nothing to restore once removed
+            Reconcile if self.own_slices.is_empty() => return Ok(()),
+            Reconcile => {
+                self.apply_endpointslices().await?;
+                if !self.is_leader() {
+                    return Ok(());
+                }
+                return set_service_label(&self.client, &self.svc_name, self.injected, self.pacing)
+                    .await;
+            }
         };
-        Ok(())
+        if !self.is_leader() {
+            debug!(
+                "Leaving the endpointslices of service/{} to the leader.",
+                self.svc_name
+            );
+            return Ok(());
+        }
+        match self.injected {
+            true => self.inject().await,
+            false => self.eject().await,
+        }
     }
 
     async fn run(mut self) {
@@ -190,48 +558,175 @@ impl Injector {
             return;
         }
 
-        while let Some(msg) = self.receiver.recv().await {
-            if let Err(e) = self.handle_message(msg).await {
-                error!("Error while handling InjectorMessage: {e}");
+        let mut leader = self.leader.clone();
+        let mut was_leader = self.is_leader();
+        loop {
+            let leader_changed = async {
+                match leader.as_mut() {
+                    Some(leader) => leader.changed().await,
+                    None => futures::future::pending().await,
+                }
             };
+            tokio::select! {
+                msg = self.receiver.recv() => {
+                    let Some(msg) = msg else {
+                        break;
+                    };
+                    if let Err(e) = self.handle_message(msg).await {
+                        error!("Error while handling InjectorMessage: {e}");
+                    };
+                }
+                _ = leader_changed => {
+                    // a new leader takes over the state the previous one left behind
+                    let is_leader = self.is_leader();
+                    if is_leader && !was_leader {
+                        let msg = match self.injected {
+                            true => InjectorMessage::Inject,
+                            false => InjectorMessage::Eject,
+                        };
+                        if let Err(e) = self.handle_message(msg).await {
+                            error!("Error while handling InjectorMessage: {e}");
+                        };
+                    }
+                    was_leader = is_leader;
+                }
+            }
         }
     }
 }
 
-#[allow(dead_code)]
 #[derive(Debug)]
 enum InjectorMessage {
     Inject,
     Eject,
+    /// Answered once this pod's EndpointSlices are deleted
+    Remove(oneshot::Sender<()>),
+    /// Restore this pod's EndpointSlices and their labels after missed events or manual edits
+    Reconcile,
 }
 
 #[derive(Clone)]
 pub struct InjectorHandle {
-    sender: mpsc::Sender<InjectorMessage>,
+    sender: Mailbox<InjectorMessage>,
 }
 
-#[allow(dead_code)]
 impl InjectorHandle {
+    /// `client` defaults to the namespace of the service, `pod_namespace` is sero's own.
+    /// Addresses of `ip_families` are injected, of both families if it is empty.
+    #[allow(clippy::too_many_arguments)]
     pub fn try_new(
         max_concurrency: usize,
         svc_name: &str,
-        svc_port_name: &str,
-        port: u16,
+        ports: &[(String, u16)],
+        zone_hints: bool,
+        ip_families: &[IpFamily],
+        pod_namespace: &str,
         client: Arc<Client>,
+        leader: Option<LeaderElectionHandle>,
+        pacing: PatchPacing,
     ) -> Result<Self> {
         let (sender, receiver) = mpsc::channel(max_concurrency);
-        let injector = Injector::try_new(receiver, svc_name, svc_port_name, port, client)?;
-        tokio::spawn(injector.run());
-        Ok(InjectorHandle { sender })
+        // read own hostname
+        let hostname = hostname::get()?;
+        let name = hostname.to_str().context("Hostname is not valid UTF8")?;
+        let injector = Injector {
+            name: name.to_owned(),
+            pod_namespace: pod_namespace.to_owned(),
+            svc_name: svc_name.to_owned(),
+            ports: ports.to_vec(),
+            zone_hints,
+            ip_families: match ip_families {
+                [] => vec![IpFamily::Ipv4, IpFamily::Ipv6],
+                families => families.to_vec(),
+            },
+            receiver,
+            client,
+            leader,
+            injected: false,
+            own_slices: Vec::new(),
+            pacing,
+        };
+        tasks::spawn("injector", injector.run());
+        Ok(InjectorHandle {
+            sender: Mailbox::new("injector", sender),
+        })
     }
 
-    pub fn inject(&self) -> Result<()> {
-        self.sender.try_send(InjectorMessage::Inject)?;
-        Ok(())
+    pub async fn inject(&self) -> Result<()> {
+        self.sender.send(InjectorMessage::Inject).await
     }
 
-    pub fn eject(&self) -> Result<()> {
-        self.sender.try_send(InjectorMessage::Eject)?;
-        Ok(())
+    pub async fn eject(&self) -> Result<()> {
+        self.sender.send(InjectorMessage::Eject).await
+    }
+
+    /// Fix drift of this pod's EndpointSlices from the injector's state.
+    pub async fn reconcile(&self) -> Result<()> {
+        self.sender.send(InjectorMessage::Reconcile).await
+    }
+
+    /// Delete this pod's EndpointSlices before it shuts down.
+    pub async fn remove(&self) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.sender.send(InjectorMessage::Remove(tx)).await?;
+        Ok(rx.await?)
+    }
+}
+
+/// Inject sero into the EndpointSlices of a service once, or eject it, e.g. to repair their
+/// labels after sero crashed.
+pub async fn run(injected: bool, args: SliceArgs) -> Result<()> {
+    let SliceArgs {
+        service: svc_name,
+        concurrency,
+        pacing: interval,
+        timeout,
+    } = args;
+    if concurrency == 0 {
+        bail!("--concurrency must be at least 1.");
+    }
+    let client = api_metrics::try_client().await?;
+    let pacing = PatchPacing {
+        concurrency,
+        interval,
+        timeout,
+    };
+    set_service_label(&client, &svc_name, injected, pacing).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::endpoint_watcher;
+
+    #[test]
+    fn injected_endpoints_are_serving() {
+        let ep = Endpoint {
+            addresses: vec!["10.0.0.1".to_owned()],
+            conditions: Some(serving_conditions()),
+            ..Default::default()
+        };
+        assert!(endpoint_watcher::is_serving(&ep));
+    }
+
+    #[test]
+    fn names_endpointslices_by_pod_uid() {
+        let uid = "0b6a2c1e-3f4d-4a5b-8c7d-9e0f1a2b3c4d";
+        let name = endpointslice_name("web", uid, "IPv4");
+        assert_eq!(name, endpointslice_name("web", uid, "IPv4"));
+        assert!(name.starts_with("web-sero-"));
+        assert_eq!(name.len(), "web-sero-".len() + 16);
+        assert_eq!(endpointslice_name("web", uid, "IPv6"), format!("{name}-v6"));
+        assert_ne!(name, endpointslice_name("web", "another-uid", "IPv4"));
+        assert_ne!(name, endpointslice_name("api", uid, "IPv4"));
+    }
+
+    #[test]
+    fn endpoints_without_conditions_are_not_serving() {
+        let ep = Endpoint {
+            addresses: vec!["10.0.0.1".to_owned()],
+            ..Default::default()
+        };
+        assert!(!endpoint_watcher::is_serving(&ep));
     }
 }
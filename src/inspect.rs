@@ -0,0 +1,70 @@
+use crate::api_metrics;
+use crate::cli::StatusArgs;
+use crate::endpoint_watcher::EndpointWatcherHandle;
+use crate::injector;
+use crate::status::EndpointStatus;
+use crate::svc_info::{self, ServicePortInfo};
+
+use anyhow::Result;
+use k8s_openapi::api::{apps::v1::Deployment, discovery::v1::EndpointSlice};
+use kube::api::{Api, ListParams};
+use serde::Serialize;
+use std::sync::Arc;
+
+/// Endpoints and replicas of a service and its backend, as seen by the cluster.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct StatusSummary {
+    service: String,
+    deployment: String,
+    replicas: Option<i32>,
+    ready_replicas: Option<i32>,
+    endpoints: EndpointStatus,
+    /// EndpointSlices of sero pods for the service
+    sero_slices: usize,
+    /// Those of them carrying the service label, which routes clients to sero
+    injected_slices: usize,
+}
+
+/// Print the current endpoints and replicas of a service as JSON, without running a proxy.
+pub async fn run(args: StatusArgs) -> Result<()> {
+    let StatusArgs {
+        service: svc_name,
+        service_port: svc_port,
+        deployment,
+    } = args;
+
+    let client = Arc::new(api_metrics::try_client().await?);
+    let deploy_name = match deployment {
+        Some(deploy_name) => deploy_name,
+        None => svc_info::selected_deployment(&svc_name, &client, &client).await?,
+    };
+    let ServicePortInfo {
+        name: svc_port_name,
+        ..
+    } = ServicePortInfo::try_new(&svc_name, svc_port.as_deref(), client.clone()).await?;
+    let mut endpoints =
+        EndpointWatcherHandle::new(&svc_name, &svc_port_name, &[], None, None, client.clone());
+    endpoints.wait_synced().await?;
+
+    let deployments: Api<Deployment> = Api::default_namespaced((*client).clone());
+    let deployment = deployments.get(&deploy_name).await?;
+    let ep_slices: Api<EndpointSlice> = Api::default_namespaced((*client).clone());
+    let selector = ListParams::default().labels(&format!("sero.rs/service-name={svc_name}"));
+    let sero_slices = ep_slices.list(&selector).await?.items;
+
+    let summary = StatusSummary {
+        service: svc_name,
+        deployment: deploy_name,
+        replicas: deployment.spec.and_then(|spec| spec.replicas),
+        ready_replicas: deployment.status.and_then(|status| status.ready_replicas),
+        endpoints: endpoints.status(),
+        sero_slices: sero_slices.len(),
+        injected_slices: sero_slices
+            .iter()
+            .filter(|ep_slice| injector::is_injected(ep_slice))
+            .count(),
+    };
+    println!("{}", serde_json::to_string_pretty(&summary)?);
+    Ok(())
+}
@@ -0,0 +1,272 @@
+use crate::activity::ActivityHandle;
+use crate::tasks;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use k8s_openapi::{
+    api::coordination::v1::{Lease, LeaseSpec},
+    apimachinery::pkg::apis::meta::v1::MicroTime,
+};
+use kube::{
+    api::{Api, PostParams},
+    core::ObjectMeta,
+    Client,
+};
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::watch;
+use tracing::*;
+
+/// Suffix of the Lease annotations by which replicas report their connections to the leader,
+/// prefixed with the replica's identity.
+const ACTIVITY_ANNOTATION_SUFFIX: &str = ".sero.rs/activity";
+
+/// Connections of a replica, as last reported on the Lease.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct ReplicaActivity {
+    active_connections: usize,
+    at: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct LeaderState {
+    leading: bool,
+    /// Other replicas with active connections, by identity
+    busy_followers: Vec<(String, usize)>,
+}
+
+/// Competes for a Lease with the other replicas of sero, renewing it while leading.
+struct LeaderElector {
+    lease_name: String,
+    identity: String,
+    lease_duration: Duration,
+    api: Api<Lease>,
+    activity: ActivityHandle,
+    sender: watch::Sender<LeaderState>,
+}
+
+impl LeaderElector {
+    /// Acquire or renew the Lease if possible. Returns whether this replica leads.
+    async fn try_lead(&self) -> Result<bool> {
+        let now = Utc::now();
+        let Some(mut lease) = self.api.get_opt(&self.lease_name).await? else {
+            let lease = Lease {
+                metadata: ObjectMeta {
+                    name: Some(self.lease_name.clone()),
+                    ..Default::default()
+                },
+                spec: Some(LeaseSpec {
+                    holder_identity: Some(self.identity.clone()),
+                    lease_duration_seconds: Some(self.lease_duration.as_secs() as i32),
+                    acquire_time: Some(MicroTime(now)),
+                    renew_time: Some(MicroTime(now)),
+                    lease_transitions: Some(0),
+                }),
+            };
+            return match self.api.create(&PostParams::default(), &lease).await {
+                Ok(_) => Ok(true),
+                Err(kube::Error::Api(e)) if e.code == 409 => Ok(false),
+                Err(e) => Err(e.into()),
+            };
+        };
+
+        let spec = lease.spec.get_or_insert_with(Default::default);
+        let leading = spec.holder_identity.as_deref() == Some(self.identity.as_str());
+        let expired = spec.renew_time.as_ref().map_or(true, |renewed| {
+            let duration = spec
+                .lease_duration_seconds
+                .map_or(self.lease_duration.as_secs() as i64, i64::from);
+            renewed.0 + chrono::Duration::seconds(duration) < now
+        });
+        if !leading && !expired {
+            self.send_state(false, &lease);
+            if self.report_activity(&mut lease, now) {
+                // a conflict means that the leader renewed in between, report on the next tick
+                match self
+                    .api
+                    .replace(&self.lease_name, &PostParams::default(), &lease)
+                    .await
+                {
+                    Ok(_) => {}
+                    Err(kube::Error::Api(e)) if e.code == 409 => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            return Ok(false);
+        }
+        if !leading {
+            spec.holder_identity = Some(self.identity.clone());
+            spec.acquire_time = Some(MicroTime(now));
+            spec.lease_transitions = Some(spec.lease_transitions.unwrap_or_default() + 1);
+        }
+        spec.renew_time = Some(MicroTime(now));
+        spec.lease_duration_seconds = Some(self.lease_duration.as_secs() as i32);
+        // the leader judges idleness by its own connections, it does not need to report them
+        let own_key = format!("{}{ACTIVITY_ANNOTATION_SUFFIX}", self.identity);
+        if let Some(annotations) = lease.metadata.annotations.as_mut() {
+            annotations.remove(&own_key);
+        }
+        // a conflict means that another replica renewed or reported in between
+        match self
+            .api
+            .replace(&self.lease_name, &PostParams::default(), &lease)
+            .await
+        {
+            Ok(lease) => {
+                self.send_state(true, &lease);
+                Ok(true)
+            }
+            Err(kube::Error::Api(e)) if e.code == 409 => Ok(leading),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Record this replica's connections on the Lease for the leader. Returns whether the
+    /// Lease has to be updated.
+    fn report_activity(&self, lease: &mut Lease, now: DateTime<Utc>) -> bool {
+        let key = format!("{}{ACTIVITY_ANNOTATION_SUFFIX}", self.identity);
+        let active_connections = self.activity.activity().active_connections;
+        let reported = lease
+            .metadata
+            .annotations
+            .as_ref()
+            .and_then(|annotations| annotations.get(&key))
+            .and_then(|value| serde_json::from_str::<ReplicaActivity>(value).ok());
+        // refresh busy reports before they count as stale, leave unchanged idle ones
+        let refresh_after = chrono::Duration::from_std(self.lease_duration / 2).unwrap_or_default();
+        let up_to_date = reported.map_or(false, |reported| {
+            reported.active_connections == active_connections
+                && (active_connections == 0 || now - reported.at < refresh_after)
+        });
+        if up_to_date {
+            return false;
+        }
+        let report = ReplicaActivity {
+            active_connections,
+            at: now,
+        };
+        lease
+            .metadata
+            .annotations
+            .get_or_insert_with(Default::default)
+            .insert(key, serde_json::to_string(&report).unwrap_or_default());
+        true
+    }
+
+    fn send_state(&self, leading: bool, lease: &Lease) {
+        let now = Utc::now();
+        let busy_followers = lease
+            .metadata
+            .annotations
+            .iter()
+            .flatten()
+            .filter_map(|(key, value)| {
+                let identity = key.strip_suffix(ACTIVITY_ANNOTATION_SUFFIX)?;
+                let activity: ReplicaActivity = serde_json::from_str(value).ok()?;
+                // reports of replicas which went away expire with their lease duration
+                let fresh = now - activity.at
+                    < chrono::Duration::from_std(self.lease_duration).unwrap_or_default();
+                (identity != self.identity && fresh && activity.active_connections > 0)
+                    .then(|| (identity.to_owned(), activity.active_connections))
+            })
+            .collect();
+        let state = LeaderState {
+            leading,
+            busy_followers,
+        };
+        self.sender.send_if_modified(|current| {
+            if *current == state {
+                return false;
+            }
+            if current.leading != state.leading {
+                match state.leading {
+                    true => info!("Became the leader of lease/{}.", self.lease_name),
+                    false => info!("Following the leader of lease/{}.", self.lease_name),
+                }
+            }
+            *current = state;
+            true
+        });
+    }
+
+    async fn run(self) {
+        let mut interval = tokio::time::interval(self.lease_duration / 3);
+        loop {
+            interval.tick().await;
+            let leading = match self.try_lead().await {
+                Ok(leading) => leading,
+                Err(e) => {
+                    error!("Error while renewing lease/{}: {e}", self.lease_name);
+                    // stop acting as leader once the lease may have been taken over
+                    false
+                }
+            };
+            if !leading {
+                self.sender.send_if_modified(|state| {
+                    let changed = state.leading;
+                    if changed {
+                        info!("Following the leader of lease/{}.", self.lease_name);
+                    }
+                    state.leading = false;
+                    changed
+                });
+            }
+        }
+    }
+}
+
+/// Elects one of several sero replicas by a Lease, so that only it scales the backend down
+/// and injects or ejects sero, while all replicas proxy.
+#[derive(Clone)]
+pub struct LeaderElectionHandle {
+    receiver: watch::Receiver<LeaderState>,
+}
+
+impl LeaderElectionHandle {
+    pub fn try_new(
+        lease_name: &str,
+        lease_duration: Duration,
+        activity: ActivityHandle,
+        client: Arc<Client>,
+    ) -> Result<Self> {
+        let identity = hostname::get()?
+            .into_string()
+            .ok()
+            .context("Hostname is not valid UTF8")?;
+        let (sender, receiver) = watch::channel(LeaderState::default());
+        let elector = LeaderElector {
+            lease_name: lease_name.to_owned(),
+            identity,
+            lease_duration,
+            api: Api::default_namespaced((*client).clone()),
+            activity,
+            sender,
+        };
+        info!("Competing for lease/{lease_name}.");
+        tasks::spawn("leader-election", elector.run());
+        Ok(LeaderElectionHandle { receiver })
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.receiver.borrow().leading
+    }
+
+    /// Wait until the leadership or the activity of the followers changes.
+    pub async fn changed(&mut self) {
+        if self.receiver.changed().await.is_err() {
+            futures::future::pending::<()>().await;
+        }
+    }
+
+    /// Why this replica must not scale the backend down right now, if it must not.
+    pub fn scale_down_blocker(&self) -> Option<String> {
+        let state = self.receiver.borrow();
+        if !state.leading {
+            return Some("this replica is not the leader".to_owned());
+        }
+        state.busy_followers.first().map(|(identity, connections)| {
+            format!("replica {identity} still has {connections} active connections")
+        })
+    }
+}
@@ -0,0 +1,56 @@
+use clap::ValueEnum;
+use serde_json::Value;
+use std::{
+    io::{self, Write},
+    sync::Arc,
+};
+
+/// How log records are written to stdout.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, for log collectors like Loki or Elasticsearch
+    Json,
+}
+
+/// Writes JSON log lines to stdout, adding fields which are the same for every record.
+#[derive(Clone)]
+pub struct StaticFieldsWriter {
+    /// Serialized fields, each followed by a comma
+    fields: Arc<str>,
+}
+
+impl StaticFieldsWriter {
+    pub fn new(fields: &[(&str, Option<&str>)]) -> Self {
+        let fields: String = fields
+            .iter()
+            .filter_map(|(key, value)| Some((key, (*value)?)))
+            .map(|(key, value)| format!("{}:{},", Value::from(*key), Value::from(value)))
+            .collect();
+        StaticFieldsWriter {
+            fields: fields.into(),
+        }
+    }
+}
+
+impl Write for StaticFieldsWriter {
+    /// Expects a whole record per write, which is how `tracing_subscriber::fmt` writes them.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut stdout = io::stdout().lock();
+        match buf.split_first() {
+            Some((b'{', record)) if !self.fields.is_empty() => {
+                stdout.write_all(b"{")?;
+                stdout.write_all(self.fields.as_bytes())?;
+                stdout.write_all(record)?;
+            }
+            _ => stdout.write_all(buf)?,
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
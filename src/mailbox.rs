@@ -0,0 +1,76 @@
+use crate::metrics;
+
+use anyhow::{anyhow, Result};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::*;
+
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+const WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Sending half of an actor's mailbox which waits for capacity instead of dropping messages.
+pub struct Mailbox<T> {
+    name: &'static str,
+    sender: mpsc::Sender<T>,
+    last_warning: Arc<Mutex<Option<Instant>>>,
+}
+
+impl<T> Clone for Mailbox<T> {
+    fn clone(&self) -> Self {
+        Mailbox {
+            name: self.name,
+            sender: self.sender.clone(),
+            last_warning: self.last_warning.clone(),
+        }
+    }
+}
+
+impl<T> Mailbox<T> {
+    pub fn new(name: &'static str, sender: mpsc::Sender<T>) -> Self {
+        Mailbox {
+            name,
+            sender,
+            last_warning: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Send a message, falling back to waiting for up to `SEND_TIMEOUT` if the mailbox is full.
+    pub async fn send(&self, msg: T) -> Result<()> {
+        let msg = match self.sender.try_send(msg) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Closed(_)) => {
+                return Err(anyhow!("The {} mailbox is closed.", self.name))
+            }
+            Err(TrySendError::Full(msg)) => msg,
+        };
+
+        metrics::MAILBOX_SATURATED
+            .with_label_values(&[self.name])
+            .inc();
+        self.warn_saturated();
+        match tokio::time::timeout(SEND_TIMEOUT, self.sender.send(msg)).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(_)) => Err(anyhow!("The {} mailbox is closed.", self.name)),
+            Err(_) => Err(anyhow!(
+                "Timed out after {SEND_TIMEOUT:?} waiting for capacity in the {} mailbox.",
+                self.name
+            )),
+        }
+    }
+
+    fn warn_saturated(&self) {
+        let mut last_warning = self.last_warning.lock().unwrap();
+        if last_warning.map_or(true, |last| last.elapsed() >= WARNING_INTERVAL) {
+            warn!(
+                mailbox = self.name,
+                capacity = self.sender.max_capacity(),
+                "The {} mailbox is full, senders are waiting for capacity.",
+                self.name
+            );
+            *last_warning = Some(Instant::now());
+        }
+    }
+}
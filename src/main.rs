@@ -1,89 +1,793 @@
+mod activity;
+mod admin;
+mod api_metrics;
+mod audit;
+mod authz;
+mod balancer;
+mod batch;
+mod build_info;
 mod cli;
+mod cold_start;
+mod config;
+mod discovery;
+mod disruption;
+mod e2e;
 mod endpoint_watcher;
+mod events;
+mod hooks;
 mod injector;
+mod inspect;
+mod leader;
+mod log_format;
+mod mailbox;
+mod messages;
+mod metrics;
+mod next_wake_debug;
+mod operator;
+mod otel;
 mod proxy;
+mod proxy_protocol;
+mod readiness_gate;
+mod reconciler;
+mod resolver;
+mod revert_detector;
 mod scaler;
+mod self_test;
+mod sidecar;
+mod sni;
+mod spool;
+mod status;
 mod svc_info;
+mod tasks;
+mod tenancy;
+mod tls;
+mod udp;
+mod usage;
+mod wake_history;
+mod wake_queue;
+mod warmup;
 
-use anyhow::Result;
-use clap::Parser;
-use cli::Cli;
-use endpoint_watcher::EndpointWatcherHandle;
-use injector::InjectorHandle;
-use kube::Client;
-use proxy::Proxy;
-use scaler::ScalerHandle;
-use std::sync::Arc;
+use activity::ActivityHandle;
+use admin::{AdminServer, AdminState};
+use anyhow::{bail, Context, Result};
+use audit::AuditLogHandle;
+use authz::Authorizer;
+use balancer::Balancer;
+use batch::Transition;
+use build_info::BuildInfo;
+use clap::{ArgMatches, CommandFactory, FromArgMatches};
+use cli::{Cli, Command, RunArgs};
+use cold_start::ColdStartTracker;
+use config::ConfigFile;
+use endpoint_watcher::{EndpointWatcherHandle, FlapLimits};
+use events::SeroEvents;
+use hooks::JobHooks;
+use injector::{InjectorHandle, PatchPacing, Topology};
+use leader::LeaderElectionHandle;
+use log_format::StaticFieldsWriter;
+use messages::Messages;
+use next_wake_debug::NextWakeDebugHandle;
+use proxy::{
+    BindOptions, ConnectRetries, ConnectionContext, ConnectionLimit, ConnectionRegistry,
+    DrainNotice, PendingConnections, Protocol, Proxy, ReplayLimits, WakeDeadline,
+};
+use readiness_gate::ReadinessGateHandle;
+use resolver::{BackendResolver, ResolverOptions};
+use scaler::{ConcurrencyScaling, ScaleStrategy, ScalerHandle};
+use self_test::SelfTestHandle;
+use sidecar::SidecarActivityHandle;
+use socket2::TcpKeepalive;
+use spool::{RequestSpool, SpoolLimits};
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
 use svc_info::ServicePortInfo;
-use tokio::signal;
+use tls::TlsTerminatorHandle;
+use tokio::{net::lookup_host, signal, sync::watch};
 use tracing::*;
+use udp::UdpProxy;
+use usage::UsageHandle;
+use wake_history::WakeHistoryHandle;
+use warmup::WarmupHandle;
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+fn main() -> Result<()> {
+    // get params, with defaults from the config file, which exports them to the environment
+    // and thus runs before the runtime starts any threads
+    let config = ConfigFile::load()?;
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(serve(config, cli, &matches))
+}
 
-    // get params
-    let Cli {
+async fn serve(config: Option<ConfigFile>, cli: Cli, matches: &ArgMatches) -> Result<()> {
+    let Cli { command, run } = cli;
+    // `sero run` takes the same options as sero without a subcommand
+    let (command, run) = match command {
+        Some(Command::Run(run)) => (None, *run),
+        command => (command, run),
+    };
+    let RunArgs {
+        config: _,
         listen_host,
+        dual_stack,
         listen_port,
+        listen_port_range,
+        sni_listen,
+        listen,
+        passive_listen,
+        listen_fd,
+        protocol,
+        connect_retries,
+        connect_backoff,
+        replay_timeout,
+        replay_max_bytes,
+        spool_dir,
+        spool_max_requests,
+        spool_max_bytes,
+        health_check_path,
+        dial_pods,
+        lb_strategy,
+        tcp_keepalive,
+        tcp_nodelay,
+        stream_idle_timeout,
+        tcp_keepalive_interval,
+        tcp_keepalive_retries,
+        wake_timeout,
+        wake_timeout_policy,
+        max_pending_connections,
+        max_connections,
+        connection_limit_policy,
+        ipv6_only,
+        bind_retries,
+        fallback_port,
+        drain_timeout,
+        drain_retry_after,
+        namespace,
+        service_namespace,
+        deployment_namespace,
         deployment: deploy_name,
+        target_kind,
+        target,
+        scale_mode,
+        scale_hook,
+        no_scale,
+        wake_job,
+        sleep_job,
+        hook_timeout,
+        hook_failure_policy,
+        idle_timeout,
+        connections_per_replica,
+        min_replicas,
+        max_replicas,
+        scale_in_delay,
+        self_test_on_start,
+        self_test_timeout,
+        sidecar_activity,
+        revert_window,
+        max_reverts,
         service: svc_name,
         service_port: svc_port,
+        all_ports,
+        extra_ports,
+        udp_listen,
+        udp_service_port,
+        udp_flow_timeout,
+        also_watch_service,
+        endpoint_selector,
+        endpoints_stale_after,
+        reconcile_interval,
+        flap_threshold,
+        flap_window,
+        hold_while_flapping,
+        mode,
         inject,
-    } = Cli::parse();
+        zone_hints,
+        injector_concurrency,
+        injector_pacing,
+        injector_timeout,
+        ip_families,
+        readiness_gate,
+        leader_elect,
+        leader_lease,
+        leader_lease_duration,
+        warmup,
+        prewarm_connections,
+        authz_url,
+        authz_timeout,
+        authz_fail_open,
+        tls_secret,
+        resolver,
+        dns_ndots,
+        dns_search,
+        dns_negative_ttl,
+        proxy_protocol_in,
+        proxy_protocol_out,
+        templates_dir,
+        admin_addr,
+        control_addr,
+        audit_sink,
+        access_log,
+        audit_buffer,
+        audit_max_bytes,
+        audit_max_files,
+        usage_configmap,
+        log_format,
+    } = run;
+    let writer = StaticFieldsWriter::new(&[
+        ("service", svc_name.as_deref()),
+        (
+            "deployment",
+            deploy_name
+                .as_deref()
+                .or(target.as_ref().map(|target| target.name.as_str())),
+        ),
+    ]);
+    let log_filter = next_wake_debug::init_logging(log_format, writer);
+    match command {
+        Some(Command::E2e(args)) => return e2e::run(args).await,
+        Some(Command::Sidecar(args)) => return sidecar::run(args).await,
+        Some(Command::Operator(args)) => return operator::run(args).await,
+        Some(Command::Discover(args)) => return discovery::run(args).await,
+        Some(Command::Wake(args)) => return batch::run(Transition::Wake, args).await,
+        Some(Command::Sleep(args)) => return batch::run(Transition::Sleep, args).await,
+        Some(Command::Inject(args)) => return injector::run(true, args).await,
+        Some(Command::Eject(args)) => return injector::run(false, args).await,
+        Some(Command::Status(args)) => return inspect::run(args).await,
+        Some(Command::Run(_)) | None => {}
+    }
+    let connection_limit = ConnectionLimit::from_options(max_connections, connection_limit_policy)?;
+    if let Some(config) = config.filter(|config| !config.services.is_empty()) {
+        config.check_options(matches)?;
+        let pipelines =
+            config::start_services(config.services, sni_listen, connection_limit).await?;
+        graceful_shutdown().await;
+        pipelines.shutdown(drain_timeout).await;
+        otel::shutdown();
+        return Ok(());
+    }
+    let listen_fds: Vec<i32> = listen_fd
+        .into_iter()
+        .chain(proxy::systemd_listen_fds())
+        .collect();
+    let (target_kind, deploy_name) = match target {
+        Some(target) => (target.kind, Some(target.name)),
+        None => (target_kind, deploy_name),
+    };
+    let svc_name = svc_name.context("Missing --service.")?;
     let max_concurrency = 512;
+    let topology = match (mode, inject) {
+        (Some(Topology::Gateway), true) => {
+            bail!("--inject contradicts --mode gateway, which never injects sero.")
+        }
+        (Some(topology), _) => topology,
+        (None, true) => Topology::Interceptor,
+        (None, false) => Topology::Gateway,
+    };
+    let concurrency_scaling = match connections_per_replica {
+        Some(0) => bail!("--connections-per-replica must be at least 1."),
+        Some(connections_per_replica) => {
+            if min_replicas < 1 || max_replicas < min_replicas {
+                bail!("--min-replicas must be at least 1 and at most --max-replicas.");
+            }
+            Some(ConcurrencyScaling {
+                connections_per_replica,
+                min_replicas,
+                max_replicas,
+                scale_in_delay,
+            })
+        }
+        None => None,
+    };
+    if flap_threshold == Some(0) {
+        bail!("--flap-threshold must be at least 1.");
+    }
+    if injector_concurrency == 0 {
+        bail!("--injector-concurrency must be at least 1.");
+    }
+    if spool_dir.is_some() && protocol != Protocol::Http {
+        bail!("--spool-dir requires --protocol http.");
+    }
+    if !health_check_path.is_empty() && protocol != Protocol::Http {
+        bail!("--health-check-path requires --protocol http.");
+    }
 
-    // set up a kube api client
-    let client = Arc::new(Client::try_default().await?);
+    // set up kube api clients for sero's own, the service's and the deployment's namespace
+    let client = Arc::new(api_metrics::try_client().await?);
+    let svc_namespace = service_namespace.or_else(|| namespace.clone());
+    let svc_client = Arc::new(api_metrics::try_client_in(svc_namespace.as_deref()).await?);
+    // qualify the backend's DNS name if it may live in another namespace
+    let backend_host = match &svc_namespace {
+        Some(namespace) => format!("{svc_name}.{namespace}"),
+        None => svc_name.clone(),
+    };
+    let deploy_namespace = deployment_namespace.or(namespace);
+    let deploy_client = Arc::new(api_metrics::try_client_in(deploy_namespace.as_deref()).await?);
     info!("Successfully connected to Kube API.");
+    let build_info = Arc::new(BuildInfo::detect(&client).await);
+    let deploy_name = match deploy_name {
+        Some(deploy_name) => deploy_name,
+        None if target_kind.is_deployment() => {
+            let deploy_name =
+                svc_info::selected_deployment(&svc_name, &svc_client, &deploy_client).await?;
+            info!("Scaling deployment/{deploy_name}, which is selected by service/{svc_name}.");
+            deploy_name
+        }
+        None => {
+            bail!("Missing --deployment or --target, which can only be derived for Deployments.")
+        }
+    };
+    let target = target_kind.resolve(&deploy_name, &deploy_client).await?;
 
     // get info about backend service
+    let primary_port =
+        ServicePortInfo::try_new(&svc_name, svc_port.as_deref(), svc_client.clone()).await?;
+    primary_port.validate(&svc_name, protocol)?;
     let ServicePortInfo {
         name: svc_port_name,
         number: svc_port_number,
-    } = ServicePortInfo::try_new(&svc_name, svc_port.as_deref(), client.clone()).await?;
+        ..
+    } = primary_port;
+    let extra_ports = if all_ports || !extra_ports.is_empty() {
+        let names = (!all_ports).then_some(&extra_ports[..]);
+        let mut ports =
+            ServicePortInfo::others(&svc_name, &svc_port_name, names, svc_client.clone()).await?;
+        if all_ports {
+            // proxy the ports which can be proxied rather than refusing to start
+            ports.retain(|port| {
+                if !port.is_tcp() {
+                    warn!(
+                        service = %svc_name,
+                        port = %port.name,
+                        protocol = %port.protocol,
+                        "Not proxying non-TCP port of --all-ports."
+                    );
+                }
+                port.is_tcp()
+            });
+        }
+        for port in &ports {
+            port.validate(&svc_name, protocol)?;
+        }
+        ports
+    } else {
+        Vec::new()
+    };
+    // ports of a listen port range which the service declares, to inject sero for them too
+    let range_ports = match listen_port_range {
+        Some(range) => ServicePortInfo::list(&svc_name, svc_client.clone())
+            .await?
+            .into_iter()
+            .filter(|port| port.is_tcp() && range.contains(port.number))
+            .filter(|port| {
+                port.name != svc_port_name && !extra_ports.iter().any(|p| p.name == port.name)
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+    let injected_ports: Vec<(String, u16)> = std::iter::once((svc_port_name.clone(), listen_port))
+        .chain(
+            extra_ports
+                .iter()
+                .chain(&range_ports)
+                .map(|port| (port.name.clone(), port.number)),
+        )
+        .collect();
+
+    // watch target endpoints
+    let flap_limits = flap_threshold.map(|transitions| FlapLimits {
+        transitions,
+        window: flap_window,
+    });
+    let extra_selectors: Vec<String> = also_watch_service
+        .iter()
+        .map(|name| format!("kubernetes.io/service-name={name}"))
+        .chain(endpoint_selector)
+        .collect();
+    let endpoints = EndpointWatcherHandle::new(
+        &svc_name,
+        &svc_port_name,
+        &extra_selectors,
+        Some(endpoints_stale_after),
+        flap_limits,
+        svc_client.clone(),
+    );
+
+    // track client activity
+    let activity = ActivityHandle::new(&deploy_name, idle_timeout, endpoints.clone());
+
+    // elect the replica which scales down and injects
+    let leader = if leader_elect {
+        let lease = leader_lease.unwrap_or_else(|| format!("sero-{svc_name}"));
+        Some(LeaderElectionHandle::try_new(
+            &lease,
+            leader_lease_duration,
+            activity.clone(),
+            client.clone(),
+        )?)
+    } else {
+        None
+    };
 
     // start endpointslice injector
-    let _injector = if inject {
+    let injector = if topology == Topology::Interceptor {
+        injector::check_permissions(&svc_client).await?;
+        // a single-stack Service only routes to endpoints of its family
+        let ip_families = if ip_families.is_empty() {
+            svc_info::ip_families(&svc_name, &svc_client).await?
+        } else {
+            ip_families
+        };
         Some(InjectorHandle::try_new(
             max_concurrency,
             &svc_name,
-            &svc_port_name,
-            listen_port,
-            client.clone(),
+            &injected_ports,
+            zone_hints,
+            &ip_families,
+            client.default_namespace(),
+            svc_client.clone(),
+            leader.clone(),
+            PatchPacing {
+                concurrency: injector_concurrency,
+                interval: injector_pacing,
+                timeout: injector_timeout,
+            },
         )?)
     } else {
+        info!(
+            "Running as a gateway, clients have to connect to sero instead of service/{svc_name}."
+        );
         None
     };
 
-    // watch target endpoints
-    let endpoints = EndpointWatcherHandle::new(&svc_name, &svc_port_name, client.clone());
+    // control readiness gates of backend pods
+    let _readiness_gate = if readiness_gate {
+        Some(ReadinessGateHandle::new(
+            target.clone(),
+            deploy_client.clone(),
+            endpoints.clone(),
+            injector.clone(),
+            hold_while_flapping,
+        ))
+    } else {
+        None
+    };
+
+    // publish events to subscribers
+    let events = SeroEvents::new();
 
     // scale backend
+    let scale_strategy = if no_scale {
+        info!("Not scaling {target}, waiting for another autoscaler instead.");
+        ScaleStrategy::Disabled
+    } else {
+        ScaleStrategy::try_new(scale_mode, scale_hook)?
+    };
+    let hooks = JobHooks::try_new(
+        wake_job.as_deref(),
+        sleep_job.as_deref(),
+        hook_timeout,
+        hook_failure_policy,
+    )?;
     let scaler = ScalerHandle::new(
         max_concurrency,
-        &deploy_name,
-        client.clone(),
+        target.clone(),
+        scale_strategy,
+        hooks,
+        None,
+        None,
+        revert_window,
+        max_reverts,
+        deploy_client.clone(),
         endpoints.clone(),
+        events.clone(),
+        leader,
+        injector.clone(),
     );
 
-    // proxy connections
-    let proxy = Proxy::try_new(
-        &listen_host,
-        listen_port,
+    // account usage for chargeback
+    let usage = UsageHandle::new(
+        max_concurrency,
         &svc_name,
+        usage_configmap.as_deref(),
+        client.clone(),
+        endpoints.clone(),
+    );
+
+    // scale down once idle, also on behalf of remote activity
+    scaler.scale_down_when_idle(&activity, endpoints.clone());
+    if let Some(scaling) = concurrency_scaling {
+        scaler.scale_with_concurrency(&activity, scaling);
+    }
+    if let Some(interval) = reconcile_interval {
+        reconciler::reconcile_periodically(interval, scaler.clone(), injector.clone());
+    }
+    let _sidecar_activity = if sidecar_activity {
+        Some(SidecarActivityHandle::new(
+            target.clone(),
+            deploy_client.clone(),
+            activity.clone(),
+        ))
+    } else {
+        None
+    };
+
+    // exercise the wake path before the first client does
+    let self_test = self_test_on_start.then(|| {
+        SelfTestHandle::new(
+            self_test_timeout,
+            scaler.clone(),
+            endpoints.clone(),
+            activity.clone(),
+        )
+    });
+
+    // capture the next wake on request
+    let next_wake_debug = NextWakeDebugHandle::new(log_filter, &events);
+
+    // serve metrics and admin api
+    let (listen_addrs_tx, listen_addrs_rx) = watch::channel(Vec::new());
+    let connections = ConnectionRegistry::default();
+    let admin_state = AdminState {
+        service: svc_name.clone(),
+        deployment: deploy_name.clone(),
+        build_info,
+        listen_addrs: listen_addrs_rx,
+        endpoints: endpoints.clone(),
+        activity: activity.clone(),
+        usage: usage.clone(),
+        self_test,
+        wakes: WakeHistoryHandle::new(
+            &events,
+            Some(ColdStartTracker::new(target.clone(), deploy_client.clone())),
+        ),
+        next_wake_debug: next_wake_debug.clone(),
+        target: target.clone(),
+        client: deploy_client.clone(),
+        scaler: scaler.clone(),
+        injector: injector.clone(),
+        connections: connections.clone(),
+    };
+    let admin = AdminServer::try_new(admin_addr, admin_state.clone())?.read_only();
+    tasks::spawn("admin", admin.run());
+    // routes changing the state of sero or the backend are only reachable from within the pod
+    let control = AdminServer::try_new(control_addr, admin_state)?;
+    tasks::spawn("control", control.run());
+
+    // persist audit records
+    let audit = match audit_sink {
+        Some(sink) => Some(
+            AuditLogHandle::try_new(audit_buffer, sink, audit_max_bytes, audit_max_files).await?,
+        ),
+        None => None,
+    };
+
+    // load client-facing messages
+    let messages = Arc::new(Messages::try_load(templates_dir.as_deref())?);
+
+    // authorize new connections
+    let authorizer = authz_url
+        .as_deref()
+        .map(|url| Authorizer::new(url, authz_timeout, authz_fail_open));
+
+    // terminate TLS of client connections
+    let tls = tls_secret
+        .as_deref()
+        .map(|secret| TlsTerminatorHandle::new(secret, client.clone()));
+
+    // resolve the backend, caching lookups across connections
+    let resolver = BackendResolver::try_new(
+        resolver,
+        &ResolverOptions {
+            ndots: dns_ndots,
+            search: dns_search,
+            negative_ttl: dns_negative_ttl,
+        },
+    )?;
+
+    // warm up the connection path when the backend wakes
+    let warmup = WarmupHandle::new(
+        &backend_host,
         svc_port_number,
-        scaler,
-    )
-    .await?;
-    tokio::spawn(proxy.run());
+        resolver.clone(),
+        warmup,
+        prewarm_connections,
+        endpoints.clone(),
+    );
+
+    // keep requests waiting for a wake across restarts, and send those left by the last run
+    let spool = match spool_dir {
+        Some(dir) => {
+            let limits = SpoolLimits {
+                max_requests: spool_max_requests,
+                max_body_bytes: spool_max_bytes,
+            };
+            let spool = RequestSpool::try_new(&dir, limits)?;
+            tasks::spawn(
+                "spool replay",
+                spool.clone().replay(
+                    scaler.clone(),
+                    backend_host.clone(),
+                    svc_port_number,
+                    resolver.clone(),
+                ),
+            );
+            Some(spool)
+        }
+        None => None,
+    };
+
+    // proxy connections
+    let ctx = ConnectionContext {
+        backend_host,
+        backend_port: svc_port_number,
+        resolver: resolver.clone(),
+        http_client: proxy::http_client(resolver),
+        pod_port: dial_pods.then(|| svc_port_name.clone()),
+        balancer: Balancer::new(lb_strategy),
+        protocol,
+        replay: replay_timeout.map(|timeout| ReplayLimits {
+            max_bytes: replay_max_bytes,
+            timeout,
+        }),
+        spool: spool.clone(),
+        health_check_paths: health_check_path.into(),
+        wake_deadline: wake_timeout.map(|timeout| WakeDeadline {
+            timeout,
+            policy: wake_timeout_policy,
+        }),
+        connect_retries: (connect_retries > 0).then_some(ConnectRetries {
+            retries: connect_retries,
+            backoff: connect_backoff,
+        }),
+        pending: max_pending_connections.map(PendingConnections::new),
+        keepalive: tcp_keepalive.map(|idle| {
+            TcpKeepalive::new()
+                .with_time(idle)
+                .with_interval(tcp_keepalive_interval)
+                .with_retries(tcp_keepalive_retries)
+        }),
+        nodelay: tcp_nodelay,
+        stream_idle_timeout,
+        scaler: scaler.clone(),
+        endpoints: endpoints.clone(),
+        audit,
+        access_log,
+        activity: activity.clone(),
+        usage,
+        warmup,
+        messages,
+        authorizer,
+        tls,
+        proxy_protocol_in,
+        proxy_protocol_out,
+        events: events.clone(),
+        next_wake_debug: Some(next_wake_debug),
+        drain_notice: DrainNotice {
+            shutdown: None,
+            retry_after: drain_retry_after,
+        },
+        connections: connections.clone(),
+    };
+    // inherited listeners replace the default listen address
+    let listen_addrs: Vec<SocketAddr> = if !listen_fds.is_empty() || !listen.is_empty() {
+        listen
+    } else {
+        resolve_listen_addrs(&listen_host, listen_port, dual_stack).await?
+    };
+    // listeners close on shutdown, before connections are drained
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let bind_options = BindOptions {
+        ipv6_only,
+        retries: bind_retries,
+        fallback_port,
+    };
+    // forward UDP datagrams to a UDP port of the service
+    if !udp_listen.is_empty() {
+        let port = ServicePortInfo::list(&svc_name, svc_client.clone())
+            .await?
+            .into_iter()
+            .filter(|port| port.protocol == "UDP")
+            .find(|port| {
+                udp_service_port
+                    .as_ref()
+                    .map_or(true, |name| &port.name == name)
+            })
+            .with_context(|| {
+                format!("Service/{svc_name} has no such UDP port for --udp-listen.")
+            })?;
+        let ctx = ConnectionContext {
+            backend_port: port.number,
+            pod_port: None,
+            warmup: None,
+            ..ctx.clone()
+        };
+        for addr in udp_listen {
+            let proxy = UdpProxy::try_new(addr, udp_flow_timeout, ctx.clone())
+                .await?
+                .with_connection_limit(connection_limit.clone());
+            tasks::spawn(format!("udp proxy {addr}"), proxy.run());
+        }
+    }
+    // further ports of the service are proxied on the same port numbers, without warmup
+    let mut extra_addrs = Vec::new();
+    for port in &extra_ports {
+        let ctx = ConnectionContext {
+            backend_port: port.number,
+            pod_port: dial_pods.then(|| port.name.clone()),
+            warmup: None,
+            ..ctx.clone()
+        };
+        let addrs = resolve_listen_addrs(&listen_host, port.number, dual_stack).await?;
+        let options = BindOptions {
+            fallback_port: None,
+            ..bind_options
+        };
+        let proxy = Proxy::try_new(&addrs, &[], options, ctx)
+            .await?
+            .with_shutdown(shutdown_rx.clone())
+            .with_connection_limit(connection_limit.clone());
+        extra_addrs.extend(proxy.local_addrs()?);
+        tasks::spawn(format!("proxy {}", port.name), proxy.run());
+    }
+    let mut proxy = Proxy::try_new(&listen_addrs, &passive_listen, bind_options, ctx)
+        .await?
+        .with_inherited(&listen_fds)?
+        .with_shutdown(shutdown_rx)
+        .with_connection_limit(connection_limit);
+    if let Some(range) = listen_port_range {
+        let mut addrs = Vec::new();
+        for port in range.ports() {
+            addrs.extend(resolve_listen_addrs(&listen_host, port, dual_stack).await?);
+        }
+        let options = BindOptions {
+            fallback_port: None,
+            ..bind_options
+        };
+        proxy = proxy.with_mirrored(&addrs, options).await?;
+    }
+    let mut local_addrs = proxy.local_addrs()?;
+    local_addrs.extend(extra_addrs);
+    listen_addrs_tx.send_replace(local_addrs);
+    tasks::spawn("proxy", proxy.run());
 
     // wait for signal to gracefully exit
     graceful_shutdown().await;
+    shutdown_tx.send_replace(true);
+    // connections still open are served by an awake backend only, new ones go to the replacement
+    if let Err(e) = scaler.drain().await {
+        warn!("Could not stop the scaler: {e}");
+    }
+    // route clients elsewhere while the remaining connections finish
+    if let Some(injector) = &injector {
+        if let Err(e) = injector.remove().await {
+            warn!("Could not delete sero's endpointslices: {e}");
+        }
+    }
+    activity.drain(drain_timeout).await;
+    // requests still waiting for a wake are dropped with the runtime, keep them for the next run
+    if let Some(spool) = &spool {
+        spool.retain();
+    }
+    otel::shutdown();
 
     Ok(())
 }
 
+/// Addresses to listen on for `port`: both unspecified addresses in dual-stack mode, else `host`.
+async fn resolve_listen_addrs(host: &str, port: u16, dual_stack: bool) -> Result<Vec<SocketAddr>> {
+    if dual_stack {
+        return Ok(vec![
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)),
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)),
+        ]);
+    }
+    Ok(lookup_host((host, port)).await?.collect())
+}
+
 async fn graceful_shutdown() {
     let ctrl_c = async {
         match signal::ctrl_c().await {
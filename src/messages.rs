@@ -0,0 +1,170 @@
+use anyhow::{Context, Result};
+use std::{collections::HashMap, fs, path::Path, time::Duration};
+use tracing::*;
+
+const DEFAULT_SPLASH: &str = "<!DOCTYPE html>
+<html><head><meta http-equiv=\"refresh\" content=\"2\"><title>{{service}}</title></head>
+<body><p>{{service}} is starting up, please wait{{eta}}.</p></body></html>
+";
+const DEFAULT_UNAVAILABLE: &str = "<!DOCTYPE html>
+<html><head><title>{{service}}</title></head>
+<body><p>{{service}} is currently unavailable, please try again later.</p></body></html>
+";
+const DEFAULT_FORBIDDEN: &str = "<!DOCTYPE html>
+<html><head><title>{{service}}</title></head>
+<body><p>You are not allowed to access {{service}}.</p></body></html>
+";
+const DEFAULT_RATE_LIMITED: &str = "<!DOCTYPE html>
+<html><head><title>{{service}}</title></head>
+<body><p>{{service}} was woken up too often, please try again in {{retry_after}} seconds.</p></body></html>
+";
+const DEFAULT_MAINTENANCE: &str = "<!DOCTYPE html>
+<html><head><title>{{service}}</title></head>
+<body><p>{{service}} is under maintenance, please try again later.</p></body></html>
+";
+
+/// Client-visible messages which can be overridden by templates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MessageKind {
+    /// HTTP page shown while the backend is starting
+    Splash,
+    /// HTTP 503 body when the backend could not be woken up
+    Unavailable,
+    /// HTTP body while sero is draining
+    Maintenance,
+    /// HTTP 403 body for clients which are not authorized
+    Forbidden,
+    /// HTTP 429 body when the backend may not be woken up again yet
+    RateLimited,
+    /// Raw text written to TCP clients when the backend could not be woken up
+    Banner,
+}
+
+impl MessageKind {
+    const ALL: [MessageKind; 6] = [
+        MessageKind::Splash,
+        MessageKind::Unavailable,
+        MessageKind::Maintenance,
+        MessageKind::Forbidden,
+        MessageKind::RateLimited,
+        MessageKind::Banner,
+    ];
+
+    fn file_name(&self) -> &'static str {
+        match self {
+            MessageKind::Splash => "splash.html",
+            MessageKind::Unavailable => "unavailable.html",
+            MessageKind::Maintenance => "maintenance.html",
+            MessageKind::Forbidden => "forbidden.html",
+            MessageKind::RateLimited => "rate_limited.html",
+            MessageKind::Banner => "banner.txt",
+        }
+    }
+
+    fn default_template(&self) -> Option<&'static str> {
+        match self {
+            MessageKind::Splash => Some(DEFAULT_SPLASH),
+            MessageKind::Unavailable => Some(DEFAULT_UNAVAILABLE),
+            MessageKind::Maintenance => Some(DEFAULT_MAINTENANCE),
+            MessageKind::Forbidden => Some(DEFAULT_FORBIDDEN),
+            MessageKind::RateLimited => Some(DEFAULT_RATE_LIMITED),
+            MessageKind::Banner => None,
+        }
+    }
+}
+
+/// Variables available in templates as `{{service}}`, `{{eta}}`, `{{queue_position}}` and
+/// `{{retry_after}}` (in seconds).
+#[derive(Debug, Default)]
+pub struct MessageVars<'a> {
+    pub service: &'a str,
+    pub eta: Option<Duration>,
+    pub queue_position: Option<usize>,
+    pub retry_after: Option<Duration>,
+}
+
+/// Templates for client-visible messages, optionally localized.
+///
+/// Templates are read from `<dir>/<file>` and localized variants from `<dir>/<lang>/<file>`,
+/// see `MessageKind::file_name`. Missing templates fall back to the built-in defaults.
+#[derive(Debug, Default)]
+pub struct Messages {
+    templates: HashMap<MessageKind, String>,
+    localized: HashMap<String, HashMap<MessageKind, String>>,
+}
+
+impl Messages {
+    pub fn try_load(dir: Option<&Path>) -> Result<Self> {
+        let Some(dir) = dir else {
+            return Ok(Messages::default());
+        };
+        let mut messages = Messages {
+            templates: read_templates(dir)?,
+            localized: HashMap::new(),
+        };
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                let lang = entry
+                    .file_name()
+                    .into_string()
+                    .ok()
+                    .context("Template language directory is not valid UTF8")?;
+                let templates = read_templates(&entry.path())?;
+                messages.localized.insert(lang.to_lowercase(), templates);
+            }
+        }
+        info!(
+            "Loaded {} message templates and {} localizations from {}.",
+            messages.templates.len(),
+            messages.localized.len(),
+            dir.display()
+        );
+        Ok(messages)
+    }
+
+    /// Render a message, preferring the first language in `langs` which has a template.
+    pub fn render(&self, kind: MessageKind, vars: &MessageVars, langs: &[&str]) -> Option<String> {
+        let template = langs
+            .iter()
+            .filter_map(|lang| self.localized.get(&lang.to_lowercase()))
+            .find_map(|templates| templates.get(&kind))
+            .or_else(|| self.templates.get(&kind))
+            .map(String::as_str)
+            .or_else(|| kind.default_template())?;
+
+        let eta = vars
+            .eta
+            .map(|eta| format!(" (about {}s)", eta.as_secs()))
+            .unwrap_or_default();
+        let queue_position = vars
+            .queue_position
+            .map(|pos| pos.to_string())
+            .unwrap_or_default();
+        let retry_after = vars
+            .retry_after
+            .map(|retry_after| retry_after.as_secs().to_string())
+            .unwrap_or_default();
+        Some(
+            template
+                .replace("{{service}}", vars.service)
+                .replace("{{eta}}", &eta)
+                .replace("{{queue_position}}", &queue_position)
+                .replace("{{retry_after}}", &retry_after),
+        )
+    }
+}
+
+fn read_templates(dir: &Path) -> Result<HashMap<MessageKind, String>> {
+    let mut templates = HashMap::new();
+    for kind in MessageKind::ALL {
+        let path = dir.join(kind.file_name());
+        if path.is_file() {
+            let template = fs::read_to_string(&path)
+                .with_context(|| format!("Could not read template {}", path.display()))?;
+            debug!("Loaded template {}.", path.display());
+            templates.insert(kind, template);
+        }
+    }
+    Ok(templates)
+}
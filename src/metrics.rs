@@ -0,0 +1,265 @@
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge_vec,
+    Encoder, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, TextEncoder,
+};
+use tracing::*;
+
+pub static REPLICA_REVERTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "sero_replica_reverts_total",
+        "Number of times replicas set by sero were reverted by another field manager.",
+        &["deployment", "manager"]
+    )
+    .expect("Failed to register sero_replica_reverts_total")
+});
+
+pub static WAKES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "sero_wakes_total",
+        "Number of times sero woke up a backend.",
+        &["deployment", "cause"]
+    )
+    .expect("Failed to register sero_wakes_total")
+});
+
+pub static MAILBOX_SATURATED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "sero_mailbox_saturated_total",
+        "Number of messages which had to wait because an actor's mailbox was full.",
+        &["mailbox"]
+    )
+    .expect("Failed to register sero_mailbox_saturated_total")
+});
+
+pub static REPLAYED_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "sero_replayed_requests_total",
+        "Number of times a failed HTTP request was replayed to the backend.",
+        &["service"]
+    )
+    .expect("Failed to register sero_replayed_requests_total")
+});
+
+pub static WAKE_TIMEOUTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "sero_wake_timeouts_total",
+        "Number of connections given up on because the backend did not wake up in time.",
+        &["service"]
+    )
+    .expect("Failed to register sero_wake_timeouts_total")
+});
+
+pub static REJECTED_CONNECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "sero_rejected_connections_total",
+        "Number of connections rejected because too many were waiting for the backend to wake up.",
+        &["service"]
+    )
+    .expect("Failed to register sero_rejected_connections_total")
+});
+
+pub static SPOOL_REPLAYS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "sero_spool_replays_total",
+        "Number of requests spooled before sero restarted which were sent to the backend.",
+        &["service"]
+    )
+    .expect("Failed to register sero_spool_replays_total")
+});
+
+pub static IDLE_STREAMS_CLOSED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "sero_idle_streams_closed_total",
+        "Number of proxied connections closed because no bytes flowed for --stream-idle-timeout.",
+        &["service"]
+    )
+    .expect("Failed to register sero_idle_streams_closed_total")
+});
+
+pub static PROXY_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "sero_proxy_errors_total",
+        "Number of proxied connections which failed, by the peer whose socket failed and error class.",
+        &["service", "peer", "class"]
+    )
+    .expect("Failed to register sero_proxy_errors_total")
+});
+
+pub static ENDPOINT_TRANSITIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "sero_endpoint_transitions_total",
+        "Number of times a backend endpoint started or stopped serving.",
+        &["service", "endpoint"]
+    )
+    .expect("Failed to register sero_endpoint_transitions_total")
+});
+
+pub static ACTIVE_CONNECTIONS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "sero_active_connections",
+        "Number of client connections currently proxied by sero.",
+        &["deployment"]
+    )
+    .expect("Failed to register sero_active_connections")
+});
+
+pub static PEAK_CONNECTIONS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "sero_peak_connections",
+        "Most simultaneous client connections during the current awake period.",
+        &["deployment"]
+    )
+    .expect("Failed to register sero_peak_connections")
+});
+
+pub static WAKE_PEAK_CONNECTIONS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "sero_wake_peak_connections",
+        "Most simultaneous client connections per completed awake period.",
+        &["deployment"],
+        prometheus::exponential_buckets(1.0, 2.0, 12).expect("Invalid buckets")
+    )
+    .expect("Failed to register sero_wake_peak_connections")
+});
+
+pub static WAKE_DEMAND: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "sero_wake_demand_connections",
+        "Client connections waiting for a wake when it started.",
+        &["deployment"],
+        prometheus::exponential_buckets(1.0, 2.0, 12).expect("Invalid buckets")
+    )
+    .expect("Failed to register sero_wake_demand_connections")
+});
+
+pub static COLD_START_PHASE_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "sero_cold_start_phase_duration_seconds",
+        "Time of wakes spent per phase of starting the backend's first ready pod.",
+        &["deployment", "phase"],
+        prometheus::exponential_buckets(0.1, 2.0, 12).expect("Invalid buckets")
+    )
+    .expect("Failed to register sero_cold_start_phase_duration_seconds")
+});
+
+pub static IDLE_TIMEOUT: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "sero_idle_timeout_seconds",
+        "Currently effective idle timeout, or 0 if the backend is never scaled down.",
+        &["deployment"]
+    )
+    .expect("Failed to register sero_idle_timeout_seconds")
+});
+
+pub static SCALE_DOWN_SCHEDULED: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "sero_scale_down_scheduled_timestamp_seconds",
+        "Unix time at which the backend will be scaled down, or 0 if no scale-down is scheduled.",
+        &["deployment"]
+    )
+    .expect("Failed to register sero_scale_down_scheduled_timestamp_seconds")
+});
+
+pub static WARMUP_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "sero_warmup_duration_seconds",
+        "Time spent warming up the connection path after a wake, saved on the first client connection.",
+        &["service", "phase"]
+    )
+    .expect("Failed to register sero_warmup_duration_seconds")
+});
+
+pub static SERVICE_ACCOUNT_TOKEN_REFRESHES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "sero_service_account_token_refreshes_total",
+        "Number of times the kubelet rotated sero's service account token."
+    )
+    .expect("Failed to register sero_service_account_token_refreshes_total")
+});
+
+pub static AUDIT_RECORDS_DROPPED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "sero_audit_records_dropped_total",
+        "Number of audit records dropped because the buffer was full or the sink failed."
+    )
+    .expect("Failed to register sero_audit_records_dropped_total")
+});
+
+pub static WAKE_QUEUE_WAIT: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "sero_wake_queue_wait_seconds",
+        "Time wakes waited for a free slot of the operator's wake queue.",
+        &["tenant"]
+    )
+    .expect("Failed to register sero_wake_queue_wait_seconds")
+});
+
+pub static CONNECTION_LIMIT_REJECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "sero_connection_limit_rejections_total",
+        "Number of connections closed right after accepting them, or UDP flows dropped, because of --max-connections.",
+        &["service"]
+    )
+    .expect("Failed to register sero_connection_limit_rejections_total")
+});
+
+pub static DENIED_WAKES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "sero_denied_wakes_total",
+        "Number of wakes denied because the namespace used up its wakes of the last hour.",
+        &["namespace"]
+    )
+    .expect("Failed to register sero_denied_wakes_total")
+});
+
+pub static KUBE_API_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "sero_kube_api_request_duration_seconds",
+        "Latency of kube API requests until the response headers arrived.",
+        &["verb", "resource", "code"]
+    )
+    .expect("Failed to register sero_kube_api_request_duration_seconds")
+});
+
+pub static DNS_LOOKUPS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "sero_dns_lookups_total",
+        "Lookups of backend hostnames, by result (ok, not_found or error).",
+        &["host", "result"]
+    )
+    .expect("Failed to register sero_dns_lookups_total")
+});
+
+pub static DNS_LOOKUP_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "sero_dns_lookup_duration_seconds",
+        "Latency of lookups of backend hostnames, including cache hits.",
+        &["host"]
+    )
+    .expect("Failed to register sero_dns_lookup_duration_seconds")
+});
+
+pub static BUILD_INFO: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "sero_build_info",
+        "Always 1, labelled with the version of sero and of the kube API server.",
+        &[
+            "version",
+            "git_sha",
+            "build_date",
+            "features",
+            "kube_api_version"
+        ]
+    )
+    .expect("Failed to register sero_build_info")
+});
+
+/// Render all registered metrics in the Prometheus text format.
+pub fn gather() -> String {
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        error!("Error while encoding metrics: {e}");
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
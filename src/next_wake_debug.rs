@@ -0,0 +1,231 @@
+use crate::events::{SeroEvent, SeroEvents};
+use crate::log_format::{LogFormat, StaticFieldsWriter};
+use crate::otel;
+use crate::tasks;
+
+use futures::StreamExt;
+use std::{
+    collections::HashSet,
+    env,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::*;
+use tracing_subscriber::{
+    filter::{LevelFilter, Targets},
+    prelude::*,
+    reload, Registry,
+};
+
+/// Bytes of each direction of a captured connection which are logged.
+pub const CAPTURED_PAYLOAD_BYTES: usize = 1024;
+
+/// Longest verbose period, in case the captured connections never close.
+const MAX_CAPTURE: Duration = Duration::from_secs(10 * 60);
+
+/// Install the global subscriber, filtered by `RUST_LOG` or at info level like before,
+/// with a filter that can be switched to verbose for a single wake. JSON records carry the
+/// static fields of `writer`. Spans are exported via OTLP if configured, see `otel::try_tracer`.
+pub fn init_logging(format: LogFormat, writer: StaticFieldsWriter) -> LogFilterHandle {
+    let targets = normal_targets();
+    let (filter, reload) = reload::Layer::new(targets.clone());
+    let (tracer, otel_error) = match otel::try_tracer() {
+        Ok(tracer) => (tracer, None),
+        Err(e) => (None, Some(e)),
+    };
+    let (text, json) = match format {
+        LogFormat::Text => (Some(tracing_subscriber::fmt::layer()), None),
+        LogFormat::Json => (
+            None,
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_writer(move || writer.clone()),
+            ),
+        ),
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(text)
+        .with(json)
+        .with(tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)))
+        .init();
+    if let Some(e) = otel_error {
+        error!("Could not set up OTLP export of spans: {e:#}");
+    }
+    LogFilterHandle { reload, targets }
+}
+
+fn normal_targets() -> Targets {
+    env::var("RUST_LOG")
+        .ok()
+        .and_then(|var| Targets::from_str(&var).ok())
+        .unwrap_or_else(|| Targets::new().with_default(LevelFilter::INFO))
+}
+
+/// Switches the log filter between normal and verbose.
+#[derive(Clone)]
+pub struct LogFilterHandle {
+    reload: reload::Handle<Targets, Registry>,
+    /// The filter of normal operation
+    targets: Targets,
+}
+
+impl LogFilterHandle {
+    fn set_verbose(&self, verbose: bool) {
+        let targets = match verbose {
+            // sero at trace, its dependencies at debug level to keep them readable
+            true => Targets::new()
+                .with_default(LevelFilter::DEBUG)
+                .with_target(env!("CARGO_CRATE_NAME"), LevelFilter::TRACE),
+            false => self.targets.clone(),
+        };
+        if let Err(e) = self.reload.reload(targets) {
+            error!("Could not change the log filter: {e}");
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum CaptureState {
+    Off,
+    /// Waiting for the next wake
+    Armed {
+        connections: usize,
+    },
+    /// Verbose since the wake started, until the connections still to capture and the captured
+    /// ones are done
+    Capturing {
+        started: Instant,
+        remaining: usize,
+        open: HashSet<u64>,
+    },
+}
+
+/// Keeps a connection captured until it is dropped.
+pub struct CaptureGuard {
+    handle: NextWakeDebugHandle,
+    id: u64,
+}
+
+impl Drop for CaptureGuard {
+    fn drop(&mut self) {
+        let mut state = self.handle.lock();
+        if let CaptureState::Capturing { open, .. } = &mut *state {
+            open.remove(&self.id);
+        }
+        self.handle.finish_if_done(&mut state);
+    }
+}
+
+/// Verbose logging and payload capture for just the next wake and the first connections
+/// after it, reverting on its own.
+#[derive(Clone)]
+pub struct NextWakeDebugHandle {
+    state: Arc<Mutex<CaptureState>>,
+    log_filter: LogFilterHandle,
+}
+
+impl NextWakeDebugHandle {
+    pub fn new(log_filter: LogFilterHandle, events: &SeroEvents) -> Self {
+        let handle = NextWakeDebugHandle {
+            state: Arc::new(Mutex::new(CaptureState::Off)),
+            log_filter,
+        };
+        let mut events = events.subscribe().boxed();
+        let watcher = handle.clone();
+        tasks::spawn("next-wake-debug", async move {
+            while let Some(event) = events.next().await {
+                if let SeroEvent::WakeStarted { deployment, .. } = event {
+                    watcher.wake_started(&deployment);
+                }
+            }
+        });
+        handle
+    }
+
+    /// Capture the next wake and the first `connections` after it started. Returns false if a
+    /// capture is already armed or running.
+    pub fn arm(&self, connections: usize) -> bool {
+        let mut state = self.lock();
+        if *state != CaptureState::Off {
+            return false;
+        }
+        info!("Capturing the next wake and the first {connections} connections after it.");
+        *state = CaptureState::Armed { connections };
+        true
+    }
+
+    /// Capture the connection `id` if the current capture still takes connections.
+    pub fn connection(&self, id: u64) -> Option<CaptureGuard> {
+        let mut state = self.lock();
+        let CaptureState::Capturing {
+            remaining, open, ..
+        } = &mut *state
+        else {
+            return None;
+        };
+        if *remaining == 0 {
+            return None;
+        }
+        *remaining -= 1;
+        open.insert(id);
+        Some(CaptureGuard {
+            handle: self.clone(),
+            id,
+        })
+    }
+
+    /// Whether the payload of connection `id` is logged.
+    pub fn is_captured(&self, id: u64) -> bool {
+        match &*self.lock() {
+            CaptureState::Capturing { open, .. } => open.contains(&id),
+            _ => false,
+        }
+    }
+
+    fn wake_started(&self, deployment: &str) {
+        let mut state = self.lock();
+        let CaptureState::Armed { connections } = *state else {
+            return;
+        };
+        info!("Capturing the wake of deployment/{deployment} at trace level.");
+        let started = Instant::now();
+        *state = CaptureState::Capturing {
+            started,
+            remaining: connections,
+            open: HashSet::new(),
+        };
+        self.log_filter.set_verbose(true);
+        drop(state);
+        let handle = self.clone();
+        tasks::spawn("next-wake-debug-timeout", async move {
+            tokio::time::sleep(MAX_CAPTURE).await;
+            let mut state = handle.lock();
+            // a later capture runs its own timeout
+            if matches!(*state, CaptureState::Capturing { started: s, .. } if s == started) {
+                warn!("Ending the capture of the wake after {MAX_CAPTURE:?}.");
+                *state = CaptureState::Off;
+                handle.log_filter.set_verbose(false);
+            }
+        });
+    }
+
+    fn finish_if_done(&self, state: &mut CaptureState) {
+        if let CaptureState::Capturing {
+            remaining: 0, open, ..
+        } = state
+        {
+            if open.is_empty() {
+                *state = CaptureState::Off;
+                self.log_filter.set_verbose(false);
+                info!("Captured the wake, logging normally again.");
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CaptureState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
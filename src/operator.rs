@@ -0,0 +1,544 @@
+use crate::activity::{ActivityHandle, IdleTimeout};
+use crate::api_metrics;
+use crate::balancer::Balancer;
+use crate::cli::OperatorArgs;
+use crate::endpoint_watcher::EndpointWatcherHandle;
+use crate::events::SeroEvents;
+use crate::hooks::{HookFailurePolicy, JobHooks};
+use crate::messages::Messages;
+use crate::proxy::{self, BindOptions, ConnectionContext, ConnectionLimit, Protocol, Proxy};
+use crate::resolver::{BackendResolver, ResolverKind, ResolverOptions};
+use crate::scaler::{ScaleStrategy, ScalerHandle, Target};
+use crate::sni::SniRouter;
+use crate::svc_info::ServicePortInfo;
+use crate::tasks::{self, TaskScope};
+use crate::tenancy::Guardrails;
+use crate::usage::UsageHandle;
+use crate::wake_queue::{TenantWakeQueue, WakeQueue};
+
+use anyhow::Result;
+use futures::{future::join_all, StreamExt};
+use k8s_openapi::api::batch::v1::Job;
+use kube::{
+    api::Api,
+    core::params::ListParams,
+    runtime::{self, watcher::Event},
+    Client, CustomResource, CustomResourceExt,
+};
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+use tokio::sync::watch;
+use tracing::*;
+
+/// A service which sero scales to zero, managed by `sero operator`.
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, PartialEq, JsonSchema)]
+#[kube(
+    group = "sero.rs",
+    version = "v1alpha1",
+    kind = "SeroService",
+    namespaced,
+    shortname = "sero"
+)]
+#[serde(rename_all = "camelCase")]
+pub struct SeroServiceSpec {
+    /// Service to proxy to
+    pub service: String,
+    /// Port name of the service, defaults to its first port
+    pub port: Option<String>,
+    /// Deployment to scale
+    pub deployment: String,
+    /// Namespace of the service, defaults to the namespace of the resource
+    pub service_namespace: Option<String>,
+    /// Namespace of the deployment, defaults to the namespace of the resource
+    pub deployment_namespace: Option<String>,
+    /// Port sero listens on for this service
+    pub listen_port: u16,
+    /// Scale the deployment down after it was idle this long, or `auto`
+    pub idle_timeout: Option<String>,
+    /// Tenant sharing the operator's wake slots fairly with others, defaults to the service
+    pub tenant: Option<String>,
+    /// Share of wake slots of the tenant relative to other tenants, defaults to 1
+    pub wake_weight: Option<u32>,
+    /// Hostnames whose TLS connections to the shared SNI listener are routed to this service
+    #[serde(default)]
+    pub sni_hosts: Vec<String>,
+    /// Job to run before waking the deployment, which is only scaled once it completed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(schema_with = "job_schema")]
+    pub wake_job: Option<Job>,
+    /// Job to run before scaling the deployment down, which is only scaled once it completed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(schema_with = "job_schema")]
+    pub sleep_job: Option<Job>,
+}
+
+/// Any Job manifest, validated by the apiserver once a hook creates it.
+fn job_schema(_: &mut SchemaGenerator) -> Schema {
+    serde_json::from_value(json!({
+        "type": "object",
+        "x-kubernetes-preserve-unknown-fields": true,
+    }))
+    .expect("Job schema is valid")
+}
+
+/// A running proxy, scaler and watchers for one `SeroService` or discovered Service.
+struct Pipeline {
+    spec: SeroServiceSpec,
+    scope: TaskScope,
+    sni_router: Option<SniRouter>,
+    /// Activity of the proxy, once started
+    activity: Arc<Mutex<Option<ActivityHandle>>>,
+}
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        self.scope.abort();
+        if let Some(router) = &self.sni_router {
+            router.remove(&self.spec.sni_hosts);
+        }
+    }
+}
+
+/// A scaler shared by the pipelines of all services scaling the same deployment, running
+/// as long as any of them.
+struct SharedScaler {
+    scaler: ScalerHandle,
+    scope: TaskScope,
+}
+
+impl Drop for SharedScaler {
+    fn drop(&mut self) {
+        self.scope.abort();
+    }
+}
+
+/// Shared scalers by `namespace/deployment`.
+type SharedScalers = Arc<Mutex<HashMap<String, Weak<SharedScaler>>>>;
+
+/// Pipelines by name, started, restarted and stopped as their specs come and go.
+pub struct Pipelines {
+    kind: &'static str,
+    pipelines: HashMap<String, Pipeline>,
+    client: Arc<Client>,
+    wake_queue: Option<WakeQueue>,
+    sni_router: Option<SniRouter>,
+    connection_limit: Option<ConnectionLimit>,
+    scalers: SharedScalers,
+    guardrails: Option<Guardrails>,
+    /// Specs not started because their namespace manages too many services
+    refused: HashMap<String, SeroServiceSpec>,
+    /// Closes the listeners of all pipelines
+    shutdown: watch::Sender<bool>,
+}
+
+impl Pipelines {
+    /// `kind` names the resource the pipelines are derived from in logs.
+    pub fn new(kind: &'static str, client: Arc<Client>) -> Self {
+        Pipelines {
+            kind,
+            pipelines: HashMap::new(),
+            client,
+            wake_queue: None,
+            sni_router: None,
+            connection_limit: None,
+            scalers: Default::default(),
+            guardrails: None,
+            refused: HashMap::new(),
+            shutdown: watch::channel(false).0,
+        }
+    }
+
+    /// Share a bounded number of concurrent wakes between the pipelines.
+    pub fn with_wake_queue(mut self, wake_queue: Option<WakeQueue>) -> Self {
+        self.wake_queue = wake_queue;
+        self
+    }
+
+    /// Route TLS connections of a shared listener to the pipelines by their `sni_hosts`.
+    pub fn with_sni_router(mut self, sni_router: Option<SniRouter>) -> Self {
+        self.sni_router = sni_router;
+        self
+    }
+
+    /// Bound the connections open at once across the listeners of all pipelines.
+    pub fn with_connection_limit(mut self, connection_limit: Option<ConnectionLimit>) -> Self {
+        self.connection_limit = connection_limit;
+        self
+    }
+
+    /// Restrict the namespaces of the pipelines and limit each of them.
+    pub fn with_guardrails(mut self, guardrails: Option<Guardrails>) -> Self {
+        self.guardrails = guardrails;
+        self
+    }
+
+    /// (Re)start the pipeline of `name` unless it already runs with the same spec.
+    pub fn reconcile(&mut self, name: String, spec: SeroServiceSpec) {
+        let kind = self.kind;
+        if self.pipelines.get(&name).map(|pipeline| &pipeline.spec) == Some(&spec) {
+            return;
+        }
+        // stop the outdated pipeline first, so that its listener is released
+        self.pipelines.remove(&name);
+        self.refused.remove(&name);
+        if let Some(guardrails) = &self.guardrails {
+            let namespace = self.deployment_namespace(&spec);
+            let managed = self
+                .pipelines
+                .values()
+                .filter(|pipeline| self.deployment_namespace(&pipeline.spec) == namespace)
+                .count();
+            if !guardrails.admits_service(managed) {
+                warn!(
+                    "Not starting {kind}/{name}, namespace/{namespace} already manages {managed} services."
+                );
+                self.refused.insert(name, spec);
+                return;
+            }
+        }
+
+        info!("Starting pipeline of {kind}/{name}.");
+        let scope = TaskScope::new();
+        let (client, pipeline_spec, pipeline_name) =
+            (self.client.clone(), spec.clone(), name.clone());
+        let wake_queue = self.wake_queue.as_ref().map(|wake_queue| {
+            let tenant = spec.tenant.as_deref().unwrap_or(&spec.service);
+            wake_queue.for_tenant(tenant, spec.wake_weight.unwrap_or(1))
+        });
+        let (sni_router, connection_limit, scalers, guardrails, shutdown) = (
+            self.sni_router.clone(),
+            self.connection_limit.clone(),
+            self.scalers.clone(),
+            self.guardrails.clone(),
+            self.shutdown.subscribe(),
+        );
+        let activity = Arc::new(Mutex::new(None));
+        let started_activity = activity.clone();
+        scope.spawn(format!("{kind} {name}"), async move {
+            let started = start_pipeline(
+                pipeline_spec,
+                client,
+                wake_queue,
+                sni_router,
+                connection_limit,
+                scalers,
+                guardrails,
+                shutdown,
+            );
+            match started.await {
+                Ok(activity) => {
+                    *started_activity.lock().unwrap_or_else(|e| e.into_inner()) = Some(activity)
+                }
+                Err(e) => error!("Failed to start pipeline of {kind}/{pipeline_name}: {e}"),
+            }
+        });
+        let sni_router = self.sni_router.clone();
+        self.pipelines.insert(
+            name,
+            Pipeline {
+                spec,
+                scope,
+                sni_router,
+                activity,
+            },
+        );
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.refused.remove(name);
+        if self.pipelines.remove(name).is_some() {
+            info!("Stopped pipeline of {}/{name}.", self.kind);
+            // the namespace may have room for a refused one now
+            for (name, spec) in std::mem::take(&mut self.refused) {
+                self.reconcile(name, spec);
+            }
+        }
+    }
+
+    /// Stop all pipelines not in `names`.
+    pub fn retain(&mut self, names: &[String]) {
+        let stale: Vec<String> = self
+            .pipelines
+            .keys()
+            .filter(|name| !names.contains(name))
+            .cloned()
+            .collect();
+        self.refused.retain(|name, _| names.contains(name));
+        for name in stale {
+            self.remove(&name);
+        }
+    }
+
+    /// Stop accepting connections and scaling, then wait up to `drain_timeout` for the open
+    /// connections of all pipelines to finish.
+    pub async fn shutdown(&self, drain_timeout: Duration) {
+        self.shutdown.send_replace(true);
+        let scalers: Vec<Arc<SharedScaler>> = self
+            .scalers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect();
+        for shared in scalers {
+            if let Err(e) = shared.scaler.drain().await {
+                warn!("Could not stop the scaler: {e}");
+            }
+        }
+        let activities: Vec<ActivityHandle> = self
+            .pipelines
+            .values()
+            .filter_map(|pipeline| {
+                pipeline
+                    .activity
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .clone()
+            })
+            .collect();
+        join_all(
+            activities
+                .iter()
+                .map(|activity| activity.drain(drain_timeout)),
+        )
+        .await;
+    }
+
+    fn deployment_namespace<'a>(&'a self, spec: &'a SeroServiceSpec) -> &'a str {
+        spec.deployment_namespace
+            .as_deref()
+            .unwrap_or_else(|| self.client.default_namespace())
+    }
+}
+
+/// Watch `SeroService`s and run a pipeline for each of them.
+pub async fn run(args: OperatorArgs) -> Result<()> {
+    if args.print_crd {
+        println!("{}", serde_json::to_string_pretty(&SeroService::crd())?);
+        return Ok(());
+    }
+
+    let client = Arc::new(api_metrics::try_client().await?);
+    let api: Api<SeroService> = Api::default_namespaced((*client).clone());
+    let mut events = runtime::watcher(api, ListParams::default()).boxed();
+    info!("Watching SeroServices.");
+
+    let wake_queue = args.max_concurrent_wakes.map(WakeQueue::new);
+    let connection_limit =
+        ConnectionLimit::from_options(args.max_connections, args.connection_limit_policy)?;
+    let sni_router = match args.sni_listen {
+        Some(addr) => Some(SniRouter::try_new(addr, connection_limit.clone()).await?),
+        None => None,
+    };
+    let guardrails = Guardrails::new(
+        args.namespace_selector,
+        args.max_services_per_namespace,
+        args.max_wakes_per_hour,
+    );
+    let mut pipelines = Pipelines::new("seroservice", client)
+        .with_wake_queue(wake_queue)
+        .with_sni_router(sni_router)
+        .with_connection_limit(connection_limit)
+        .with_guardrails(Some(guardrails));
+    while let Some(event) = events.next().await {
+        match event {
+            Err(e) => error!("Error getting next event for SeroServices: {e}"),
+            Ok(Event::Applied(sero_svc)) => {
+                pipelines.reconcile(sero_svc.metadata.name.unwrap_or_default(), sero_svc.spec)
+            }
+            Ok(Event::Deleted(sero_svc)) => {
+                pipelines.remove(&sero_svc.metadata.name.unwrap_or_default())
+            }
+            Ok(Event::Restarted(sero_svcs)) => {
+                let names: Vec<String> = sero_svcs
+                    .iter()
+                    .filter_map(|sero_svc| sero_svc.metadata.name.clone())
+                    .collect();
+                pipelines.retain(&names);
+                for sero_svc in sero_svcs {
+                    pipelines.reconcile(sero_svc.metadata.name.unwrap_or_default(), sero_svc.spec);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Set up everything a single sero process would, spawning its tasks into the current scope.
+/// Returns the activity of its proxy, whose listener closes once `shutdown` turns true.
+#[allow(clippy::too_many_arguments)]
+async fn start_pipeline(
+    spec: SeroServiceSpec,
+    client: Arc<Client>,
+    wake_queue: Option<TenantWakeQueue>,
+    sni_router: Option<SniRouter>,
+    connection_limit: Option<ConnectionLimit>,
+    scalers: SharedScalers,
+    guardrails: Option<Guardrails>,
+    shutdown: watch::Receiver<bool>,
+) -> Result<ActivityHandle> {
+    let max_concurrency = 512;
+    let idle_timeout: Option<IdleTimeout> =
+        spec.idle_timeout.as_deref().map(str::parse).transpose()?;
+    let svc_client = match &spec.service_namespace {
+        Some(namespace) => Arc::new(api_metrics::try_client_in(Some(namespace)).await?),
+        None => client.clone(),
+    };
+    let deploy_client = match &spec.deployment_namespace {
+        Some(namespace) => Arc::new(api_metrics::try_client_in(Some(namespace)).await?),
+        None => client.clone(),
+    };
+    if let Some(guardrails) = &guardrails {
+        guardrails
+            .check_namespace(svc_client.default_namespace(), &client)
+            .await?;
+        guardrails
+            .check_namespace(deploy_client.default_namespace(), &client)
+            .await?;
+    }
+    let wake_budget = guardrails
+        .as_ref()
+        .and_then(|guardrails| guardrails.wake_budget(deploy_client.default_namespace()));
+    let backend_host = match &spec.service_namespace {
+        Some(namespace) => format!("{}.{namespace}", spec.service),
+        None => spec.service.clone(),
+    };
+    let svc_port =
+        ServicePortInfo::try_new(&spec.service, spec.port.as_deref(), svc_client.clone()).await?;
+    svc_port.validate(&spec.service, Protocol::Tcp)?;
+    let ServicePortInfo {
+        name: svc_port_name,
+        number: svc_port_number,
+        ..
+    } = svc_port;
+    let endpoints = EndpointWatcherHandle::new(
+        &spec.service,
+        &svc_port_name,
+        &[],
+        Some(Duration::from_secs(5 * 60)),
+        None,
+        svc_client.clone(),
+    );
+    let events = SeroEvents::new();
+    // services scaling the same deployment share its scaler, which sleeps once all are idle
+    let deploy_key = format!("{}/{}", deploy_client.default_namespace(), spec.deployment);
+    let shared_scaler = {
+        let mut scalers = scalers.lock().unwrap_or_else(|e| e.into_inner());
+        match scalers.get(&deploy_key).and_then(Weak::upgrade) {
+            Some(shared) => {
+                info!(
+                    "Sharing deployment/{} with the other services scaling it.",
+                    spec.deployment
+                );
+                shared
+            }
+            None => {
+                // outlive the pipeline which happens to start the scaler
+                let scope = TaskScope::new();
+                let scaler = scope.enter(|| {
+                    let endpoints = EndpointWatcherHandle::new(
+                        &spec.service,
+                        &svc_port_name,
+                        &[],
+                        Some(Duration::from_secs(5 * 60)),
+                        None,
+                        svc_client,
+                    );
+                    ScalerHandle::new(
+                        max_concurrency,
+                        Target::deployment(&spec.deployment),
+                        ScaleStrategy::PatchScale,
+                        JobHooks::new(
+                            spec.wake_job.clone(),
+                            spec.sleep_job.clone(),
+                            Duration::from_secs(5 * 60),
+                            HookFailurePolicy::Abort,
+                        ),
+                        wake_queue,
+                        wake_budget,
+                        Duration::from_secs(60),
+                        None,
+                        deploy_client,
+                        endpoints,
+                        events.clone(),
+                        None,
+                        None,
+                    )
+                });
+                let shared = Arc::new(SharedScaler { scaler, scope });
+                scalers.insert(deploy_key, Arc::downgrade(&shared));
+                shared
+            }
+        }
+    };
+    let scaler = shared_scaler.scaler.clone();
+    let activity = ActivityHandle::new(&spec.deployment, idle_timeout, endpoints.clone());
+    scaler.scale_down_when_idle(&activity, endpoints.clone());
+    let usage = UsageHandle::new(
+        max_concurrency,
+        &spec.service,
+        None,
+        client,
+        endpoints.clone(),
+    );
+    let resolver = BackendResolver::try_new(ResolverKind::default(), &ResolverOptions::default())?;
+    let ctx = ConnectionContext {
+        backend_host,
+        backend_port: svc_port_number,
+        http_client: proxy::http_client(resolver.clone()),
+        resolver,
+        pod_port: None,
+        balancer: Balancer::default(),
+        protocol: Protocol::Tcp,
+        replay: None,
+        spool: None,
+        health_check_paths: Default::default(),
+        wake_deadline: None,
+        connect_retries: None,
+        pending: None,
+        keepalive: None,
+        nodelay: false,
+        stream_idle_timeout: None,
+        scaler,
+        endpoints,
+        audit: None,
+        access_log: false,
+        activity: activity.clone(),
+        usage,
+        warmup: None,
+        messages: Arc::new(Messages::default()),
+        authorizer: None,
+        tls: None,
+        proxy_protocol_in: false,
+        proxy_protocol_out: false,
+        events,
+        next_wake_debug: None,
+        drain_notice: Default::default(),
+        connections: Default::default(),
+    };
+    if let Some(router) = &sni_router {
+        router.insert(&spec.sni_hosts, &ctx);
+    }
+    let listen_addr = SocketAddr::from(([0, 0, 0, 0], spec.listen_port));
+    // the listener of a replaced pipeline is released asynchronously
+    let bind_options = BindOptions {
+        retries: 5,
+        ..Default::default()
+    };
+    let proxy = Proxy::try_new(&[listen_addr], &[], bind_options, ctx)
+        .await?
+        .with_shutdown(shutdown)
+        .with_connection_limit(connection_limit);
+    tasks::spawn("proxy", async move {
+        let _shared_scaler = shared_scaler;
+        proxy.run().await;
+    });
+    Ok(activity)
+}
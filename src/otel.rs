@@ -0,0 +1,41 @@
+use anyhow::Result;
+use opentelemetry::{
+    global,
+    runtime::Tokio,
+    sdk::{
+        trace::{self, Tracer},
+        Resource,
+    },
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use std::env;
+
+/// Where spans are exported to, e.g. `http://otel-collector:4317`.
+const ENDPOINT_VAR: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+
+/// A tracer exporting spans via OTLP/gRPC, if `OTEL_EXPORTER_OTLP_ENDPOINT` is set. Must be
+/// called within the tokio runtime, which exports batches of spans in the background.
+pub fn try_tracer() -> Result<Option<Tracer>> {
+    let Ok(endpoint) = env::var(ENDPOINT_VAR) else {
+        return Ok(None);
+    };
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config()
+                .with_resource(Resource::new(vec![KeyValue::new("service.name", "sero")])),
+        )
+        .install_batch(Tokio)?;
+    Ok(Some(tracer))
+}
+
+/// Export the spans which are still buffered.
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
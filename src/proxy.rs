@@ -1,69 +1,1811 @@
-use crate::scaler::ScalerHandle;
+use crate::activity::{ActivityHandle, ConnectionGuard};
+use crate::audit::{AuditLogHandle, AuditRecord, Outcome};
+use crate::authz::{Authorizer, AuthzRequest};
+use crate::balancer::Balancer;
+use crate::endpoint_watcher::EndpointWatcherHandle;
+use crate::events::{SeroEvent, SeroEvents};
+use crate::messages::{MessageKind, MessageVars, Messages};
+use crate::metrics;
+use crate::next_wake_debug::{CaptureGuard, NextWakeDebugHandle, CAPTURED_PAYLOAD_BYTES};
+use crate::proxy_protocol;
+use crate::resolver::BackendResolver;
+use crate::scaler::{ScalerHandle, WakeCause, WakeSuppressed};
+use crate::spool::RequestSpool;
+use crate::status::ConnectionInfo;
+use crate::tasks;
+use crate::tenancy::WakeBudgetExhausted;
+use crate::tls::TlsTerminatorHandle;
+use crate::usage::UsageHandle;
+use crate::warmup::WarmupHandle;
 
-use anyhow::Result;
-use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use futures::future::join_all;
+use hyper::{
+    client::HttpConnector, header, server::conn::Http, service::service_fn, Body, Method, Request,
+    Response, StatusCode,
+};
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    fmt,
+    hash::{BuildHasher, Hasher},
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    ops::RangeInclusive,
+    os::unix::io::{FromRawFd, RawFd},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{watch, OwnedSemaphorePermit, Semaphore},
+};
 use tracing::*;
 
+/// Identifies connections in logs.
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Size of the buffer of each direction of a proxied TCP connection.
+const COPY_BUFFER_SIZE: usize = 8 * 1024;
+
+/// How connections are proxied to the backend.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum Protocol {
+    /// Proxy raw TCP streams
+    #[default]
+    Tcp,
+    /// Parse HTTP/1 requests, hold them while the backend wakes up and replay them to it
+    Http,
+}
+
+/// Bounds for replaying failed HTTP requests.
+#[derive(Clone, Copy, Debug)]
+pub struct ReplayLimits {
+    /// Largest request body to buffer
+    pub max_bytes: u64,
+    /// Give up replaying after this long
+    pub timeout: Duration,
+}
+
+/// Retries of connects to the backend, whose pods may not listen yet right after a wake.
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectRetries {
+    pub retries: u32,
+    /// Backoff before the first retry, doubled for each further one
+    pub backoff: Duration,
+}
+
+/// Longest backoff between connect retries.
+const MAX_CONNECT_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Pause after a failed accept, e.g. while out of file descriptors, before accepting again.
+pub const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// What clients get if the backend did not wake up within `--wake-timeout`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum WakeTimeoutPolicy {
+    /// Close the connection without a response
+    Close,
+    /// Write the banner to TCP clients, answer HTTP requests with 503
+    #[default]
+    Respond,
+}
+
+/// Bound for waiting on the backend to wake up.
+#[derive(Clone, Copy, Debug)]
+pub struct WakeDeadline {
+    pub timeout: Duration,
+    pub policy: WakeTimeoutPolicy,
+}
+
+/// Returned by `ensure_backend` if the backend did not wake up within its deadline.
+#[derive(Debug)]
+struct WakeTimedOut(Duration);
+
+impl fmt::Display for WakeTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the backend did not wake up within {:?}", self.0)
+    }
+}
+
+impl std::error::Error for WakeTimedOut {}
+
+/// Bounds the connections and requests waiting for the backend to wake up.
+#[derive(Clone)]
+pub struct PendingConnections {
+    permits: Arc<Semaphore>,
+    depth: usize,
+}
+
+impl PendingConnections {
+    pub fn new(depth: usize) -> Self {
+        PendingConnections {
+            permits: Arc::new(Semaphore::new(depth)),
+            depth,
+        }
+    }
+
+    /// Take a place in the queue until the returned permit is dropped, or fail right away
+    /// with `PendingQueueFull`.
+    fn enter(&self, service: &str) -> Result<OwnedSemaphorePermit> {
+        self.permits.clone().try_acquire_owned().map_err(|_| {
+            metrics::REJECTED_CONNECTIONS
+                .with_label_values(&[service])
+                .inc();
+            PendingQueueFull(self.depth).into()
+        })
+    }
+}
+
+/// Returned by `ensure_backend` if too many connections wait for the backend already.
+#[derive(Debug)]
+struct PendingQueueFull(usize);
+
+impl fmt::Display for PendingQueueFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} connections are already waiting for the backend to wake up",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for PendingQueueFull {}
+
+/// What happens to connections beyond `--max-connections`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum ConnectionLimitPolicy {
+    /// Close them right after accepting them
+    #[default]
+    Reject,
+    /// Stop accepting until a connection closes, leaving them in the listen backlog.
+    /// UDP flows beyond the limit are always dropped, as there is no backlog to wait in.
+    Wait,
+}
+
+/// Bounds the connections open at once across all proxies sharing it.
+#[derive(Clone)]
+pub struct ConnectionLimit {
+    permits: Arc<Semaphore>,
+    max: usize,
+    policy: ConnectionLimitPolicy,
+}
+
+impl ConnectionLimit {
+    pub fn new(max: usize, policy: ConnectionLimitPolicy) -> Self {
+        ConnectionLimit {
+            permits: Arc::new(Semaphore::new(max)),
+            max,
+            policy,
+        }
+    }
+
+    /// The limit given by `--max-connections` and `--connection-limit-policy`, if any.
+    pub fn from_options(max: Option<usize>, policy: ConnectionLimitPolicy) -> Result<Option<Self>> {
+        match max {
+            Some(0) => bail!("--max-connections must be at least 1."),
+            max => Ok(max.map(|max| ConnectionLimit::new(max, policy))),
+        }
+    }
+
+    /// Accept the next connection within the limit, with a permit to hold until it closes.
+    pub async fn accept(
+        &self,
+        listener: &TcpListener,
+        service: &str,
+    ) -> io::Result<(TcpStream, SocketAddr, OwnedSemaphorePermit)> {
+        loop {
+            if self.policy == ConnectionLimitPolicy::Wait {
+                // wait for a free permit without holding it, so that idle listeners sharing the
+                // limit do not starve busy ones
+                drop(
+                    self.permits
+                        .acquire()
+                        .await
+                        .expect("Connection limit semaphore is never closed"),
+                );
+            }
+            let (ingress, client) = listener.accept().await?;
+            let permit = match self.policy {
+                // another listener may have taken the permit meanwhile, then wait for the next
+                ConnectionLimitPolicy::Wait => self
+                    .permits
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("Connection limit semaphore is never closed"),
+                ConnectionLimitPolicy::Reject => match self.try_acquire(service, client) {
+                    Some(permit) => permit,
+                    None => continue,
+                },
+            };
+            return Ok((ingress, client, permit));
+        }
+    }
+
+    /// A permit for a connection or flow of `client` to hold until it closes, unless the limit
+    /// is reached.
+    pub fn try_acquire(&self, service: &str, client: SocketAddr) -> Option<OwnedSemaphorePermit> {
+        match self.permits.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                metrics::CONNECTION_LIMIT_REJECTIONS
+                    .with_label_values(&[service])
+                    .inc();
+                debug!(
+                    "Rejected connection from {client}, {} connections are open already.",
+                    self.max
+                );
+                None
+            }
+        }
+    }
+}
+
 pub struct Proxy {
-    backend_host: String,
-    backend_port: u16,
+    listeners: Vec<Listener>,
+    ctx: ConnectionContext,
+    /// Stop accepting connections once this turns true
+    shutdown: Option<watch::Receiver<bool>>,
+    connection_limit: Option<ConnectionLimit>,
+}
+
+struct Listener {
     listener: TcpListener,
-    scaler: ScalerHandle,
+    /// Whether connections accepted here wake the backend and count as activity
+    activating: bool,
+    /// Forward to this port of the backend instead of the context's
+    backend_port: Option<u16>,
+}
+
+/// Ports which are each forwarded to the same port of the backend, e.g. `7000-7010`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PortRange {
+    pub first: u16,
+    pub last: u16,
+}
+
+impl PortRange {
+    pub fn ports(&self) -> RangeInclusive<u16> {
+        self.first..=self.last
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        self.ports().contains(&port)
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (first, last) = s
+            .split_once('-')
+            .context("Expected a port range like 7000-7010")?;
+        let range = PortRange {
+            first: first.trim().parse()?,
+            last: last.trim().parse()?,
+        };
+        if range.first > range.last {
+            bail!("Port range {s} is empty");
+        }
+        Ok(range)
+    }
+}
+
+/// How to bind the listeners of a `Proxy`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BindOptions {
+    /// Only accept IPv6 connections on IPv6 sockets
+    pub ipv6_only: bool,
+    /// Retry binding an address in use this many times
+    pub retries: u32,
+    /// Bind this port instead once all retries failed
+    pub fallback_port: Option<u16>,
 }
 
 impl Proxy {
+    /// Bind a listener for each address. IPv6 sockets only accept IPv6 connections if
+    /// `ipv6_only` is set or if an IPv4 address is bound as well.
+    ///
+    /// Connections to `passive_addrs` are only proxied while the backend is up, they
+    /// neither wake it nor keep it awake.
     pub async fn try_new(
-        listen_host: &str,
-        listen_port: u16,
-        backend_host: &str,
-        backend_port: u16,
-        scaler: ScalerHandle,
+        listen_addrs: &[SocketAddr],
+        passive_addrs: &[SocketAddr],
+        options: BindOptions,
+        ctx: ConnectionContext,
     ) -> Result<Self> {
-        let listener = TcpListener::bind((listen_host, listen_port)).await?;
-        info!("Listening for TCP connections on {listen_host}:{listen_port}, proxying connections to {backend_host}:{backend_port}.");
-        Ok(Proxy {
-            backend_host: backend_host.to_owned(),
-            backend_port,
+        let all_addrs = || listen_addrs.iter().chain(passive_addrs);
+        let options = BindOptions {
+            ipv6_only: options.ipv6_only || all_addrs().any(|addr| addr.is_ipv4()),
+            ..options
+        };
+        let mut listeners = Vec::with_capacity(listen_addrs.len() + passive_addrs.len());
+        for addr in all_addrs() {
+            listeners.push(Listener {
+                listener: bind_with_retry(*addr, options).await?,
+                activating: !passive_addrs.contains(addr),
+                backend_port: None,
+            });
+        }
+        for Listener {
             listener,
-            scaler,
+            activating,
+            ..
+        } in &listeners
+        {
+            info!(
+                "Listening for {}TCP connections on {}, proxying connections to {}:{}.",
+                if *activating { "" } else { "non-activating " },
+                listener.local_addr()?,
+                ctx.backend_host,
+                ctx.backend_port
+            );
+        }
+        Ok(Proxy {
+            listeners,
+            ctx,
+            shutdown: None,
+            connection_limit: None,
         })
     }
 
+    /// Close the listeners once `shutdown` turns true, leaving accepted connections to finish.
+    pub fn with_shutdown(mut self, shutdown: watch::Receiver<bool>) -> Self {
+        self.ctx.drain_notice.shutdown = Some(shutdown.clone());
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Bound the connections open at once, sharing the bound with the proxies of other ports.
+    pub fn with_connection_limit(mut self, connection_limit: Option<ConnectionLimit>) -> Self {
+        self.connection_limit = connection_limit;
+        self
+    }
+
+    /// Also accept connections on listening sockets inherited from a parent process, e.g.
+    /// by systemd socket activation. These listeners are activating.
+    pub fn with_inherited(mut self, fds: &[RawFd]) -> Result<Self> {
+        for &fd in fds {
+            // SAFETY: the fd was handed to us for exclusive use and is not used elsewhere
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            let listener = TcpListener::from_std(listener)
+                .with_context(|| format!("File descriptor {fd} is not a TCP listener"))?;
+            info!(
+                "Listening for TCP connections on inherited fd {fd} ({}), proxying connections to {}:{}.",
+                listener.local_addr()?,
+                self.ctx.backend_host,
+                self.ctx.backend_port
+            );
+            self.listeners.push(Listener {
+                listener,
+                activating: true,
+                backend_port: None,
+            });
+        }
+        Ok(self)
+    }
+
+    /// Also listen on `addrs`, forwarding each connection to the port of the backend with the
+    /// number of the port it was accepted on, e.g. for the ports of game servers or passive FTP.
+    pub async fn with_mirrored(
+        mut self,
+        addrs: &[SocketAddr],
+        options: BindOptions,
+    ) -> Result<Self> {
+        let options = BindOptions {
+            ipv6_only: options.ipv6_only || addrs.iter().any(|addr| addr.is_ipv4()),
+            ..options
+        };
+        for addr in addrs {
+            self.listeners.push(Listener {
+                listener: bind_with_retry(*addr, options).await?,
+                activating: true,
+                backend_port: Some(addr.port()),
+            });
+        }
+        info!(
+            "Listening for TCP connections on {} addresses, proxying each to the same port of {}.",
+            addrs.len(),
+            self.ctx.backend_host
+        );
+        Ok(self)
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        let listener = self.listeners.first().context("Proxy has no listeners.")?;
+        Ok(listener.listener.local_addr()?)
+    }
+
+    pub fn local_addrs(&self) -> Result<Vec<SocketAddr>> {
+        let addrs = self
+            .listeners
+            .iter()
+            .map(|listener| listener.listener.local_addr())
+            .collect::<std::io::Result<_>>()?;
+        Ok(addrs)
+    }
+
     pub async fn run(self) {
-        while let Ok((ingress, _)) = self.listener.accept().await {
-            let scaler = self.scaler.clone();
-            let backend = (self.backend_host.to_owned(), self.backend_port);
-            tokio::spawn(async move {
-                // only connect if backend is up
-                if let Err(e) = scaler.ensure_up().await {
-                    error!("Failed to ensure a serving backend, dropping connection: {e}");
-                } else {
-                    proxy_tcp_stream(ingress, backend).await;
+        let ctx = self.ctx;
+        let shutdown = self.shutdown;
+        let connection_limit = self.connection_limit;
+        join_all(self.listeners.into_iter().map(|listener| {
+            let ctx = match listener.backend_port {
+                // pods are dialed by port name and there is no warmup of the mirrored ports
+                Some(backend_port) => ConnectionContext {
+                    backend_port,
+                    pod_port: None,
+                    warmup: None,
+                    ..ctx.clone()
+                },
+                None => ctx.clone(),
+            };
+            let mut shutdown = shutdown.clone();
+            let connection_limit = connection_limit.clone();
+            async move {
+                loop {
+                    let accept = async {
+                        match &connection_limit {
+                            Some(limit) => limit
+                                .accept(&listener.listener, &ctx.backend_host)
+                                .await
+                                .map(|(ingress, client, permit)| (ingress, client, Some(permit))),
+                            None => listener
+                                .listener
+                                .accept()
+                                .await
+                                .map(|(ingress, client)| (ingress, client, None)),
+                        }
+                    };
+                    let (ingress, client, permit) = tokio::select! {
+                        accepted = accept => match accepted {
+                            Ok(accepted) => accepted,
+                            Err(e) => {
+                                warn!(
+                                    "Failed to accept a connection on {:?}: {e}",
+                                    listener.listener.local_addr()
+                                );
+                                tokio::select! {
+                                    _ = tokio::time::sleep(ACCEPT_ERROR_BACKOFF) => continue,
+                                    _ = shutdown_requested(&mut shutdown) => break,
+                                }
+                            }
+                        },
+                        _ = shutdown_requested(&mut shutdown) => {
+                            info!(
+                                "Closing listener on {:?}, no longer accepting connections.",
+                                listener.listener.local_addr()
+                            );
+                            break;
+                        }
+                    };
+                    let handle = ctx.clone().handle(ingress, client, listener.activating);
+                    tasks::spawn(format!("connection {client}"), async move {
+                        let _permit = permit;
+                        handle.await
+                    });
+                }
+            }
+        }))
+        .await;
+    }
+}
+
+/// A random delay between half of `backoff` and `backoff`, so that clients waiting for the
+/// same backend do not retry in lockstep.
+fn jittered(backoff: Duration) -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    backoff / 2 + backoff.mul_f64(random as f64 / u64::MAX as f64 / 2.0)
+}
+
+/// Resolves once `shutdown` turns true, never without a receiver.
+async fn shutdown_requested(shutdown: &mut Option<watch::Receiver<bool>>) {
+    let Some(shutdown) = shutdown else {
+        return futures::future::pending().await;
+    };
+    while !*shutdown.borrow_and_update() {
+        if shutdown.changed().await.is_err() {
+            return futures::future::pending().await;
+        }
+    }
+}
+
+/// Listening sockets passed by systemd socket activation, which start at fd 3.
+pub fn systemd_listen_fds() -> Vec<RawFd> {
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse().ok())
+        == Some(std::process::id());
+    let count: RawFd = match std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+    {
+        Some(count) if for_us => count,
+        _ => return Vec::new(),
+    };
+    // do not pass the sockets on to child processes, e.g. scale hooks
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    (3..3 + count).collect()
+}
+
+/// Bind an address, retrying with backoff while it is in use and falling back to another port.
+async fn bind_with_retry(addr: SocketAddr, options: BindOptions) -> Result<TcpListener> {
+    let mut backoff = Duration::from_millis(500);
+    let mut attempt = 0;
+    loop {
+        let e = match bind(addr, options.ipv6_only) {
+            Ok(listener) => return Ok(listener),
+            Err(e) if is_addr_in_use(&e) => e,
+            Err(e) => return Err(e),
+        };
+        if attempt < options.retries {
+            attempt += 1;
+            warn!(
+                "{addr} is in use, retrying in {backoff:?} (attempt {attempt} of {}).",
+                options.retries
+            );
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(Duration::from_secs(8));
+            continue;
+        }
+        return match options.fallback_port {
+            Some(port) => {
+                let fallback = SocketAddr::new(addr.ip(), port);
+                warn!("Could not bind to {addr}, falling back to {fallback}.");
+                bind(fallback, options.ipv6_only)
+            }
+            None => Err(e),
+        };
+    }
+}
+
+fn is_addr_in_use(e: &anyhow::Error) -> bool {
+    e.root_cause()
+        .downcast_ref::<std::io::Error>()
+        .map(|e| e.kind() == ErrorKind::AddrInUse)
+        == Some(true)
+}
+
+fn bind(addr: SocketAddr, ipv6_only: bool) -> Result<TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(addr),
+        Type::STREAM,
+        Some(socket2::Protocol::TCP),
+    )?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket
+        .bind(&addr.into())
+        .with_context(|| format!("Could not bind to {addr}"))?;
+    socket.listen(1024)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+/// Client forwarding HTTP requests to the backend.
+pub type HttpClient = hyper::Client<HttpConnector<BackendResolver>>;
+
+pub fn http_client(resolver: BackendResolver) -> HttpClient {
+    hyper::Client::builder().build(HttpConnector::new_with_resolver(resolver))
+}
+
+/// Everything needed to serve a single connection, shared by all connections of a listener.
+#[derive(Clone)]
+pub struct ConnectionContext {
+    pub backend_host: String,
+    pub backend_port: u16,
+    pub resolver: BackendResolver,
+    /// Pools HTTP connections to the backend across client connections in HTTP mode
+    pub http_client: HttpClient,
+    /// Dial serving pods of the service directly on the target port of this service port,
+    /// instead of resolving the service
+    pub pod_port: Option<String>,
+    /// Orders the addresses of the backend to dial
+    pub balancer: Balancer,
+    pub protocol: Protocol,
+    /// Replay failed idempotent requests in HTTP mode
+    pub replay: Option<ReplayLimits>,
+    /// Spill HTTP requests waiting for a wake to disk, to send them after sero restarted
+    pub spool: Option<RequestSpool>,
+    /// Answer HTTP requests for these paths with 200 without waking or forwarding to the backend
+    pub health_check_paths: Arc<[String]>,
+    /// Give up on connections waiting for the backend to wake up
+    pub wake_deadline: Option<WakeDeadline>,
+    /// Retry connects to the backend which fail
+    pub connect_retries: Option<ConnectRetries>,
+    /// Reject connections beyond these while the backend wakes up
+    pub pending: Option<PendingConnections>,
+    /// Probe idle connections on both sides, so that half-open ones of vanished peers end
+    pub keepalive: Option<TcpKeepalive>,
+    /// Send small writes on both sides right away instead of coalescing them
+    pub nodelay: bool,
+    /// Close proxied connections on which no bytes flowed in either direction for this long
+    pub stream_idle_timeout: Option<Duration>,
+    pub scaler: ScalerHandle,
+    pub endpoints: EndpointWatcherHandle,
+    pub audit: Option<AuditLogHandle>,
+    /// Log a structured record of each connection at target `sero::access`
+    pub access_log: bool,
+    pub activity: ActivityHandle,
+    pub usage: UsageHandle,
+    pub warmup: Option<WarmupHandle>,
+    pub messages: Arc<Messages>,
+    pub authorizer: Option<Authorizer>,
+    /// Terminate TLS of client connections
+    pub tls: Option<TlsTerminatorHandle>,
+    /// Expect a PROXY protocol header carrying the real client address on each connection
+    pub proxy_protocol_in: bool,
+    /// Send a PROXY protocol header carrying the client address on each TCP connection to the backend
+    pub proxy_protocol_out: bool,
+    pub events: SeroEvents,
+    /// Captures the next wake and the connections after it on request of the admin API
+    pub next_wake_debug: Option<NextWakeDebugHandle>,
+    pub drain_notice: DrainNotice,
+    /// TCP connections being proxied, listed by the admin API
+    pub connections: ConnectionRegistry,
+}
+
+/// Connections which are being proxied, with their bytes transferred so far.
+#[derive(Clone, Default)]
+pub struct ConnectionRegistry {
+    open: Arc<Mutex<HashMap<u64, OpenConnection>>>,
+}
+
+struct OpenConnection {
+    client: SocketAddr,
+    opened_at: DateTime<Utc>,
+    transfer: Arc<Transfer>,
+}
+
+/// Removes a connection from its registry once it closed.
+struct TrackedConnection {
+    registry: ConnectionRegistry,
+    id: u64,
+}
+
+impl Drop for TrackedConnection {
+    fn drop(&mut self) {
+        self.registry.lock().remove(&self.id);
+    }
+}
+
+impl ConnectionRegistry {
+    fn lock(&self) -> MutexGuard<'_, HashMap<u64, OpenConnection>> {
+        self.open.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn track(&self, id: u64, client: SocketAddr, transfer: Arc<Transfer>) -> TrackedConnection {
+        let connection = OpenConnection {
+            client,
+            opened_at: Utc::now(),
+            transfer,
+        };
+        self.lock().insert(id, connection);
+        TrackedConnection {
+            registry: self.clone(),
+            id,
+        }
+    }
+
+    /// Open connections, oldest first.
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let mut connections: Vec<ConnectionInfo> = self
+            .lock()
+            .iter()
+            .map(|(id, connection)| {
+                let (bytes_from_client, bytes_from_backend) = connection.transfer.bytes();
+                ConnectionInfo {
+                    id: *id,
+                    client: connection.client,
+                    opened_at: connection.opened_at,
+                    bytes_from_client,
+                    bytes_from_backend,
+                }
+            })
+            .collect();
+        connections.sort_by_key(|connection| connection.id);
+        connections
+    }
+}
+
+/// Tells keep-alive HTTP clients to reconnect while sero drains, so that their next request
+/// does not hit a closed connection.
+#[derive(Clone, Debug, Default)]
+pub struct DrainNotice {
+    /// Turns true once sero shuts down, see `Proxy::with_shutdown`
+    pub shutdown: Option<watch::Receiver<bool>>,
+    /// Also ask clients to wait this long before their next request
+    pub retry_after: Option<Duration>,
+}
+
+impl DrainNotice {
+    fn is_shutting_down(&self) -> bool {
+        self.shutdown
+            .as_ref()
+            .map_or(false, |shutdown| *shutdown.borrow())
+    }
+
+    fn notify(&self, res: &mut Response<Body>) {
+        let headers = res.headers_mut();
+        headers.insert(
+            header::CONNECTION,
+            header::HeaderValue::from_static("close"),
+        );
+        if let Some(retry_after) = self.retry_after {
+            headers.insert(header::RETRY_AFTER, retry_after.as_secs().max(1).into());
+        }
+    }
+}
+
+struct ConnectionSummary {
+    outcome: Outcome,
+    wake_cause: Option<WakeCause>,
+    /// How long the connection waited for the backend to wake up
+    wake_latency: Option<Duration>,
+    /// Address of the backend connection, unknown when proxying HTTP
+    backend: Option<SocketAddr>,
+    bytes_from_client: u64,
+    bytes_from_backend: u64,
+}
+
+impl From<Outcome> for ConnectionSummary {
+    fn from(outcome: Outcome) -> Self {
+        ConnectionSummary {
+            outcome,
+            wake_cause: None,
+            wake_latency: None,
+            backend: None,
+            bytes_from_client: 0,
+            bytes_from_backend: 0,
+        }
+    }
+}
+
+/// State shared by the requests of an HTTP connection.
+#[derive(Default)]
+struct HttpConnection {
+    /// Counts the connection as active once it carried a request other than a health check
+    activity: Option<ConnectionGuard>,
+    wake_latency: Option<Duration>,
+}
+
+impl ConnectionContext {
+    /// Serve a connection accepted from `client` and record its outcome.
+    pub async fn handle(self, mut ingress: TcpStream, mut client: SocketAddr, activating: bool) {
+        self.tune_socket(&ingress);
+        if self.proxy_protocol_in {
+            match proxy_protocol::read_header(&mut ingress).await {
+                Ok(Some(addr)) => client = addr,
+                Ok(None) => {}
+                Err(e) => {
+                    debug!("Dropping connection from {client}: {e}");
+                    return;
                 }
+            }
+        }
+        let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+        let start = Instant::now();
+        let timestamp = Utc::now();
+        self.events.publish(SeroEvent::ConnectionAccepted {
+            service: self.backend_host.clone(),
+            client,
+            timestamp,
+        });
+        let span = info_span!("connection", id, %client, service = %self.backend_host);
+        let summary = self
+            .serve(ingress, client, activating, id)
+            .instrument(span)
+            .await;
+        if summary.outcome != Outcome::Proxied {
+            self.events.publish(SeroEvent::ConnectionFailed {
+                service: self.backend_host.clone(),
+                client,
+                outcome: summary.outcome,
+                timestamp: Utc::now(),
+            });
+        }
+        if let Err(e) = self
+            .usage
+            .record(summary.bytes_from_client, summary.bytes_from_backend)
+            .await
+        {
+            error!("Failed to record usage of connection: {e}");
+        }
+        let duration_ms = start.elapsed().as_millis() as u64;
+        if self.access_log {
+            let backend = summary.backend.map_or_else(
+                || format!("{}:{}", self.backend_host, self.backend_port),
+                |addr| addr.to_string(),
+            );
+            info!(
+                target: "sero::access",
+                service = %self.backend_host,
+                %client,
+                backend,
+                outcome = ?summary.outcome,
+                bytes_from_client = summary.bytes_from_client,
+                bytes_from_backend = summary.bytes_from_backend,
+                duration_ms,
+                cold_start = summary.wake_cause.is_some(),
+                wake_latency_ms = summary.wake_latency.map(|latency| latency.as_millis() as u64),
+                "Connection {id} closed."
+            );
+        }
+        if let Some(audit) = &self.audit {
+            audit.record(AuditRecord {
+                timestamp,
+                service: self.backend_host.clone(),
+                client,
+                backend: format!("{}:{}", self.backend_host, self.backend_port),
+                outcome: summary.outcome,
+                wake_cause: summary.wake_cause,
+                bytes_from_client: summary.bytes_from_client,
+                bytes_from_backend: summary.bytes_from_backend,
+                duration_ms,
             });
         }
     }
+
+    async fn serve(
+        &self,
+        ingress: TcpStream,
+        client: SocketAddr,
+        activating: bool,
+        id: u64,
+    ) -> ConnectionSummary {
+        let authorized = self.is_authorized(&ingress, client).await;
+        if !authorized && self.protocol != Protocol::Http {
+            debug!("Connection from {client} was not authorized, dropping it.");
+            return Outcome::Denied.into();
+        }
+        let Some(tls) = &self.tls else {
+            return match authorized {
+                true => self.serve_stream(ingress, client, activating, id).await,
+                false => self.deny_http(ingress, client).await,
+            };
+        };
+        match tls.accept(ingress).await {
+            Ok(ingress) if authorized => self.serve_stream(ingress, client, activating, id).await,
+            Ok(ingress) => self.deny_http(ingress, client).await,
+            Err(e) => {
+                debug!("TLS handshake with {client} failed, dropping connection: {e}");
+                Outcome::HandshakeFailed.into()
+            }
+        }
+    }
+
+    /// Answer the requests of an unauthorized HTTP client with 403 until it gives up.
+    async fn deny_http<S>(&self, ingress: S, client: SocketAddr) -> ConnectionSummary
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        debug!("Connection from {client} was not authorized, answering with 403.");
+        let ctx = self.clone();
+        let svc = service_fn(move |req| {
+            let res =
+                ctx.message_response(&req, MessageKind::Forbidden, StatusCode::FORBIDDEN, None);
+            futures::future::ok::<_, hyper::Error>(res)
+        });
+        if let Err(e) = Http::new()
+            .http1_only(true)
+            .serve_connection(ingress, svc)
+            .await
+        {
+            debug!("Error while answering unauthorized client {client}: {e}");
+        }
+        Outcome::Denied.into()
+    }
+
+    /// Serve a connection after authorization and TLS termination.
+    async fn serve_stream<S>(
+        &self,
+        mut ingress: S,
+        client: SocketAddr,
+        activating: bool,
+        id: u64,
+    ) -> ConnectionSummary
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        if self.protocol == Protocol::Http {
+            let _capture = self.capture(id);
+            return self.serve_http(ingress, activating).await;
+        }
+        let _connection = activating.then(|| self.activity.connection_opened());
+
+        // only connect if backend is up
+        let wake_start = Instant::now();
+        let woke = match self.ensure_backend(activating).await {
+            Ok(woke) => woke,
+            // overflowing bursts are expected during cold starts, as are exhausted wake budgets
+            // and wakes left to the replacement of a terminating sero
+            Err(e)
+                if e.is::<PendingQueueFull>()
+                    || e.is::<WakeBudgetExhausted>()
+                    || e.is::<WakeSuppressed>() =>
+            {
+                debug!("Rejecting connection from {client}: {e}");
+                self.write_banner(&mut ingress).await;
+                return Outcome::BackendUnavailable.into();
+            }
+            Err(e) => {
+                error!(
+                    "Failed to ensure a serving backend, dropping connection from {client}: {e}"
+                );
+                if !(e.is::<WakeTimedOut>() && self.closes_on_timeout()) {
+                    self.write_banner(&mut ingress).await;
+                }
+                return Outcome::BackendUnavailable.into();
+            }
+        };
+        // connections waking the backend count towards a capture of the wake
+        let _capture = self.capture(id);
+
+        // prefer connections and addresses prepared while the backend woke up, which go through
+        // the service unless pods are dialed directly
+        let warmup = self.warmup.as_ref().filter(|_| self.pod_port.is_none());
+        let prewarmed = warmup.and_then(WarmupHandle::take_connection);
+        let addrs = warmup.map(WarmupHandle::addrs).unwrap_or_default();
+        let mut summary = if let Some(egress) = prewarmed {
+            trace!("Using a pre-established connection to backend.");
+            self.proxy_streams(ingress, client, egress, id).await
+        } else if addrs.is_empty() {
+            match self.backend_addrs().await {
+                Ok(addrs) => self.proxy_tcp_stream(ingress, client, &addrs[..], id).await,
+                Err(e) => {
+                    error!("Error while resolving backend for connection {id}: {e:#}");
+                    Outcome::ConnectFailed.into()
+                }
+            }
+        } else {
+            self.proxy_tcp_stream(ingress, client, &addrs[..], id).await
+        };
+        if summary.outcome == Outcome::ConnectFailed {
+            self.endpoints.report_unreachable();
+        }
+        if woke {
+            summary.wake_cause = Some(WakeCause::ClientConnection);
+            summary.wake_latency = Some(wake_start.elapsed());
+        }
+        summary
+    }
+
+    /// Let the kernel probe `stream` once idle and reset it if the peer stopped answering, and
+    /// disable Nagle's algorithm on it if configured.
+    fn tune_socket(&self, stream: &TcpStream) {
+        if let Some(keepalive) = &self.keepalive {
+            if let Err(e) = SockRef::from(stream).set_tcp_keepalive(keepalive) {
+                debug!(
+                    "Could not enable TCP keepalive on {}: {e}",
+                    peer_addr(stream)
+                );
+            }
+        }
+        if self.nodelay {
+            if let Err(e) = stream.set_nodelay(true) {
+                debug!("Could not set TCP_NODELAY on {}: {e}", peer_addr(stream));
+            }
+        }
+    }
+
+    /// Addresses of the serving pods to dial if `pod_port` is set, in the order of the
+    /// load-balancing strategy.
+    fn pod_addrs(&self) -> Vec<SocketAddr> {
+        let Some(port_name) = &self.pod_port else {
+            return Vec::new();
+        };
+        let mut addrs = self.endpoints.backend_addrs(port_name);
+        self.balancer.order(&mut addrs);
+        addrs
+    }
+
+    /// Addresses to dial: those of the serving pods, or those the service resolves to, in the
+    /// order of the load-balancing strategy.
+    async fn backend_addrs(&self) -> Result<Vec<SocketAddr>> {
+        let addrs = self.pod_addrs();
+        if !addrs.is_empty() {
+            return Ok(addrs);
+        }
+        let mut addrs = self
+            .resolver
+            .lookup(&self.backend_host, self.backend_port)
+            .await?;
+        self.balancer.order(&mut addrs);
+        Ok(addrs)
+    }
+
+    /// Capture connection `id` if a capture of the next wake is running.
+    fn capture(&self, id: u64) -> Option<CaptureGuard> {
+        self.next_wake_debug.as_ref()?.connection(id)
+    }
+
+    /// Wait until the backend is serving. Returns whether it had to be woken up. Fails with
+    /// `WakeTimedOut` once the wake deadline passed, and with `PendingQueueFull` if too many
+    /// connections wait already.
+    async fn ensure_backend(&self, activating: bool) -> Result<bool> {
+        if activating {
+            let serving = self.endpoints.backend_is_serving();
+            let _pending = match &self.pending {
+                Some(pending) if !serving => Some(pending.enter(&self.backend_host)?),
+                _ => None,
+            };
+            // connections arriving at a sleeping backend together wake it together
+            let wake = async {
+                match serving {
+                    true => self.scaler.ensure_up(WakeCause::ClientConnection).await,
+                    false => self.scaler.join_wake(WakeCause::ClientConnection).await,
+                }
+            }
+            .instrument(info_span!("ensure_up"));
+            let Some(deadline) = self.wake_deadline else {
+                return wake.await;
+            };
+            match tokio::time::timeout(deadline.timeout, wake).await {
+                Ok(woke) => woke,
+                Err(_) => {
+                    metrics::WAKE_TIMEOUTS
+                        .with_label_values(&[&self.backend_host])
+                        .inc();
+                    Err(WakeTimedOut(deadline.timeout).into())
+                }
+            }
+        } else if self.endpoints.backend_is_serving() {
+            Ok(false)
+        } else {
+            bail!("backend is not serving and the listener is non-activating.")
+        }
+    }
+
+    /// Whether to close connections without a response once the backend did not wake up in time.
+    fn closes_on_timeout(&self) -> bool {
+        self.wake_deadline.map_or(false, |deadline| {
+            deadline.policy == WakeTimeoutPolicy::Close
+        })
+    }
+
+    /// Serve HTTP/1 requests, each held until the backend is up, so that clients never
+    /// see a slow TCP connect or a reset. Upgrades (e.g. WebSockets) are not supported.
+    async fn serve_http<S>(&self, ingress: S, activating: bool) -> ConnectionSummary
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let connection = Arc::new(Mutex::new(HttpConnection::default()));
+        let svc = {
+            let ctx = self.clone();
+            let connection = connection.clone();
+            service_fn(move |req| ctx.clone().forward(req, activating, connection.clone()))
+        };
+        let conn = Http::new().http1_only(true).serve_connection(ingress, svc);
+        tokio::pin!(conn);
+        // finish the request in flight on shutdown, but do not wait for another one
+        let mut shutdown = self.drain_notice.shutdown.clone();
+        let result = tokio::select! {
+            result = conn.as_mut() => result,
+            _ = shutdown_requested(&mut shutdown) => {
+                conn.as_mut().graceful_shutdown();
+                conn.await
+            }
+        };
+        let outcome = match result {
+            Ok(()) => Outcome::Proxied,
+            // the connection was closed on purpose, see `WakeTimeoutPolicy::Close`
+            Err(e)
+                if std::error::Error::source(&e)
+                    .map_or(false, |cause| cause.is::<WakeTimedOut>()) =>
+            {
+                debug!("Closing HTTP connection: {e}");
+                Outcome::BackendUnavailable
+            }
+            Err(e) => {
+                error!("Error while serving HTTP: {e}");
+                Outcome::ProxyFailed
+            }
+        };
+        let wake_latency = connection
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .wake_latency;
+        ConnectionSummary {
+            wake_cause: wake_latency.map(|_| WakeCause::ClientConnection),
+            wake_latency,
+            ..outcome.into()
+        }
+    }
+
+    async fn forward(
+        self,
+        req: Request<Body>,
+        activating: bool,
+        connection: Arc<Mutex<HttpConnection>>,
+    ) -> Result<Response<Body>, WakeTimedOut> {
+        // health checks of load balancers in front of sero must neither wake the backend, nor
+        // keep it awake, nor fail while it sleeps
+        if self
+            .health_check_paths
+            .iter()
+            .any(|path| path == req.uri().path())
+        {
+            return Ok(status_response(StatusCode::OK));
+        }
+        if activating {
+            let mut connection = connection.lock().unwrap_or_else(|e| e.into_inner());
+            if connection.activity.is_none() {
+                connection.activity = Some(self.activity.connection_opened());
+            }
+        }
+        // keep requests waiting for a wake, in case sero restarts before the backend is up
+        let (mut req, _spooled) = match &self.spool {
+            Some(spool) if activating && !self.endpoints.backend_is_serving() => {
+                match spool.spill(req).await {
+                    Ok(spilled) => spilled,
+                    Err(e) => {
+                        debug!("Could not read request body: {e}");
+                        return Ok(status_response(StatusCode::BAD_REQUEST));
+                    }
+                }
+            }
+            _ => (req, None),
+        };
+        // the request is held here until the backend is up
+        let wake_start = Instant::now();
+        match self.ensure_backend(activating).await {
+            Ok(true) => {
+                let mut connection = connection.lock().unwrap_or_else(|e| e.into_inner());
+                connection.wake_latency.get_or_insert(wake_start.elapsed());
+            }
+            Ok(false) => {}
+            Err(e) if e.is::<PendingQueueFull>() || e.is::<WakeSuppressed>() => {
+                debug!("Rejecting request: {e}");
+                return Ok(self.unavailable_response(&req));
+            }
+            Err(e) if e.is::<WakeBudgetExhausted>() => {
+                debug!("Rejecting request: {e}");
+                let retry_after = e
+                    .downcast_ref::<WakeBudgetExhausted>()
+                    .map(|exhausted| exhausted.retry_after);
+                return Ok(self.message_response(
+                    &req,
+                    MessageKind::RateLimited,
+                    StatusCode::TOO_MANY_REQUESTS,
+                    retry_after,
+                ));
+            }
+            Err(e) => {
+                error!("Failed to ensure a serving backend, rejecting request: {e}");
+                return match e.downcast::<WakeTimedOut>() {
+                    // failing the service makes hyper close the connection
+                    Ok(timed_out) if self.closes_on_timeout() => Err(timed_out),
+                    _ => Ok(self.unavailable_response(&req)),
+                };
+            }
+        }
+        let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+        let pod = self.pod_addrs().first().copied();
+        let _connection = pod.map(|addr| self.balancer.connect(addr));
+        let authority = match pod {
+            Some(addr) => addr.to_string(),
+            None => format!("{}:{}", self.backend_host, self.backend_port),
+        };
+        let uri = format!("http://{authority}{path}");
+        *req.uri_mut() = match uri.parse() {
+            Ok(uri) => uri,
+            Err(e) => {
+                debug!("Could not build backend URI {uri}: {e}");
+                return Ok(status_response(StatusCode::BAD_REQUEST));
+            }
+        };
+        let res = match self.replay {
+            Some(limits) if is_replayable(&req, limits) => {
+                self.request_with_replay(req, activating, limits).await
+            }
+            _ => {
+                self.http_client
+                    .request(req)
+                    .instrument(info_span!("forward", %authority))
+                    .await
+            }
+        };
+        let mut res = match res {
+            Ok(res) => res,
+            Err(e) => {
+                if e.is_connect() {
+                    self.endpoints.report_unreachable();
+                }
+                error!("Error while forwarding request to backend: {e}");
+                status_response(StatusCode::BAD_GATEWAY)
+            }
+        };
+        if self.is_draining(activating) {
+            self.drain_notice.notify(&mut res);
+        }
+        Ok(res)
+    }
+
+    /// Whether sero shuts down or the backend is being put to sleep, so that clients should
+    /// reconnect, ending up at another replica of sero or at the backend directly.
+    fn is_draining(&self, activating: bool) -> bool {
+        self.drain_notice.is_shutting_down() || (activating && self.scaler.is_sleeping())
+    }
+
+    /// Buffer an idempotent request and replay it if the backend fails before responding,
+    /// e.g. because its pod crashed, until it succeeds or `limits.timeout` passed.
+    async fn request_with_replay(
+        &self,
+        req: Request<Body>,
+        activating: bool,
+        limits: ReplayLimits,
+    ) -> hyper::Result<Response<Body>> {
+        let (parts, body) = req.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+        let rebuild = || {
+            let mut req = Request::new(Body::from(body.clone()));
+            *req.method_mut() = parts.method.clone();
+            *req.uri_mut() = parts.uri.clone();
+            *req.version_mut() = parts.version;
+            *req.headers_mut() = parts.headers.clone();
+            req
+        };
+        let mut error = match self.http_client.request(rebuild()).await {
+            Ok(res) => return Ok(res),
+            Err(e) => e,
+        };
+
+        let deadline = Instant::now() + limits.timeout;
+        let mut backoff = Duration::from_millis(250);
+        let replays = metrics::REPLAYED_REQUESTS.with_label_values(&[&self.backend_host]);
+        while Instant::now() + backoff < deadline {
+            warn!(
+                "Request {} {} failed ({error}), replaying it in {backoff:?}.",
+                parts.method, parts.uri
+            );
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(Duration::from_secs(4));
+            // wait for the endpoints to recover, but not beyond the deadline
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match tokio::time::timeout(remaining, self.ensure_backend(activating)).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => debug!("Backend did not recover for replay: {e}"),
+                Err(_) => break,
+            }
+            replays.inc();
+            match self.http_client.request(rebuild()).await {
+                Ok(res) => return Ok(res),
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+
+    fn unavailable_response(&self, req: &Request<Body>) -> Response<Body> {
+        self.message_response(
+            req,
+            MessageKind::Unavailable,
+            StatusCode::SERVICE_UNAVAILABLE,
+            None,
+        )
+    }
+
+    /// Answer `req` with the message `kind` in the client's language, telling it when to retry.
+    fn message_response(
+        &self,
+        req: &Request<Body>,
+        kind: MessageKind,
+        status: StatusCode,
+        retry_after: Option<Duration>,
+    ) -> Response<Body> {
+        let vars = MessageVars {
+            service: &self.backend_host,
+            retry_after,
+            ..Default::default()
+        };
+        let langs = accept_languages(req);
+        let langs: Vec<&str> = langs.iter().map(String::as_str).collect();
+        let body = self
+            .messages
+            .render(kind, &vars, &langs)
+            .unwrap_or_default();
+        let mut res = Response::new(Body::from(body));
+        *res.status_mut() = status;
+        let headers = res.headers_mut();
+        headers.insert(
+            header::CONTENT_TYPE,
+            header::HeaderValue::from_static("text/html; charset=utf-8"),
+        );
+        if let Some(retry_after) = retry_after {
+            headers.insert(header::RETRY_AFTER, retry_after.as_secs().max(1).into());
+        }
+        if status == StatusCode::FORBIDDEN {
+            // there is no point in further requests of the client on this connection
+            headers.insert(
+                header::CONNECTION,
+                header::HeaderValue::from_static("close"),
+            );
+        }
+        res
+    }
+
+    async fn is_authorized(&self, ingress: &TcpStream, client: SocketAddr) -> bool {
+        match &self.authorizer {
+            Some(authorizer) => {
+                let req = AuthzRequest {
+                    service: &self.backend_host,
+                    client,
+                    local: ingress.local_addr().ok(),
+                };
+                authorizer.is_allowed(&req).await
+            }
+            None => true,
+        }
+    }
+
+    async fn write_banner<S: AsyncWrite + Unpin>(&self, ingress: &mut S) {
+        let vars = MessageVars {
+            service: &self.backend_host,
+            ..Default::default()
+        };
+        if let Some(banner) = self.messages.render(MessageKind::Banner, &vars, &[]) {
+            if let Err(e) = ingress.write_all(banner.as_bytes()).await {
+                debug!("Could not write banner to client: {e}");
+            }
+        }
+    }
+
+    /// Tell the backend where the connection came from, naming the backend as destination.
+    async fn send_proxy_header(&self, egress: &mut TcpStream, client: SocketAddr) -> Result<()> {
+        let header = proxy_protocol::header_v1(client, egress.peer_addr()?);
+        egress.write_all(header.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Proxy a TCP Stream to the first backend address accepting it.
+    async fn proxy_tcp_stream<S>(
+        &self,
+        ingress: S,
+        client: SocketAddr,
+        backend: &[SocketAddr],
+        id: u64,
+    ) -> ConnectionSummary
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let connect = self.connect_backend(backend, id);
+        match connect.instrument(info_span!("connect")).await {
+            Some(egress) => self.proxy_streams(ingress, client, egress, id).await,
+            None => Outcome::ConnectFailed.into(),
+        }
+    }
+
+    /// Connect to the first backend address accepting the connection, retrying with backoff
+    /// if none does.
+    async fn connect_backend(&self, backend: &[SocketAddr], id: u64) -> Option<TcpStream> {
+        let mut attempt = 0;
+        let mut backoff = self
+            .connect_retries
+            .map_or(Duration::ZERO, |retries| retries.backoff);
+        loop {
+            let e = match TcpStream::connect(backend).await {
+                Ok(egress) => {
+                    trace!("Successfully connected to backend. Proxying connection {id}.");
+                    return Some(egress);
+                }
+                Err(e) => e,
+            };
+            match self.connect_retries {
+                Some(retries) if attempt < retries.retries => {
+                    attempt += 1;
+                    let delay = jittered(backoff);
+                    debug!(
+                        "Could not connect connection {id} to backend ({e}), retrying in {delay:?} (attempt {attempt} of {}).",
+                        retries.retries
+                    );
+                    tokio::time::sleep(delay).await;
+                    backoff = (backoff * 2).min(MAX_CONNECT_BACKOFF);
+                }
+                _ => {
+                    error!("Error while connecting connection {id} to backend: {e}");
+                    return None;
+                }
+            }
+        }
+    }
+
+    async fn proxy_streams<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        ingress: S,
+        client: SocketAddr,
+        mut egress: TcpStream,
+        id: u64,
+    ) -> ConnectionSummary {
+        let backend = peer_addr(&egress);
+        let backend_addr = egress.peer_addr().ok();
+        let _connection = backend_addr.map(|addr| self.balancer.connect(addr));
+        let summary = |outcome, bytes_from_client, bytes_from_backend| ConnectionSummary {
+            outcome,
+            wake_cause: None,
+            wake_latency: None,
+            backend: backend_addr,
+            bytes_from_client,
+            bytes_from_backend,
+        };
+        self.tune_socket(&egress);
+        if self.proxy_protocol_out {
+            if let Err(e) = self.send_proxy_header(&mut egress, client).await {
+                error!(
+                    "Error while sending PROXY protocol header of connection {id} to backend: {e}"
+                );
+                return summary(Outcome::ConnectFailed, 0, 0);
+            }
+        }
+        let capture = self
+            .next_wake_debug
+            .as_ref()
+            .map_or(false, |debug| debug.is_captured(id))
+            .then_some(id);
+        let (client_read, client_write) = tokio::io::split(ingress);
+        let (backend_read, backend_write) = egress.split();
+        let transfer = Arc::new(Transfer::new());
+        let _tracked = self.connections.track(id, client, transfer.clone());
+        let copy = async {
+            tokio::try_join!(
+                copy_half(
+                    client_read,
+                    backend_write,
+                    Peer::Client,
+                    Peer::Backend,
+                    capture,
+                    &transfer
+                ),
+                copy_half(
+                    backend_read,
+                    client_write,
+                    Peer::Backend,
+                    Peer::Client,
+                    capture,
+                    &transfer
+                ),
+            )
+        }
+        .instrument(info_span!("stream", %backend));
+        let result = match self.stream_idle_timeout {
+            Some(timeout) => tokio::select! {
+                result = copy => result,
+                _ = transfer.idle(timeout) => {
+                    let (bytes_to_backend, bytes_from_backend) = transfer.bytes();
+                    debug!("Closing connection {id} from {client} to {backend}, as it was idle for {timeout:?} ({bytes_to_backend} bytes from client, {bytes_from_backend} bytes from server)");
+                    metrics::IDLE_STREAMS_CLOSED
+                        .with_label_values(&[&self.backend_host])
+                        .inc();
+                    return summary(Outcome::Proxied, bytes_to_backend, bytes_from_backend);
+                }
+            },
+            None => copy.await,
+        };
+        match result {
+            Ok(_) => {
+                let (bytes_to_backend, bytes_from_backend) = transfer.bytes();
+                trace!("Connection {id} ended gracefully ({bytes_to_backend} bytes from client, {bytes_from_backend} bytes from server)");
+                summary(Outcome::Proxied, bytes_to_backend, bytes_from_backend)
+            }
+            Err(e) => {
+                let class = ErrorClass::of(&e.error);
+                metrics::PROXY_ERRORS
+                    .with_label_values(&[&self.backend_host, e.peer.as_str(), class.as_str()])
+                    .inc();
+                // clients going away mid-connection are routine, everything else is worth a look
+                if e.peer == Peer::Client && class != ErrorClass::Other {
+                    debug!(
+                        "Connection {id} from {client} to {backend}: {class} by {}: {}",
+                        e.peer, e.error
+                    );
+                } else {
+                    error!(
+                        "Connection {id} from {client} to {backend}: {class} by {}: {}",
+                        e.peer, e.error
+                    );
+                }
+                let (bytes_to_backend, bytes_from_backend) = transfer.bytes();
+                summary(Outcome::ProxyFailed, bytes_to_backend, bytes_from_backend)
+            }
+        }
+    }
 }
 
-/// Proxy a TCP Stream to a backend address
-async fn proxy_tcp_stream<A: ToSocketAddrs>(mut ingress: TcpStream, backend: A) {
-    let mut egress = match TcpStream::connect(backend).await {
-        Ok(egress) => {
-            trace!("Successfully connected to backend. Proxying connections.");
-            egress
+fn peer_addr(stream: &TcpStream) -> String {
+    stream
+        .peer_addr()
+        .map_or_else(|_| "unknown".to_owned(), |addr| addr.to_string())
+}
+
+/// Side of a proxied connection.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Peer {
+    Client,
+    Backend,
+}
+
+impl Peer {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Peer::Client => "client",
+            Peer::Backend => "backend",
         }
-        Err(e) => {
-            error!("Error while connecting to backend: {e}");
-            return;
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Class of a socket error, to tell benign resets from real failures.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ErrorClass {
+    Reset,
+    Timeout,
+    BrokenPipe,
+    Other,
+}
+
+impl ErrorClass {
+    fn of(error: &io::Error) -> Self {
+        match error.kind() {
+            ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => ErrorClass::Reset,
+            ErrorKind::TimedOut => ErrorClass::Timeout,
+            ErrorKind::BrokenPipe => ErrorClass::BrokenPipe,
+            _ => ErrorClass::Other,
         }
-    };
+    }
 
-    match tokio::io::copy_bidirectional(&mut ingress, &mut egress).await {
-        Ok((bytes_to_backend, bytes_from_backend)) => {
-            trace!("Connection ended gracefully ({bytes_to_backend} bytes from client, {bytes_from_backend} bytes from server)");
+    fn as_str(&self) -> &'static str {
+        match self {
+            ErrorClass::Reset => "reset",
+            ErrorClass::Timeout => "timeout",
+            ErrorClass::BrokenPipe => "broken_pipe",
+            ErrorClass::Other => "other",
         }
-        Err(e) => {
-            error!("Error while proxying: {e}");
+    }
+}
+
+impl fmt::Display for ErrorClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A socket error of a proxied connection, attributed to the peer whose socket failed.
+struct ProxyError {
+    peer: Peer,
+    error: io::Error,
+}
+
+/// Bytes copied in each direction of a proxied connection and when the last ones were read.
+struct Transfer {
+    started: Instant,
+    /// Milliseconds since `started`
+    last_read: AtomicU64,
+    from_client: AtomicU64,
+    from_backend: AtomicU64,
+}
+
+impl Transfer {
+    fn new() -> Self {
+        Transfer {
+            started: Instant::now(),
+            last_read: AtomicU64::new(0),
+            from_client: AtomicU64::new(0),
+            from_backend: AtomicU64::new(0),
         }
     }
+
+    fn record(&self, from: Peer, bytes: usize) {
+        let copied = match from {
+            Peer::Client => &self.from_client,
+            Peer::Backend => &self.from_backend,
+        };
+        copied.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.last_read
+            .store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Bytes read from the client and from the backend.
+    fn bytes(&self) -> (u64, u64) {
+        (
+            self.from_client.load(Ordering::Relaxed),
+            self.from_backend.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Resolves once nothing was read in either direction for `timeout`.
+    async fn idle(&self, timeout: Duration) {
+        loop {
+            let last_read =
+                self.started + Duration::from_millis(self.last_read.load(Ordering::Relaxed));
+            let deadline = last_read + timeout;
+            if Instant::now() >= deadline {
+                return;
+            }
+            tokio::time::sleep_until(deadline.into()).await;
+        }
+    }
+}
+
+/// Copy from `reader` to `writer` until EOF, then shut down `writer`. Logs the start of the
+/// payload of a captured connection.
+async fn copy_half<R, W>(
+    mut reader: R,
+    mut writer: W,
+    from: Peer,
+    to: Peer,
+    capture: Option<u64>,
+    transfer: &Transfer,
+) -> Result<(), ProxyError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; COPY_BUFFER_SIZE];
+    let mut to_capture = capture.map_or(0, |_| CAPTURED_PAYLOAD_BYTES);
+    loop {
+        let read = reader
+            .read(&mut buf)
+            .await
+            .map_err(|error| ProxyError { peer: from, error })?;
+        if read == 0 {
+            break;
+        }
+        if let Some(id) = capture.filter(|_| to_capture > 0) {
+            let len = read.min(to_capture);
+            trace!(
+                "Payload of connection {id} from {from}: {:?}",
+                String::from_utf8_lossy(&buf[..len])
+            );
+            to_capture -= len;
+        }
+        writer
+            .write_all(&buf[..read])
+            .await
+            .map_err(|error| ProxyError { peer: to, error })?;
+        transfer.record(from, read);
+    }
+    writer
+        .shutdown()
+        .await
+        .map_err(|error| ProxyError { peer: to, error })?;
+    Ok(())
+}
+
+/// Languages of the `Accept-Language` header in the order given by the client.
+fn accept_languages(req: &Request<Body>) -> Vec<String> {
+    req.headers()
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| {
+            value
+                .split(',')
+                .filter_map(|lang| lang.split(';').next())
+                .map(|lang| lang.trim().to_owned())
+                .filter(|lang| !lang.is_empty() && lang != "*")
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Only requests which can safely be sent twice and whose body is small enough are replayed.
+fn is_replayable(req: &Request<Body>, limits: ReplayLimits) -> bool {
+    let idempotent = matches!(
+        *req.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::PUT | Method::DELETE | Method::TRACE
+    );
+    let headers = req.headers();
+    // without a length the body size is unknown until it was read completely
+    let small = match headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse::<u64>().ok())
+    {
+        Some(len) => len <= limits.max_bytes,
+        None => !headers.contains_key(header::TRANSFER_ENCODING),
+    };
+    idempotent && small
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    let mut res = Response::new(Body::from(
+        status.canonical_reason().unwrap_or_default().to_owned(),
+    ));
+    *res.status_mut() = status;
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: Method, headers: &[(header::HeaderName, &str)]) -> Request<Body> {
+        let mut req = Request::new(Body::empty());
+        *req.method_mut() = method;
+        for (name, value) in headers {
+            req.headers_mut()
+                .insert(name, header::HeaderValue::from_str(value).unwrap());
+        }
+        req
+    }
+
+    const LIMITS: ReplayLimits = ReplayLimits {
+        max_bytes: 1024,
+        timeout: Duration::from_secs(10),
+    };
+
+    #[test]
+    fn parses_port_ranges() {
+        let range: PortRange = "7000-7010".parse().unwrap();
+        assert_eq!(
+            range,
+            PortRange {
+                first: 7000,
+                last: 7010
+            }
+        );
+        assert!(range.contains(7005));
+        assert!(!range.contains(7011));
+        assert_eq!(" 80 - 80 ".parse::<PortRange>().unwrap().ports().count(), 1);
+    }
+
+    #[test]
+    fn rejects_invalid_port_ranges() {
+        assert!("7000".parse::<PortRange>().is_err());
+        assert!("7010-7000".parse::<PortRange>().is_err());
+        assert!("7000-70000".parse::<PortRange>().is_err());
+        assert!("a-b".parse::<PortRange>().is_err());
+    }
+
+    #[test]
+    fn replays_small_idempotent_requests() {
+        assert!(is_replayable(&request(Method::GET, &[]), LIMITS));
+        assert!(is_replayable(
+            &request(Method::PUT, &[(header::CONTENT_LENGTH, "1024")]),
+            LIMITS
+        ));
+    }
+
+    #[test]
+    fn does_not_replay_unsafe_or_large_requests() {
+        assert!(!is_replayable(&request(Method::POST, &[]), LIMITS));
+        assert!(!is_replayable(
+            &request(Method::PUT, &[(header::CONTENT_LENGTH, "1025")]),
+            LIMITS
+        ));
+        assert!(!is_replayable(
+            &request(Method::PUT, &[(header::TRANSFER_ENCODING, "chunked")]),
+            LIMITS
+        ));
+    }
+
+    #[test]
+    fn lists_accepted_languages_in_order() {
+        let req = request(
+            Method::GET,
+            &[(
+                header::ACCEPT_LANGUAGE,
+                "de-CH, fr;q=0.9, en;q=0.8, *;q=0.5",
+            )],
+        );
+        assert_eq!(accept_languages(&req), ["de-CH", "fr", "en"]);
+        assert!(accept_languages(&request(Method::GET, &[])).is_empty());
+    }
 }
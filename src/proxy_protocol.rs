@@ -0,0 +1,218 @@
+use anyhow::{bail, Context, Result};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{io::AsyncReadExt, net::TcpStream};
+
+/// Give up on load balancers which do not send a complete header within this time.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest version 1 header, including its CRLF.
+const MAX_V1_HEADER_SIZE: usize = 107;
+
+/// Signature starting a version 2 header.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Read and strip the PROXY protocol (v1 or v2) header a load balancer sends ahead of the
+/// client's data. Returns the original client address, or `None` if the load balancer did not
+/// pass one on, e.g. for its own health checks.
+pub async fn read_header(stream: &mut TcpStream) -> Result<Option<SocketAddr>> {
+    tokio::time::timeout(HEADER_TIMEOUT, read(stream))
+        .await
+        .with_context(|| format!("No PROXY protocol header within {HEADER_TIMEOUT:?}"))?
+}
+
+async fn read(stream: &mut TcpStream) -> Result<Option<SocketAddr>> {
+    // the shortest header, `PROXY UNKNOWN\r\n`, is longer than the v2 signature
+    let mut prefix = [0; V2_SIGNATURE.len()];
+    stream.read_exact(&mut prefix).await?;
+    if prefix == V2_SIGNATURE {
+        let mut fixed = [0; 4];
+        stream.read_exact(&mut fixed).await?;
+        let len = u16::from_be_bytes([fixed[2], fixed[3]]) as usize;
+        let mut addrs = vec![0; len];
+        stream.read_exact(&mut addrs).await?;
+        parse_v2(fixed[0], fixed[1], &addrs)
+    } else if prefix.starts_with(b"PROXY ") {
+        // read byte by byte, so that no client data is consumed
+        let mut line = prefix.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() == MAX_V1_HEADER_SIZE {
+                bail!("PROXY protocol header is too long.");
+            }
+            line.push(stream.read_u8().await?);
+        }
+        parse_v1(std::str::from_utf8(&line)?)
+    } else {
+        bail!("Connection does not start with a PROXY protocol header.")
+    }
+}
+
+/// Version 1 header announcing a connection from `client` to `destination`.
+pub fn header_v1(client: SocketAddr, destination: SocketAddr) -> String {
+    match (client, destination) {
+        (SocketAddr::V4(client), SocketAddr::V4(destination)) => format!(
+            "PROXY TCP4 {} {} {} {}\r\n",
+            client.ip(),
+            destination.ip(),
+            client.port(),
+            destination.port()
+        ),
+        (SocketAddr::V6(client), SocketAddr::V6(destination)) => format!(
+            "PROXY TCP6 {} {} {} {}\r\n",
+            client.ip(),
+            destination.ip(),
+            client.port(),
+            destination.port()
+        ),
+        // both addresses have to be of the same family
+        _ => "PROXY UNKNOWN\r\n".to_owned(),
+    }
+}
+
+/// Parse e.g. `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n`.
+fn parse_v1(line: &str) -> Result<Option<SocketAddr>> {
+    let fields: Vec<&str> = line.trim_end().split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, _dst, src_port, _dst_port] => {
+            let ip: IpAddr = src.parse().context("Invalid source address")?;
+            let port = src_port.parse().context("Invalid source port")?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => bail!("Invalid PROXY protocol header '{}'.", line.trim_end()),
+    }
+}
+
+/// Parse the address block of a v2 header, given its version/command and family bytes.
+fn parse_v2(version_command: u8, family: u8, addrs: &[u8]) -> Result<Option<SocketAddr>> {
+    if version_command >> 4 != 2 {
+        bail!(
+            "Unsupported PROXY protocol version {}.",
+            version_command >> 4
+        );
+    }
+    // LOCAL connections are made by the load balancer itself
+    if version_command & 0x0f == 0 {
+        return Ok(None);
+    }
+    let port = |at: usize| u16::from_be_bytes([addrs[at], addrs[at + 1]]);
+    match family {
+        // TCP over IPv4
+        0x11 if addrs.len() >= 12 => {
+            let ip: [u8; 4] = addrs[..4].try_into()?;
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port(8))))
+        }
+        // TCP over IPv6
+        0x21 if addrs.len() >= 36 => {
+            let ip: [u8; 16] = addrs[..16].try_into()?;
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port(32))))
+        }
+        // unspecified, UDP or UNIX sockets carry no usable client address
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    #[test]
+    fn parses_v1_headers() {
+        assert_eq!(
+            parse_v1("PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n").unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(
+            parse_v1("PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n").unwrap(),
+            Some("[2001:db8::1]:56324".parse().unwrap())
+        );
+        assert_eq!(parse_v1("PROXY UNKNOWN\r\n").unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_invalid_v1_headers() {
+        assert!(parse_v1("PROXY TCP4 192.0.2.1\r\n").is_err());
+        assert!(parse_v1("PROXY TCP4 example.com 198.51.100.1 56324 443\r\n").is_err());
+        assert!(parse_v1("PROXY TCP4 192.0.2.1 198.51.100.1 70000 443\r\n").is_err());
+    }
+
+    #[test]
+    fn parses_the_v1_headers_it_writes() {
+        let client = "[2001:db8::1]:56324".parse().unwrap();
+        let destination = "[2001:db8::2]:443".parse().unwrap();
+        assert_eq!(
+            parse_v1(&header_v1(client, destination)).unwrap(),
+            Some(client)
+        );
+        let mixed = header_v1("192.0.2.1:56324".parse().unwrap(), destination);
+        assert_eq!(parse_v1(&mixed).unwrap(), None);
+    }
+
+    #[test]
+    fn parses_v2_addresses() {
+        let ipv4 = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb];
+        assert_eq!(
+            parse_v2(0x21, 0x11, &ipv4).unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        let mut ipv6 = [0; 36];
+        ipv6[..16].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        ipv6[32..34].copy_from_slice(&56324u16.to_be_bytes());
+        assert_eq!(
+            parse_v2(0x21, 0x21, &ipv6).unwrap(),
+            Some("[2001:db8::1]:56324".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn ignores_v2_local_and_unknown_families() {
+        assert_eq!(parse_v2(0x20, 0x11, &[0; 12]).unwrap(), None);
+        assert_eq!(parse_v2(0x21, 0x00, &[]).unwrap(), None);
+        assert_eq!(parse_v2(0x21, 0x11, &[0; 4]).unwrap(), None);
+        assert!(parse_v2(0x11, 0x11, &[0; 12]).is_err());
+    }
+
+    /// Send `bytes` over a TCP connection and read the header on the other end, returning the
+    /// address and the data left in the stream.
+    async fn read_sent(bytes: &[u8]) -> (Result<Option<SocketAddr>>, Vec<u8>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        client.write_all(bytes).await.unwrap();
+        client.shutdown().await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let addr = read_header(&mut server).await;
+        let mut rest = Vec::new();
+        server.read_to_end(&mut rest).await.unwrap();
+        (addr, rest)
+    }
+
+    #[tokio::test]
+    async fn strips_v1_header_only() {
+        let (addr, rest) =
+            read_sent(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n").await;
+        assert_eq!(addr.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn strips_v2_header_only() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x21, 0x11, 0, 12]);
+        header.extend([192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        header.extend(b"hello");
+        let (addr, rest) = read_sent(&header).await;
+        assert_eq!(addr.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"hello");
+    }
+
+    #[tokio::test]
+    async fn rejects_connections_without_header() {
+        let (addr, _) = read_sent(b"GET / HTTP/1.1\r\nHost: example.com\r\n").await;
+        assert!(addr.is_err());
+    }
+}
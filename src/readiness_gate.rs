@@ -0,0 +1,178 @@
+use crate::endpoint_watcher::EndpointWatcherHandle;
+use crate::injector::InjectorHandle;
+use crate::scaler::Target;
+use crate::tasks;
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::Pod;
+use kube::{
+    api::{Api, Patch, PatchParams},
+    core::params::ListParams,
+    runtime::{self, WatchStreamExt},
+    Client,
+};
+use serde_json::json;
+use std::{sync::Arc, time::Duration};
+use tracing::*;
+
+/// Condition type of the readiness gate controlled by sero.
+///
+/// Backend pods opt in by declaring it in their spec:
+/// ```yaml
+/// readinessGates:
+///   - conditionType: sero.rs/cutover
+/// ```
+pub const READINESS_GATE: &str = "sero.rs/cutover";
+
+/// How long the gate of a pod waits for stable backend endpoints, and for sero's endpoints to
+/// drain, before it is opened anyway. Pod events are handled one at a time, and a pod must
+/// not stay unready because e.g. another replica of sero holds the lease to eject them.
+const GATE_WAIT_TIMEOUT: Duration = Duration::from_secs(60);
+
+struct ReadinessGate {
+    target: Target,
+    client: Arc<Client>,
+    endpoints: EndpointWatcherHandle,
+    injector: Option<InjectorHandle>,
+    /// Do not drain sero's endpoints while backend endpoints flap
+    hold_while_flapping: bool,
+}
+
+/// Label selector of the pods of a workload.
+pub async fn pod_selector(target: &Target, client: &Client) -> Result<String> {
+    let object = target.api(client).get(&target.name).await?;
+    let selector = object.data["spec"]["selector"]["matchLabels"]
+        .as_object()
+        .with_context(|| format!("{target} has no matchLabels selector."))?
+        .iter()
+        .map(|(key, value)| format!("{key}={}", value.as_str().unwrap_or_default()))
+        .collect::<Vec<_>>()
+        .join(",");
+    Ok(selector)
+}
+
+impl ReadinessGate {
+    /// Open the gate of a pod once sero has drained its own endpoints.
+    async fn reconcile(&mut self, api: &Api<Pod>, pod: Pod) -> Result<()> {
+        if !waits_for_gate(&pod) {
+            return Ok(());
+        }
+        let name = pod.metadata.name.context("Pod has no name.")?;
+
+        if self.hold_while_flapping && self.endpoints.is_flapping() {
+            info!("Holding readiness gate of pod/{name} until the backend endpoints stabilize.");
+            let stable = self.endpoints.wait_for(|status| status.flapping == 0);
+            match tokio::time::timeout(GATE_WAIT_TIMEOUT, stable).await {
+                Ok(stable) => stable?,
+                Err(_) => warn!(
+                    "Backend endpoints are still flapping after {GATE_WAIT_TIMEOUT:?}, opening readiness gate of pod/{name} anyway."
+                ),
+            }
+        }
+        if self.endpoints.sero_is_serving() {
+            info!("Draining sero endpoints before opening readiness gate of pod/{name}.");
+            if let Some(injector) = &self.injector {
+                injector.eject().await?;
+            }
+            let drained = self.endpoints.wait_for(|status| status.sero == 0);
+            match tokio::time::timeout(GATE_WAIT_TIMEOUT, drained).await {
+                Ok(drained) => drained?,
+                Err(_) => warn!(
+                    "sero is still a serving endpoint after {GATE_WAIT_TIMEOUT:?}, opening readiness gate of pod/{name} anyway."
+                ),
+            }
+        }
+
+        let patch = json!({
+            "status": {
+                "conditions": [{
+                    "type": READINESS_GATE,
+                    "status": "True",
+                    "lastTransitionTime": Utc::now(),
+                }],
+            },
+        });
+        api.patch_status(&name, &PatchParams::default(), &Patch::Strategic(patch))
+            .await?;
+        info!("Opened readiness gate {READINESS_GATE} of pod/{name}.");
+        Ok(())
+    }
+
+    async fn run(mut self) {
+        let selector = match pod_selector(&self.target, &self.client).await {
+            Ok(selector) => selector,
+            Err(e) => {
+                error!("Error while getting pod selector of {}: {e}", self.target);
+                return;
+            }
+        };
+        let api: Api<Pod> = Api::default_namespaced((*self.client).clone());
+        let mut events = runtime::watcher(api.clone(), ListParams::default().labels(&selector))
+            .touched_objects()
+            .boxed();
+        info!(
+            "Controlling readiness gate {READINESS_GATE} of pods of {}.",
+            self.target
+        );
+
+        while let Some(event) = events.next().await {
+            match event {
+                Err(e) => error!("Error getting next event for Pods: {e}"),
+                Ok(pod) => {
+                    if let Err(e) = self.reconcile(&api, pod).await {
+                        error!("Error while opening readiness gate: {e}");
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Whether the pod declares sero's readiness gate, has ready containers and a closed gate.
+fn waits_for_gate(pod: &Pod) -> bool {
+    let declares_gate = pod
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.readiness_gates.as_ref())
+        .map(|gates| {
+            gates
+                .iter()
+                .any(|gate| gate.condition_type == READINESS_GATE)
+        })
+        == Some(true);
+    let conditions = pod
+        .status
+        .as_ref()
+        .and_then(|status| status.conditions.as_deref())
+        .unwrap_or_default();
+    let is_true = |type_: &str| {
+        conditions
+            .iter()
+            .any(|condition| condition.type_ == type_ && condition.status == "True")
+    };
+    declares_gate && is_true("ContainersReady") && !is_true(READINESS_GATE)
+}
+
+pub struct ReadinessGateHandle;
+
+impl ReadinessGateHandle {
+    pub fn new(
+        target: Target,
+        client: Arc<Client>,
+        endpoints: EndpointWatcherHandle,
+        injector: Option<InjectorHandle>,
+        hold_while_flapping: bool,
+    ) -> Self {
+        let gate = ReadinessGate {
+            target,
+            client,
+            endpoints,
+            injector,
+            hold_while_flapping,
+        };
+        tasks::spawn("readiness-gate", gate.run());
+        ReadinessGateHandle
+    }
+}
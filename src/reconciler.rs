@@ -0,0 +1,34 @@
+use crate::injector::InjectorHandle;
+use crate::scaler::ScalerHandle;
+use crate::tasks;
+
+use std::time::Duration;
+use tracing::*;
+
+/// Level-triggered safety net for the event-driven actors: every `interval`, have them
+/// compare their intent with the cluster and fix drift from missed watch events or manual
+/// edits.
+pub fn reconcile_periodically(
+    interval: Duration,
+    scaler: ScalerHandle,
+    injector: Option<InjectorHandle>,
+) {
+    tasks::spawn("reconciler", async move {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        // the actors just derived their state from the cluster
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            debug!("Reconciling the scale and endpointslices with sero's intent.");
+            if let Err(e) = scaler.reconcile().await {
+                error!("Failed to reconcile the scale of the target: {e}");
+            }
+            if let Some(injector) = &injector {
+                if let Err(e) = injector.reconcile().await {
+                    error!("Failed to reconcile sero's endpointslices: {e}");
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,157 @@
+use crate::metrics;
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use hickory_resolver::{
+    config::{ResolverConfig, ResolverOpts},
+    error::ResolveErrorKind,
+    system_conf, Name, TokioAsyncResolver,
+};
+use hyper::client::connect::dns::Name as HyperName;
+use std::{
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+    time::{Duration, Instant},
+};
+use tokio::net::lookup_host;
+use tracing::*;
+
+/// How backend hostnames are resolved.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum ResolverKind {
+    /// Query the nameservers of /etc/resolv.conf directly, caching answers across connections
+    #[default]
+    Hickory,
+    /// Ask the system's resolver (getaddrinfo) on every lookup
+    System,
+}
+
+/// Overrides of the system's resolver configuration.
+#[derive(Clone, Debug, Default)]
+pub struct ResolverOptions {
+    /// Names with at least this many dots are tried as absolute names before the search domains
+    pub ndots: Option<usize>,
+    /// Search domains to use instead of those of /etc/resolv.conf
+    pub search: Vec<String>,
+    /// Longest time a failed lookup is cached
+    pub negative_ttl: Option<Duration>,
+}
+
+/// Resolves the backend's hostname, recording lookups as metrics.
+#[derive(Clone)]
+pub struct BackendResolver {
+    hickory: Option<Arc<TokioAsyncResolver>>,
+}
+
+impl BackendResolver {
+    pub fn try_new(kind: ResolverKind, options: &ResolverOptions) -> Result<Self> {
+        if kind == ResolverKind::System {
+            if options.ndots.is_some()
+                || !options.search.is_empty()
+                || options.negative_ttl.is_some()
+            {
+                bail!("DNS options only apply to --resolver hickory.");
+            }
+            return Ok(BackendResolver { hickory: None });
+        }
+        let (mut config, mut opts) =
+            system_conf::read_system_conf().context("Could not read /etc/resolv.conf")?;
+        if !options.search.is_empty() {
+            let search = options
+                .search
+                .iter()
+                .map(Name::from_utf8)
+                .collect::<Result<Vec<_>, _>>()
+                .context("Invalid search domain")?;
+            config = ResolverConfig::from_parts(
+                config.domain().cloned(),
+                search,
+                config.name_servers().to_vec(),
+            );
+        }
+        if let Some(ndots) = options.ndots {
+            opts.ndots = ndots;
+        }
+        if let Some(ttl) = options.negative_ttl {
+            opts.negative_max_ttl = Some(ttl);
+        }
+        info!(
+            "Resolving backends via {} nameservers, searching {:?} for names with less than {} dots.",
+            config.name_servers().len(),
+            config.search().iter().map(Name::to_utf8).collect::<Vec<_>>(),
+            opts.ndots
+        );
+        Ok(BackendResolver {
+            hickory: Some(Arc::new(TokioAsyncResolver::tokio(config, opts))),
+        })
+    }
+
+    /// The system's resolver, for tools which do not expose resolver options.
+    pub fn system() -> Self {
+        BackendResolver { hickory: None }
+    }
+
+    /// Addresses of `host` with `port`. Fails if the host does not resolve to any address.
+    pub async fn lookup(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        // IP literals need no lookup
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        let start = Instant::now();
+        let (result, addrs) = match &self.hickory {
+            Some(resolver) => match resolver.lookup_ip(host).await {
+                Ok(lookup) => {
+                    let addrs: Vec<SocketAddr> =
+                        lookup.iter().map(|ip| SocketAddr::new(ip, port)).collect();
+                    ("ok", Ok(addrs))
+                }
+                Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => {
+                    ("not_found", Err(e.into()))
+                }
+                Err(e) => ("error", Err(e.into())),
+            },
+            None => match lookup_host((host, port)).await {
+                Ok(addrs) => ("ok", Ok(addrs.collect())),
+                Err(e) => ("error", Err(anyhow::Error::from(e))),
+            },
+        };
+        metrics::DNS_LOOKUP_DURATION
+            .with_label_values(&[host])
+            .observe(start.elapsed().as_secs_f64());
+        metrics::DNS_LOOKUPS
+            .with_label_values(&[host, result])
+            .inc();
+        let addrs = addrs.with_context(|| format!("Could not resolve {host}"))?;
+        if addrs.is_empty() {
+            bail!("{host} did not resolve to any address.");
+        }
+        trace!("Resolved {host} to {addrs:?}.");
+        Ok(addrs)
+    }
+}
+
+/// Lets hyper's `HttpConnector` resolve through sero, which sets the port itself.
+impl tower::Service<HyperName> for BackendResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: HyperName) -> Self::Future {
+        let resolver = self.clone();
+        Box::pin(async move {
+            resolver
+                .lookup(name.as_str(), 0)
+                .await
+                .map(Vec::into_iter)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{e:#}")))
+        })
+    }
+}
@@ -0,0 +1,198 @@
+use crate::metrics;
+use crate::scaler::Target;
+use crate::tasks;
+
+use anyhow::Result;
+use futures::{Stream, StreamExt};
+use kube::{
+    api::DynamicObject,
+    core::params::ListParams,
+    runtime::{
+        self,
+        events::{Event, EventType, Recorder, Reporter},
+        WatchStreamExt,
+    },
+    Client, Resource,
+};
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tracing::*;
+
+const FIELD_MANAGER: &str = "scaler.sero.rs";
+
+#[derive(Clone, Copy, Debug)]
+struct Expectation {
+    replicas: i32,
+    since: Instant,
+    /// whether the target has been observed in the expected state yet
+    reached: bool,
+}
+
+impl Expectation {
+    fn is_met_by(&self, replicas: i32) -> bool {
+        (self.replicas > 0) == (replicas > 0)
+    }
+}
+
+struct RevertDetector {
+    target: Target,
+    window: Duration,
+    expectation: Arc<Mutex<Option<Expectation>>>,
+    reverts: Arc<AtomicUsize>,
+    client: Arc<Client>,
+    events: Pin<Box<dyn Stream<Item = Result<DynamicObject, runtime::watcher::Error>> + Send>>,
+}
+
+impl RevertDetector {
+    fn new(
+        target: &Target,
+        window: Duration,
+        expectation: Arc<Mutex<Option<Expectation>>>,
+        reverts: Arc<AtomicUsize>,
+        client: Arc<Client>,
+    ) -> Self {
+        let selector = ListParams::default().fields(&format!("metadata.name={}", target.name));
+        let events = runtime::watcher(target.api(&client), selector)
+            .touched_objects()
+            .boxed();
+        RevertDetector {
+            target: target.clone(),
+            window,
+            expectation,
+            reverts,
+            client,
+            events,
+        }
+    }
+
+    async fn run(mut self) {
+        while let Some(event) = self.events.next().await {
+            match event {
+                Err(e) => error!("Error getting next event for {}: {e}", self.target),
+                Ok(target) => self.check(target).await,
+            }
+        }
+    }
+
+    async fn check(&self, target: DynamicObject) {
+        let replicas = target.data["spec"]["replicas"]
+            .as_i64()
+            .map_or(1, |replicas| replicas as i32);
+        let reverted = {
+            let mut expectation = self.expectation.lock().unwrap();
+            match *expectation {
+                Some(exp) if exp.since.elapsed() > self.window => {
+                    *expectation = None;
+                    None
+                }
+                Some(ref mut exp) if exp.is_met_by(replicas) => {
+                    exp.reached = true;
+                    None
+                }
+                Some(exp) if exp.reached => {
+                    *expectation = None;
+                    Some(exp.replicas)
+                }
+                _ => None,
+            }
+        };
+        if let Some(desired) = reverted {
+            self.report(&target, desired, replicas).await;
+        }
+    }
+
+    async fn report(&self, target: &DynamicObject, desired: i32, replicas: i32) {
+        let manager = last_replicas_manager(target).unwrap_or_else(|| "unknown".to_owned());
+        let reverts = self.reverts.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!(
+            "Replicas of {} were reverted from {desired} to {replicas} by {manager} ({reverts} reverts so far). If it is managed by a GitOps tool, consider running sero with `--scale-mode annotation-toggle`.",
+            self.target
+        );
+        metrics::REPLICA_REVERTS
+            .with_label_values(&[&self.target.name, &manager])
+            .inc();
+
+        let reporter = Reporter {
+            controller: "sero".to_owned(),
+            instance: hostname::get()
+                .ok()
+                .and_then(|name| name.into_string().ok()),
+        };
+        let recorder = Recorder::new(
+            (*self.client).clone(),
+            reporter,
+            target.object_ref(self.target.api_resource()),
+        );
+        let event = Event {
+            type_: EventType::Warning,
+            reason: "ReplicasReverted".to_owned(),
+            note: Some(format!(
+                "Replicas set to {desired} by sero were reverted to {replicas} by {manager}."
+            )),
+            action: "Scaling".to_owned(),
+            secondary: None,
+        };
+        if let Err(e) = recorder.publish(event).await {
+            warn!("Could not publish event for {}: {e}", self.target);
+        }
+    }
+}
+
+/// Find the most recent field manager other than sero which owns `spec.replicas`.
+fn last_replicas_manager(target: &DynamicObject) -> Option<String> {
+    target
+        .metadata
+        .managed_fields
+        .as_ref()?
+        .iter()
+        .filter(|entry| entry.manager.as_deref() != Some(FIELD_MANAGER))
+        .filter(|entry| {
+            entry
+                .fields_v1
+                .as_ref()
+                .and_then(|fields| fields.0.get("f:spec"))
+                .and_then(|spec| spec.get("f:replicas"))
+                .is_some()
+        })
+        .max_by_key(|entry| entry.time.as_ref().map(|time| time.0))
+        .and_then(|entry| entry.manager.clone())
+}
+
+#[derive(Clone)]
+pub struct RevertDetectorHandle {
+    expectation: Arc<Mutex<Option<Expectation>>>,
+    reverts: Arc<AtomicUsize>,
+}
+
+impl RevertDetectorHandle {
+    pub fn new(target: &Target, window: Duration, client: Arc<Client>) -> Self {
+        let expectation = Arc::new(Mutex::new(None));
+        let reverts = Arc::new(AtomicUsize::new(0));
+        let detector =
+            RevertDetector::new(target, window, expectation.clone(), reverts.clone(), client);
+        tasks::spawn("revert-detector", detector.run());
+        RevertDetectorHandle {
+            expectation,
+            reverts,
+        }
+    }
+
+    /// Expect the target to stay at `replicas` for the configured window.
+    pub fn expect(&self, replicas: i32) {
+        *self.expectation.lock().unwrap() = Some(Expectation {
+            replicas,
+            since: Instant::now(),
+            reached: false,
+        });
+    }
+
+    pub fn reverts(&self) -> usize {
+        self.reverts.load(Ordering::Relaxed)
+    }
+}
@@ -1,52 +1,396 @@
+use crate::activity::ActivityHandle;
+use crate::disruption;
 use crate::endpoint_watcher::EndpointWatcherHandle;
+use crate::events::{SeroEvent, SeroEvents};
+use crate::hooks::{HookKind, JobHooks};
+use crate::injector::InjectorHandle;
+use crate::leader::LeaderElectionHandle;
+use crate::mailbox::Mailbox;
+use crate::metrics;
+use crate::revert_detector::RevertDetectorHandle;
+use crate::status::PlannedAction;
+use crate::tasks;
+use crate::tenancy::{WakeBudget, WakeBudgetExhausted};
+use crate::wake_queue::TenantWakeQueue;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::Utc;
+use clap::ValueEnum;
 use k8s_openapi::api::{apps::v1::Deployment, autoscaling::v1::Scale};
 use kube::{
-    api::{Api, Patch, PatchParams, ScaleSpec},
-    Client,
+    api::{Api, ApiResource, DynamicObject, Patch, PatchParams, ScaleSpec},
+    core::ObjectMeta,
+    discovery::Discovery,
+    runtime::events::{Event, EventType, Recorder, Reporter},
+    Client, Resource,
+};
+use serde::Serialize;
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    process::Command,
+    sync::{mpsc, oneshot, watch},
 };
-use std::sync::Arc;
-use tokio::sync::{mpsc, oneshot};
 use tracing::*;
 
+/// Number of times a scale transaction is attempted before giving up on conflicts.
+const MAX_SCALE_ATTEMPTS: u32 = 5;
+
+/// How often an awake backend is checked for serving endpoints.
+const LIVENESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long a wake or sleep waits for the endpoints before checking whether the replicas were
+/// reverted meanwhile.
+const SCALE_RECHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long sero waits to become a serving endpoint of the service before a scale-down, and
+/// to leave the service's endpoints after a wake.
+const SERO_SERVING_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a scale-down waits for the backend to stop serving before it is deferred.
+const SCALE_DOWN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Identifies the activities registered with a scaler.
+static NEXT_ACTIVITY_ID: AtomicU64 = AtomicU64::new(0);
+
+/// How long a deferred scale-down waits before checking again.
+const DEFER_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Annotation toggled on the target by `ScaleStrategy::AnnotationToggle`.
+pub const DESIRED_SLEEP_ANNOTATION: &str = "sero.rs/desired-sleep";
+
+/// Annotation remembering the replicas of the target before it was scaled to zero.
+pub const PREVIOUS_REPLICAS_ANNOTATION: &str = "sero.rs/previous-replicas";
+
+/// Kind of a workload with a scale subresource as `kind[.group]`, like kubectl takes it
+/// (e.g. `statefulset`, `replicaset.apps` or `rollout.argoproj.io`).
+#[derive(Clone, Debug, PartialEq)]
+pub struct TargetKind {
+    kind: String,
+    group: Option<String>,
+}
+
+impl FromStr for TargetKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, group) = match s.split_once('.') {
+            Some((kind, group)) => (kind, Some(group.to_owned())),
+            None => (s, None),
+        };
+        if kind.is_empty() {
+            bail!("Invalid target kind '{s}', expected <kind>[.<group>].");
+        }
+        Ok(TargetKind {
+            kind: kind.to_lowercase(),
+            group,
+        })
+    }
+}
+
+impl TargetKind {
+    /// Whether this kind names `Deployment`s of the `apps` group.
+    pub fn is_deployment(&self) -> bool {
+        matches!(self.kind.as_str(), "deployment" | "deployments")
+            && matches!(self.group.as_deref(), None | Some("apps"))
+    }
+
+    /// Look up the API resource of this kind, searching all groups if none was given.
+    pub async fn resolve(&self, name: &str, client: &Client) -> Result<Target> {
+        let mut discovery = Discovery::new(client.clone());
+        if let Some(group) = &self.group {
+            discovery = discovery.filter(&[group.as_str()]);
+        }
+        let discovery = discovery.run().await?;
+        let (resource, _) = discovery
+            .groups()
+            .flat_map(|group| group.recommended_resources())
+            .find(|(resource, _)| {
+                resource.kind.to_lowercase() == self.kind || resource.plural == self.kind
+            })
+            .with_context(|| format!("Could not find resource type '{}'.", self.kind))?;
+        Ok(Target {
+            name: name.to_owned(),
+            resource,
+        })
+    }
+}
+
+/// A workload given as `kind[.group]/name`, e.g. `rollout.argoproj.io/web`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TargetRef {
+    pub kind: TargetKind,
+    pub name: String,
+}
+
+impl FromStr for TargetRef {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, name) = s
+            .split_once('/')
+            .with_context(|| format!("Invalid target '{s}', expected <kind>[.<group>]/<name>."))?;
+        Ok(TargetRef {
+            kind: kind.parse()?,
+            name: name.to_owned(),
+        })
+    }
+}
+
+/// A workload sero scales through the dynamic API.
+#[derive(Clone, Debug)]
+pub struct Target {
+    pub name: String,
+    resource: ApiResource,
+}
+
+impl Target {
+    /// A Deployment, which needs no discovery.
+    pub fn deployment(name: &str) -> Self {
+        Target {
+            name: name.to_owned(),
+            resource: ApiResource::erase::<Deployment>(&()),
+        }
+    }
+
+    pub fn api_resource(&self) -> &ApiResource {
+        &self.resource
+    }
+
+    /// Namespaced API of workloads of this kind.
+    pub fn api(&self, client: &Client) -> Api<DynamicObject> {
+        Api::default_namespaced_with(client.clone(), &self.resource)
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.resource.kind.to_lowercase(), self.name)
+    }
+}
+
+/// How sero expresses the desired number of replicas.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum ScaleMode {
+    /// Patch the scale subresource of the target
+    #[value(alias = "replicas")]
+    PatchScale,
+    /// Patch `spec.replicas` of the target, for workloads whose scale subresource is unusable
+    PatchSpecReplicas,
+    /// Only toggle the `sero.rs/desired-sleep` annotation on the target.
+    ///
+    /// Replicas are then set by the companion mutation in `deploy/desired-sleep-policy.yaml`,
+    /// so GitOps tools applying the target and sero never write the same field.
+    #[value(alias = "annotation")]
+    AnnotationToggle,
+    /// Run `--scale-hook <deployment> <replicas>` and let it scale the workload
+    ExecHook,
+}
+
+/// A `ScaleMode` together with its parameters.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ScaleStrategy {
+    PatchScale,
+    PatchSpecReplicas,
+    AnnotationToggle,
+    ExecHook(PathBuf),
+    /// Never scale; another system owns the replicas and sero only waits for endpoints
+    Disabled,
+}
+
+impl ScaleStrategy {
+    pub fn try_new(mode: ScaleMode, hook: Option<PathBuf>) -> Result<Self> {
+        let strategy = match (mode, hook) {
+            (ScaleMode::ExecHook, Some(hook)) => ScaleStrategy::ExecHook(hook),
+            (ScaleMode::ExecHook, None) => bail!("--scale-mode exec-hook requires --scale-hook."),
+            (_, Some(_)) => bail!("--scale-hook is only used with --scale-mode exec-hook."),
+            (ScaleMode::PatchScale, None) => ScaleStrategy::PatchScale,
+            (ScaleMode::PatchSpecReplicas, None) => ScaleStrategy::PatchSpecReplicas,
+            (ScaleMode::AnnotationToggle, None) => ScaleStrategy::AnnotationToggle,
+        };
+        Ok(strategy)
+    }
+}
+
 struct Scaler {
     receiver: mpsc::Receiver<ScalerMessage>,
     deploy_name: String,
+    target: Target,
+    strategy: ScaleStrategy,
+    hooks: JobHooks,
+    /// Bounds concurrent wakes across the services of an operator
+    wake_queue: Option<TenantWakeQueue>,
+    /// Limits the wakes of the target's namespace per hour
+    wake_budget: Option<WakeBudget>,
+    reverts: RevertDetectorHandle,
+    max_reverts: Option<usize>,
     client: Arc<Client>,
     endpoints: EndpointWatcherHandle,
+    /// Whether the backend is supposed to be awake
+    awake: watch::Sender<bool>,
+    /// Whether the backend was last woken or put to sleep, unknown before either
+    intent: Option<bool>,
+    /// Set once sero is shutting down, leaving wakes and sleeps to its replacement
+    draining: bool,
+    events: SeroEvents,
+    /// Gates scale-downs when several replicas of sero run
+    leader: Option<LeaderElectionHandle>,
+    /// Injects sero into the service's endpoints before the backend is scaled down
+    injector: Option<InjectorHandle>,
 }
 
 impl Scaler {
-    fn new(
-        receiver: mpsc::Receiver<ScalerMessage>,
-        deploy_name: &str,
-        client: Arc<Client>,
-        endpoints: EndpointWatcherHandle,
-    ) -> Self {
-        Scaler {
-            receiver,
-            deploy_name: deploy_name.to_owned(),
-            client,
-            endpoints,
+    /// Current replicas and the resourceVersion they were read at.
+    async fn get_replicas(&self) -> Result<(i32, Option<String>)> {
+        let api = self.target.api(&self.client);
+        let (replicas, resource_version) = match self.strategy {
+            ScaleStrategy::PatchScale => {
+                let scale = api.get_scale(&self.deploy_name).await?;
+                let spec = scale
+                    .spec
+                    .with_context(|| format!("{} has no ScaleSpec.", self.target))?;
+                (spec.replicas, scale.metadata.resource_version)
+            }
+            // the scale subresource may be unusable, read the spec instead
+            _ => {
+                let target = api.get(&self.deploy_name).await?;
+                let replicas = target.data["spec"]["replicas"]
+                    .as_i64()
+                    .map(|replicas| replicas as i32);
+                (replicas, target.metadata.resource_version)
+            }
+        };
+        Ok((replicas.unwrap_or_default(), resource_version))
+    }
+
+    /// Read the replicas, let `desired` decide on new replicas and write them unless the
+    /// target changed in between. Retries a bounded number of times on conflicts. The
+    /// `reason` is recorded in an Event on the target.
+    async fn scale_transaction<F>(&self, reason: &str, desired: F) -> Result<()>
+    where
+        F: Fn(i32) -> Option<i32>,
+    {
+        let mut attempt = 1;
+        loop {
+            let (current, resource_version) = self.get_replicas().await?;
+            let Some(replicas) = desired(current) else {
+                return Ok(());
+            };
+            match self.set_replicas(replicas, resource_version).await {
+                Err(e) if is_conflict(&e) && attempt < MAX_SCALE_ATTEMPTS => {
+                    debug!(
+                        "{} changed while scaling it, retrying (attempt {attempt}).",
+                        self.target
+                    );
+                    attempt += 1;
+                }
+                Ok(()) => {
+                    self.publish_scale_event(current, replicas, reason).await;
+                    return Ok(());
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Tell `kubectl describe` why the replicas of the target changed.
+    async fn publish_scale_event(&self, from: i32, to: i32, reason: &str) {
+        let target = match self.target.api(&self.client).get(&self.deploy_name).await {
+            Ok(target) => target,
+            Err(e) => {
+                warn!("Could not publish event for {}: {e}", self.target);
+                return;
+            }
+        };
+        let reporter = Reporter {
+            controller: "sero".to_owned(),
+            instance: hostname::get()
+                .ok()
+                .and_then(|name| name.into_string().ok()),
+        };
+        let recorder = Recorder::new(
+            (*self.client).clone(),
+            reporter,
+            target.object_ref(self.target.api_resource()),
+        );
+        let event = Event {
+            type_: EventType::Normal,
+            reason: if to > from {
+                "ScaledUpBySero"
+            } else {
+                "ScaledDownBySero"
+            }
+            .to_owned(),
+            note: Some(format!("Scaled from {from} to {to} replicas ({reason}).")),
+            action: "Scaling".to_owned(),
+            secondary: None,
+        };
+        if let Err(e) = recorder.publish(event).await {
+            warn!("Could not publish event for {}: {e}", self.target);
+        }
+    }
+
+    async fn set_replicas(&self, replicas: i32, resource_version: Option<String>) -> Result<()> {
+        // stop fighting other field managers after too many reverts
+        let reverts = self.reverts.reverts();
+        if let Some(max_reverts) = self.max_reverts {
+            if reverts >= max_reverts {
+                bail!(
+                    "Refusing to scale {} after {reverts} reverts by other field managers.",
+                    self.target
+                );
+            }
+        }
+        self.reverts.expect(replicas);
+        match &self.strategy {
+            ScaleStrategy::PatchScale => self.patch_scale(replicas, resource_version, false).await,
+            ScaleStrategy::PatchSpecReplicas => {
+                self.patch_spec_replicas(replicas, resource_version, false)
+                    .await
+            }
+            ScaleStrategy::AnnotationToggle => self.patch_desired_sleep(replicas == 0, false).await,
+            ScaleStrategy::ExecHook(hook) => self.exec_hook(hook, replicas).await,
+            ScaleStrategy::Disabled => Ok(()),
         }
     }
 
-    async fn get_replicas(&self) -> Result<i32> {
-        let deploy: Api<Deployment> = Api::default_namespaced((*self.client).clone());
-        let replicas = deploy
-            .get_scale(&self.deploy_name)
-            .await?
-            .spec
-            .context("Deployment has no ScaleSpec.")?
-            .replicas
-            .unwrap_or_default();
-        Ok(replicas)
+    /// Verify permissions and the target's scale subresource by scaling to the current replicas
+    /// without persisting it. Scale hooks cannot be dry-run and are skipped.
+    async fn dry_run(&self) -> Result<()> {
+        let (replicas, _) = self.get_replicas().await?;
+        match &self.strategy {
+            ScaleStrategy::PatchScale => self.patch_scale(replicas, None, true).await,
+            ScaleStrategy::PatchSpecReplicas => {
+                self.patch_spec_replicas(replicas, None, true).await
+            }
+            ScaleStrategy::AnnotationToggle => self.patch_desired_sleep(replicas == 0, true).await,
+            ScaleStrategy::ExecHook(_) | ScaleStrategy::Disabled => Ok(()),
+        }
     }
 
-    async fn set_replicas(&self, replicas: i32) -> Result<()> {
-        let deploy: Api<Deployment> = Api::default_namespaced((*self.client).clone());
+    async fn patch_scale(
+        &self,
+        replicas: i32,
+        resource_version: Option<String>,
+        dry_run: bool,
+    ) -> Result<()> {
+        let api = self.target.api(&self.client);
+        // the resourceVersion makes the apiserver reject the patch if the scale changed
         let scale = Scale {
+            metadata: ObjectMeta {
+                resource_version,
+                ..Default::default()
+            },
             spec: Some(ScaleSpec {
                 replicas: Some(replicas),
             }),
@@ -55,62 +399,420 @@ impl Scaler {
         // do a forced server side apply where "sero" is the fieldManager
         // TODO: maybe first try without `force`, then warn and retry with `force`?
         let patch = Patch::Apply(&scale);
-        let params = PatchParams::apply("scaler.sero.rs").force();
+        let params = patch_params(dry_run);
+        if !dry_run {
+            info!("Scaling {} to {replicas} replicas.", self.target);
+        }
+        api.patch_scale(&self.deploy_name, &params, &patch).await?;
+
+        Ok(())
+    }
+
+    async fn patch_spec_replicas(
+        &self,
+        replicas: i32,
+        resource_version: Option<String>,
+        dry_run: bool,
+    ) -> Result<()> {
+        let api_resource = self.target.api_resource();
+        let mut patch = json!({
+            "apiVersion": api_resource.api_version,
+            "kind": api_resource.kind,
+            "metadata": {
+                "name": self.deploy_name,
+            },
+            "spec": {
+                "replicas": replicas,
+            },
+        });
+        // the resourceVersion makes the apiserver reject the patch if the target changed
+        if let Some(resource_version) = resource_version {
+            patch["metadata"]["resourceVersion"] = resource_version.into();
+        }
+        let patch = Patch::Apply(&patch);
+        let params = patch_params(dry_run);
+        if !dry_run {
+            info!("Setting spec.replicas of {} to {replicas}.", self.target);
+        }
+        self.target
+            .api(&self.client)
+            .patch(&self.deploy_name, &params, &patch)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn exec_hook(&self, hook: &Path, replicas: i32) -> Result<()> {
         info!(
-            "Scaling deployment/{} to {replicas} replicas.",
-            self.deploy_name
+            "Running scale hook {} for {} with {replicas} replicas.",
+            hook.display(),
+            self.target
         );
-        deploy
-            .patch_scale(&self.deploy_name, &params, &patch)
-            .await?;
+        let status = Command::new(hook)
+            .arg(&self.deploy_name)
+            .arg(replicas.to_string())
+            .env("SERO_DEPLOYMENT", &self.deploy_name)
+            .env("SERO_REPLICAS", replicas.to_string())
+            .kill_on_drop(true)
+            .status()
+            .await
+            .with_context(|| format!("Could not run scale hook {}", hook.display()))?;
+        if !status.success() {
+            bail!("Scale hook {} failed with {status}.", hook.display());
+        }
 
         Ok(())
     }
 
-    async fn scale_up(&self) -> Result<()> {
-        let current_replicas = self.get_replicas().await?;
-        if current_replicas < 1 {
-            self.set_replicas(1).await?;
+    async fn patch_desired_sleep(&self, sleep: bool, dry_run: bool) -> Result<()> {
+        let api_resource = self.target.api_resource();
+        let patch = json!({
+            "apiVersion": api_resource.api_version,
+            "kind": api_resource.kind,
+            "metadata": {
+                "name": self.deploy_name,
+                "annotations": {
+                    DESIRED_SLEEP_ANNOTATION: sleep.to_string(),
+                },
+            },
+        });
+        let patch = Patch::Apply(&patch);
+        let params = patch_params(dry_run);
+        if !dry_run {
+            info!(
+                "Annotating {} with {DESIRED_SLEEP_ANNOTATION}={sleep}.",
+                self.target
+            );
         }
+        self.target
+            .api(&self.client)
+            .patch(&self.deploy_name, &params, &patch)
+            .await?;
+
         Ok(())
     }
 
-    async fn scale_down(&self) -> Result<()> {
-        let current_replicas = self.get_replicas().await?;
-        if current_replicas >= 0 {
-            self.set_replicas(0).await?;
+    /// Inject sero into the service's endpoints and wait until it is serving.
+    async fn ensure_sero_serving(&mut self) -> Result<()> {
+        let Some(injector) = &self.injector else {
+            return Ok(());
+        };
+        injector.inject().await?;
+        let serving = self.endpoints.wait_for(|status| status.sero > 0);
+        tokio::time::timeout(SERO_SERVING_TIMEOUT, serving)
+            .await
+            .with_context(|| {
+                format!(
+                    "sero did not become a serving endpoint within {SERO_SERVING_TIMEOUT:?}, not scaling {} down",
+                    self.target
+                )
+            })?
+    }
+
+    /// Eject sero from the service's endpoints once the backend serves, so that clients no
+    /// longer go through sero. Clients are still proxied if this takes too long.
+    async fn ensure_sero_ejected(&mut self) -> Result<()> {
+        let Some(injector) = &self.injector else {
+            return Ok(());
+        };
+        injector.eject().await?;
+        // followers leave the endpoints to the leader, waiting for it would hold up connections
+        if self.leader.as_ref().map_or(false, |l| !l.is_leader()) {
+            return Ok(());
+        }
+        let ejected = self.endpoints.wait_for(|status| status.sero == 0);
+        match tokio::time::timeout(SERO_SERVING_TIMEOUT, ejected).await {
+            Ok(ejected) => ejected,
+            Err(_) => {
+                warn!(
+                    "sero is still a serving endpoint of the service after {SERO_SERVING_TIMEOUT:?}, proxying its clients to {}.",
+                    self.target
+                );
+                Ok(())
+            }
         }
+    }
+
+    /// Replicas the target had before it was last scaled to zero, if remembered.
+    async fn previous_replicas(&self) -> Result<Option<i32>> {
+        let target = self.target.api(&self.client).get(&self.deploy_name).await?;
+        let previous = target
+            .metadata
+            .annotations
+            .as_ref()
+            .and_then(|annotations| annotations.get(PREVIOUS_REPLICAS_ANNOTATION))
+            .and_then(|replicas| replicas.parse().ok());
+        Ok(previous)
+    }
+
+    /// Annotate the target with its replicas, to restore them on the next wake.
+    async fn remember_replicas(&self, replicas: i32) -> Result<()> {
+        let api_resource = self.target.api_resource();
+        let patch = json!({
+            "apiVersion": api_resource.api_version,
+            "kind": api_resource.kind,
+            "metadata": {
+                "name": self.deploy_name,
+                "annotations": {
+                    PREVIOUS_REPLICAS_ANNOTATION: replicas.to_string(),
+                },
+            },
+        });
+        // a field manager of its own, so that applying only the annotation keeps the replicas
+        let params = PatchParams::apply("replicas.scaler.sero.rs").force();
+        self.target
+            .api(&self.client)
+            .patch(&self.deploy_name, &params, &Patch::Apply(&patch))
+            .await?;
         Ok(())
     }
 
+    async fn scale_up(&self, reason: &str) -> Result<()> {
+        if self.strategy == ScaleStrategy::Disabled {
+            return Ok(());
+        }
+        let replicas = match self.previous_replicas().await {
+            Ok(previous) => previous.unwrap_or(1).max(1),
+            Err(e) => {
+                warn!(
+                    "Could not read the previous replicas of {}: {e}",
+                    self.target
+                );
+                1
+            }
+        };
+        self.scale_transaction(reason, |current| (current < 1).then_some(replicas))
+            .await
+    }
+
+    /// Wait until the backend is serving, scaling it up again if its replicas were reverted.
+    async fn wait_for_backend(&mut self, reason: &str) -> Result<()> {
+        loop {
+            let serving = self.endpoints.wait_for(|status| status.backend > 0);
+            if let Ok(serving) = tokio::time::timeout(SCALE_RECHECK_INTERVAL, serving).await {
+                return serving;
+            }
+            self.scale_up(reason).await?;
+        }
+    }
+
+    async fn scale_down(&self, reason: &str) -> Result<()> {
+        if self.strategy == ScaleStrategy::Disabled {
+            return Ok(());
+        }
+        let (current, _) = self.get_replicas().await?;
+        if current > 0 {
+            self.remember_replicas(current).await?;
+        }
+        self.scale_transaction(reason, |current| (current != 0).then_some(0))
+            .await
+    }
+
+    fn set_intent(&mut self, awake: bool) {
+        self.intent = Some(awake);
+        self.awake.send_replace(awake);
+    }
+
+    /// Scale the target back to the replicas sero last intended if they were changed behind
+    /// its back, e.g. by a manual edit or while a watch event was missed.
+    async fn reconcile(&self) -> Result<()> {
+        let Some(awake) = self.intent else {
+            return Ok(());
+        };
+        if self.strategy == ScaleStrategy::Disabled
+            || self.leader.as_ref().map_or(false, |l| !l.is_leader())
+        {
+            return Ok(());
+        }
+        let (current, _) = self.get_replicas().await?;
+        match (awake, current) {
+            (true, 0) => {
+                warn!(
+                    "{} was scaled to zero while awake, scaling it up again.",
+                    self.target
+                );
+                self.scale_up("reverted while awake").await
+            }
+            (false, current) if current > 0 => {
+                warn!(
+                    "{} was scaled to {current} while asleep, scaling it down again.",
+                    self.target
+                );
+                self.scale_down("reverted while asleep").await
+            }
+            _ => Ok(()),
+        }
+    }
+
     async fn handle_message(&mut self, msg: ScalerMessage) -> Result<()> {
         use ScalerMessage::*;
         match msg {
-            ScaleUp => self.scale_up().await,
-            ScaleDown => self.scale_up().await,
-            EnsureUp(sender) => {
+            ScaleAwake(replicas) => {
+                if self.strategy == ScaleStrategy::Disabled
+                    || self.leader.as_ref().map_or(false, |l| !l.is_leader())
+                {
+                    return Ok(());
+                }
+                // waking and sleeping stay with EnsureUp and EnsureDown
+                self.scale_transaction("concurrency", |current| {
+                    (current > 0 && current != replicas).then_some(replicas)
+                })
+                .await
+            }
+            EnsureUp(cause, connections, span, sender) => {
                 // first make sure that the backend is serving
-                while !self.endpoints.backend_is_serving() {
-                    self.scale_up().await?;
-                    self.endpoints.changed().await;
+                let woke = !self.endpoints.backend_is_serving();
+                if woke && self.draining {
+                    let suppressed = WakeSuppressed {
+                        target: self.target.to_string(),
+                    };
+                    return sender.send(Err(suppressed.into())).ok().context(
+                        "Could not answer to EnsureUp message because sender end was dropped.",
+                    );
+                }
+                if let (Some(wake_budget), true) = (&self.wake_budget, woke) {
+                    if let Err(e) = wake_budget.spend() {
+                        return sender.send(Err(e)).ok().context(
+                            "Could not answer to EnsureUp message because sender end was dropped.",
+                        );
+                    }
                 }
-                while self.endpoints.sero_is_serving() {
-                    // TODO: drain sero endpointslices
-                    self.endpoints.changed().await;
+                self.set_intent(true);
+                let start = Instant::now();
+                let _permit = match (&self.wake_queue, woke) {
+                    (Some(wake_queue), true) => Some(wake_queue.acquire().await),
+                    _ => None,
+                };
+                if woke {
+                    match connections {
+                        1 => info!("Waking {} ({cause}).", self.target),
+                        _ => info!(
+                            "Waking {} ({cause}, {connections} connections waiting).",
+                            self.target
+                        ),
+                    }
+                    metrics::WAKE_DEMAND
+                        .with_label_values(&[&self.deploy_name])
+                        .observe(connections as f64);
+                    self.events.publish(SeroEvent::WakeStarted {
+                        deployment: self.deploy_name.clone(),
+                        cause,
+                        timestamp: Utc::now(),
+                    });
+                    metrics::WAKES
+                        .with_label_values(&[&self.deploy_name, cause.as_str()])
+                        .inc();
+                    self.hooks
+                        .run(HookKind::Wake, &self.deploy_name, &self.client)
+                        .await?;
+                }
+                if !self.endpoints.backend_is_serving() {
+                    self.scale_up(cause.as_str())
+                        .instrument(info_span!(parent: &span, "scale"))
+                        .await?;
+                    self.wait_for_backend(cause.as_str())
+                        .instrument(info_span!(parent: &span, "wait_for_endpoints"))
+                        .await?;
+                }
+                // route clients to the backend directly again, unless that already happened
+                if woke || self.endpoints.sero_is_serving() {
+                    self.ensure_sero_ejected().await?;
+                }
+                if woke {
+                    self.events.publish(SeroEvent::WakeCompleted {
+                        deployment: self.deploy_name.clone(),
+                        cause,
+                        timestamp: Utc::now(),
+                        duration_ms: start.elapsed().as_millis() as u64,
+                    });
                 }
                 sender
-                    .send(())
+                    .send(Ok(woke))
                     .ok()
                     .context("Could not answer to EnsureUp message because sender end was dropped.")
             }
-            EnsureDown(sender) => {
-                // TODO: first, make sure that sero is serving (add self to endpointslice)
-                // then, scale down backend
+            Reconcile => self.reconcile().await,
+            Drain => {
+                info!(
+                    "Sero is shutting down, no longer waking or sleeping {}.",
+                    self.target
+                );
+                self.draining = true;
+                Ok(())
+            }
+            DryRun(sender) => sender
+                .send(self.dry_run().await)
+                .ok()
+                .context("Could not answer to DryRun message because sender end was dropped."),
+            EnsureDown(cause, sender) => {
+                // a terminating sero leaves sleeping to its replacement, and only the elected
+                // replica scales down, followers may still proxy connections
+                let blocker = match self.draining {
+                    true => Some("sero is shutting down".to_owned()),
+                    false => self.leader.as_ref().and_then(|l| l.scale_down_blocker()),
+                };
+                if let Some(blocker) = blocker {
+                    return sender.send(Some(blocker)).ok().context(
+                        "Could not answer to EnsureDown message because sender end was dropped.",
+                    );
+                }
+                // do not interfere with rollouts or disruption budgets of a serving backend
+                if self.strategy != ScaleStrategy::Disabled && self.endpoints.backend_is_serving() {
+                    let blocker =
+                        disruption::scale_down_blocker(&self.target, &self.client).await?;
+                    if blocker.is_some() {
+                        return sender.send(blocker).ok().context(
+                            "Could not answer to EnsureDown message because sender end was dropped.",
+                        );
+                    }
+                }
+                self.set_intent(false);
+                // take over the service's traffic before the backend goes away
+                self.ensure_sero_serving().await?;
+                let was_serving = self.endpoints.backend_is_serving();
+                if was_serving {
+                    self.hooks
+                        .run(HookKind::Sleep, &self.deploy_name, &self.client)
+                        .await?;
+                }
+                let deadline = Instant::now() + SCALE_DOWN_TIMEOUT;
+                let mut scaled = false;
                 while self.endpoints.backend_is_serving() {
-                    self.scale_down().await?;
-                    self.endpoints.changed().await;
+                    if Instant::now() >= deadline {
+                        let blocker = match self.strategy {
+                            ScaleStrategy::AnnotationToggle => format!(
+                                "{} is still serving {SCALE_DOWN_TIMEOUT:?} after setting {DESIRED_SLEEP_ANNOTATION}, is its companion mutation installed?",
+                                self.target
+                            ),
+                            _ => format!(
+                                "{} is still serving {SCALE_DOWN_TIMEOUT:?} after scaling it down",
+                                self.target
+                            ),
+                        };
+                        warn!("{blocker}");
+                        return sender.send(Some(blocker)).ok().context(
+                            "Could not answer to EnsureDown message because sender end was dropped.",
+                        );
+                    }
+                    // the companion mutation scales an annotated target, annotating it again
+                    // would only publish another event
+                    if !scaled || self.strategy != ScaleStrategy::AnnotationToggle {
+                        self.scale_down(cause.as_str()).await?;
+                        scaled = true;
+                    }
+                    // scale down again if the replicas were reverted meanwhile
+                    let asleep = self.endpoints.wait_for(|status| status.backend == 0);
+                    if let Ok(asleep) = tokio::time::timeout(SCALE_RECHECK_INTERVAL, asleep).await {
+                        asleep?;
+                    }
                 }
-                sender.send(()).ok().context(
+                if was_serving {
+                    self.events.publish(SeroEvent::SleepCompleted {
+                        deployment: self.deploy_name.clone(),
+                        timestamp: Utc::now(),
+                    });
+                }
+                sender.send(None).ok().context(
                     "Could not answer to EnsureDown message because sender end was dropped.",
                 )
             }
@@ -126,49 +828,605 @@ impl Scaler {
     }
 }
 
-#[allow(dead_code)]
+/// Forced server side apply as sero's scaler, which the apiserver only validates on `dry_run`.
+fn patch_params(dry_run: bool) -> PatchParams {
+    PatchParams {
+        dry_run,
+        ..PatchParams::apply("scaler.sero.rs").force()
+    }
+}
+
+/// Whether a request was rejected because its resourceVersion precondition failed.
+fn is_conflict(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<kube::Error>(),
+        Some(kube::Error::Api(response)) if response.code == 409
+    )
+}
+
+/// Returned by `ensure_down` if scaling down was deferred to avoid interfering with the target.
+#[derive(Debug)]
+pub struct ScaleDownDeferred {
+    pub target: String,
+    pub reason: String,
+}
+
+impl fmt::Display for ScaleDownDeferred {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Deferring scale-down of {}: {}.",
+            self.target, self.reason
+        )
+    }
+}
+
+impl std::error::Error for ScaleDownDeferred {}
+
+/// Returned by `ensure_up` instead of waking a sleeping backend while sero shuts down, as its
+/// replacement takes over new connections.
+#[derive(Clone, Debug)]
+pub struct WakeSuppressed {
+    pub target: String,
+}
+
+impl fmt::Display for WakeSuppressed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Not waking {} as sero is shutting down.", self.target)
+    }
+}
+
+impl std::error::Error for WakeSuppressed {}
+
+/// The error of a batched wake for one of its connections, keeping the types that decide how
+/// clients are answered.
+fn batched_wake_error(e: &anyhow::Error) -> anyhow::Error {
+    if let Some(exhausted) = e.downcast_ref::<WakeBudgetExhausted>() {
+        return exhausted.clone().into();
+    }
+    if let Some(suppressed) = e.downcast_ref::<WakeSuppressed>() {
+        return suppressed.clone().into();
+    }
+    anyhow!("{e:#}")
+}
+
+/// Replicas of an awake backend by its concurrent connections, on top of waking and sleeping.
+#[derive(Clone, Copy, Debug)]
+pub struct ConcurrencyScaling {
+    pub connections_per_replica: usize,
+    pub min_replicas: i32,
+    pub max_replicas: i32,
+    /// How long fewer replicas must have sufficed before replicas are removed
+    pub scale_in_delay: Duration,
+}
+
+impl ConcurrencyScaling {
+    fn desired_replicas(&self, connections: usize) -> i32 {
+        let per_replica = self.connections_per_replica.max(1);
+        let replicas = (connections + per_replica - 1) / per_replica;
+        i32::try_from(replicas)
+            .unwrap_or(i32::MAX)
+            .clamp(self.min_replicas, self.max_replicas)
+    }
+}
+
+/// Why the backend is being woken up.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum WakeCause {
+    ClientConnection,
+    AdminApi,
+    /// All endpoints of an awake backend disappeared
+    BackendLost,
+    /// `--self-test-on-start` cycled the sleeping backend
+    SelfTest,
+    /// Requests spooled before sero restarted are waiting to be sent
+    SpooledRequests,
+}
+
+impl WakeCause {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WakeCause::ClientConnection => "client_connection",
+            WakeCause::AdminApi => "admin_api",
+            WakeCause::BackendLost => "backend_lost",
+            WakeCause::SelfTest => "self_test",
+            WakeCause::SpooledRequests => "spooled_requests",
+        }
+    }
+}
+
+impl fmt::Display for WakeCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Why a backend was put to sleep.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SleepCause {
+    /// No connections for the configured timeout
+    IdleTimeout,
+    AdminApi,
+    /// `--self-test-on-start` or `sero e2e` cycled the backend
+    SelfTest,
+}
+
+impl SleepCause {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SleepCause::IdleTimeout => "idle_timeout",
+            SleepCause::AdminApi => "admin_api",
+            SleepCause::SelfTest => "self_test",
+        }
+    }
+}
+
 #[derive(Debug)]
 enum ScalerMessage {
-    ScaleUp,
-    ScaleDown,
-    EnsureUp(oneshot::Sender<()>),
-    EnsureDown(oneshot::Sender<()>),
+    /// Scale an awake backend to these replicas, leaving a sleeping one asleep
+    ScaleAwake(i32),
+    /// Answered with whether the backend was woken up for this many waiting connections, or
+    /// why it may not be. Scaling and waiting for endpoints are traced within the span.
+    EnsureUp(WakeCause, usize, Span, oneshot::Sender<Result<bool>>),
+    /// Answered with the reason if the scale-down was deferred
+    EnsureDown(SleepCause, oneshot::Sender<Option<String>>),
+    /// Restore the intended replicas if they drifted
+    Reconcile,
+    /// Stop waking and sleeping the backend as sero is shutting down
+    Drain,
+    /// Answered with the error of a scale to the current replicas which is not persisted
+    DryRun(oneshot::Sender<Result<()>>),
 }
 
 #[derive(Clone)]
 pub struct ScalerHandle {
-    sender: mpsc::Sender<ScalerMessage>,
+    sender: Mailbox<ScalerMessage>,
+    awake: watch::Receiver<bool>,
+    /// `kind/name` of the target, for logs
+    target: String,
+    /// Activity of each service scaling the target, which sleeps only once all are idle
+    activities: Arc<Mutex<HashMap<u64, ActivityHandle>>>,
+    /// Connections collected into the next wake
+    batch: WakeBatch,
+}
+
+/// How long connections to a sleeping backend are collected into a single wake.
+const WAKE_BATCH_WINDOW: Duration = Duration::from_millis(50);
+
+/// Connections waiting for the same wake, each answered with whether it is credited with the
+/// wake, or why the wake failed.
+type WakeBatch = Arc<Mutex<Option<Vec<oneshot::Sender<Result<bool, Arc<anyhow::Error>>>>>>>;
+
+/// Removes the activity of a service from its scaler once it is no longer watched.
+struct ActivityRegistration {
+    activities: Arc<Mutex<HashMap<u64, ActivityHandle>>>,
+    id: u64,
+}
+
+impl Drop for ActivityRegistration {
+    fn drop(&mut self) {
+        self.activities
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.id);
+    }
 }
 
-#[allow(dead_code)]
 impl ScalerHandle {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         max_concurrency: usize,
-        deploy_name: &str,
+        target: Target,
+        strategy: ScaleStrategy,
+        hooks: JobHooks,
+        wake_queue: Option<TenantWakeQueue>,
+        wake_budget: Option<WakeBudget>,
+        revert_window: Duration,
+        max_reverts: Option<usize>,
         client: Arc<Client>,
         endpoints: EndpointWatcherHandle,
+        events: SeroEvents,
+        leader: Option<LeaderElectionHandle>,
+        injector: Option<InjectorHandle>,
     ) -> Self {
         let (sender, receiver) = mpsc::channel(max_concurrency);
-        let scaler = Scaler::new(receiver, deploy_name, client, endpoints);
-        tokio::spawn(scaler.run());
+        let (awake_tx, awake) = watch::channel(false);
+        let reverts = RevertDetectorHandle::new(&target, revert_window, client.clone());
+        let scaler = Scaler {
+            receiver,
+            deploy_name: target.name.clone(),
+            target: target.clone(),
+            strategy,
+            hooks,
+            wake_queue,
+            wake_budget,
+            reverts,
+            max_reverts,
+            client,
+            endpoints: endpoints.clone(),
+            awake: awake_tx,
+            intent: None,
+            draining: false,
+            events,
+            leader,
+            injector,
+        };
+        tasks::spawn("scaler", scaler.run());
 
-        ScalerHandle { sender }
+        let handle = ScalerHandle {
+            sender: Mailbox::new("scaler", sender),
+            awake,
+            target: target.to_string(),
+            activities: Default::default(),
+            batch: Default::default(),
+        };
+        tasks::spawn("liveness", recover_lost_backend(handle.clone(), endpoints));
+        handle
     }
 
-    pub fn scale_up(&self) -> Result<()> {
-        self.sender.try_send(ScalerMessage::ScaleUp)?;
-        Ok(())
+    /// Whether the backend was put to sleep last, or is being put to sleep right now.
+    pub fn is_sleeping(&self) -> bool {
+        !*self.awake.borrow()
+    }
+
+    /// Fix drift of the target's replicas from whether the backend is supposed to be awake.
+    pub async fn reconcile(&self) -> Result<()> {
+        self.sender.send(ScalerMessage::Reconcile).await
+    }
+
+    /// Scale the backend to `replicas` if it is awake.
+    pub async fn scale_awake(&self, replicas: i32) -> Result<()> {
+        self.sender.send(ScalerMessage::ScaleAwake(replicas)).await
+    }
+
+    /// Wait until the backend is serving. Returns whether this call had to wake it up. Fails
+    /// with `WakeBudgetExhausted` if its namespace may not wake it yet.
+    pub async fn ensure_up(&self, cause: WakeCause) -> Result<bool> {
+        self.ensure_up_batch(cause, 1).await
+    }
+
+    /// Like `ensure_up`, on behalf of `connections` waiting connections.
+    pub async fn ensure_up_batch(&self, cause: WakeCause, connections: usize) -> Result<bool> {
+        let (tx, rx) = oneshot::channel();
+        self.sender
+            .send(ScalerMessage::EnsureUp(
+                cause,
+                connections,
+                Span::current(),
+                tx,
+            ))
+            .await?;
+        rx.await?
+    }
+
+    /// Like `ensure_up`, but join the connections arriving within `WAKE_BATCH_WINDOW` into a
+    /// single wake, of which only the first is credited.
+    pub async fn join_wake(&self, cause: WakeCause) -> Result<bool> {
+        let (tx, rx) = oneshot::channel();
+        let first = {
+            let mut batch = self.batch.lock().unwrap_or_else(|e| e.into_inner());
+            let first = batch.is_none();
+            batch.get_or_insert_with(Vec::new).push(tx);
+            first
+        };
+        if first {
+            // outlives the first connection, which may give up before the others
+            let scaler = self.clone();
+            // traced as part of the first connection
+            let wake = async move {
+                tokio::time::sleep(WAKE_BATCH_WINDOW).await;
+                let waiters = scaler
+                    .batch
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .take()
+                    .unwrap_or_default();
+                let woke = scaler
+                    .ensure_up_batch(cause, waiters.len())
+                    .await
+                    .map_err(Arc::new);
+                for (i, waiter) in waiters.into_iter().enumerate() {
+                    let woke = match &woke {
+                        Ok(woke) => Ok(*woke && i == 0),
+                        Err(e) => Err(e.clone()),
+                    };
+                    waiter.send(woke).ok();
+                }
+            }
+            .instrument(Span::current());
+            tasks::spawn("wake batch", wake);
+        }
+        // a failed wake is not retried by each connection, which would spend the wake budget
+        // and run the hooks again
+        rx.await?.map_err(|e| batched_wake_error(&e))
     }
 
-    pub fn scale_down(&self) -> Result<()> {
-        self.sender.try_send(ScalerMessage::ScaleDown)?;
+    /// Stop waking and sleeping the backend, while connections to an awake backend are still
+    /// proxied. Wakes fail with `WakeSuppressed` and scale-downs are deferred from then on.
+    pub async fn drain(&self) -> Result<()> {
+        self.sender.send(ScalerMessage::Drain).await?;
         Ok(())
     }
 
-    pub async fn ensure_up(&self) -> Result<()> {
+    /// Check that scaling the target would be accepted, without changing it.
+    pub async fn dry_run(&self) -> Result<()> {
         let (tx, rx) = oneshot::channel();
-        self.sender.try_send(ScalerMessage::EnsureUp(tx))?;
-        rx.await?;
+        self.sender.send(ScalerMessage::DryRun(tx)).await?;
+        rx.await?
+    }
+
+    /// Wait until the backend is no longer serving. Fails with `ScaleDownDeferred` while the
+    /// target is mid-rollout or protected by a PodDisruptionBudget, or this replica does not lead.
+    pub async fn ensure_down(&self, cause: SleepCause) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.sender
+            .send(ScalerMessage::EnsureDown(cause, tx))
+            .await?;
+        if let Some(reason) = rx.await? {
+            return Err(ScaleDownDeferred {
+                target: self.target.clone(),
+                reason,
+            }
+            .into());
+        }
         Ok(())
     }
+
+    /// Scale the backend down whenever the idle timeout of `activity` expires.
+    ///
+    /// With several services scaling the same target, the target sleeps once all of them are idle.
+    pub fn scale_down_when_idle(
+        &self,
+        activity: &ActivityHandle,
+        endpoints: EndpointWatcherHandle,
+    ) {
+        let id = NEXT_ACTIVITY_ID.fetch_add(1, Ordering::Relaxed);
+        self.activities
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(id, activity.clone());
+        let registration = ActivityRegistration {
+            activities: self.activities.clone(),
+            id,
+        };
+        let future = scale_down_when_idle(self.clone(), id, activity.clone(), endpoints);
+        tasks::spawn("idle-scaler", async move {
+            let _registration = registration;
+            future.await;
+        });
+    }
+
+    /// Scale the awake backend between the bounds of `scaling` following the concurrent
+    /// connections of `activity`.
+    pub fn scale_with_concurrency(&self, activity: &ActivityHandle, scaling: ConcurrencyScaling) {
+        tasks::spawn(
+            "concurrency-scaler",
+            scale_with_concurrency(self.clone(), activity.clone(), scaling),
+        );
+    }
+
+    /// Whether the idle timeouts of all services scaling the target but the one of `id` expired.
+    fn others_idle(&self, id: u64) -> bool {
+        let now = Utc::now();
+        self.activities
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .filter(|(other, _)| **other != id)
+            .all(|(_, activity)| match activity.activity().planned_action() {
+                PlannedAction::ScaleDown { at } => at <= now,
+                _ => false,
+            })
+    }
+}
+
+/// Wake the backend again if it lost all serving endpoints while it was supposed to be awake,
+/// instead of waiting for the next client to discover the outage.
+async fn recover_lost_backend(scaler: ScalerHandle, endpoints: EndpointWatcherHandle) {
+    let target = &scaler.target;
+    let mut interval = tokio::time::interval(LIVENESS_INTERVAL);
+    let mut served = false;
+    let mut missed = 0;
+    loop {
+        interval.tick().await;
+        if !*scaler.awake.borrow() {
+            (served, missed) = (false, 0);
+            continue;
+        }
+        if endpoints.backend_is_serving() {
+            (served, missed) = (true, 0);
+            continue;
+        }
+        // a backend which is still waking up has not been lost
+        if !served {
+            continue;
+        }
+        // require two failed checks in a row to ride out brief rollouts
+        missed += 1;
+        if missed < 2 {
+            continue;
+        }
+        warn!("{target} lost all serving endpoints while awake, waking it again.");
+        if let Err(e) = scaler.ensure_up(WakeCause::BackendLost).await {
+            error!("Failed to wake {target} after losing its endpoints: {e}");
+        }
+        missed = 0;
+    }
+}
+
+async fn scale_down_when_idle(
+    scaler: ScalerHandle,
+    id: u64,
+    activity: ActivityHandle,
+    endpoints: EndpointWatcherHandle,
+) {
+    let target = &scaler.target;
+    let mut receiver = activity.subscribe();
+    loop {
+        let action = receiver.borrow_and_update().planned_action();
+        if let PlannedAction::ScaleDown { at } = action {
+            let idle_for = (at - Utc::now()).to_std().unwrap_or_default();
+            tokio::select! {
+                _ = tokio::time::sleep(idle_for) => {
+                    // the last service to become idle scales the shared target down
+                    if !scaler.others_idle(id) {
+                        debug!("{target} is still in use by another service.");
+                    } else if endpoints.backend_is_serving() {
+                        info!("{target} has been idle since {at}, scaling it down.");
+                        match scaler.ensure_down(SleepCause::IdleTimeout).await {
+                            Err(e) if e.is::<ScaleDownDeferred>() => {
+                                info!("{e}");
+                                // check again later, or once the activity changes
+                                tokio::select! {
+                                    _ = tokio::time::sleep(DEFER_INTERVAL) => continue,
+                                    _ = receiver.changed() => continue,
+                                }
+                            }
+                            Err(e) => error!("Failed to scale down idle {target}: {e}"),
+                            Ok(()) => {}
+                        }
+                    }
+                }
+                // new activity moves the deadline
+                _ = receiver.changed() => continue,
+            }
+        }
+        // wait for the next connection or wake before planning again
+        if receiver.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn scale_with_concurrency(
+    scaler: ScalerHandle,
+    activity: ActivityHandle,
+    scaling: ConcurrencyScaling,
+) {
+    let target = &scaler.target;
+    let mut receiver = activity.subscribe();
+    let mut awake = scaler.awake.clone();
+    // replicas last scaled to during the current awake period
+    let mut applied: Option<i32> = None;
+    let mut scale_in_at: Option<Instant> = None;
+    loop {
+        let activity = *receiver.borrow_and_update();
+        if !*awake.borrow_and_update() {
+            (applied, scale_in_at) = (None, None);
+        } else {
+            let connections = activity.active_connections + activity.remote_connections;
+            let desired = scaling.desired_replicas(connections);
+            let scale = match applied {
+                Some(applied) if desired == applied => {
+                    scale_in_at = None;
+                    false
+                }
+                // scale in only once the lower replicas sufficed for the whole delay
+                Some(applied) if desired < applied => {
+                    let at =
+                        *scale_in_at.get_or_insert_with(|| Instant::now() + scaling.scale_in_delay);
+                    at <= Instant::now()
+                }
+                _ => true,
+            };
+            if scale {
+                info!("Scaling {target} to {desired} replicas for {connections} connections.");
+                match scaler.scale_awake(desired).await {
+                    Ok(()) => (applied, scale_in_at) = (Some(desired), None),
+                    Err(e) => error!("Failed to scale {target} to {desired} replicas: {e}"),
+                }
+            }
+        }
+        let scale_in = async {
+            match scale_in_at {
+                Some(at) => tokio::time::sleep_until(at.into()).await,
+                None => futures::future::pending().await,
+            }
+        };
+        tokio::select! {
+            changed = receiver.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+            }
+            changed = awake.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+            }
+            _ = scale_in => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_target_kinds() {
+        let kind: TargetKind = "StatefulSet".parse().unwrap();
+        assert_eq!(
+            kind,
+            TargetKind {
+                kind: "statefulset".to_owned(),
+                group: None
+            }
+        );
+        let kind: TargetKind = "rollout.argoproj.io".parse().unwrap();
+        assert_eq!(kind.group.as_deref(), Some("argoproj.io"));
+        assert!(".apps".parse::<TargetKind>().is_err());
+    }
+
+    #[test]
+    fn recognizes_deployments() {
+        for kind in ["deployment", "Deployments", "deployment.apps"] {
+            assert!(
+                kind.parse::<TargetKind>().unwrap().is_deployment(),
+                "{kind}"
+            );
+        }
+        for kind in ["statefulset", "deployment.example.com"] {
+            assert!(
+                !kind.parse::<TargetKind>().unwrap().is_deployment(),
+                "{kind}"
+            );
+        }
+    }
+
+    #[test]
+    fn parses_target_refs() {
+        let target: TargetRef = "rollout.argoproj.io/web".parse().unwrap();
+        assert_eq!(target.kind, "rollout.argoproj.io".parse().unwrap());
+        assert_eq!(target.name, "web");
+        assert!("web".parse::<TargetRef>().is_err());
+    }
+
+    #[test]
+    fn scales_replicas_by_connections() {
+        let scaling = ConcurrencyScaling {
+            connections_per_replica: 10,
+            min_replicas: 1,
+            max_replicas: 5,
+            scale_in_delay: Duration::from_secs(60),
+        };
+        assert_eq!(scaling.desired_replicas(0), 1);
+        assert_eq!(scaling.desired_replicas(10), 1);
+        assert_eq!(scaling.desired_replicas(11), 2);
+        assert_eq!(scaling.desired_replicas(1000), 5);
+    }
+
+    #[test]
+    fn shares_batched_wake_errors() {
+        let suppressed = anyhow::Error::from(WakeSuppressed {
+            target: "deployment/web".to_owned(),
+        });
+        assert!(batched_wake_error(&suppressed).is::<WakeSuppressed>());
+        let failed = anyhow!("connection refused").context("Failed to scale deployment/web");
+        assert_eq!(
+            batched_wake_error(&failed).to_string(),
+            "Failed to scale deployment/web: connection refused"
+        );
+    }
 }
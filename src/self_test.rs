@@ -0,0 +1,103 @@
+use crate::activity::ActivityHandle;
+use crate::endpoint_watcher::EndpointWatcherHandle;
+use crate::scaler::{ScaleDownDeferred, ScalerHandle, SleepCause, WakeCause};
+use crate::tasks;
+
+use anyhow::{Context, Result};
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::*;
+
+#[derive(Clone, Debug, PartialEq)]
+enum SelfTestState {
+    Running,
+    Passed,
+    Failed(String),
+}
+
+/// Exercises the wake path once after startup: the EndpointSlice watch, permissions and
+/// patching the target's scale, and a wake and sleep of the backend if it is asleep.
+struct SelfTest {
+    scaler: ScalerHandle,
+    endpoints: EndpointWatcherHandle,
+    activity: ActivityHandle,
+}
+
+impl SelfTest {
+    /// Returns what was tested.
+    async fn test(mut self) -> Result<&'static str> {
+        self.endpoints.wait_synced().await?;
+        self.scaler
+            .dry_run()
+            .await
+            .context("Scaling the target was rejected")?;
+        // do not cycle a backend clients may be using
+        if self.endpoints.backend_is_serving() || self.activity.activity().active_connections > 0 {
+            return Ok("dry run, the backend is awake");
+        }
+        self.scaler
+            .ensure_up(WakeCause::SelfTest)
+            .await
+            .context("Waking the backend failed")?;
+        if self.activity.activity().active_connections > 0 {
+            return Ok("woke the backend, leaving it to the clients connected meanwhile");
+        }
+        match self.scaler.ensure_down(SleepCause::SelfTest).await {
+            Err(e) if e.is::<ScaleDownDeferred>() => {
+                Ok("woke the backend, putting it back to sleep was deferred")
+            }
+            Err(e) => Err(e.context("Putting the backend back to sleep failed")),
+            Ok(()) => Ok("woke the backend and put it back to sleep"),
+        }
+    }
+}
+
+/// Reports sero unready until the self-test passed.
+#[derive(Clone)]
+pub struct SelfTestHandle {
+    receiver: watch::Receiver<SelfTestState>,
+}
+
+impl SelfTestHandle {
+    pub fn new(
+        timeout: Duration,
+        scaler: ScalerHandle,
+        endpoints: EndpointWatcherHandle,
+        activity: ActivityHandle,
+    ) -> Self {
+        let (sender, receiver) = watch::channel(SelfTestState::Running);
+        let self_test = SelfTest {
+            scaler,
+            endpoints,
+            activity,
+        };
+        tasks::spawn("self-test", async move {
+            info!("Testing the wake path.");
+            let state = match tokio::time::timeout(timeout, self_test.test()).await {
+                Ok(Ok(tested)) => {
+                    info!("Self-test passed ({tested}).");
+                    SelfTestState::Passed
+                }
+                Ok(Err(e)) => {
+                    error!("Self-test failed: {e:#}");
+                    SelfTestState::Failed(format!("{e:#}"))
+                }
+                Err(_) => {
+                    error!("Self-test did not complete within {timeout:?}.");
+                    SelfTestState::Failed(format!("did not complete within {timeout:?}"))
+                }
+            };
+            sender.send_replace(state);
+        });
+        SelfTestHandle { receiver }
+    }
+
+    /// Why the self-test keeps sero from being ready, if it does.
+    pub fn readiness_blocker(&self) -> Option<String> {
+        match &*self.receiver.borrow() {
+            SelfTestState::Running => Some("self-test running".to_owned()),
+            SelfTestState::Passed => None,
+            SelfTestState::Failed(e) => Some(format!("self-test failed: {e}")),
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use crate::activity::ActivityHandle;
+use crate::api_metrics;
+use crate::cli::SidecarArgs;
+use crate::readiness_gate::pod_selector;
+use crate::scaler::Target;
+use crate::tasks;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::Pod;
+use kube::{
+    api::{Api, Patch, PatchParams},
+    core::params::ListParams,
+    runtime::{self, watcher::Event},
+    Client,
+};
+use serde_json::json;
+use std::{collections::HashMap, fs, sync::Arc};
+use tracing::*;
+
+/// Pod annotations written by the sidecar and read by the gateway.
+pub const ACTIVE_CONNECTIONS_ANNOTATION: &str = "sero.rs/active-connections";
+pub const LAST_ACTIVITY_ANNOTATION: &str = "sero.rs/last-activity";
+
+/// Run inside a backend pod and publish the activity measured there on the pod itself.
+///
+/// This also counts traffic which does not pass through the gateway (e.g. mesh-internal
+/// calls), so that it can veto scaling down the backend.
+pub async fn run(args: SidecarArgs) -> Result<()> {
+    let SidecarArgs {
+        pod,
+        ports,
+        interval,
+    } = args;
+    let pod = match pod {
+        Some(pod) => pod,
+        None => hostname::get()?
+            .into_string()
+            .ok()
+            .context("Hostname is not valid UTF8")?,
+    };
+    let client = api_metrics::try_client().await?;
+    let api: Api<Pod> = Api::default_namespaced(client);
+    info!("Publishing activity on ports {ports:?} to pod/{pod} every {interval:?}.");
+
+    let mut published = None;
+    let mut last_activity = Utc::now();
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let connections = established_connections(&ports);
+        if connections > 0 {
+            last_activity = Utc::now();
+        }
+        // an idle pod only needs to be annotated once
+        if published == Some(0) && connections == 0 {
+            continue;
+        }
+        let patch = json!({
+            "metadata": {
+                "annotations": {
+                    ACTIVE_CONNECTIONS_ANNOTATION: connections.to_string(),
+                    LAST_ACTIVITY_ANNOTATION: last_activity.to_rfc3339(),
+                },
+            },
+        });
+        match api
+            .patch(&pod, &PatchParams::default(), &Patch::Merge(patch))
+            .await
+        {
+            Ok(_) => published = Some(connections),
+            Err(e) => error!("Error while publishing activity to pod/{pod}: {e}"),
+        }
+    }
+}
+
+/// Count established TCP connections to any of the local `ports` in this network namespace.
+fn established_connections(ports: &[u16]) -> usize {
+    let mut count = 0;
+    for table in ["/proc/net/tcp", "/proc/net/tcp6"] {
+        let Ok(content) = fs::read_to_string(table) else {
+            continue;
+        };
+        // columns: sl local_address rem_address st ...
+        count += content
+            .lines()
+            .skip(1)
+            .filter_map(|line| {
+                let mut columns = line.split_whitespace().skip(1);
+                let local = columns.next()?;
+                let state = columns.nth(1)?;
+                let port = u16::from_str_radix(local.rsplit(':').next()?, 16).ok()?;
+                Some(state == "01" && ports.contains(&port))
+            })
+            .filter(|&established| established)
+            .count();
+    }
+    count
+}
+
+/// Feed the activity published by sidecars in backend pods into the gateway's activity.
+pub struct SidecarActivityHandle;
+
+impl SidecarActivityHandle {
+    pub fn new(target: Target, client: Arc<Client>, activity: ActivityHandle) -> Self {
+        tasks::spawn("sidecar-activity", watch_sidecars(target, client, activity));
+        SidecarActivityHandle
+    }
+}
+
+async fn watch_sidecars(target: Target, client: Arc<Client>, activity: ActivityHandle) {
+    let selector = match pod_selector(&target, &client).await {
+        Ok(selector) => selector,
+        Err(e) => {
+            error!("Error while getting pod selector of {target}: {e}");
+            return;
+        }
+    };
+    let api: Api<Pod> = Api::default_namespaced((*client).clone());
+    let mut events = runtime::watcher(api, ListParams::default().labels(&selector)).boxed();
+    info!("Watching sidecar activity of pods of {target}.");
+
+    let mut pods: HashMap<String, (usize, DateTime<Utc>)> = HashMap::new();
+    while let Some(event) = events.next().await {
+        match event {
+            Err(e) => error!("Error getting next event for Pods: {e}"),
+            Ok(Event::Applied(pod)) => {
+                let name = pod.metadata.name.clone().unwrap_or_default();
+                match sidecar_activity(&pod) {
+                    Some(pod_activity) => pods.insert(name, pod_activity),
+                    None => pods.remove(&name),
+                };
+            }
+            Ok(Event::Deleted(pod)) => {
+                pods.remove(&pod.metadata.name.unwrap_or_default());
+            }
+            Ok(Event::Restarted(restarted)) => {
+                pods = restarted
+                    .iter()
+                    .filter_map(|pod| {
+                        let name = pod.metadata.name.clone()?;
+                        Some((name, sidecar_activity(pod)?))
+                    })
+                    .collect();
+            }
+        }
+        let connections = pods.values().map(|(connections, _)| connections).sum();
+        let last_activity = pods.values().map(|(_, last_activity)| *last_activity).max();
+        activity.set_remote(connections, last_activity);
+    }
+}
+
+fn sidecar_activity(pod: &Pod) -> Option<(usize, DateTime<Utc>)> {
+    let annotations = pod.metadata.annotations.as_ref()?;
+    let connections = annotations
+        .get(ACTIVE_CONNECTIONS_ANNOTATION)?
+        .parse()
+        .ok()?;
+    let last_activity = DateTime::parse_from_rfc3339(annotations.get(LAST_ACTIVITY_ANNOTATION)?)
+        .ok()?
+        .with_timezone(&Utc);
+    Some((connections, last_activity))
+}
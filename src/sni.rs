@@ -0,0 +1,314 @@
+use crate::proxy::{ConnectionContext, ConnectionLimit, ACCEPT_ERROR_BACKOFF};
+use crate::tasks;
+
+use anyhow::{Context, Result};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::*;
+
+/// Largest TLS record, plus its header.
+const MAX_RECORD_SIZE: usize = 5 + (1 << 14);
+
+/// Give up on clients which do not send a complete ClientHello within this time.
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Routes TLS connections accepted on a shared listener to the service named by the SNI of
+/// their ClientHello, waking it like its own listener would. TLS is passed through untouched.
+#[derive(Clone)]
+pub struct SniRouter {
+    routes: Arc<Mutex<HashMap<String, ConnectionContext>>>,
+}
+
+impl SniRouter {
+    /// Connections count against `connection_limit`, if any, until they close.
+    pub async fn try_new(
+        addr: SocketAddr,
+        connection_limit: Option<ConnectionLimit>,
+    ) -> Result<Self> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Could not bind to {addr}"))?;
+        info!("Listening for TLS connections on {addr}, routing them by SNI.");
+        let router = SniRouter {
+            routes: Default::default(),
+        };
+        tasks::spawn("sni router", router.clone().run(listener, connection_limit));
+        Ok(router)
+    }
+
+    /// Route connections for `hosts` to the service of `ctx`.
+    pub fn insert(&self, hosts: &[String], ctx: &ConnectionContext) {
+        let mut routes = self.routes.lock().unwrap_or_else(|e| e.into_inner());
+        for host in hosts {
+            info!(
+                "Routing TLS connections for {host} to {}.",
+                ctx.backend_host
+            );
+            routes.insert(host.to_lowercase(), ctx.clone());
+        }
+    }
+
+    pub fn remove(&self, hosts: &[String]) {
+        let mut routes = self.routes.lock().unwrap_or_else(|e| e.into_inner());
+        for host in hosts {
+            routes.remove(&host.to_lowercase());
+        }
+    }
+
+    async fn run(self, listener: TcpListener, connection_limit: Option<ConnectionLimit>) {
+        loop {
+            let accepted = match &connection_limit {
+                Some(limit) => limit
+                    .accept(&listener, "sni")
+                    .await
+                    .map(|(ingress, client, permit)| (ingress, client, Some(permit))),
+                None => listener
+                    .accept()
+                    .await
+                    .map(|(ingress, client)| (ingress, client, None)),
+            };
+            let (ingress, client, permit) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Failed to accept a TLS connection: {e}");
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+            let route = self.clone().route(ingress, client);
+            tasks::spawn(format!("sni connection {client}"), async move {
+                let _permit = permit;
+                route.await
+            });
+        }
+    }
+
+    async fn route(self, ingress: TcpStream, client: SocketAddr) {
+        let host = match tokio::time::timeout(CLIENT_HELLO_TIMEOUT, peek_server_name(&ingress))
+            .await
+        {
+            Ok(Ok(Some(host))) => host,
+            Ok(Ok(None)) => {
+                debug!("Connection from {client} did not send a TLS ClientHello with SNI, dropping it.");
+                return;
+            }
+            Ok(Err(e)) => {
+                debug!("Could not read ClientHello of connection from {client}: {e}");
+                return;
+            }
+            Err(_) => {
+                debug!("Connection from {client} sent no ClientHello within {CLIENT_HELLO_TIMEOUT:?}, dropping it.");
+                return;
+            }
+        };
+        let ctx = self
+            .routes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&host)
+            .cloned();
+        match ctx {
+            Some(ctx) => ctx.handle(ingress, client, true).await,
+            None => {
+                debug!("No service is routed for SNI {host}, dropping connection from {client}.")
+            }
+        }
+    }
+}
+
+/// Server name of the ClientHello a client starts with, leaving it in the socket for the backend.
+async fn peek_server_name(stream: &TcpStream) -> Result<Option<String>> {
+    let mut buf = vec![0; MAX_RECORD_SIZE];
+    let mut peeked = 0;
+    loop {
+        let len = stream.peek(&mut buf).await?;
+        if len == 0 {
+            return Ok(None);
+        }
+        match parse_client_hello(&buf[..len]) {
+            ClientHello::Parsed(host) => return Ok(host),
+            ClientHello::Invalid => return Ok(None),
+            ClientHello::Incomplete if len == buf.len() => return Ok(None),
+            ClientHello::Incomplete => {
+                // peek returns at once while data is buffered, wait for more to arrive
+                if len == peeked {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                peeked = len;
+            }
+        }
+    }
+}
+
+enum ClientHello {
+    /// More data is needed
+    Incomplete,
+    /// Not a TLS handshake
+    Invalid,
+    Parsed(Option<String>),
+}
+
+fn parse_client_hello(data: &[u8]) -> ClientHello {
+    let mut header = Reader(data);
+    let (Some(content_type), Some(_version), Some(len)) = (header.u8(), header.u16(), header.u16())
+    else {
+        return ClientHello::Incomplete;
+    };
+    // handshake record
+    if content_type != 0x16 {
+        return ClientHello::Invalid;
+    }
+    let Some(record) = header.take(len as usize) else {
+        return ClientHello::Incomplete;
+    };
+    match server_name(record) {
+        Some(host) => ClientHello::Parsed(host),
+        None => ClientHello::Invalid,
+    }
+}
+
+/// SNI of the ClientHello in a handshake record, or `None` if it is malformed.
+fn server_name(record: &[u8]) -> Option<Option<String>> {
+    let mut hello = Reader(record);
+    // client hello
+    if hello.u8()? != 0x01 {
+        return None;
+    }
+    // length, version and random
+    hello.skip(3 + 2 + 32)?;
+    let session_id = hello.u8()?;
+    hello.skip(session_id as usize)?;
+    let cipher_suites = hello.u16()?;
+    hello.skip(cipher_suites as usize)?;
+    let compression_methods = hello.u8()?;
+    hello.skip(compression_methods as usize)?;
+    if hello.0.is_empty() {
+        return Some(None);
+    }
+    let len = hello.u16()?;
+    let mut extensions = Reader(hello.take(len as usize)?);
+    while !extensions.0.is_empty() {
+        let extension_type = extensions.u16()?;
+        let len = extensions.u16()?;
+        let mut extension = Reader(extensions.take(len as usize)?);
+        // server_name
+        if extension_type != 0x0000 {
+            continue;
+        }
+        let len = extension.u16()?;
+        let mut names = Reader(extension.take(len as usize)?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let len = names.u16()?;
+            let name = names.take(len as usize)?;
+            // host_name
+            if name_type == 0 {
+                return Some(std::str::from_utf8(name).ok().map(str::to_lowercase));
+            }
+        }
+    }
+    Some(None)
+}
+
+/// Reads big-endian fields off the front of a byte slice.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A TLS record carrying a ClientHello with the server name `host`.
+    fn client_hello(host: Option<&str>) -> Vec<u8> {
+        let mut extensions = Vec::new();
+        if let Some(host) = host {
+            let name_len = host.len() as u16;
+            extensions.extend([0x00, 0x00]);
+            extensions.extend((name_len + 5).to_be_bytes());
+            extensions.extend((name_len + 3).to_be_bytes());
+            extensions.push(0x00);
+            extensions.extend(name_len.to_be_bytes());
+            extensions.extend(host.as_bytes());
+        }
+        let mut hello = vec![0x03, 0x03];
+        hello.extend([0; 32]);
+        // session id, one cipher suite and the null compression method
+        hello.extend([0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+        hello.extend((extensions.len() as u16).to_be_bytes());
+        hello.extend(extensions);
+        let mut handshake = vec![0x01, 0x00];
+        handshake.extend((hello.len() as u16).to_be_bytes());
+        handshake.extend(hello);
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend((handshake.len() as u16).to_be_bytes());
+        record.extend(handshake);
+        record
+    }
+
+    fn parsed(data: &[u8]) -> Option<Option<String>> {
+        match parse_client_hello(data) {
+            ClientHello::Parsed(host) => Some(host),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn parses_the_server_name() {
+        assert_eq!(
+            parsed(&client_hello(Some("App.Example.com"))),
+            Some(Some("app.example.com".to_owned()))
+        );
+        assert_eq!(parsed(&client_hello(None)), Some(None));
+    }
+
+    #[test]
+    fn waits_for_the_complete_record() {
+        let hello = client_hello(Some("app.example.com"));
+        for len in [0, 3, hello.len() - 1] {
+            assert!(matches!(
+                parse_client_hello(&hello[..len]),
+                ClientHello::Incomplete
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_other_protocols() {
+        assert!(matches!(
+            parse_client_hello(b"GET / HTTP/1.1\r\n"),
+            ClientHello::Invalid
+        ));
+        let mut hello = client_hello(Some("app.example.com"));
+        // not a ClientHello
+        hello[5] = 0x02;
+        assert!(matches!(parse_client_hello(&hello), ClientHello::Invalid));
+    }
+}
@@ -0,0 +1,251 @@
+use crate::metrics;
+use crate::resolver::BackendResolver;
+use crate::scaler::{ScalerHandle, WakeCause};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use hyper::{client::HttpConnector, header, Body, Request};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::*;
+
+/// Distinguishes requests spilled within the same nanosecond.
+static NEXT_SPOOL_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Bounds for spilling requests to disk.
+#[derive(Clone, Copy, Debug)]
+pub struct SpoolLimits {
+    /// Most requests on disk at once
+    pub max_requests: usize,
+    /// Largest request body to spill
+    pub max_body_bytes: u64,
+}
+
+/// A request waiting for a wake, as written to disk.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SpooledRequest {
+    method: String,
+    path: String,
+    headers: Vec<(String, Vec<u8>)>,
+    body: Vec<u8>,
+    spooled_at: DateTime<Utc>,
+}
+
+/// Spills HTTP requests waiting for the backend to wake up to a local directory, so that
+/// those still waiting when sero exits are sent to the backend after it restarted.
+///
+/// Their clients are gone by then, so the responses to replayed requests are only logged.
+#[derive(Clone)]
+pub struct RequestSpool {
+    dir: PathBuf,
+    limits: SpoolLimits,
+    slots: Arc<Semaphore>,
+    /// Set on shutdown, from when on spilled requests are kept on disk
+    retain: Arc<AtomicBool>,
+}
+
+/// A request on disk, removed once it was answered or given up on before shutdown.
+pub struct SpoolEntry {
+    path: PathBuf,
+    retain: Arc<AtomicBool>,
+    _slot: OwnedSemaphorePermit,
+}
+
+impl Drop for SpoolEntry {
+    fn drop(&mut self) {
+        if !self.retain.load(Ordering::Relaxed) {
+            remove(&self.path);
+        }
+    }
+}
+
+impl RequestSpool {
+    pub fn try_new(dir: &Path, limits: SpoolLimits) -> Result<Self> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Could not create spool directory {}", dir.display()))?;
+        Ok(RequestSpool {
+            dir: dir.to_owned(),
+            limits,
+            slots: Arc::new(Semaphore::new(limits.max_requests)),
+            retain: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Keep the requests on disk which are still waiting, as sero is shutting down.
+    pub fn retain(&self) {
+        self.retain.store(true, Ordering::Relaxed);
+    }
+
+    /// Write `req` to disk if it is small enough and the spool is not full. Returns the request
+    /// to forward and the entry to drop once it was answered.
+    pub async fn spill(
+        &self,
+        req: Request<Body>,
+    ) -> hyper::Result<(Request<Body>, Option<SpoolEntry>)> {
+        if !self.fits(&req) {
+            return Ok((req, None));
+        }
+        let Ok(slot) = self.slots.clone().try_acquire_owned() else {
+            debug!(
+                "Request spool is full, not spilling {} {}.",
+                req.method(),
+                req.uri()
+            );
+            return Ok((req, None));
+        };
+        let (parts, body) = req.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+        let spooled = SpooledRequest {
+            method: parts.method.to_string(),
+            path: parts
+                .uri
+                .path_and_query()
+                .map_or("/", |path| path.as_str())
+                .to_owned(),
+            headers: parts
+                .headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.as_bytes().to_vec()))
+                .collect(),
+            body: body.to_vec(),
+            spooled_at: Utc::now(),
+        };
+        let req = Request::from_parts(parts, Body::from(body));
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let id = NEXT_SPOOL_ID.fetch_add(1, Ordering::Relaxed);
+        let path = self.dir.join(format!("{nanos}-{id}.json"));
+        match write_atomically(&path, &spooled).await {
+            Ok(()) => Ok((
+                req,
+                Some(SpoolEntry {
+                    path,
+                    retain: self.retain.clone(),
+                    _slot: slot,
+                }),
+            )),
+            Err(e) => {
+                warn!("Could not spill request to {}: {e}", path.display());
+                Ok((req, None))
+            }
+        }
+    }
+
+    /// Only requests whose body is known to be small enough are spilled.
+    fn fits(&self, req: &Request<Body>) -> bool {
+        let headers = req.headers();
+        match headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok()?.parse::<u64>().ok())
+        {
+            Some(len) => len <= self.limits.max_body_bytes,
+            None => !headers.contains_key(header::TRANSFER_ENCODING),
+        }
+    }
+
+    /// Wake the backend and send it the requests left on disk by a previous run of sero,
+    /// in the order they arrived.
+    pub async fn replay(
+        self,
+        scaler: ScalerHandle,
+        backend_host: String,
+        backend_port: u16,
+        resolver: BackendResolver,
+    ) {
+        let mut paths = match self.leftovers() {
+            Ok(paths) => paths,
+            Err(e) => {
+                error!("Could not list spooled requests: {e}");
+                return;
+            }
+        };
+        if paths.is_empty() {
+            return;
+        }
+        paths.sort();
+        info!(
+            "Replaying {} requests spooled before sero restarted.",
+            paths.len()
+        );
+        if let Err(e) = scaler.ensure_up(WakeCause::SpooledRequests).await {
+            error!("Could not wake the backend for spooled requests, keeping them: {e}");
+            return;
+        }
+        let connector = HttpConnector::new_with_resolver(resolver);
+        let backend = hyper::Client::builder().build::<_, Body>(connector);
+        let replays = metrics::SPOOL_REPLAYS.with_label_values(&[&backend_host]);
+        for path in paths {
+            let req = match read_request(&path, &backend_host, backend_port).await {
+                Ok(req) => req,
+                Err(e) => {
+                    warn!("Discarding spooled request {}: {e}", path.display());
+                    remove(&path);
+                    continue;
+                }
+            };
+            let method = req.method().clone();
+            let uri = req.uri().clone();
+            match backend.request(req).await {
+                Ok(res) => info!("Replayed spooled request {method} {uri}: {}", res.status()),
+                Err(e) => warn!("Spooled request {method} {uri} failed: {e}"),
+            }
+            replays.inc();
+            remove(&path);
+        }
+    }
+
+    fn leftovers(&self) -> Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(false, |ext| ext == "json") {
+                paths.push(path);
+            }
+        }
+        Ok(paths)
+    }
+}
+
+/// Write to a temporary file first, so that a crash never leaves a partial request behind.
+async fn write_atomically(path: &Path, spooled: &SpooledRequest) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, serde_json::to_vec(spooled)?).await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+async fn read_request(path: &Path, backend_host: &str, backend_port: u16) -> Result<Request<Body>> {
+    let spooled: SpooledRequest = serde_json::from_slice(&tokio::fs::read(path).await?)?;
+    debug!(
+        "Replaying {} {} spooled at {}.",
+        spooled.method, spooled.path, spooled.spooled_at
+    );
+    let mut req = Request::builder()
+        .method(spooled.method.as_str())
+        .uri(format!(
+            "http://{backend_host}:{backend_port}{}",
+            spooled.path
+        ));
+    for (name, value) in spooled.headers {
+        req = req.header(name, value);
+    }
+    Ok(req.body(Body::from(spooled.body))?)
+}
+
+fn remove(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        warn!("Could not remove spooled request {}: {e}", path.display());
+    }
+}
@@ -0,0 +1,300 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+
+/// Version of the status schema. Bump this on any breaking change to the types below.
+pub const SCHEMA_VERSION: &str = "sero.rs/v1";
+
+/// Everything sero knows about a single proxied service.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ServiceStatus {
+    pub schema_version: String,
+    pub service: String,
+    pub deployment: String,
+    /// Addresses sero accepts connections on, empty while still binding
+    #[serde(default)]
+    pub listen_addrs: Vec<SocketAddr>,
+    pub endpoints: EndpointStatus,
+    pub replicas: Option<i32>,
+    pub active_connections: usize,
+    #[serde(default)]
+    pub peak_connections: usize,
+    #[serde(default)]
+    pub idle_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub next_action: PlannedAction,
+    pub last_wake: Option<WakeRecord>,
+    #[serde(default)]
+    pub connections: Vec<ConnectionInfo>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct EndpointStatus {
+    pub sero: usize,
+    pub backend: usize,
+    /// Backend endpoints which changed between serving and not serving too often recently
+    #[serde(default)]
+    pub flapping: usize,
+}
+
+/// What sero will do next with the backend if nothing changes.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(tag = "action", rename_all = "camelCase")]
+pub enum PlannedAction {
+    /// The backend will be scaled down at the given time.
+    ScaleDown { at: DateTime<Utc> },
+    /// Scale-down is blocked by open client connections.
+    #[serde(rename_all = "camelCase")]
+    BlockedByConnections { active_connections: usize },
+    /// No automatic scale-down is configured.
+    #[default]
+    None,
+}
+
+/// A single wake cycle of the backend.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct WakeRecord {
+    pub started_at: DateTime<Utc>,
+    pub completed_at: Option<DateTime<Utc>>,
+    pub duration_ms: Option<u64>,
+}
+
+/// A wake of the backend, and when it went back to sleep.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct WakeCycle {
+    pub cause: String,
+    pub woke_at: DateTime<Utc>,
+    pub wake_duration_ms: u64,
+    pub slept_at: Option<DateTime<Utc>>,
+    /// Where the time of a cold start went, once known
+    #[serde(default)]
+    pub cold_start: Option<ColdStartBreakdown>,
+}
+
+/// Time of a wake per phase of starting the pod which served it first. Phases which could not
+/// be determined, e.g. because their events expired, are missing.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ColdStartBreakdown {
+    pub pod: String,
+    /// From the start of the wake until the pod was created
+    pub pod_creation_ms: Option<u64>,
+    /// From the creation of the pod until it was scheduled
+    pub scheduling_ms: Option<u64>,
+    /// Pulling images, zero if they were present on the node
+    pub image_pull_ms: Option<u64>,
+    /// From scheduling until the containers started, besides pulling images
+    pub container_start_ms: Option<u64>,
+    /// From the start of the containers until the pod was ready
+    pub app_boot_ms: Option<u64>,
+    /// From readiness until sero saw a serving endpoint
+    pub endpoint_propagation_ms: Option<u64>,
+}
+
+impl ColdStartBreakdown {
+    /// Phases by their metric label.
+    pub fn phases(&self) -> [(&'static str, Option<u64>); 6] {
+        [
+            ("pod_creation", self.pod_creation_ms),
+            ("scheduling", self.scheduling_ms),
+            ("image_pull", self.image_pull_ms),
+            ("container_start", self.container_start_ms),
+            ("app_boot", self.app_boot_ms),
+            ("endpoint_propagation", self.endpoint_propagation_ms),
+        ]
+    }
+}
+
+/// A single proxied connection.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub client: SocketAddr,
+    pub opened_at: DateTime<Utc>,
+    pub bytes_from_client: u64,
+    pub bytes_from_backend: u64,
+}
+
+impl ServiceStatus {
+    pub fn new(service: &str, deployment: &str) -> Self {
+        ServiceStatus {
+            schema_version: SCHEMA_VERSION.to_owned(),
+            service: service.to_owned(),
+            deployment: deployment.to_owned(),
+            listen_addrs: Vec::new(),
+            endpoints: EndpointStatus::default(),
+            replicas: None,
+            active_connections: 0,
+            peak_connections: 0,
+            idle_timeout_ms: None,
+            next_action: PlannedAction::None,
+            last_wake: None,
+            connections: Vec::new(),
+        }
+    }
+}
+
+/// Usage of a service during one hour, for chargeback.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageBucket {
+    pub hour: DateTime<Utc>,
+    pub awake_seconds: f64,
+    pub replica_seconds: f64,
+    pub connections: u64,
+    pub bytes_from_client: u64,
+    pub bytes_from_backend: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageReport {
+    pub schema_version: String,
+    pub service: String,
+    pub buckets: Vec<UsageBucket>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(secs, 0).unwrap()
+    }
+
+    fn status() -> ServiceStatus {
+        ServiceStatus {
+            listen_addrs: vec!["0.0.0.0:8080".parse().unwrap()],
+            endpoints: EndpointStatus {
+                sero: 1,
+                backend: 2,
+                flapping: 0,
+            },
+            replicas: Some(2),
+            active_connections: 1,
+            peak_connections: 3,
+            idle_timeout_ms: Some(60_000),
+            next_action: PlannedAction::BlockedByConnections {
+                active_connections: 1,
+            },
+            last_wake: Some(WakeRecord {
+                started_at: at(1_700_000_000),
+                completed_at: Some(at(1_700_000_002)),
+                duration_ms: Some(2_000),
+            }),
+            connections: vec![ConnectionInfo {
+                id: 7,
+                client: "10.0.0.1:51234".parse().unwrap(),
+                opened_at: at(1_700_000_010),
+                bytes_from_client: 120,
+                bytes_from_backend: 4_096,
+            }],
+            ..ServiceStatus::new("web", "web-deploy")
+        }
+    }
+
+    #[test]
+    fn service_status_round_trips() {
+        let status = status();
+        let json = serde_json::to_string(&status).unwrap();
+        assert_eq!(
+            serde_json::from_str::<ServiceStatus>(&json).unwrap(),
+            status
+        );
+    }
+
+    #[test]
+    fn service_status_snapshot() {
+        assert_eq!(
+            serde_json::to_value(status()).unwrap(),
+            json!({
+                "schemaVersion": "sero.rs/v1",
+                "service": "web",
+                "deployment": "web-deploy",
+                "listenAddrs": ["0.0.0.0:8080"],
+                "endpoints": {"sero": 1, "backend": 2, "flapping": 0},
+                "replicas": 2,
+                "activeConnections": 1,
+                "peakConnections": 3,
+                "idleTimeoutMs": 60000,
+                "nextAction": {"action": "blockedByConnections", "activeConnections": 1},
+                "lastWake": {
+                    "startedAt": "2023-11-14T22:13:20Z",
+                    "completedAt": "2023-11-14T22:13:22Z",
+                    "durationMs": 2000
+                },
+                "connections": [{
+                    "id": 7,
+                    "client": "10.0.0.1:51234",
+                    "openedAt": "2023-11-14T22:13:30Z",
+                    "bytesFromClient": 120,
+                    "bytesFromBackend": 4096
+                }]
+            })
+        );
+    }
+
+    #[test]
+    fn connection_info_round_trips() {
+        let connection = status().connections.remove(0);
+        let json = serde_json::to_string(&connection).unwrap();
+        assert_eq!(
+            serde_json::from_str::<ConnectionInfo>(&json).unwrap(),
+            connection
+        );
+    }
+
+    #[test]
+    fn unfinished_wake_record_round_trips() {
+        let wake = WakeRecord {
+            started_at: at(1_700_000_000),
+            completed_at: None,
+            duration_ms: None,
+        };
+        let json = serde_json::to_value(&wake).unwrap();
+        assert_eq!(
+            json,
+            json!({"startedAt": "2023-11-14T22:13:20Z", "completedAt": null, "durationMs": null})
+        );
+        assert_eq!(serde_json::from_value::<WakeRecord>(json).unwrap(), wake);
+    }
+
+    #[test]
+    fn endpoint_status_defaults_missing_flapping() {
+        let endpoints: EndpointStatus = serde_json::from_str(r#"{"sero":0,"backend":1}"#).unwrap();
+        assert_eq!(endpoints.flapping, 0);
+    }
+
+    #[test]
+    fn service_status_reads_earlier_v1_output() {
+        let status: ServiceStatus = serde_json::from_value(json!({
+            "schemaVersion": "sero.rs/v1",
+            "service": "web",
+            "deployment": "web-deploy",
+            "endpoints": {"sero": 0, "backend": 1},
+            "replicas": 1,
+            "activeConnections": 0,
+            "lastWake": null
+        }))
+        .unwrap();
+        assert_eq!(
+            status,
+            ServiceStatus {
+                endpoints: EndpointStatus {
+                    sero: 0,
+                    backend: 1,
+                    flapping: 0,
+                },
+                replicas: Some(1),
+                ..ServiceStatus::new("web", "web-deploy")
+            }
+        );
+    }
+}
@@ -1,6 +1,9 @@
-use anyhow::{Context, Result};
-use k8s_openapi::api::core::v1::Service;
-use kube::{api::Api, Client};
+use crate::injector::IpFamily;
+use crate::proxy::Protocol;
+
+use anyhow::{bail, Context, Result};
+use k8s_openapi::api::{apps::v1::Deployment, core::v1::Service};
+use kube::{api::Api, core::params::ListParams, Client};
 use std::sync::Arc;
 use tracing::*;
 
@@ -8,32 +11,160 @@ use tracing::*;
 pub struct ServicePortInfo {
     pub name: String,
     pub number: u16,
+    /// Transport protocol, `TCP`, `UDP` or `SCTP`
+    pub protocol: String,
+    pub app_protocol: Option<String>,
 }
 
 impl ServicePortInfo {
     pub async fn try_new(name: &str, port_name: Option<&str>, client: Arc<Client>) -> Result<Self> {
+        let ports = Self::list(name, client).await?;
+        let port = match port_name {
+            None => ports.into_iter().next(),
+            Some(port_name) => ports.into_iter().find(|port| port.name == port_name),
+        }
+        .context("Could not find secified port in service.")?;
+        debug!("Successfully got info about backend service/{name}: {port:?}");
+
+        Ok(port)
+    }
+
+    /// All ports of a service.
+    pub async fn list(name: &str, client: Arc<Client>) -> Result<Vec<Self>> {
         let svc: Api<Service> = Api::default_namespaced((*client).clone());
         let ports = svc
             .get(name)
             .await?
             .spec
             .and_then(|spec| spec.ports)
-            .context("Service does not have any ports.")?;
-        let port = match port_name {
-            None => ports.first(),
-            Some(port_name) => ports
-                .iter()
-                .find(|port| port.name == Some(port_name.to_owned())),
+            .context("Service does not have any ports.")?
+            .into_iter()
+            .map(|port| ServicePortInfo {
+                name: port.name.unwrap_or_default(),
+                number: port.port as u16,
+                protocol: port.protocol.unwrap_or_else(|| "TCP".to_owned()),
+                app_protocol: port.app_protocol,
+            })
+            .collect();
+        Ok(ports)
+    }
+
+    /// Ports of a service besides `primary`: all of them, or those named in `names`.
+    pub async fn others(
+        name: &str,
+        primary: &str,
+        names: Option<&[String]>,
+        client: Arc<Client>,
+    ) -> Result<Vec<Self>> {
+        let ports = Self::list(name, client).await?;
+        if let Some(missing) = names
+            .unwrap_or_default()
+            .iter()
+            .find(|name| !ports.iter().any(|port| &port.name == *name))
+        {
+            bail!("Service does not have a port named {missing}.");
         }
-        .context("Could not find secified port in service.")?
-        .clone();
+        Ok(ports
+            .into_iter()
+            .filter(|port| port.name != primary)
+            .filter(|port| names.map_or(true, |names| names.contains(&port.name)))
+            .collect())
+    }
+
+    pub fn is_tcp(&self) -> bool {
+        self.protocol == "TCP"
+    }
+
+    /// Check that this port of `service` can be proxied in `mode`, suggesting the right
+    /// configuration if it cannot, and warn if another mode would suit it better.
+    pub fn validate(&self, service: &str, mode: Protocol) -> Result<()> {
+        if !self.is_tcp() {
+            bail!(
+                "Port '{}' ({}) of service/{service} uses {}, but sero only proxies TCP, with --protocol tcp or http. \
+                Select a TCP port with --service-port, or leave this one out of --extra-ports.",
+                self.name,
+                self.number,
+                self.protocol
+            );
+        }
+        let app_protocol = self.app_protocol.as_deref().unwrap_or_default();
+        let is_http = matches!(app_protocol, "http" | "HTTP" | "kubernetes.io/h2c");
+        match mode {
+            Protocol::Tcp if is_http => warn!(
+                service,
+                port = %self.name,
+                app_protocol,
+                "Port declares an HTTP appProtocol, consider --protocol http to hold requests while the backend wakes up."
+            ),
+            Protocol::Http if !app_protocol.is_empty() && !is_http => warn!(
+                service,
+                port = %self.name,
+                app_protocol,
+                "Port declares a non-HTTP appProtocol, but is proxied with --protocol http; use --protocol tcp if clients fail."
+            ),
+            _ => {}
+        }
+        Ok(())
+    }
+}
 
-        let res = ServicePortInfo {
-            name: port.name.unwrap_or_default(),
-            number: port.port as u16,
-        };
-        debug!("Successfully got info about backend service/{name}: {res:?}");
+/// IP families of a service, primary first, as assigned by its `ipFamilyPolicy`. Empty if the
+/// cluster does not report them.
+pub async fn ip_families(name: &str, client: &Client) -> Result<Vec<IpFamily>> {
+    let svc: Api<Service> = Api::default_namespaced(client.clone());
+    let spec = svc.get(name).await?.spec.unwrap_or_default();
+    let families = spec
+        .ip_families
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|family| IpFamily::from_address_type(family))
+        .collect();
+    debug!(
+        "Service/{name} has the IP families {families:?} by policy {}.",
+        spec.ip_family_policy.as_deref().unwrap_or("SingleStack")
+    );
+    Ok(families)
+}
 
-        Ok(res)
+/// Name of the only Deployment whose pod template is selected by a service.
+pub async fn selected_deployment(
+    name: &str,
+    svc_client: &Client,
+    deploy_client: &Client,
+) -> Result<String> {
+    let svc: Api<Service> = Api::default_namespaced(svc_client.clone());
+    let selector = svc
+        .get(name)
+        .await?
+        .spec
+        .and_then(|spec| spec.selector)
+        .filter(|selector| !selector.is_empty())
+        .with_context(|| {
+            format!("Service/{name} has no selector to derive the deployment from.")
+        })?;
+    let deployments: Api<Deployment> = Api::default_namespaced(deploy_client.clone());
+    let matching: Vec<String> = deployments
+        .list(&ListParams::default())
+        .await?
+        .into_iter()
+        .filter(|deployment| {
+            let labels = deployment
+                .spec
+                .as_ref()
+                .and_then(|spec| spec.template.metadata.as_ref())
+                .and_then(|metadata| metadata.labels.as_ref());
+            selector
+                .iter()
+                .all(|(key, value)| labels.and_then(|labels| labels.get(key)) == Some(value))
+        })
+        .filter_map(|deployment| deployment.metadata.name)
+        .collect();
+    match &matching[..] {
+        [deployment] => Ok(deployment.clone()),
+        [] => bail!("No deployment matches the selector of service/{name}, pass --deployment."),
+        _ => bail!(
+            "Several deployments match the selector of service/{name} ({}), pass --deployment.",
+            matching.join(", ")
+        ),
     }
 }
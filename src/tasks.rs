@@ -0,0 +1,151 @@
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::task::JoinHandle;
+use tracing::*;
+
+static TASKS: Lazy<Mutex<BTreeMap<u64, TaskInfo>>> = Lazy::new(Default::default);
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+tokio::task_local! {
+    static SCOPE: TaskScope;
+}
+
+/// A task spawned with `spawn` which has not finished yet.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TaskInfo {
+    pub id: u64,
+    pub name: String,
+    pub spawned_at: DateTime<Utc>,
+}
+
+/// Removes a task from the registry once it finished, was cancelled or panicked.
+struct TaskGuard {
+    id: u64,
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        let task = TASKS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.id);
+        let Some(task) = task else {
+            return;
+        };
+        let lifetime = Utc::now() - task.spawned_at;
+        if std::thread::panicking() {
+            error!(
+                "Task '{}' ({}) panicked after {}ms.",
+                task.name,
+                task.id,
+                lifetime.num_milliseconds()
+            );
+        } else {
+            trace!(
+                "Task '{}' ({}) ended after {}ms.",
+                task.name,
+                task.id,
+                lifetime.num_milliseconds()
+            );
+        }
+    }
+}
+
+/// A group of tasks which are aborted together.
+///
+/// Tasks spawned from within a task of the scope belong to the scope as well.
+#[derive(Clone, Default)]
+pub struct TaskScope {
+    tasks: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl TaskScope {
+    pub fn new() -> Self {
+        TaskScope::default()
+    }
+
+    /// Spawn a named task in this scope.
+    pub fn spawn<F>(&self, name: impl Into<String>, future: F)
+    where
+        F: Future + Send + 'static,
+    {
+        let future = SCOPE.scope(self.clone(), async move {
+            future.await;
+        });
+        let handle = spawn_unscoped(name, future);
+        let mut tasks = self.tasks.lock().unwrap_or_else(|e| e.into_inner());
+        tasks.retain(|task| !task.is_finished());
+        tasks.push(handle);
+    }
+
+    /// Run `f` so that the tasks it spawns belong to this scope.
+    pub fn enter<R>(&self, f: impl FnOnce() -> R) -> R {
+        SCOPE.sync_scope(self.clone(), f)
+    }
+
+    /// Abort all tasks of this scope.
+    pub fn abort(&self) {
+        let tasks = std::mem::take(&mut *self.tasks.lock().unwrap_or_else(|e| e.into_inner()));
+        for task in tasks {
+            task.abort();
+        }
+    }
+}
+
+/// Spawn a named task which is tracked until it ends. Inside a `TaskScope` the task belongs
+/// to that scope.
+pub fn spawn<F>(name: impl Into<String>, future: F)
+where
+    F: Future + Send + 'static,
+{
+    match SCOPE.try_with(TaskScope::clone) {
+        Ok(scope) => scope.spawn(name, future),
+        Err(_) => {
+            spawn_unscoped(name, async move {
+                future.await;
+            });
+        }
+    }
+}
+
+fn spawn_unscoped<F>(name: impl Into<String>, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let task = TaskInfo {
+        id,
+        name: name.into(),
+        spawned_at: Utc::now(),
+    };
+    TASKS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(id, task);
+    let guard = TaskGuard { id };
+    tokio::spawn(async move {
+        let _guard = guard;
+        future.await
+    })
+}
+
+/// All running tasks, oldest first.
+pub fn running() -> Vec<TaskInfo> {
+    TASKS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .values()
+        .cloned()
+        .collect()
+}
@@ -0,0 +1,189 @@
+use crate::metrics;
+
+use anyhow::{bail, Result};
+use k8s_openapi::api::core::v1::Namespace;
+use kube::{
+    api::{Api, ListParams},
+    Client,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+const HOUR: Duration = Duration::from_secs(60 * 60);
+
+/// Restricts an operator to labelled namespaces and limits each of them, so that it can be
+/// offered to tenants without one of them exhausting the shared capacity.
+#[derive(Clone)]
+pub struct Guardrails {
+    /// Only manage services in namespaces matching this label selector
+    namespace_selector: Option<String>,
+    /// Managed services per namespace
+    max_services: Option<usize>,
+    /// Wakes per namespace within the last hour
+    max_wakes_per_hour: Option<usize>,
+    budgets: Arc<Mutex<HashMap<String, WakeBudget>>>,
+}
+
+impl Guardrails {
+    pub fn new(
+        namespace_selector: Option<String>,
+        max_services: Option<usize>,
+        max_wakes_per_hour: Option<usize>,
+    ) -> Self {
+        Guardrails {
+            namespace_selector,
+            max_services,
+            max_wakes_per_hour,
+            budgets: Default::default(),
+        }
+    }
+
+    /// Fail unless `namespace` matches the namespace selector.
+    pub async fn check_namespace(&self, namespace: &str, client: &Client) -> Result<()> {
+        let Some(selector) = &self.namespace_selector else {
+            return Ok(());
+        };
+        let namespaces: Api<Namespace> = Api::all(client.clone());
+        let params = ListParams::default()
+            .labels(selector)
+            .fields(&format!("metadata.name={namespace}"));
+        if namespaces.list(&params).await?.items.is_empty() {
+            bail!("namespace/{namespace} does not match the namespace selector {selector}");
+        }
+        Ok(())
+    }
+
+    /// Whether another service may be managed in a namespace already managing `managed`.
+    pub fn admits_service(&self, managed: usize) -> bool {
+        self.max_services.map_or(true, |max| managed < max)
+    }
+
+    /// The wake budget shared by the services of `namespace`, if wakes are limited.
+    pub fn wake_budget(&self, namespace: &str) -> Option<WakeBudget> {
+        let max_wakes = self.max_wakes_per_hour?;
+        let mut budgets = self.budgets.lock().unwrap_or_else(|e| e.into_inner());
+        let budget = budgets
+            .entry(namespace.to_owned())
+            .or_insert_with(|| WakeBudget {
+                namespace: namespace.to_owned(),
+                max_wakes,
+                wakes: Default::default(),
+            });
+        Some(budget.clone())
+    }
+}
+
+/// Wakes of a namespace within a sliding hour.
+#[derive(Clone)]
+pub struct WakeBudget {
+    namespace: String,
+    max_wakes: usize,
+    wakes: Arc<Mutex<VecDeque<Instant>>>,
+}
+
+impl WakeBudget {
+    /// Account a wake, failing with `WakeBudgetExhausted` if the namespace already used up its
+    /// wakes of the last hour.
+    pub fn spend(&self) -> Result<()> {
+        let mut wakes = self.wakes.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        while wakes
+            .front()
+            .map_or(false, |woke| now.duration_since(*woke) >= HOUR)
+        {
+            wakes.pop_front();
+        }
+        if wakes.len() >= self.max_wakes {
+            metrics::DENIED_WAKES
+                .with_label_values(&[&self.namespace])
+                .inc();
+            // the oldest wake leaves the window first
+            let retry_after = wakes
+                .front()
+                .map_or(HOUR, |woke| HOUR.saturating_sub(now.duration_since(*woke)));
+            return Err(WakeBudgetExhausted {
+                namespace: self.namespace.clone(),
+                max_wakes: self.max_wakes,
+                retry_after,
+            }
+            .into());
+        }
+        wakes.push_back(now);
+        Ok(())
+    }
+}
+
+/// Returned by `WakeBudget::spend` if the namespace may not wake another service yet.
+#[derive(Clone, Debug)]
+pub struct WakeBudgetExhausted {
+    namespace: String,
+    max_wakes: usize,
+    /// Until the next wake is possible
+    pub retry_after: Duration,
+}
+
+impl fmt::Display for WakeBudgetExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "namespace/{} already used its {} wakes of the last hour",
+            self.namespace, self.max_wakes
+        )
+    }
+}
+
+impl std::error::Error for WakeBudgetExhausted {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spends_wakes_until_the_budget_is_exhausted() {
+        let guardrails = Guardrails::new(None, None, Some(2));
+        let budget = guardrails.wake_budget("team").unwrap();
+        budget.spend().unwrap();
+        budget.spend().unwrap();
+        let e = budget.spend().unwrap_err();
+        let exhausted = e.downcast_ref::<WakeBudgetExhausted>().unwrap();
+        assert!(exhausted.retry_after <= HOUR);
+        assert!(exhausted.retry_after > HOUR - Duration::from_secs(60));
+        assert_eq!(
+            e.to_string(),
+            "namespace/team already used its 2 wakes of the last hour"
+        );
+    }
+
+    #[test]
+    fn shares_budgets_within_a_namespace() {
+        let guardrails = Guardrails::new(None, None, Some(1));
+        guardrails.wake_budget("team").unwrap().spend().unwrap();
+        assert!(guardrails.wake_budget("team").unwrap().spend().is_err());
+        assert!(guardrails.wake_budget("other").unwrap().spend().is_ok());
+    }
+
+    #[test]
+    fn forgets_wakes_older_than_an_hour() {
+        let budget = Guardrails::new(None, None, Some(1))
+            .wake_budget("team")
+            .unwrap();
+        let Some(long_ago) = Instant::now().checked_sub(HOUR) else {
+            return;
+        };
+        budget.wakes.lock().unwrap().push_back(long_ago);
+        budget.spend().unwrap();
+        assert_eq!(budget.wakes.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn unlimited_guardrails() {
+        let guardrails = Guardrails::new(None, None, None);
+        assert!(guardrails.wake_budget("team").is_none());
+        assert!(guardrails.admits_service(1000));
+        assert!(!Guardrails::new(None, Some(2), None).admits_service(2));
+    }
+}
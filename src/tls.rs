@@ -0,0 +1,129 @@
+use crate::tasks;
+
+use anyhow::{bail, Context, Result};
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::Secret;
+use kube::{
+    api::Api,
+    core::params::ListParams,
+    runtime::{self, WatchStreamExt},
+    Client,
+};
+use std::{sync::Arc, time::Duration};
+use tokio::{net::TcpStream, sync::watch};
+use tokio_rustls::{
+    rustls::{Certificate, PrivateKey, ServerConfig},
+    server::TlsStream,
+    TlsAcceptor,
+};
+use tracing::*;
+
+/// Give up on clients which do not complete the TLS handshake within this time, including the
+/// wait for a certificate.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Keeps the acceptor up to date with the certificate of a `kubernetes.io/tls` Secret.
+struct CertificateWatcher {
+    secret: String,
+    client: Arc<Client>,
+    sender: watch::Sender<Option<TlsAcceptor>>,
+}
+
+impl CertificateWatcher {
+    async fn run(self) {
+        let api: Api<Secret> = Api::default_namespaced((*self.client).clone());
+        let params = ListParams::default().fields(&format!("metadata.name={}", self.secret));
+        let mut events = runtime::watcher(api, params).applied_objects().boxed();
+        info!(
+            "Terminating TLS with the certificate of secret/{}.",
+            self.secret
+        );
+
+        while let Some(event) = events.next().await {
+            match event {
+                Err(e) => error!("Error getting next event for secret/{}: {e}", self.secret),
+                Ok(secret) => match acceptor_from_secret(&secret) {
+                    Ok(acceptor) => {
+                        info!("Loaded TLS certificate from secret/{}.", self.secret);
+                        self.sender.send_replace(Some(acceptor));
+                    }
+                    // keep serving the previous certificate rather than none at all
+                    Err(e) => error!(
+                        "Could not load TLS certificate from secret/{}, keeping the previous one: {e}",
+                        self.secret
+                    ),
+                },
+            }
+        }
+    }
+}
+
+/// Build an acceptor from the PEM encoded `tls.crt` and `tls.key` of a Secret.
+fn acceptor_from_secret(secret: &Secret) -> Result<TlsAcceptor> {
+    let data = secret.data.as_ref().context("Secret has no data.")?;
+    let cert = data.get("tls.crt").context("Secret has no tls.crt.")?;
+    let key = data.get("tls.key").context("Secret has no tls.key.")?;
+
+    let certs: Vec<_> = rustls_pemfile::certs(&mut cert.0.as_slice())?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        bail!("tls.crt contains no certificates.");
+    }
+    let key = rustls_pemfile::read_all(&mut key.0.as_slice())?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .context("tls.key contains no private key.")?;
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid certificate or key")?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Terminates TLS of client connections, with the certificate reloaded whenever the Secret
+/// is rotated.
+#[derive(Clone)]
+pub struct TlsTerminatorHandle {
+    acceptor: watch::Receiver<Option<TlsAcceptor>>,
+}
+
+impl TlsTerminatorHandle {
+    pub fn new(secret: &str, client: Arc<Client>) -> Self {
+        let (sender, acceptor) = watch::channel(None);
+        let watcher = CertificateWatcher {
+            secret: secret.to_owned(),
+            client,
+            sender,
+        };
+        tasks::spawn("tls certificate", watcher.run());
+        Self { acceptor }
+    }
+
+    /// Perform the TLS handshake with a client, waiting for a certificate if none is loaded yet.
+    pub async fn accept(&self, stream: TcpStream) -> Result<TlsStream<TcpStream>> {
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, self.handshake(stream))
+            .await
+            .with_context(|| format!("No TLS handshake within {HANDSHAKE_TIMEOUT:?}"))?
+    }
+
+    async fn handshake(&self, stream: TcpStream) -> Result<TlsStream<TcpStream>> {
+        let mut receiver = self.acceptor.clone();
+        let acceptor = loop {
+            let current = receiver.borrow().clone();
+            if let Some(acceptor) = current {
+                break acceptor;
+            }
+            receiver.changed().await?;
+        };
+        Ok(acceptor.accept(stream).await?)
+    }
+}
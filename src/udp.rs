@@ -0,0 +1,177 @@
+use crate::proxy::{ConnectionContext, ConnectionLimit};
+use crate::scaler::WakeCause;
+use crate::tasks;
+
+use anyhow::{Context, Result};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, OwnedSemaphorePermit},
+};
+use tracing::*;
+
+/// Largest datagram forwarded.
+const MAX_DATAGRAM_SIZE: usize = 64 * 1024;
+
+/// Datagrams of a flow held while the backend wakes up, later ones are dropped.
+const FLOW_BUFFER: usize = 64;
+
+/// Forwards UDP datagrams to the backend, waking it on the first datagram of each flow.
+///
+/// A flow is identified by the client address and counts as a connection until no datagram
+/// passed in either direction for the flow timeout.
+pub struct UdpProxy {
+    socket: Arc<UdpSocket>,
+    ctx: ConnectionContext,
+    flow_timeout: Duration,
+    flows: Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>>,
+    connection_limit: Option<ConnectionLimit>,
+}
+
+impl UdpProxy {
+    pub async fn try_new(
+        addr: SocketAddr,
+        flow_timeout: Duration,
+        ctx: ConnectionContext,
+    ) -> Result<Self> {
+        let socket = UdpSocket::bind(addr)
+            .await
+            .with_context(|| format!("Could not bind to {addr}"))?;
+        info!(
+            "Listening for UDP datagrams on {}, forwarding them to {}:{}.",
+            socket.local_addr()?,
+            ctx.backend_host,
+            ctx.backend_port
+        );
+        Ok(UdpProxy {
+            socket: Arc::new(socket),
+            ctx,
+            flow_timeout,
+            flows: Default::default(),
+            connection_limit: None,
+        })
+    }
+
+    /// Count flows against the limit shared with the TCP listeners, dropping new flows beyond it.
+    pub fn with_connection_limit(mut self, connection_limit: Option<ConnectionLimit>) -> Self {
+        self.connection_limit = connection_limit;
+        self
+    }
+
+    pub async fn run(self) {
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        loop {
+            let (len, client) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    error!("Error while receiving UDP datagram: {e}");
+                    continue;
+                }
+            };
+            let datagram = buf[..len].to_vec();
+            let mut flows = self.flows.lock().unwrap_or_else(|e| e.into_inner());
+            let sender = match flows.get(&client) {
+                Some(sender) if !sender.is_closed() => sender.clone(),
+                _ => {
+                    let permit = match &self.connection_limit {
+                        Some(limit) => match limit.try_acquire(&self.ctx.backend_host, client) {
+                            Some(permit) => Some(permit),
+                            None => continue,
+                        },
+                        None => None,
+                    };
+                    let (sender, receiver) = mpsc::channel(FLOW_BUFFER);
+                    flows.insert(client, sender.clone());
+                    let flow = Flow {
+                        client,
+                        socket: self.socket.clone(),
+                        ctx: self.ctx.clone(),
+                        timeout: self.flow_timeout,
+                        flows: self.flows.clone(),
+                        _permit: permit,
+                    };
+                    tasks::spawn(format!("udp flow {client}"), flow.run(receiver));
+                    sender
+                }
+            };
+            drop(flows);
+            if sender.try_send(datagram).is_err() {
+                debug!("Dropping UDP datagram from {client}, the flow is not keeping up.");
+            }
+        }
+    }
+}
+
+/// Datagrams exchanged between a client and the backend.
+struct Flow {
+    client: SocketAddr,
+    /// The listening socket, to answer the client from the address it sent to
+    socket: Arc<UdpSocket>,
+    ctx: ConnectionContext,
+    timeout: Duration,
+    flows: Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>>,
+    /// Counts the flow against `--max-connections` until it expires
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Flow {
+    async fn run(self, receiver: mpsc::Receiver<Vec<u8>>) {
+        let _connection = self.ctx.activity.connection_opened();
+        if let Err(e) = self.forward(receiver).await {
+            error!("Error while forwarding UDP flow of {}: {e}", self.client);
+        }
+        // a new flow of the same client may have replaced this one already
+        let mut flows = self.flows.lock().unwrap_or_else(|e| e.into_inner());
+        if flows
+            .get(&self.client)
+            .map_or(false, mpsc::Sender::is_closed)
+        {
+            flows.remove(&self.client);
+        }
+    }
+
+    async fn forward(&self, mut receiver: mpsc::Receiver<Vec<u8>>) -> Result<()> {
+        // datagrams queue up in the receiver until the backend is up
+        self.ctx
+            .scaler
+            .ensure_up(WakeCause::ClientConnection)
+            .await?;
+        let backend = self
+            .ctx
+            .resolver
+            .lookup(&self.ctx.backend_host, self.ctx.backend_port)
+            .await?[0];
+        let local: SocketAddr = match backend {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let egress = UdpSocket::bind(local).await?;
+        egress.connect(backend).await?;
+        trace!("Forwarding UDP flow of {} to {backend}.", self.client);
+
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        loop {
+            tokio::select! {
+                datagram = receiver.recv() => {
+                    let Some(datagram) = datagram else {
+                        return Ok(());
+                    };
+                    egress.send(&datagram).await?;
+                }
+                received = egress.recv(&mut buf) => {
+                    let len = received?;
+                    self.socket.send_to(&buf[..len], self.client).await?;
+                }
+                _ = tokio::time::sleep(self.timeout) => {
+                    trace!("UDP flow of {} expired.", self.client);
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use crate::endpoint_watcher::EndpointWatcherHandle;
+use crate::mailbox::Mailbox;
+use crate::status::{UsageBucket, UsageReport, SCHEMA_VERSION};
+use crate::tasks;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration as ChronoDuration, DurationRound, Utc};
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::{
+    api::{Api, Patch, PatchParams},
+    Client,
+};
+use serde_json::json;
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::{mpsc, oneshot};
+use tracing::*;
+
+const ACCOUNT_INTERVAL: Duration = Duration::from_secs(10);
+const PERSIST_INTERVAL: Duration = Duration::from_secs(60);
+/// Keep about a month of hourly buckets.
+const MAX_BUCKETS: usize = 31 * 24;
+const CONFIGMAP_KEY: &str = "usage.json";
+
+struct Usage {
+    receiver: mpsc::Receiver<UsageMessage>,
+    svc_name: String,
+    configmap: Option<String>,
+    client: Arc<Client>,
+    endpoints: EndpointWatcherHandle,
+    buckets: BTreeMap<DateTime<Utc>, UsageBucket>,
+    serving_backends: usize,
+    last_account: Instant,
+}
+
+impl Usage {
+    fn bucket(&mut self) -> &mut UsageBucket {
+        let hour = Utc::now()
+            .duration_trunc(ChronoDuration::hours(1))
+            .unwrap_or_else(|_| Utc::now());
+        self.buckets.entry(hour).or_insert_with(|| UsageBucket {
+            hour,
+            awake_seconds: 0.0,
+            replica_seconds: 0.0,
+            connections: 0,
+            bytes_from_client: 0,
+            bytes_from_backend: 0,
+        })
+    }
+
+    /// Add the time since the last call to the current bucket, weighted by serving backends.
+    fn account(&mut self) {
+        let elapsed = self.last_account.elapsed().as_secs_f64();
+        let serving_backends = self.serving_backends;
+        let bucket = self.bucket();
+        if serving_backends > 0 {
+            bucket.awake_seconds += elapsed;
+            bucket.replica_seconds += elapsed * serving_backends as f64;
+        }
+        self.last_account = Instant::now();
+        self.serving_backends = self.endpoints.serving_backends();
+
+        while self.buckets.len() > MAX_BUCKETS {
+            let oldest = *self.buckets.keys().next().unwrap();
+            self.buckets.remove(&oldest);
+        }
+    }
+
+    async fn load(&mut self) -> Result<()> {
+        let Some(name) = &self.configmap else {
+            return Ok(());
+        };
+        let api: Api<ConfigMap> = Api::default_namespaced((*self.client).clone());
+        let Some(configmap) = api.get_opt(name).await? else {
+            return Ok(());
+        };
+        let data = configmap
+            .data
+            .and_then(|mut data| data.remove(CONFIGMAP_KEY))
+            .with_context(|| format!("configmap/{name} has no key {CONFIGMAP_KEY}."))?;
+        let buckets: Vec<UsageBucket> = serde_json::from_str(&data)?;
+        info!(
+            "Loaded {} usage buckets from configmap/{name}.",
+            buckets.len()
+        );
+        self.buckets = buckets
+            .into_iter()
+            .map(|bucket| (bucket.hour, bucket))
+            .collect();
+        Ok(())
+    }
+
+    async fn persist(&self) -> Result<()> {
+        let Some(name) = &self.configmap else {
+            return Ok(());
+        };
+        let api: Api<ConfigMap> = Api::default_namespaced((*self.client).clone());
+        let buckets: Vec<&UsageBucket> = self.buckets.values().collect();
+        let patch = json!({
+            "apiVersion": "v1",
+            "kind": "ConfigMap",
+            "metadata": {
+                "name": name,
+                "labels": { "sero.rs/service-name": self.svc_name },
+            },
+            "data": { CONFIGMAP_KEY: serde_json::to_string(&buckets)? },
+        });
+        let params = PatchParams::apply("usage.sero.rs").force();
+        api.patch(name, &params, &Patch::Apply(&patch)).await?;
+        debug!(
+            "Persisted {} usage buckets to configmap/{name}.",
+            buckets.len()
+        );
+        Ok(())
+    }
+
+    fn handle_message(&mut self, msg: UsageMessage) {
+        use UsageMessage::*;
+        match msg {
+            Record {
+                bytes_from_client,
+                bytes_from_backend,
+            } => {
+                let bucket = self.bucket();
+                bucket.connections += 1;
+                bucket.bytes_from_client += bytes_from_client;
+                bucket.bytes_from_backend += bytes_from_backend;
+            }
+            Report(sender) => {
+                self.account();
+                let report = UsageReport {
+                    schema_version: SCHEMA_VERSION.to_owned(),
+                    service: self.svc_name.clone(),
+                    buckets: self.buckets.values().cloned().collect(),
+                };
+                if sender.send(report).is_err() {
+                    warn!("Could not answer to Report message because sender end was dropped.");
+                }
+            }
+        }
+    }
+
+    async fn run(mut self) {
+        if let Err(e) = self.load().await {
+            error!("Error while loading persisted usage: {e}");
+        }
+        let mut interval = tokio::time::interval(ACCOUNT_INTERVAL);
+        let mut last_persist = Instant::now();
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {},
+                _ = self.endpoints.changed() => {},
+                msg = self.receiver.recv() => match msg {
+                    Some(msg) => self.handle_message(msg),
+                    None => return,
+                },
+            }
+            self.account();
+            if last_persist.elapsed() >= PERSIST_INTERVAL {
+                if let Err(e) = self.persist().await {
+                    error!("Error while persisting usage: {e}");
+                }
+                last_persist = Instant::now();
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+enum UsageMessage {
+    Record {
+        bytes_from_client: u64,
+        bytes_from_backend: u64,
+    },
+    Report(oneshot::Sender<UsageReport>),
+}
+
+#[derive(Clone)]
+pub struct UsageHandle {
+    sender: Mailbox<UsageMessage>,
+}
+
+impl UsageHandle {
+    pub fn new(
+        max_concurrency: usize,
+        svc_name: &str,
+        configmap: Option<&str>,
+        client: Arc<Client>,
+        endpoints: EndpointWatcherHandle,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel(max_concurrency);
+        let usage = Usage {
+            receiver,
+            svc_name: svc_name.to_owned(),
+            configmap: configmap.map(str::to_owned),
+            client,
+            endpoints,
+            buckets: BTreeMap::new(),
+            serving_backends: 0,
+            last_account: Instant::now(),
+        };
+        tasks::spawn("usage", usage.run());
+        UsageHandle {
+            sender: Mailbox::new("usage", sender),
+        }
+    }
+
+    pub async fn record(&self, bytes_from_client: u64, bytes_from_backend: u64) -> Result<()> {
+        self.sender
+            .send(UsageMessage::Record {
+                bytes_from_client,
+                bytes_from_backend,
+            })
+            .await
+    }
+
+    pub async fn report(&self) -> Result<UsageReport> {
+        let (tx, rx) = oneshot::channel();
+        self.sender.send(UsageMessage::Report(tx)).await?;
+        Ok(rx.await?)
+    }
+}
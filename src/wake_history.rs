@@ -0,0 +1,92 @@
+use crate::cold_start::ColdStartTracker;
+use crate::events::{SeroEvent, SeroEvents};
+use crate::status::WakeCycle;
+use crate::tasks;
+
+use futures::StreamExt;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+use tracing::*;
+
+/// Wake cycles kept, older ones are dropped.
+const MAX_CYCLES: usize = 50;
+
+/// Recent wake cycles of the backend, recorded from sero's events.
+#[derive(Clone)]
+pub struct WakeHistoryHandle {
+    cycles: Arc<Mutex<VecDeque<WakeCycle>>>,
+}
+
+impl WakeHistoryHandle {
+    /// With `cold_starts`, completed wakes are broken down into the phases of starting a pod.
+    pub fn new(events: &SeroEvents, cold_starts: Option<ColdStartTracker>) -> Self {
+        let cycles: Arc<Mutex<VecDeque<WakeCycle>>> = Default::default();
+        let mut events = events.subscribe().boxed();
+        let recorded = cycles.clone();
+        tasks::spawn("wake-history", async move {
+            while let Some(event) = events.next().await {
+                let mut cycles = recorded.lock().unwrap_or_else(|e| e.into_inner());
+                match event {
+                    SeroEvent::WakeCompleted {
+                        cause,
+                        timestamp,
+                        duration_ms,
+                        ..
+                    } => {
+                        if cycles.len() == MAX_CYCLES {
+                            cycles.pop_front();
+                        }
+                        cycles.push_back(WakeCycle {
+                            cause: cause.to_string(),
+                            woke_at: timestamp,
+                            wake_duration_ms: duration_ms,
+                            slept_at: None,
+                            cold_start: None,
+                        });
+                        if let Some(tracker) = cold_starts.clone() {
+                            let started =
+                                timestamp - chrono::Duration::milliseconds(duration_ms as i64);
+                            let recorded = recorded.clone();
+                            tasks::spawn("cold-start", async move {
+                                let breakdown = match tracker.breakdown(started, timestamp).await {
+                                    Ok(Some(breakdown)) => breakdown,
+                                    Ok(None) => return,
+                                    Err(e) => {
+                                        warn!("Could not break down the cold start: {e}");
+                                        return;
+                                    }
+                                };
+                                info!("Cold start breakdown: {breakdown:?}");
+                                let mut cycles = recorded.lock().unwrap_or_else(|e| e.into_inner());
+                                if let Some(cycle) =
+                                    cycles.iter_mut().rev().find(|c| c.woke_at == timestamp)
+                                {
+                                    cycle.cold_start = Some(breakdown);
+                                }
+                            });
+                        }
+                    }
+                    SeroEvent::SleepCompleted { timestamp, .. } => {
+                        if let Some(cycle) = cycles.back_mut().filter(|c| c.slept_at.is_none()) {
+                            cycle.slept_at = Some(timestamp);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        });
+        WakeHistoryHandle { cycles }
+    }
+
+    /// Recorded wake cycles, oldest first.
+    pub fn cycles(&self) -> Vec<WakeCycle> {
+        self.cycles
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .cloned()
+            .collect()
+    }
+}
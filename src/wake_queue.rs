@@ -0,0 +1,231 @@
+use crate::metrics;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use tokio::sync::oneshot;
+use tracing::*;
+
+/// Bounds the number of concurrent wakes across services and hands out free slots by weighted
+/// fair queuing, so that a flood of wakes for one tenant cannot starve the others.
+#[derive(Clone)]
+pub struct WakeQueue {
+    state: Arc<Mutex<QueueState>>,
+}
+
+struct QueueState {
+    available: usize,
+    /// Start tag of the wake granted last
+    virtual_time: f64,
+    tenants: HashMap<String, Tenant>,
+}
+
+#[derive(Default)]
+struct Tenant {
+    /// Finish tag of the tenant's last granted wake
+    finish: f64,
+    waiting: VecDeque<(f64, oneshot::Sender<WakePermit>)>,
+}
+
+impl QueueState {
+    /// Account a granted wake to `tenant`.
+    fn charge(&mut self, tenant: &str, weight: f64) {
+        let virtual_time = self.virtual_time;
+        let tenant = self.tenants.entry(tenant.to_owned()).or_default();
+        let start = tenant.finish.max(virtual_time);
+        tenant.finish = start + 1.0 / weight;
+        self.virtual_time = start;
+    }
+
+    /// The waiting tenant with the smallest finish tag, which is served next.
+    fn next_tenant(&self) -> Option<String> {
+        self.tenants
+            .iter()
+            .filter(|(_, tenant)| !tenant.waiting.is_empty())
+            .min_by(|(_, a), (_, b)| a.finish.total_cmp(&b.finish))
+            .map(|(name, _)| name.clone())
+    }
+}
+
+impl WakeQueue {
+    pub fn new(max_concurrent_wakes: usize) -> Self {
+        WakeQueue {
+            state: Arc::new(Mutex::new(QueueState {
+                available: max_concurrent_wakes,
+                virtual_time: 0.0,
+                tenants: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Queue of a single tenant, whose share of wakes is proportional to `weight`.
+    pub fn for_tenant(&self, tenant: &str, weight: u32) -> TenantWakeQueue {
+        TenantWakeQueue {
+            queue: self.clone(),
+            tenant: tenant.to_owned(),
+            weight: weight.max(1) as f64,
+        }
+    }
+
+    /// Hand a released slot to the next waiting wake, or return it to the pool.
+    fn release(&self, tenant: String) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let mut permit = WakePermit {
+            queue: None,
+            tenant,
+        };
+        while let Some(next) = state.next_tenant() {
+            let Some((weight, sender)) = state
+                .tenants
+                .get_mut(&next)
+                .and_then(|tenant| tenant.waiting.pop_front())
+            else {
+                continue;
+            };
+            state.charge(&next, weight);
+            permit.queue = Some(self.clone());
+            permit.tenant = next;
+            match sender.send(permit) {
+                Ok(()) => return,
+                // the wake was cancelled while waiting, disarm the permit and try the next one
+                Err(mut returned) => {
+                    returned.queue = None;
+                    permit = returned;
+                }
+            }
+        }
+        state.available += 1;
+    }
+}
+
+/// The share of a `WakeQueue` of one tenant.
+#[derive(Clone)]
+pub struct TenantWakeQueue {
+    queue: WakeQueue,
+    tenant: String,
+    weight: f64,
+}
+
+impl TenantWakeQueue {
+    /// Wait for a free wake slot, which is held until the permit is dropped.
+    pub async fn acquire(&self) -> WakePermit {
+        let start = Instant::now();
+        let receiver = {
+            let mut state = self.queue.state.lock().unwrap_or_else(|e| e.into_inner());
+            if state.available > 0 {
+                state.available -= 1;
+                state.charge(&self.tenant, self.weight);
+                None
+            } else {
+                let (sender, receiver) = oneshot::channel();
+                state
+                    .tenants
+                    .entry(self.tenant.clone())
+                    .or_default()
+                    .waiting
+                    .push_back((self.weight, sender));
+                Some(receiver)
+            }
+        };
+        let permit = match receiver {
+            None => WakePermit {
+                queue: Some(self.queue.clone()),
+                tenant: self.tenant.clone(),
+            },
+            Some(receiver) => {
+                debug!("Waiting for a free wake slot for tenant {}.", self.tenant);
+                receiver
+                    .await
+                    .expect("Wake queue dropped a waiting wake without a permit")
+            }
+        };
+        metrics::WAKE_QUEUE_WAIT
+            .with_label_values(&[&self.tenant])
+            .observe(start.elapsed().as_secs_f64());
+        permit
+    }
+}
+
+/// A slot of a `WakeQueue`, released when dropped.
+pub struct WakePermit {
+    queue: Option<WakeQueue>,
+    tenant: String,
+}
+
+impl Drop for WakePermit {
+    fn drop(&mut self) {
+        if let Some(queue) = self.queue.take() {
+            queue.release(std::mem::take(&mut self.tenant));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::poll;
+
+    fn waiting_state(tenants: &[&str]) -> QueueState {
+        let mut state = QueueState {
+            available: 0,
+            virtual_time: 0.0,
+            tenants: HashMap::new(),
+        };
+        for tenant in tenants {
+            let (sender, _) = oneshot::channel();
+            state
+                .tenants
+                .entry(tenant.to_string())
+                .or_default()
+                .waiting
+                .push_back((1.0, sender));
+        }
+        state
+    }
+
+    #[test]
+    fn serves_tenants_in_proportion_to_their_weights() {
+        let mut state = waiting_state(&["light", "heavy"]);
+        let mut granted = HashMap::new();
+        for _ in 0..8 {
+            let next = state.next_tenant().unwrap();
+            let weight = if next == "heavy" { 3.0 } else { 1.0 };
+            state.charge(&next, weight);
+            *granted.entry(next).or_insert(0) += 1;
+        }
+        assert_eq!(granted["heavy"], 6);
+        assert_eq!(granted["light"], 2);
+    }
+
+    #[test]
+    fn idle_tenants_do_not_bank_their_share() {
+        let mut state = waiting_state(&[]);
+        for _ in 0..10 {
+            state.charge("busy", 1.0);
+        }
+        // a tenant arriving later starts at the current virtual time, not at zero
+        state.charge("late", 1.0);
+        assert_eq!(state.tenants["late"].finish, state.virtual_time + 1.0);
+    }
+
+    #[tokio::test]
+    async fn release_skips_cancelled_waiters() {
+        let queue = WakeQueue::new(1);
+        let tenant = queue.for_tenant("team", 1);
+        let permit = tenant.acquire().await;
+
+        let mut cancelled = Box::pin(tenant.acquire());
+        assert!(poll!(cancelled.as_mut()).is_pending());
+        drop(cancelled);
+        let mut waiting = Box::pin(tenant.acquire());
+        assert!(poll!(waiting.as_mut()).is_pending());
+
+        drop(permit);
+        let permit = waiting.await;
+        assert_eq!(queue.state.lock().unwrap().available, 0);
+        drop(permit);
+        assert_eq!(queue.state.lock().unwrap().available, 1);
+    }
+}
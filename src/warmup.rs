@@ -0,0 +1,177 @@
+use crate::endpoint_watcher::EndpointWatcherHandle;
+use crate::metrics;
+use crate::resolver::BackendResolver;
+use crate::tasks;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use futures::future::join_all;
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{net::TcpStream, sync::watch};
+use tracing::*;
+
+/// How long pre-established connections are held for clients before they are closed
+const PREWARM_HOLD: Duration = Duration::from_secs(10);
+
+/// How much of the connection path to prepare as soon as the backend wakes up.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum WarmupMode {
+    /// Resolve the backend for every connection
+    Off,
+    /// Resolve the backend once it wakes and reuse the addresses for client connections
+    Resolve,
+    /// Additionally open and discard a connection to populate ARP and conntrack tables
+    Connect,
+}
+
+struct Warmup {
+    backend_host: String,
+    backend_port: u16,
+    resolver: BackendResolver,
+    mode: WarmupMode,
+    prewarm_connections: usize,
+    endpoints: EndpointWatcherHandle,
+    sender: watch::Sender<Vec<SocketAddr>>,
+    pool: Arc<Mutex<Vec<TcpStream>>>,
+}
+
+impl Warmup {
+    /// Prepare the connection path and report how long each phase took.
+    async fn warm_up(&self) -> Result<()> {
+        let start = Instant::now();
+        let addrs = self
+            .resolver
+            .lookup(&self.backend_host, self.backend_port)
+            .await?;
+        let resolve = start.elapsed();
+        metrics::WARMUP_DURATION
+            .with_label_values(&[&self.backend_host, "resolve"])
+            .observe(resolve.as_secs_f64());
+        self.sender.send_replace(addrs.clone());
+
+        let mut connect = None;
+        if self.mode == WarmupMode::Connect {
+            let start = Instant::now();
+            drop(TcpStream::connect(&addrs[..]).await?);
+            let elapsed = start.elapsed();
+            metrics::WARMUP_DURATION
+                .with_label_values(&[&self.backend_host, "connect"])
+                .observe(elapsed.as_secs_f64());
+            connect = Some(elapsed);
+        }
+        info!(
+            "Warmed up connection path to {}:{} (resolve {resolve:?}, connect {connect:?}), saving that time on the first client connection.",
+            self.backend_host, self.backend_port
+        );
+        if self.prewarm_connections > 0 {
+            self.prewarm(&addrs).await;
+        }
+        Ok(())
+    }
+
+    /// Open connections for the clients queued during the wake and hold them for a while.
+    async fn prewarm(&self, addrs: &[SocketAddr]) {
+        let connects = (0..self.prewarm_connections).map(|_| TcpStream::connect(addrs));
+        let connections: Vec<TcpStream> = join_all(connects)
+            .await
+            .into_iter()
+            .filter_map(|connection| match connection {
+                Ok(connection) => Some(connection),
+                Err(e) => {
+                    debug!("Could not pre-establish a connection to backend: {e}");
+                    None
+                }
+            })
+            .collect();
+        info!(
+            "Pre-established {} of {} connections to {}:{}.",
+            connections.len(),
+            self.prewarm_connections,
+            self.backend_host,
+            self.backend_port
+        );
+        *self.pool.lock().unwrap_or_else(|e| e.into_inner()) = connections;
+
+        let pool = self.pool.clone();
+        tasks::spawn("prewarm-expiry", async move {
+            tokio::time::sleep(PREWARM_HOLD).await;
+            let unused = std::mem::take(&mut *pool.lock().unwrap_or_else(|e| e.into_inner()));
+            if !unused.is_empty() {
+                debug!(
+                    "Closing {} unused pre-established connections.",
+                    unused.len()
+                );
+            }
+        });
+    }
+
+    async fn run(mut self) {
+        let mut serving = self.endpoints.backend_is_serving();
+        loop {
+            let changed = self
+                .endpoints
+                .wait_for(|status| (status.backend > 0) != serving)
+                .await;
+            if let Err(e) = changed {
+                warn!("Stopped warming up connection path to backend: {e}");
+                return;
+            }
+            serving = !serving;
+            if serving {
+                if let Err(e) = self.warm_up().await {
+                    warn!("Could not warm up connection path to backend: {e}");
+                }
+            }
+        }
+    }
+}
+
+/// Resolved backend addresses, refreshed each time the backend wakes up.
+#[derive(Clone)]
+pub struct WarmupHandle {
+    receiver: watch::Receiver<Vec<SocketAddr>>,
+    pool: Arc<Mutex<Vec<TcpStream>>>,
+}
+
+impl WarmupHandle {
+    pub fn new(
+        backend_host: &str,
+        backend_port: u16,
+        resolver: BackendResolver,
+        mode: WarmupMode,
+        prewarm_connections: usize,
+        endpoints: EndpointWatcherHandle,
+    ) -> Option<Self> {
+        if mode == WarmupMode::Off && prewarm_connections == 0 {
+            return None;
+        }
+        let (sender, receiver) = watch::channel(Vec::new());
+        let pool = Arc::new(Mutex::new(Vec::new()));
+        let warmup = Warmup {
+            backend_host: backend_host.to_owned(),
+            backend_port,
+            resolver,
+            mode,
+            prewarm_connections,
+            endpoints,
+            sender,
+            pool: pool.clone(),
+        };
+        tasks::spawn("warmup", warmup.run());
+        Some(WarmupHandle { receiver, pool })
+    }
+
+    /// Addresses of the backend resolved during the last wake, if any.
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.receiver.borrow().clone()
+    }
+
+    /// Take one of the connections pre-established after the last wake, if any are left.
+    pub fn take_connection(&self) -> Option<TcpStream> {
+        self.pool.lock().unwrap_or_else(|e| e.into_inner()).pop()
+    }
+}